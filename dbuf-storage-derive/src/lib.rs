@@ -0,0 +1,103 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitStr, Type};
+
+/// Implements `dbuf_storage::DbufMessage` for a struct with `body` and
+/// `dependencies` fields, as emitted by DependoBuf codegen.
+///
+/// Messages are stored in the collection named by `#[dbuf(collection = "...")]`,
+/// or by the struct name in snake case without it.
+#[proc_macro_derive(DbufMessage, attributes(dbuf))]
+pub fn derive_dbuf_message(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "DbufMessage cannot be derived for generic types",
+        ));
+    }
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(not_a_message(input)),
+        },
+        _ => return Err(not_a_message(input)),
+    };
+
+    let field_type = |name: &str| -> syn::Result<&Type> {
+        fields
+            .iter()
+            .find(|field| field.ident.as_ref().is_some_and(|ident| ident == name))
+            .map(|field| &field.ty)
+            .ok_or_else(|| not_a_message(input))
+    };
+
+    let body = field_type("body")?;
+    let dependencies = field_type("dependencies")?;
+    let collection = collection_name(input)?;
+    let name = &input.ident;
+
+    Ok(quote! {
+        impl ::dbuf_storage::DbufMessage for #name {
+            type Body = #body;
+            type Dependencies = #dependencies;
+
+            const COLLECTION: &'static str = #collection;
+        }
+    })
+}
+
+fn not_a_message(input: &DeriveInput) -> syn::Error {
+    syn::Error::new_spanned(
+        &input.ident,
+        "DbufMessage can only be derived for structs with `body` and `dependencies` fields",
+    )
+}
+
+fn collection_name(input: &DeriveInput) -> syn::Result<String> {
+    let mut collection = None;
+
+    for attr in &input.attrs {
+        if !attr.path().is_ident("dbuf") {
+            continue;
+        }
+
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("collection") {
+                let name: LitStr = meta.value()?.parse()?;
+                collection = Some(name.value());
+                Ok(())
+            } else {
+                Err(meta.error("unsupported dbuf attribute"))
+            }
+        })?;
+    }
+
+    Ok(collection.unwrap_or_else(|| snake_case(&input.ident.to_string())))
+}
+
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+
+    snake
+}
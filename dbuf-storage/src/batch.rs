@@ -0,0 +1,282 @@
+use serde::Serialize;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree,
+};
+
+use crate::{Collection, DbError, Subcollection};
+
+/// How `insert_many`, `update_many` and `delete_many` treat items that
+/// cannot be written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BatchMode {
+    /// The first failing item fails the whole call and nothing is written.
+    #[default]
+    Atomic,
+    /// Failing items are reported in the results, all others are written.
+    PerItem,
+}
+
+/// Outcome of every item of a batch, in input order.
+pub type BatchResults<T> = Vec<Result<T, DbError>>;
+
+impl Collection {
+    /// Inserts all messages in one transaction with a single flush and
+    /// returns their ids.
+    pub fn insert_many<T: Serialize>(
+        &self,
+        values: &[T],
+        mode: BatchMode,
+    ) -> Result<BatchResults<String>, DbError> {
+        let jsons = values
+            .iter()
+            .map(|value| {
+                serde_json::to_string(value).map_err(|e| DbError::SerializationError(e.to_string()))
+            })
+            .collect();
+
+        self.insert_many_checked(jsons, mode)
+    }
+
+    pub fn insert_many_json(
+        &self,
+        jsons: Vec<String>,
+        mode: BatchMode,
+    ) -> Result<BatchResults<String>, DbError> {
+        self.insert_many_checked(jsons.into_iter().map(Ok).collect(), mode)
+    }
+
+    pub fn update_many<T: Serialize>(
+        &self,
+        items: &[(String, T)],
+        mode: BatchMode,
+    ) -> Result<BatchResults<()>, DbError> {
+        let items = items
+            .iter()
+            .map(|(id, value)| {
+                let json = serde_json::to_string(value)
+                    .map_err(|e| DbError::SerializationError(e.to_string()))?;
+                Ok((id.clone(), json))
+            })
+            .collect();
+
+        self.update_many_checked(items, mode)
+    }
+
+    pub fn update_many_json(
+        &self,
+        items: Vec<(String, String)>,
+        mode: BatchMode,
+    ) -> Result<BatchResults<()>, DbError> {
+        self.update_many_checked(items.into_iter().map(Ok).collect(), mode)
+    }
+
+    pub fn delete_many(
+        &self,
+        ids: &[String],
+        mode: BatchMode,
+    ) -> Result<BatchResults<()>, DbError> {
+        self.delete_many_checked(ids.iter().cloned().map(Ok).collect(), mode)
+    }
+
+    fn insert_many_checked(
+        &self,
+        jsons: Vec<Result<String, DbError>>,
+        mode: BatchMode,
+    ) -> Result<BatchResults<String>, DbError> {
+        let items = jsons
+            .into_iter()
+            .map(|json| self.prepare_message(&json?))
+            .collect();
+
+        self.write_many(items, mode, |tx_tree, message| {
+            self.insert_new(tx_tree, message)
+        })
+    }
+
+    fn update_many_checked(
+        &self,
+        items: Vec<Result<(String, String), DbError>>,
+        mode: BatchMode,
+    ) -> Result<BatchResults<()>, DbError> {
+        let items = items
+            .into_iter()
+            .map(|item| {
+                let (id, json) = item?;
+                Ok((id, self.prepare_message(&json)?))
+            })
+            .collect();
+
+        self.write_many(items, mode, |tx_tree, (id, message)| {
+            self.update_prepared(tx_tree, id, message)?;
+            Ok(())
+        })
+    }
+
+    fn delete_many_checked(
+        &self,
+        ids: Vec<Result<String, DbError>>,
+        mode: BatchMode,
+    ) -> Result<BatchResults<()>, DbError> {
+        self.write_many(ids, mode, |tx_tree, id| self.delete_in(tx_tree, id))
+    }
+
+    // Applies `write` to all items that were prepared successfully in one
+    // transaction and flushes once. In `PerItem` mode an item failing inside
+    // the transaction is recorded and the transaction is run again without
+    // it, so none of its partial writes are kept.
+    fn write_many<P, T, F>(
+        &self,
+        items: Vec<Result<P, DbError>>,
+        mode: BatchMode,
+        write: F,
+    ) -> Result<BatchResults<T>, DbError>
+    where
+        F: Fn(&TransactionalTree, &P) -> ConflictableTransactionResult<T, DbError>,
+    {
+        self.check_writable()?;
+
+        let mut results = Vec::with_capacity(items.len());
+        let mut pending = Vec::new();
+
+        for (index, item) in items.into_iter().enumerate() {
+            match item {
+                Ok(item) => {
+                    pending.push((index, item));
+                    results.push(None);
+                }
+                Err(e) if mode == BatchMode::Atomic => return Err(e),
+                Err(e) => results.push(Some(Err(e))),
+            }
+        }
+
+        loop {
+            let outcome = self.tree.transaction(|tx_tree| {
+                let mut written = Vec::with_capacity(pending.len());
+
+                for (position, (_, item)) in pending.iter().enumerate() {
+                    match write(tx_tree, item) {
+                        Ok(value) => written.push(value),
+                        Err(ConflictableTransactionError::Abort(e)) => {
+                            return Err(ConflictableTransactionError::Abort((position, e)))
+                        }
+                        Err(ConflictableTransactionError::Conflict) => {
+                            return Err(ConflictableTransactionError::Conflict)
+                        }
+                        Err(ConflictableTransactionError::Storage(e)) => {
+                            return Err(ConflictableTransactionError::Storage(e))
+                        }
+                    }
+                }
+
+                Ok(written)
+            });
+
+            match outcome {
+                Ok(written) => {
+                    for ((index, _), value) in pending.iter().zip(written) {
+                        results[*index] = Some(Ok(value));
+                    }
+                    break;
+                }
+                Err(TransactionError::Abort((_, e))) if mode == BatchMode::Atomic => return Err(e),
+                Err(TransactionError::Abort((position, e))) => {
+                    let (index, _) = pending.remove(position);
+                    results[index] = Some(Err(e));
+                }
+                Err(TransactionError::Storage(e)) => return Err(DbError::from(e)),
+            }
+        }
+
+        if !pending.is_empty() {
+            self.flusher.commit()?;
+        }
+
+        Ok(results.into_iter().flatten().collect())
+    }
+}
+
+impl<'a> Subcollection<'a> {
+    pub fn insert_many<T: Serialize>(
+        &self,
+        bodies: &[T],
+        mode: BatchMode,
+    ) -> Result<BatchResults<String>, DbError> {
+        let jsons = bodies
+            .iter()
+            .map(|body| {
+                let body_json = serde_json::to_string(body).map_err(|e| {
+                    DbError::SerializationError(format!("Failed to serialize body: {}", e))
+                })?;
+                self.message_json(&body_json)
+            })
+            .collect();
+
+        self.collection.insert_many_checked(jsons, mode)
+    }
+
+    pub fn insert_many_json(
+        &self,
+        body_jsons: Vec<String>,
+        mode: BatchMode,
+    ) -> Result<BatchResults<String>, DbError> {
+        let jsons = body_jsons
+            .iter()
+            .map(|body_json| self.message_json(body_json))
+            .collect();
+
+        self.collection.insert_many_checked(jsons, mode)
+    }
+
+    pub fn update_many<T: Serialize>(
+        &self,
+        items: &[(String, T)],
+        mode: BatchMode,
+    ) -> Result<BatchResults<()>, DbError> {
+        let items = items
+            .iter()
+            .map(|(id, body)| {
+                let body_json = serde_json::to_string(body).map_err(|e| {
+                    DbError::SerializationError(format!("Failed to serialize body: {}", e))
+                })?;
+                self.check_belongs_to_subcollection(id)?;
+                Ok((id.clone(), self.message_json(&body_json)?))
+            })
+            .collect();
+
+        self.collection.update_many_checked(items, mode)
+    }
+
+    pub fn update_many_json(
+        &self,
+        items: Vec<(String, String)>,
+        mode: BatchMode,
+    ) -> Result<BatchResults<()>, DbError> {
+        let items = items
+            .into_iter()
+            .map(|(id, body_json)| {
+                self.check_belongs_to_subcollection(&id)?;
+                let json = self.message_json(&body_json)?;
+                Ok((id, json))
+            })
+            .collect();
+
+        self.collection.update_many_checked(items, mode)
+    }
+
+    pub fn delete_many(
+        &self,
+        ids: &[String],
+        mode: BatchMode,
+    ) -> Result<BatchResults<()>, DbError> {
+        let ids = ids
+            .iter()
+            .map(|id| {
+                self.check_belongs_to_subcollection(id)?;
+                Ok(id.clone())
+            })
+            .collect();
+
+        self.collection.delete_many_checked(ids, mode)
+    }
+}
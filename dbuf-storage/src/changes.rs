@@ -0,0 +1,368 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionalTree,
+};
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+
+use crate::keys::{change_key, CHANGE_PREFIX, CHANGE_SEQ_KEY};
+use crate::{Collection, DbError, Subcollection};
+
+/// Number of changes a collection keeps for `watch_from` unless its options
+/// say otherwise: none, the change feed is opt-in.
+pub const DEFAULT_CHANGES_RETAINED: usize = 0;
+
+/// A write to a collection, as seen by a `Watcher`. `seq` numbers the
+/// changes of a collection in commit order, without gaps.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ChangeEvent<T = Value> {
+    Insert {
+        seq: u64,
+        id: String,
+        new: T,
+    },
+    Update {
+        seq: u64,
+        id: String,
+        old: T,
+        new: T,
+    },
+    Delete {
+        seq: u64,
+        id: String,
+        old: T,
+    },
+}
+
+impl<T> ChangeEvent<T> {
+    pub fn seq(&self) -> u64 {
+        match self {
+            ChangeEvent::Insert { seq, .. }
+            | ChangeEvent::Update { seq, .. }
+            | ChangeEvent::Delete { seq, .. } => *seq,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        match self {
+            ChangeEvent::Insert { id, .. }
+            | ChangeEvent::Update { id, .. }
+            | ChangeEvent::Delete { id, .. } => id,
+        }
+    }
+}
+
+// Stored form of a change: the full messages before and after it.
+#[derive(Serialize, Deserialize)]
+struct ChangeRecord {
+    seq: u64,
+    id: String,
+    old: Option<Value>,
+    new: Option<Value>,
+}
+
+impl ChangeRecord {
+    fn from_slice(data: &[u8]) -> Result<Self, DbError> {
+        serde_json::from_slice(data)
+            .map_err(|e| DbError::DeserializationError(format!("Invalid change record: {}", e)))
+    }
+}
+
+/// Blocking iterator over the changes of a collection or subcollection,
+/// from `Collection::watch` and friends. It ends when the database is
+/// closed.
+pub struct Watcher<T = Value> {
+    tree: sled::Tree,
+    subscriber: sled::Subscriber,
+    // Changes read from the log but not yet handed out.
+    pending: VecDeque<ChangeRecord>,
+    next_seq: u64,
+    // Dependencies of the watched subcollection, if any.
+    dependencies: Option<Value>,
+    _event: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> Watcher<T> {
+    /// Sequence number of the next change this watcher will yield.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// Waits at most `timeout` for the next change; `Ok(None)` if none came.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<ChangeEvent<T>>, DbError> {
+        loop {
+            if let Some(event) = self.next_pending()? {
+                return Ok(Some(event));
+            }
+
+            match self.subscriber.next_timeout(timeout) {
+                Ok(event) => self.receive(event)?,
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(DbError::DatabaseError("Database was closed".to_string()))
+                }
+            }
+        }
+    }
+
+    fn next_pending(&mut self) -> Result<Option<ChangeEvent<T>>, DbError> {
+        while let Some(record) = self.pending.pop_front() {
+            if let Some(event) = self.event(record)? {
+                return Ok(Some(event));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Queues the change announced by the subscriber. sled may announce
+    // concurrent commits out of order, but a change is only committed after
+    // all earlier ones, so any gap is read back from the log.
+    fn receive(&mut self, event: sled::Event) -> Result<(), DbError> {
+        let data = match event {
+            sled::Event::Insert { value, .. } => value,
+            // Changes dropped from the log by retention.
+            sled::Event::Remove { .. } => return Ok(()),
+        };
+
+        let record = ChangeRecord::from_slice(&data)?;
+        if record.seq < self.next_seq {
+            return Ok(());
+        }
+
+        if record.seq > self.next_seq {
+            self.read_log(record.seq)?;
+        }
+
+        self.next_seq = record.seq + 1;
+        self.pending.push_back(record);
+
+        Ok(())
+    }
+
+    // Queues the logged changes from `next_seq` up to `end`, exclusive.
+    fn read_log(&mut self, end: u64) -> Result<(), DbError> {
+        for entry in self.tree.range(change_key(self.next_seq)..change_key(end)) {
+            let (_, data) = entry?;
+            let record = ChangeRecord::from_slice(&data)?;
+
+            self.next_seq = record.seq + 1;
+            self.pending.push_back(record);
+        }
+
+        Ok(())
+    }
+
+    // The change as seen from the watched subcollection, if it touches it.
+    fn event(&self, record: ChangeRecord) -> Result<Option<ChangeEvent<T>>, DbError> {
+        let (old, new) = match &self.dependencies {
+            None => (record.old, record.new),
+            Some(dependencies) => (
+                body_in(record.old, dependencies),
+                body_in(record.new, dependencies),
+            ),
+        };
+
+        let seq = record.seq;
+        let id = record.id;
+
+        let event = match (old, new) {
+            (None, None) => return Ok(None),
+            (None, Some(new)) => ChangeEvent::Insert {
+                seq,
+                id,
+                new: decode(new)?,
+            },
+            (Some(old), Some(new)) => ChangeEvent::Update {
+                seq,
+                id,
+                old: decode(old)?,
+                new: decode(new)?,
+            },
+            (Some(old), None) => ChangeEvent::Delete {
+                seq,
+                id,
+                old: decode(old)?,
+            },
+        };
+
+        Ok(Some(event))
+    }
+}
+
+impl<T: DeserializeOwned> Iterator for Watcher<T> {
+    type Item = Result<ChangeEvent<T>, DbError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_pending() {
+                Ok(Some(event)) => return Some(Ok(event)),
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
+            }
+
+            let event = self.subscriber.next()?;
+            if let Err(e) = self.receive(event) {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+// The body of a message of the subcollection with these dependencies.
+pub(crate) fn body_in(message: Option<Value>, dependencies: &Value) -> Option<Value> {
+    match message {
+        Some(mut message) if &message["dependencies"] == dependencies => {
+            Some(message["body"].take())
+        }
+        _ => None,
+    }
+}
+
+fn decode<T: DeserializeOwned>(value: Value) -> Result<T, DbError> {
+    serde_json::from_value(value)
+        .map_err(|e| DbError::DeserializationError(format!("Failed to deserialize change: {}", e)))
+}
+
+impl Collection {
+    /// Sequence number of the last change, 0 before the first one.
+    pub fn last_change_seq(&self) -> Result<u64, DbError> {
+        read_seq(self.tree.get(CHANGE_SEQ_KEY)?.as_deref())
+    }
+
+    /// Watches the changes made from now on; `T` is the message type. Fails
+    /// with `DbError::NotFound` if the collection has no change feed.
+    pub fn watch<T: DeserializeOwned>(&self) -> Result<Watcher<T>, DbError> {
+        self.watcher(None, None)
+    }
+
+    /// Watches the changes from sequence number `seq` on, starting with
+    /// those already made. Pass the `seq` of the last change seen plus one
+    /// to resume a watch. Fails with `DbError::NotFound` if some of these
+    /// changes are no longer retained.
+    pub fn watch_from<T: DeserializeOwned>(&self, seq: u64) -> Result<Watcher<T>, DbError> {
+        self.watcher(Some(seq), None)
+    }
+
+    pub fn get_changes_retained(&self) -> usize {
+        self.metadata.changes_retained
+    }
+
+    fn watcher<T: DeserializeOwned>(
+        &self,
+        from: Option<u64>,
+        dependencies: Option<Value>,
+    ) -> Result<Watcher<T>, DbError> {
+        if self.metadata.changes_retained == 0 {
+            return Err(DbError::NotFound);
+        }
+
+        // Subscribe first so that no change slips between reading the log
+        // and listening for new entries.
+        let subscriber = self.tree.watch_prefix(CHANGE_PREFIX);
+        let last_seq = self.last_change_seq()?;
+
+        let mut watcher = Watcher {
+            tree: self.tree.clone(),
+            subscriber,
+            pending: VecDeque::new(),
+            next_seq: last_seq + 1,
+            dependencies,
+            _event: PhantomData,
+        };
+
+        if let Some(from) = from {
+            let from = from.max(1);
+
+            if from <= last_seq {
+                let oldest = match self.tree.scan_prefix(CHANGE_PREFIX).next() {
+                    Some(entry) => ChangeRecord::from_slice(&entry?.1)?.seq,
+                    None => last_seq + 1,
+                };
+
+                if from < oldest {
+                    return Err(DbError::NotFound);
+                }
+            }
+
+            watcher.next_seq = from;
+            watcher.read_log(last_seq + 1)?;
+        }
+
+        Ok(watcher)
+    }
+
+    // Appends a change to the log in the transaction making it, dropping
+    // the oldest one beyond the retention limit.
+    pub(crate) fn record_change(
+        &self,
+        tx_tree: &TransactionalTree,
+        id: &str,
+        old: Option<Value>,
+        new: Option<Value>,
+    ) -> ConflictableTransactionResult<(), DbError> {
+        let retained = self.metadata.changes_retained as u64;
+        if retained == 0 {
+            return Ok(());
+        }
+
+        let seq = read_seq(tx_tree.get(CHANGE_SEQ_KEY)?.as_deref())
+            .map_err(ConflictableTransactionError::Abort)?
+            + 1;
+
+        let record = ChangeRecord {
+            seq,
+            id: id.to_string(),
+            old,
+            new,
+        };
+        let data = serde_json::to_vec(&record).map_err(|e| {
+            ConflictableTransactionError::Abort(DbError::SerializationError(format!(
+                "Failed to serialize change: {}",
+                e
+            )))
+        })?;
+
+        tx_tree.insert(CHANGE_SEQ_KEY, &seq.to_be_bytes())?;
+        tx_tree.insert(change_key(seq), data)?;
+
+        if seq > retained {
+            tx_tree.remove(change_key(seq - retained))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> Subcollection<'a> {
+    /// Watches the changes of this subcollection made from now on; `T` is
+    /// the body type. An update moving an item to other dependencies is
+    /// seen as a delete here and as an insert in its new subcollection.
+    pub fn watch<T: DeserializeOwned>(&self) -> Result<Watcher<T>, DbError> {
+        self.collection
+            .watcher(None, Some(self.dependencies.clone()))
+    }
+
+    /// See `Collection::watch_from`; sequence numbers are those of the
+    /// whole collection.
+    pub fn watch_from<T: DeserializeOwned>(&self, seq: u64) -> Result<Watcher<T>, DbError> {
+        self.collection
+            .watcher(Some(seq), Some(self.dependencies.clone()))
+    }
+}
+
+fn read_seq(data: Option<&[u8]>) -> Result<u64, DbError> {
+    match data {
+        Some(data) => {
+            let bytes: [u8; 8] = data.try_into().map_err(|_| {
+                DbError::DatabaseError("Corrupted change sequence number".to_string())
+            })?;
+            Ok(u64::from_be_bytes(bytes))
+        }
+        None => Ok(0),
+    }
+}
@@ -0,0 +1,226 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionalTree,
+};
+
+use crate::keys::{item_key, CODEC_KEY};
+use crate::{Collection, DbError};
+
+/// Encoding of the items of a collection on disk.
+///
+/// Binary encodings start with a tag byte, while JSON items are plain JSON
+/// text as in older versions, so every item can be decoded without knowing
+/// the codec of its collection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageCodec {
+    #[default]
+    Json,
+    Cbor,
+    MessagePack,
+    Bincode,
+}
+
+const CBOR_TAG: u8 = 1;
+const MESSAGE_PACK_TAG: u8 = 2;
+const BINCODE_TAG: u8 = 3;
+
+impl StorageCodec {
+    pub(crate) fn encode(self, value: &Value) -> Result<Vec<u8>, DbError> {
+        let encoding_error = |e: &dyn std::fmt::Display| {
+            DbError::SerializationError(format!("Failed to encode item as {:?}: {}", self, e))
+        };
+
+        match self {
+            StorageCodec::Json => serde_json::to_vec(value).map_err(|e| encoding_error(&e)),
+            StorageCodec::Cbor => {
+                let mut data = vec![CBOR_TAG];
+                ciborium::into_writer(value, &mut data).map_err(|e| encoding_error(&e))?;
+                Ok(data)
+            }
+            StorageCodec::MessagePack => {
+                let mut data = vec![MESSAGE_PACK_TAG];
+                rmp_serde::encode::write(&mut data, value).map_err(|e| encoding_error(&e))?;
+                Ok(data)
+            }
+            StorageCodec::Bincode => {
+                let mut data = vec![BINCODE_TAG];
+                bincode::serialize_into(&mut data, &BinaryValue::from(value))
+                    .map_err(|e| encoding_error(&e))?;
+                Ok(data)
+            }
+        }
+    }
+}
+
+// The codec an item was written with.
+fn detect(data: &[u8]) -> StorageCodec {
+    match data.first() {
+        Some(&CBOR_TAG) => StorageCodec::Cbor,
+        Some(&MESSAGE_PACK_TAG) => StorageCodec::MessagePack,
+        Some(&BINCODE_TAG) => StorageCodec::Bincode,
+        _ => StorageCodec::Json,
+    }
+}
+
+pub(crate) fn decode(data: &[u8]) -> Result<Value, DbError> {
+    let decoding_error = |e: &dyn std::fmt::Display| {
+        DbError::DeserializationError(format!("Failed to decode item: {}", e))
+    };
+
+    match detect(data) {
+        StorageCodec::Json => serde_json::from_slice(data).map_err(|e| decoding_error(&e)),
+        StorageCodec::Cbor => ciborium::from_reader(&data[1..]).map_err(|e| decoding_error(&e)),
+        StorageCodec::MessagePack => {
+            rmp_serde::from_slice(&data[1..]).map_err(|e| decoding_error(&e))
+        }
+        StorageCodec::Bincode => bincode::deserialize::<BinaryValue>(&data[1..])
+            .map(Value::from)
+            .map_err(|e| decoding_error(&e)),
+    }
+}
+
+fn parse_codec(data: Option<&[u8]>) -> Result<StorageCodec, DbError> {
+    match data {
+        Some(data) => serde_json::from_slice(data).map_err(|e| {
+            DbError::DeserializationError(format!("Failed to deserialize codec: {}", e))
+        }),
+        None => Ok(StorageCodec::default()),
+    }
+}
+
+pub(crate) fn serialize_codec(codec: StorageCodec) -> Result<Vec<u8>, DbError> {
+    serde_json::to_vec(&codec)
+        .map_err(|e| DbError::SerializationError(format!("Failed to serialize codec: {}", e)))
+}
+
+pub(crate) fn load_codec(tree: &sled::Tree) -> Result<StorageCodec, DbError> {
+    parse_codec(tree.get(CODEC_KEY)?.as_deref())
+}
+
+// Writers read the codec inside their transaction, so once a collection is
+// switched to another codec no handle writes the old one any more.
+pub(crate) fn load(
+    tx_tree: &TransactionalTree,
+) -> ConflictableTransactionResult<StorageCodec, DbError> {
+    parse_codec(tx_tree.get(CODEC_KEY)?.as_deref()).map_err(ConflictableTransactionError::Abort)
+}
+
+impl Collection {
+    pub fn get_codec(&self) -> Result<StorageCodec, DbError> {
+        load_codec(&self.tree)
+    }
+
+    /// Switches the collection to `codec` and rewrites its items with it,
+    /// returning the number of rewritten items. Every handle writes with the
+    /// new codec as soon as it is switched, and existing items are converted
+    /// one transaction at a time, so the collection stays usable meanwhile.
+    /// Items are decoded by their tag byte rather than by the codec of the
+    /// collection, so reads see both encodings alike during the rewrite, and
+    /// an interrupted run can simply be repeated. Fails with
+    /// `DbError::Conflict` if the collection is switched to another codec
+    /// before the rewrite is over.
+    pub fn reencode(&self, codec: StorageCodec) -> Result<usize, DbError> {
+        self.check_writable()?;
+
+        let codec_data = serialize_codec(codec)?;
+        self.tree.transaction(|tx_tree| {
+            tx_tree.insert(CODEC_KEY, codec_data.as_slice())?;
+            Ok(())
+        })?;
+
+        let mut rewritten = 0;
+
+        for id in self.keys() {
+            let id = id?;
+
+            let changed = self.tree.transaction(|tx_tree| {
+                let current = load(tx_tree)?;
+                if current != codec {
+                    return Err(ConflictableTransactionError::Abort(DbError::Conflict(
+                        format!("Collection was switched to {:?} meanwhile", current),
+                    )));
+                }
+
+                let item_data = match tx_tree.get(item_key(&id))? {
+                    Some(data) if detect(&data) != codec => data,
+                    _ => return Ok(false),
+                };
+
+                let storage_value =
+                    decode(&item_data).map_err(ConflictableTransactionError::Abort)?;
+                let storage_data = codec
+                    .encode(&storage_value)
+                    .map_err(ConflictableTransactionError::Abort)?;
+                tx_tree.insert(item_key(&id), storage_data)?;
+
+                Ok(true)
+            })?;
+
+            if changed {
+                rewritten += 1;
+            }
+        }
+
+        self.flusher.commit()?;
+        Ok(rewritten)
+    }
+}
+
+// bincode is not self-describing and cannot decode a `Value` directly, so
+// values are stored through this mirror with explicit variants.
+#[derive(Serialize, Deserialize)]
+enum BinaryValue {
+    Null,
+    Bool(bool),
+    PositiveInt(u64),
+    NegativeInt(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<BinaryValue>),
+    Object(Vec<(String, BinaryValue)>),
+}
+
+impl From<&Value> for BinaryValue {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Null => BinaryValue::Null,
+            Value::Bool(b) => BinaryValue::Bool(*b),
+            Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+                (Some(u), _) => BinaryValue::PositiveInt(u),
+                (None, Some(i)) => BinaryValue::NegativeInt(i),
+                (None, None) => BinaryValue::Float(n.as_f64().unwrap_or_default()),
+            },
+            Value::String(s) => BinaryValue::String(s.clone()),
+            Value::Array(items) => {
+                BinaryValue::Array(items.iter().map(BinaryValue::from).collect())
+            }
+            Value::Object(map) => BinaryValue::Object(
+                map.iter()
+                    .map(|(key, value)| (key.clone(), BinaryValue::from(value)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<BinaryValue> for Value {
+    fn from(value: BinaryValue) -> Self {
+        match value {
+            BinaryValue::Null => Value::Null,
+            BinaryValue::Bool(b) => Value::Bool(b),
+            BinaryValue::PositiveInt(u) => Value::Number(u.into()),
+            BinaryValue::NegativeInt(i) => Value::Number(i.into()),
+            BinaryValue::Float(f) => Number::from_f64(f).map_or(Value::Null, Value::Number),
+            BinaryValue::String(s) => Value::String(s),
+            BinaryValue::Array(items) => Value::Array(items.into_iter().map(Value::from).collect()),
+            BinaryValue::Object(entries) => Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, Value::from(value)))
+                    .collect::<Map<String, Value>>(),
+            ),
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use serde::Serialize;
+use serde_json::Value;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionalTree,
+};
+use std::error::Error;
+use std::fmt;
+
+use crate::helper::canonicalize;
+use crate::id::check_id;
+use crate::keys::member_key;
+use crate::{Collection, DbError, PreparedMessage, Subcollection};
+
+/// Returned by `compare_and_swap` when the stored item is not the expected
+/// one. Nothing was written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompareAndSwapError {
+    /// JSON of the item as stored, `None` if there is no such item. A full
+    /// message for collections, a body for subcollections.
+    pub current: Option<String>,
+}
+
+impl fmt::Display for CompareAndSwapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.current {
+            Some(_) => write!(
+                f,
+                "Compare and swap failed: item differs from the expected one"
+            ),
+            None => write!(f, "Compare and swap failed: item does not exist"),
+        }
+    }
+}
+
+impl Error for CompareAndSwapError {}
+
+/// Outcome of `compare_and_swap`: the outer error reports a failed write,
+/// the inner one a stored item that did not match.
+pub type CompareAndSwapResult = Result<Result<(), CompareAndSwapError>, DbError>;
+
+impl Collection {
+    /// Inserts the message under `id`, or replaces the item stored there.
+    /// Returns whether the item was created.
+    pub fn upsert<T: Serialize>(&self, id: &str, value: &T) -> Result<bool, DbError> {
+        self.upsert_json(id, to_json(value)?)
+    }
+
+    pub fn upsert_json(&self, id: &str, json: String) -> Result<bool, DbError> {
+        self.upsert_scoped(id, json, None)
+    }
+
+    /// Inserts the message under `id` unless an item is stored there.
+    /// Returns whether it was inserted.
+    pub fn insert_if_absent<T: Serialize>(&self, id: &str, value: &T) -> Result<bool, DbError> {
+        self.insert_if_absent_json(id, to_json(value)?)
+    }
+
+    pub fn insert_if_absent_json(&self, id: &str, json: String) -> Result<bool, DbError> {
+        self.check_writable()?;
+        check_id(id)?;
+
+        let message = self.prepare_message(&json)?;
+
+        let inserted = self.tree.transaction(|tx_tree| {
+            if self.live_item_in(tx_tree, id)?.is_some() {
+                return Ok(false);
+            }
+
+            self.insert_prepared(tx_tree, id, &message)?;
+            Ok(true)
+        })?;
+
+        if inserted {
+            self.flusher.commit()?;
+        }
+
+        Ok(inserted)
+    }
+
+    /// Atomically replaces the item under `id` with `new` if it currently
+    /// equals `expected`. `None` stands for a missing item on both sides,
+    /// so `expected = None` inserts and `new = None` deletes.
+    pub fn compare_and_swap<T: Serialize>(
+        &self,
+        id: &str,
+        expected: Option<&T>,
+        new: Option<&T>,
+    ) -> CompareAndSwapResult {
+        self.compare_and_swap_json(
+            id,
+            expected.map(to_json).transpose()?,
+            new.map(to_json).transpose()?,
+        )
+    }
+
+    pub fn compare_and_swap_json(
+        &self,
+        id: &str,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> CompareAndSwapResult {
+        self.compare_and_swap_scoped(id, expected, new, None)
+    }
+
+    // `scope` is the dependencies hash of the subcollection the item must
+    // belong to, if any.
+    fn upsert_scoped(&self, id: &str, json: String, scope: Option<&str>) -> Result<bool, DbError> {
+        self.check_writable()?;
+        check_id(id)?;
+
+        let message = self.prepare_message(&json)?;
+
+        let (created, written) = self.tree.transaction(|tx_tree| {
+            if !self.exists_in_scope(tx_tree, id, scope)? {
+                self.insert_prepared(tx_tree, id, &message)?;
+                return Ok((true, true));
+            }
+
+            Ok((false, self.update_prepared(tx_tree, id, &message)?))
+        })?;
+
+        if written {
+            self.flusher.commit()?;
+        }
+
+        Ok(created)
+    }
+
+    fn compare_and_swap_scoped(
+        &self,
+        id: &str,
+        expected: Option<String>,
+        new: Option<String>,
+        scope: Option<&str>,
+    ) -> CompareAndSwapResult {
+        self.check_writable()?;
+        check_id(id)?;
+
+        let expected = expected.as_deref().map(parse_json).transpose()?;
+        let new = new
+            .as_deref()
+            .map(|json| self.prepare_message(json))
+            .transpose()?;
+
+        let result = self.tree.transaction(|tx_tree| {
+            self.compare_and_swap_in(tx_tree, id, expected.as_ref(), new.as_ref(), scope)
+        })?;
+
+        if let Ok(true) = result {
+            self.flusher.commit()?;
+        }
+
+        Ok(result.map(|_| ()))
+    }
+
+    // Returns whether anything was written, or the current item if it is
+    // not the expected one.
+    fn compare_and_swap_in(
+        &self,
+        tx_tree: &TransactionalTree,
+        id: &str,
+        expected: Option<&Value>,
+        new: Option<&PreparedMessage>,
+        scope: Option<&str>,
+    ) -> ConflictableTransactionResult<Result<bool, CompareAndSwapError>, DbError> {
+        let current = if self.exists_in_scope(tx_tree, id, scope)? {
+            Some(self.get_json_in(tx_tree, id)?)
+        } else {
+            None
+        };
+
+        let current_value = current
+            .as_deref()
+            .map(parse_json)
+            .transpose()
+            .map_err(ConflictableTransactionError::Abort)?;
+
+        if current_value.as_ref().map(canonicalize) != expected.map(canonicalize) {
+            return Ok(Err(CompareAndSwapError { current }));
+        }
+
+        let written = match (current, new) {
+            (None, None) => false,
+            (None, Some(message)) => {
+                self.insert_prepared(tx_tree, id, message)?;
+                true
+            }
+            (Some(_), Some(message)) => self.update_prepared(tx_tree, id, message)?,
+            (Some(_), None) => {
+                self.delete_in(tx_tree, id)?;
+                true
+            }
+        };
+
+        Ok(Ok(written))
+    }
+
+    // Whether an unexpired item is stored under `id`. Items of other
+    // subcollections than `scope` are neither visible nor replaceable
+    // through it.
+    pub(crate) fn exists_in_scope(
+        &self,
+        tx_tree: &TransactionalTree,
+        id: &str,
+        scope: Option<&str>,
+    ) -> ConflictableTransactionResult<bool, DbError> {
+        if self.live_item_in(tx_tree, id)?.is_none() {
+            return Ok(false);
+        }
+
+        if let Some(deps_hash) = scope {
+            if tx_tree.get(member_key(deps_hash, id))?.is_none() {
+                return Err(ConflictableTransactionError::Abort(DbError::AlreadyExists(
+                    format!("Item {} belongs to another subcollection", id),
+                )));
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl<'a> Subcollection<'a> {
+    pub fn upsert<T: Serialize>(&self, id: &str, body: &T) -> Result<bool, DbError> {
+        self.upsert_json(id, to_json(body)?)
+    }
+
+    /// Fails with `DbError::AlreadyExists` if `id` belongs to another
+    /// subcollection.
+    pub fn upsert_json(&self, id: &str, body_json: String) -> Result<bool, DbError> {
+        self.collection.upsert_scoped(
+            id,
+            self.message_json(&body_json)?,
+            Some(&self.dependencies_hash),
+        )
+    }
+
+    /// Returns false without writing if `id` is taken, in this or any other
+    /// subcollection.
+    pub fn insert_if_absent<T: Serialize>(&self, id: &str, body: &T) -> Result<bool, DbError> {
+        self.insert_if_absent_json(id, to_json(body)?)
+    }
+
+    pub fn insert_if_absent_json(&self, id: &str, body_json: String) -> Result<bool, DbError> {
+        self.collection
+            .insert_if_absent_json(id, self.message_json(&body_json)?)
+    }
+
+    /// Compares and swaps bodies; see `Collection::compare_and_swap`.
+    pub fn compare_and_swap<T: Serialize>(
+        &self,
+        id: &str,
+        expected: Option<&T>,
+        new: Option<&T>,
+    ) -> CompareAndSwapResult {
+        self.compare_and_swap_json(
+            id,
+            expected.map(to_json).transpose()?,
+            new.map(to_json).transpose()?,
+        )
+    }
+
+    pub fn compare_and_swap_json(
+        &self,
+        id: &str,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> CompareAndSwapResult {
+        let expected = expected
+            .map(|body_json| self.message_json(&body_json))
+            .transpose()?;
+        let new = new
+            .map(|body_json| self.message_json(&body_json))
+            .transpose()?;
+
+        let result = self.collection.compare_and_swap_scoped(
+            id,
+            expected,
+            new,
+            Some(&self.dependencies_hash),
+        )?;
+
+        // Report the current body rather than the whole message.
+        match result {
+            Err(CompareAndSwapError {
+                current: Some(message_json),
+            }) => {
+                let message = parse_json(&message_json)?;
+                let body_json = serde_json::to_string(&message["body"]).map_err(|e| {
+                    DbError::SerializationError(format!("Failed to serialize body: {}", e))
+                })?;
+
+                Ok(Err(CompareAndSwapError {
+                    current: Some(body_json),
+                }))
+            }
+            result => Ok(result),
+        }
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, DbError> {
+    serde_json::to_string(value).map_err(|e| DbError::SerializationError(e.to_string()))
+}
+
+fn parse_json(json: &str) -> Result<Value, DbError> {
+    serde_json::from_str(json)
+        .map_err(|e| DbError::DeserializationError(format!("JSON parsing error: {}", e)))
+}
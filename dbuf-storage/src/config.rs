@@ -0,0 +1,154 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::{Database, DbError, Durability};
+
+/// Options for opening a `Database`.
+///
+/// ```no_run
+/// # use dbuf_storage::DatabaseConfig;
+/// let db = DatabaseConfig::new("./dbuf_db")
+///     .cache_capacity(256 * 1024 * 1024)
+///     .create_if_missing(false)
+///     .open()?;
+/// # Ok::<(), dbuf_storage::DbError>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct DatabaseConfig {
+    path: Option<PathBuf>,
+    cache_capacity: Option<u64>,
+    compression: Option<i32>,
+    segment_size: Option<usize>,
+    read_only: bool,
+    create_if_missing: bool,
+    durability: Durability,
+    sweep_interval: Option<Duration>,
+}
+
+impl DatabaseConfig {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        DatabaseConfig {
+            path: Some(path.as_ref().to_path_buf()),
+            cache_capacity: None,
+            compression: None,
+            segment_size: None,
+            read_only: false,
+            create_if_missing: true,
+            durability: Durability::default(),
+            sweep_interval: None,
+        }
+    }
+
+    /// A database in a fresh temporary location that is deleted when the
+    /// last handle to it is dropped.
+    pub fn temporary() -> Self {
+        DatabaseConfig {
+            path: None,
+            ..DatabaseConfig::new("")
+        }
+    }
+
+    /// Size of the page cache in bytes.
+    pub fn cache_capacity(mut self, bytes: u64) -> Self {
+        self.cache_capacity = Some(bytes);
+        self
+    }
+
+    /// Compresses data on disk with zstd at the given level (1 to 22).
+    /// Requires the `compression` feature.
+    pub fn compression(mut self, level: i32) -> Self {
+        self.compression = Some(level);
+        self
+    }
+
+    /// Size of the log segments in bytes; must be a power of two. An
+    /// existing database has to be opened with the size it was created with.
+    pub fn segment_size(mut self, bytes: usize) -> Self {
+        self.segment_size = Some(bytes);
+        self
+    }
+
+    /// Rejects every write with `DbError::ReadOnly`. A read-only database
+    /// must already exist. This is enforced by this crate only: sled still
+    /// opens the files for writing and locks them exclusively, so no other
+    /// process can open the database, read-only or not, at the same time.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Whether opening a path without a database creates one (the default)
+    /// or fails.
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Removes expired items in the background once per interval. Without
+    /// it they are only removed by `Database::sweep_expired`.
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        self.sweep_interval = Some(interval);
+        self
+    }
+
+    pub fn open(&self) -> Result<Database, DbError> {
+        let mut config = sled::Config::new();
+
+        match &self.path {
+            Some(path) => {
+                if (self.read_only || !self.create_if_missing) && !path.join("db").exists() {
+                    return Err(DbError::DatabaseError(format!(
+                        "No database at {}",
+                        path.display()
+                    )));
+                }
+
+                config = config.path(path);
+            }
+            None => config = config.temporary(true),
+        }
+
+        if let Some(bytes) = self.cache_capacity {
+            config = config.cache_capacity(bytes);
+        }
+
+        if let Some(level) = self.compression {
+            if !cfg!(feature = "compression") {
+                return Err(DbError::DatabaseError(
+                    "Compression requires the `compression` feature".to_string(),
+                ));
+            }
+
+            config = config.use_compression(true).compression_factor(level);
+        }
+
+        if let Some(bytes) = self.segment_size {
+            config = config.segment_size(bytes);
+        }
+
+        match self.durability {
+            Durability::Sync => {}
+            Durability::Periodic(interval) => {
+                config = config.flush_every_ms(Some(interval.as_millis().max(1) as u64));
+            }
+            Durability::OnDemand => config = config.flush_every_ms(None),
+        }
+
+        let db = config
+            .open()
+            .map_err(|e| DbError::DatabaseError(e.to_string()))?;
+
+        let mut database = Database::from_sled(db, self.durability, self.read_only);
+
+        if let Some(interval) = self.sweep_interval.filter(|_| !self.read_only) {
+            database.start_sweeper(interval);
+        }
+
+        Ok(database)
+    }
+}
@@ -0,0 +1,543 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Number, Value};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionalTree,
+};
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::keys::CONSTRAINTS_KEY;
+use crate::query::{compare, equal, lookup};
+use crate::{Collection, DbError, ValidationError};
+
+/// An invariant every message of a collection must satisfy, stored with the
+/// collection so that it holds whichever client writes.
+///
+/// A constraint compares two expressions with `==`, `!=`, `<`, `<=`, `>` or
+/// `>=`, e.g. `body.sum.dependencies.a == dependencies.b - dependencies.a`
+/// or `len(body.items) == dependencies.n`. Expressions are built from paths
+/// starting at `body` or `dependencies` (as in `Filter`), numbers, strings in
+/// double quotes, `true`, `false`, `null`, `len(path)` for the length of an
+/// array, string or object, and `+`, `-`, `*`, `/` with parentheses.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Constraint {
+    source: String,
+    left: Expr,
+    op: Comparison,
+    right: Expr,
+}
+
+impl Constraint {
+    pub fn parse(source: &str) -> Result<Self, DbError> {
+        let tokens = tokenize(source).map_err(|e| invalid(source, &e))?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+        };
+
+        let (left, op, right) = parser.constraint().map_err(|e| invalid(source, &e))?;
+
+        Ok(Constraint {
+            source: source.trim().to_string(),
+            left,
+            op,
+            right,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Checks a full message, i.e. an object with `body` and `dependencies`.
+    pub fn check(&self, message: &Value) -> Result<(), ValidationError> {
+        let error = |message: String| ValidationError {
+            validator: format!("constraint `{}`", self.source),
+            path: self.left.path().map(|path| path.to_string()),
+            message,
+        };
+
+        let left = self.left.eval(message).map_err(error)?;
+        let right = self.right.eval(message).map_err(error)?;
+
+        let holds = match self.op {
+            Comparison::Eq => equal(&left, &right),
+            Comparison::Ne => !equal(&left, &right),
+            op => {
+                let ordering = compare(&left, &right)
+                    .ok_or_else(|| error(format!("cannot compare {} with {}", left, right)))?;
+
+                match op {
+                    Comparison::Lt => ordering == Ordering::Less,
+                    Comparison::Le => ordering != Ordering::Greater,
+                    Comparison::Gt => ordering == Ordering::Greater,
+                    _ => ordering != Ordering::Less,
+                }
+            }
+        };
+
+        if !holds {
+            return Err(error(format!(
+                "does not hold, {} {} {}",
+                left, self.op, right
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl TryFrom<String> for Constraint {
+    type Error = DbError;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        Constraint::parse(&source)
+    }
+}
+
+impl From<Constraint> for String {
+    fn from(constraint: Constraint) -> Self {
+        constraint.source
+    }
+}
+
+fn invalid(source: &str, reason: &str) -> DbError {
+    DbError::SchemaError(format!("Invalid constraint `{}`: {}", source, reason))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Arithmetic {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    Literal(Value),
+    Path(String),
+    Len(String),
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, Arithmetic, Box<Expr>),
+}
+
+impl Expr {
+    // The path a violation is reported at.
+    fn path(&self) -> Option<&str> {
+        match self {
+            Expr::Path(path) | Expr::Len(path) => Some(path),
+            _ => None,
+        }
+    }
+
+    fn eval(&self, message: &Value) -> Result<Value, String> {
+        match self {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Path(path) => lookup(message, path)
+                .cloned()
+                .ok_or_else(|| format!("{} does not exist", path)),
+            Expr::Len(path) => {
+                let len = match lookup(message, path) {
+                    Some(Value::Array(items)) => items.len(),
+                    Some(Value::String(s)) => s.chars().count(),
+                    Some(Value::Object(obj)) => obj.len(),
+                    Some(value) => return Err(format!("{} has no length: {}", path, value)),
+                    None => return Err(format!("{} does not exist", path)),
+                };
+                Ok(Value::from(len))
+            }
+            Expr::Neg(expr) => {
+                let value = expr.eval(message)?;
+                arithmetic(&Value::from(0), Arithmetic::Sub, &value)
+            }
+            Expr::Binary(left, op, right) => {
+                arithmetic(&left.eval(message)?, *op, &right.eval(message)?)
+            }
+        }
+    }
+}
+
+// Integers stay integers, failing on overflow; any float operand makes the
+// result a float.
+fn arithmetic(left: &Value, op: Arithmetic, right: &Value) -> Result<Value, String> {
+    let (Value::Number(x), Value::Number(y)) = (left, right) else {
+        return Err(format!("cannot compute with {} and {}", left, right));
+    };
+
+    if let (Some(x), Some(y)) = (x.as_i64(), y.as_i64()) {
+        let result = match op {
+            Arithmetic::Add => x.checked_add(y),
+            Arithmetic::Sub => x.checked_sub(y),
+            Arithmetic::Mul => x.checked_mul(y),
+            Arithmetic::Div if y == 0 => return Err("division by zero".to_string()),
+            Arithmetic::Div => x.checked_div(y),
+        };
+        return result
+            .map(Value::from)
+            .ok_or_else(|| "integer overflow".to_string());
+    }
+
+    let (x, y) = match (x.as_f64(), y.as_f64()) {
+        (Some(x), Some(y)) => (x, y),
+        _ => return Err(format!("cannot compute with {} and {}", left, right)),
+    };
+    let result = match op {
+        Arithmetic::Add => x + y,
+        Arithmetic::Sub => x - y,
+        Arithmetic::Mul => x * y,
+        Arithmetic::Div => x / y,
+    };
+
+    Number::from_f64(result)
+        .map(Value::Number)
+        .ok_or_else(|| format!("{} is not a finite number", result))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(Value),
+    String(String),
+    Ident(String),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: [&str; 12] = [
+    "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "(", ")",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(rest.len());
+            let number: Number = rest[..len]
+                .parse()
+                .map_err(|_| format!("invalid number {}", &rest[..len]))?;
+            tokens.push(Token::Number(Value::Number(number)));
+            len
+        } else if c.is_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..len].to_string()));
+            len
+        } else if c == '"' {
+            let mut escaped = false;
+            let end = rest[1..]
+                .find(|c: char| {
+                    let end = c == '"' && !escaped;
+                    escaped = c == '\\' && !escaped;
+                    end
+                })
+                .ok_or("unterminated string")?;
+            let len = end + 2;
+            let string: String = serde_json::from_str(&rest[..len])
+                .map_err(|e| format!("invalid string {}: {}", &rest[..len], e))?;
+            tokens.push(Token::String(string));
+            len
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| rest.starts_with(*symbol))
+                .ok_or_else(|| format!("unexpected character {}", c))?;
+            tokens.push(Token::Symbol(symbol));
+            symbol.len()
+        };
+
+        rest = rest[len..].trim_start();
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, symbol: &'static str) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.position += 1;
+            return true;
+        }
+
+        false
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), String> {
+        if !self.eat(symbol) {
+            return Err(format!("expected {}", symbol));
+        }
+
+        Ok(())
+    }
+
+    fn constraint(&mut self) -> Result<(Expr, Comparison, Expr), String> {
+        let left = self.sum()?;
+
+        let op = match self.next() {
+            Some(Token::Symbol("==")) => Comparison::Eq,
+            Some(Token::Symbol("!=")) => Comparison::Ne,
+            Some(Token::Symbol("<")) => Comparison::Lt,
+            Some(Token::Symbol("<=")) => Comparison::Le,
+            Some(Token::Symbol(">")) => Comparison::Gt,
+            Some(Token::Symbol(">=")) => Comparison::Ge,
+            _ => return Err("expected a comparison".to_string()),
+        };
+
+        let right = self.sum()?;
+
+        if self.peek().is_some() {
+            return Err("unexpected input after the constraint".to_string());
+        }
+
+        Ok((left, op, right))
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut expr = self.product()?;
+
+        loop {
+            let op = if self.eat("+") {
+                Arithmetic::Add
+            } else if self.eat("-") {
+                Arithmetic::Sub
+            } else {
+                return Ok(expr);
+            };
+
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut expr = self.factor()?;
+
+        loop {
+            let op = if self.eat("*") {
+                Arithmetic::Mul
+            } else if self.eat("/") {
+                Arithmetic::Div
+            } else {
+                return Ok(expr);
+            };
+
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.factor()?));
+        }
+    }
+
+    fn factor(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Expr::Literal(number)),
+            Some(Token::String(string)) => Ok(Expr::Literal(Value::String(string))),
+            Some(Token::Symbol("-")) => Ok(Expr::Neg(Box::new(self.factor()?))),
+            Some(Token::Symbol("(")) => {
+                let expr = self.sum()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some(Token::Ident(ident)) => match ident.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "null" => Ok(Expr::Literal(Value::Null)),
+                "len" => {
+                    self.expect("(")?;
+                    let path = match self.next() {
+                        Some(Token::Ident(path)) => message_path(path)?,
+                        _ => return Err("expected a path in len()".to_string()),
+                    };
+                    self.expect(")")?;
+                    Ok(Expr::Len(path))
+                }
+                _ => Ok(Expr::Path(message_path(ident)?)),
+            },
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err("unexpected end of constraint".to_string()),
+        }
+    }
+}
+
+fn message_path(path: String) -> Result<String, String> {
+    let root = path.split('.').next().unwrap_or_default();
+
+    if root != "body" && root != "dependencies" {
+        return Err(format!(
+            "path {} must start with body or dependencies",
+            path
+        ));
+    }
+
+    if path.split('.').any(str::is_empty) {
+        return Err(format!("invalid path {}", path));
+    }
+
+    Ok(path)
+}
+
+fn parse_constraints(data: Option<&[u8]>) -> Result<Vec<Constraint>, DbError> {
+    match data {
+        Some(data) => serde_json::from_slice(data).map_err(|e| {
+            DbError::DeserializationError(format!("Failed to deserialize constraints: {}", e))
+        }),
+        None => Ok(Vec::new()),
+    }
+}
+
+pub(crate) fn serialize_constraints(constraints: &[Constraint]) -> Result<Vec<u8>, DbError> {
+    serde_json::to_vec(constraints)
+        .map_err(|e| DbError::SerializationError(format!("Failed to serialize constraints: {}", e)))
+}
+
+fn load(tx_tree: &TransactionalTree) -> ConflictableTransactionResult<Vec<Constraint>, DbError> {
+    parse_constraints(tx_tree.get(CONSTRAINTS_KEY)?.as_deref())
+        .map_err(ConflictableTransactionError::Abort)
+}
+
+fn store(
+    tx_tree: &TransactionalTree,
+    constraints: &[Constraint],
+) -> ConflictableTransactionResult<(), DbError> {
+    if constraints.is_empty() {
+        tx_tree.remove(CONSTRAINTS_KEY)?;
+    } else {
+        let data =
+            serialize_constraints(constraints).map_err(ConflictableTransactionError::Abort)?;
+        tx_tree.insert(CONSTRAINTS_KEY, data)?;
+    }
+
+    Ok(())
+}
+
+// Writers check the constraints inside their transaction, so a constraint
+// added through one `Collection` is enforced by every other handle too.
+pub(crate) fn check_in(
+    tx_tree: &TransactionalTree,
+    message: &Value,
+) -> ConflictableTransactionResult<(), DbError> {
+    for constraint in load(tx_tree)? {
+        constraint
+            .check(message)
+            .map_err(|e| ConflictableTransactionError::Abort(DbError::ValidationError(e)))?;
+    }
+
+    Ok(())
+}
+
+impl Collection {
+    pub fn get_constraints(&self) -> Result<Vec<Constraint>, DbError> {
+        parse_constraints(self.tree.get(CONSTRAINTS_KEY)?.as_deref())
+    }
+
+    /// Adds a constraint to the collection. Writes are checked against it as
+    /// soon as it is stored; the stored messages are checked next, and if
+    /// one of them violates it the constraint is removed again and the
+    /// violation returned.
+    pub fn add_constraint(&self, source: &str) -> Result<(), DbError> {
+        self.check_writable()?;
+
+        let constraint = Constraint::parse(source)?;
+
+        self.tree.transaction(|tx_tree| {
+            let mut constraints = load(tx_tree)?;
+
+            if constraints
+                .iter()
+                .any(|existing| existing.as_str() == constraint.as_str())
+            {
+                return Err(ConflictableTransactionError::Abort(DbError::AlreadyExists(
+                    format!("Constraint `{}` already exists", constraint),
+                )));
+            }
+
+            constraints.push(constraint.clone());
+            store(tx_tree, &constraints)
+        })?;
+
+        // Writes committed before the constraint was stored are seen by this
+        // scan, and later ones were checked against it.
+        let violation = self.iter::<Value>().find_map(|entry| match entry {
+            Ok((id, message)) => constraint.check(&message).err().map(|mut e| {
+                e.message = format!("item {} {}", id, e.message);
+                DbError::ValidationError(e)
+            }),
+            Err(e) => Some(e),
+        });
+
+        if let Some(e) = violation {
+            self.remove_constraint(constraint.as_str())?;
+            return Err(e);
+        }
+
+        self.flusher.commit()
+    }
+
+    /// Returns whether the constraint existed.
+    pub fn remove_constraint(&self, source: &str) -> Result<bool, DbError> {
+        self.check_writable()?;
+
+        let removed = self.tree.transaction(|tx_tree| {
+            let mut constraints = load(tx_tree)?;
+            let count = constraints.len();
+
+            constraints.retain(|constraint| constraint.as_str() != source.trim());
+            if constraints.len() == count {
+                return Ok(false);
+            }
+
+            store(tx_tree, &constraints)?;
+            Ok(true)
+        })?;
+
+        if removed {
+            self.flusher.commit()?;
+        }
+
+        Ok(removed)
+    }
+}
@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sled::transaction::TransactionalTree;
+use sled::transaction::{ConflictableTransactionError, ConflictableTransactionResult};
+
+use crate::helper::{canonical_json, verify_dependencies};
+use crate::index::encode_value;
+use crate::keys::{self, dependency_key};
+use crate::query::Filter;
+use crate::{Collection, DbError, Subcollection};
+
+// Stored under the dependency hash. `refs` counts the items pointing at the
+// record and is only changed inside the transaction that adds or removes
+// such an item, so the record disappears together with its last item.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct DependencyRecord {
+    pub refs: u64,
+    pub dependencies: Value,
+}
+
+impl DependencyRecord {
+    pub fn from_slice(data: &[u8]) -> Result<Self, DbError> {
+        serde_json::from_slice(data).map_err(|e| {
+            DbError::DeserializationError(format!("Failed to deserialize dependencies: {}", e))
+        })
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>, DbError> {
+        serde_json::to_vec(self).map_err(|e| {
+            DbError::SerializationError(format!("Failed to serialize dependencies: {}", e))
+        })
+    }
+
+    pub fn verify(&self, hash: &str, dependencies_json: &str) -> Result<(), DbError> {
+        verify_dependencies(
+            hash,
+            canonical_json(&self.dependencies)?.as_bytes(),
+            dependencies_json,
+        )
+    }
+}
+
+// Every field of a dependency record, nested ones under their dotted path,
+// is indexed as `v/{path}{value}{hash}`. The entries are written together
+// with the record and removed together with it, so subcollections can be
+// found by field values without reading the records themselves.
+pub(crate) fn field_prefix(path: &str) -> Vec<u8> {
+    let mut key = keys::FIELD_PREFIX.to_vec();
+    encode_value(Some(&Value::String(path.to_string())), &mut key);
+    key
+}
+
+pub(crate) fn field_keys(hash: &str, dependencies: &Value) -> Vec<Vec<u8>> {
+    let mut keys = Vec::new();
+    collect_field_keys(hash, "", dependencies, &mut keys);
+    keys
+}
+
+fn collect_field_keys(hash: &str, path: &str, value: &Value, keys: &mut Vec<Vec<u8>>) {
+    let fields: Vec<(String, &Value)> = match value {
+        Value::Object(obj) => obj.iter().map(|(k, v)| (k.clone(), v)).collect(),
+        Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (i.to_string(), v))
+            .collect(),
+        _ => return,
+    };
+
+    for (name, field) in fields {
+        let field_path = if path.is_empty() {
+            name
+        } else {
+            format!("{}.{}", path, name)
+        };
+
+        let mut key = field_prefix(&field_path);
+        encode_value(Some(field), &mut key);
+        key.extend_from_slice(hash.as_bytes());
+        keys.push(key);
+
+        collect_field_keys(hash, &field_path, field, keys);
+    }
+}
+
+// Registers one more item using the dependencies stored under `hash`,
+// creating the record if this is the first one.
+pub(crate) fn acquire(
+    tx_tree: &TransactionalTree,
+    hash: &str,
+    dependencies_json: &str,
+) -> ConflictableTransactionResult<(), DbError> {
+    let record = match tx_tree.get(dependency_key(hash))? {
+        Some(data) => {
+            let mut record =
+                DependencyRecord::from_slice(&data).map_err(ConflictableTransactionError::Abort)?;
+            record
+                .verify(hash, dependencies_json)
+                .map_err(ConflictableTransactionError::Abort)?;
+            record.refs += 1;
+            record
+        }
+        None => {
+            let record = DependencyRecord {
+                refs: 1,
+                dependencies: serde_json::from_str(dependencies_json).map_err(|e| {
+                    ConflictableTransactionError::Abort(DbError::DeserializationError(format!(
+                        "JSON parsing error: {}",
+                        e
+                    )))
+                })?,
+            };
+
+            for key in field_keys(hash, &record.dependencies) {
+                tx_tree.insert(key, &[])?;
+            }
+
+            record
+        }
+    };
+
+    let data = record
+        .to_vec()
+        .map_err(ConflictableTransactionError::Abort)?;
+    tx_tree.insert(dependency_key(hash), data)?;
+
+    Ok(())
+}
+
+// Drops one reference to the record stored under `hash` and removes the
+// record once nothing points at it anymore.
+pub(crate) fn release(
+    tx_tree: &TransactionalTree,
+    hash: &str,
+) -> ConflictableTransactionResult<(), DbError> {
+    let data = match tx_tree.get(dependency_key(hash))? {
+        Some(data) => data,
+        None => return Ok(()),
+    };
+
+    let mut record =
+        DependencyRecord::from_slice(&data).map_err(ConflictableTransactionError::Abort)?;
+
+    if record.refs <= 1 {
+        tx_tree.remove(dependency_key(hash))?;
+
+        for key in field_keys(hash, &record.dependencies) {
+            tx_tree.remove(key)?;
+        }
+    } else {
+        record.refs -= 1;
+        let data = record
+            .to_vec()
+            .map_err(ConflictableTransactionError::Abort)?;
+        tx_tree.insert(dependency_key(hash), data)?;
+    }
+
+    Ok(())
+}
+
+impl Collection {
+    /// Returns the subcollections whose dependencies match `filter`. Paths
+    /// start at the message as in queries, e.g. `dependencies.a`; conditions
+    /// on dependency fields are answered from an index over the dependency
+    /// records instead of reading every item.
+    pub fn find_subcollections(&self, filter: &Filter) -> Result<Vec<Subcollection<'_>>, DbError> {
+        let mut subcollections = Vec::new();
+
+        for hash in self.scan_dependencies(filter)? {
+            let record = match self.tree.get(dependency_key(&hash))? {
+                Some(data) => DependencyRecord::from_slice(&data)?,
+                None => continue,
+            };
+
+            let message = serde_json::json!({ "dependencies": record.dependencies });
+            if !filter.matches(&message) {
+                continue;
+            }
+
+            subcollections.push(Subcollection {
+                collection: self,
+                dependencies: record.dependencies,
+                dependencies_hash: hash,
+            });
+        }
+
+        Ok(subcollections)
+    }
+}
@@ -0,0 +1,119 @@
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use crate::{Database, DatabaseConfig, DbError};
+
+/// When writes to a `Database` reach the disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Every write is flushed before it returns. Writers that finish while
+    /// a flush is running share the next one.
+    #[default]
+    Sync,
+    /// Writes are flushed in the background once per interval and may be
+    /// lost on a crash before that.
+    Periodic(Duration),
+    /// Writes are only guaranteed to be on disk after `Database::flush`.
+    OnDemand,
+}
+
+// Group commit: a writer takes a ticket after its write and waits until a
+// flush that started after the ticket was taken has finished. Only one flush
+// runs at a time, and it covers every ticket handed out before it started.
+pub(crate) struct Flusher {
+    db: sled::Db,
+    durability: Durability,
+    state: Mutex<FlushState>,
+    flushed: Condvar,
+}
+
+#[derive(Default)]
+struct FlushState {
+    requested: u64,
+    completed: u64,
+    flushing: bool,
+}
+
+impl Flusher {
+    pub(crate) fn new(db: sled::Db, durability: Durability) -> Self {
+        Flusher {
+            db,
+            durability,
+            state: Mutex::new(FlushState::default()),
+            flushed: Condvar::new(),
+        }
+    }
+
+    /// Called after every write; flushes according to the durability.
+    pub(crate) fn commit(&self) -> Result<(), DbError> {
+        match self.durability {
+            Durability::Sync => self.flush(),
+            Durability::Periodic(_) | Durability::OnDemand => Ok(()),
+        }
+    }
+
+    pub(crate) fn flush(&self) -> Result<(), DbError> {
+        let mut state = self.lock()?;
+        state.requested += 1;
+        let ticket = state.requested;
+
+        loop {
+            if state.completed >= ticket {
+                return Ok(());
+            }
+
+            if state.flushing {
+                state = self
+                    .flushed
+                    .wait(state)
+                    .map_err(|e| DbError::DatabaseError(format!("Flush lock poisoned: {}", e)))?;
+                continue;
+            }
+
+            state.flushing = true;
+            let target = state.requested;
+            drop(state);
+
+            let result = self.db.flush();
+
+            state = self.lock()?;
+            state.flushing = false;
+            if result.is_ok() {
+                state.completed = target;
+            }
+            self.flushed.notify_all();
+
+            result?;
+        }
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, FlushState>, DbError> {
+        self.state
+            .lock()
+            .map_err(|e| DbError::DatabaseError(format!("Flush lock poisoned: {}", e)))
+    }
+}
+
+impl Database {
+    pub fn with_durability(path: Option<&str>, durability: Durability) -> Result<Self, DbError> {
+        DatabaseConfig::new(path.unwrap_or("./dbuf_db"))
+            .durability(durability)
+            .open()
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.flusher.durability
+    }
+
+    /// Writes everything written so far to disk. Concurrent calls, and
+    /// writers with `Durability::Sync`, share flushes.
+    pub fn flush(&self) -> Result<(), DbError> {
+        self.flusher.flush()
+    }
+
+    /// Like `flush`, but without blocking the calling thread.
+    pub async fn flush_async(&self) -> Result<(), DbError> {
+        self.db.flush_async().await?;
+        Ok(())
+    }
+}
@@ -1,32 +1,97 @@
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use serde_json::{Map, Number, Value};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-fn create_alphanumeric_hash(input: &str) -> String {
-    let mut hasher = DefaultHasher::new();
+use crate::DbError;
 
-    input.hash(&mut hasher);
+/// Returns the key under which a dependency record (or schema) is stored.
+///
+/// The key is the lowercase hex SHA-256 digest of the JSON text. Unlike
+/// `std`'s `DefaultHasher`, the output is fixed by the algorithm and never
+/// changes between Rust releases, so keys persisted on disk stay valid.
+pub fn get_json_hash(input: &str) -> String {
+    to_hex(&Sha256::digest(input.as_bytes()))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
 
-    let hash_value = hasher.finish();
+/// Checks that a dependency record already stored under `hash` holds exactly
+/// `dependencies_json`, so that two different dependency objects can never
+/// share a subcollection.
+pub fn verify_dependencies(
+    hash: &str,
+    stored: &[u8],
+    dependencies_json: &str,
+) -> Result<(), DbError> {
+    if stored != dependencies_json.as_bytes() {
+        return Err(DbError::HashCollision(format!(
+            "Stored dependencies with hash {} differ from {}",
+            hash, dependencies_json
+        )));
+    }
+
+    Ok(())
+}
+
+/// Serializes `value` in the canonical form used for dependency identity:
+/// object keys sorted, no insignificant whitespace and integral floats
+/// (`1.0`, `1e2`) written as integers, so logically equal dependencies
+/// produced by different clients hash to the same key.
+pub fn canonical_json(value: &Value) -> Result<String, DbError> {
+    serde_json::to_string(&canonicalize(value)).map_err(|e| {
+        DbError::SerializationError(format!("Failed to serialize canonical JSON: {}", e))
+    })
+}
 
-    const CHARSET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
-    const BASE: u64 = 62;
+pub fn canonicalize(value: &Value) -> Value {
+    match value {
+        Value::Object(obj) => {
+            let mut keys: Vec<&String> = obj.keys().collect();
+            keys.sort();
 
-    let mut result = String::new();
-    let mut remainder = hash_value;
+            let mut canonical = Map::new();
+            for key in keys {
+                canonical.insert(key.clone(), canonicalize(&obj[key]));
+            }
 
-    if remainder == 0 {
-        return "0".to_string();
+            Value::Object(canonical)
+        }
+        Value::Array(items) => Value::Array(items.iter().map(canonicalize).collect()),
+        Value::Number(number) => Value::Number(canonical_number(number)),
+        other => other.clone(),
     }
+}
 
-    while remainder > 0 {
-        let index = (remainder % BASE) as usize;
-        result.insert(0, CHARSET[index] as char);
-        remainder /= BASE;
+fn canonical_number(number: &Number) -> Number {
+    if number.is_i64() || number.is_u64() {
+        return number.clone();
     }
 
-    result
+    match number.as_f64() {
+        Some(f) if f.fract() == 0.0 && f >= i64::MIN as f64 && f < i64::MAX as f64 => {
+            Number::from(f as i64)
+        }
+        Some(f) if f.fract() == 0.0 && f >= 0.0 && f < u64::MAX as f64 => Number::from(f as u64),
+        _ => number.clone(),
+    }
 }
 
-pub fn get_json_hash(input: &str) -> String {
-    create_alphanumeric_hash(input)
+// Timestamps of history entries and deleted items.
+pub fn millis_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
 }
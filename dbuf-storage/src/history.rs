@@ -0,0 +1,334 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionalTree,
+};
+use std::time::SystemTime;
+
+use crate::changes::body_in;
+use crate::helper::millis_since_epoch;
+use crate::keys::{history_first_key, history_key, history_prefix, item_key};
+use crate::{parse_storage_value, storage_version, Collection, DbError, Subcollection};
+
+/// How much of the history of its items a collection keeps, fixed when it
+/// is created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryRetention {
+    /// No history is kept.
+    #[default]
+    Off,
+    /// Every version ever written.
+    All,
+    /// The last n versions of each item, the current one included.
+    Versions(usize),
+    /// The versions replaced less than this many seconds ago, and the
+    /// current one.
+    Seconds(u64),
+}
+
+impl HistoryRetention {
+    // Drops the entries this policy no longer keeps at `now`, for reads:
+    // stored versions outliving `Seconds` are only removed by the next
+    // write. The last one always stays: it records the current version of
+    // the item.
+    fn retain(self, mut entries: Vec<HistoryEntry>, now: u64) -> Vec<HistoryEntry> {
+        let first = match self {
+            HistoryRetention::Off | HistoryRetention::All => 0,
+            HistoryRetention::Versions(versions) => entries.len().saturating_sub(versions.max(1)),
+            HistoryRetention::Seconds(seconds) => {
+                let cutoff = now.saturating_sub(seconds.saturating_mul(1000));
+
+                entries
+                    .windows(2)
+                    .position(|pair| pair[1].timestamp >= cutoff)
+                    .unwrap_or(entries.len().saturating_sub(1))
+            }
+        };
+
+        entries.drain(..first);
+        entries
+    }
+}
+
+/// A version of an item, from `Collection::history`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry<T = Value> {
+    pub version: u64,
+    /// When the version was written, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The item at this version, `None` if it was deleted.
+    pub message: Option<T>,
+}
+
+/// A point in the history of an item, for `Collection::get_at`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum At {
+    Version(u64),
+    /// Milliseconds since the Unix epoch.
+    Time(u64),
+}
+
+impl From<SystemTime> for At {
+    fn from(time: SystemTime) -> Self {
+        At::Time(millis_since_epoch(time))
+    }
+}
+
+// With history on, every write stores the version it creates under its own
+// key, including deletes, and drops the versions the retention policy no
+// longer keeps. Starting from the oldest retained version, recorded next to
+// them, the write touches only the keys it adds or drops.
+impl Collection {
+    pub fn get_history_retention(&self) -> HistoryRetention {
+        self.metadata.history
+    }
+
+    /// The retained versions of the item, oldest first. Empty if history is
+    /// off or the item never existed. The history of a deleted item is kept
+    /// so that it can be brought back with `revert`.
+    pub fn history<T: DeserializeOwned>(&self, id: &str) -> Result<Vec<HistoryEntry<T>>, DbError> {
+        self.history_values(id)?.into_iter().map(decode).collect()
+    }
+
+    /// The item as it was at the given version or time. Fails with
+    /// `DbError::NotFound` if it did not exist then, or that part of its
+    /// history is not retained.
+    pub fn get_at<T: DeserializeOwned>(&self, id: &str, at: At) -> Result<T, DbError> {
+        from_value(message_at(self.history_values(id)?, at)?)
+    }
+
+    pub fn get_json_at(&self, id: &str, at: At) -> Result<String, DbError> {
+        to_json(&message_at(self.history_values(id)?, at)?)
+    }
+
+    /// Writes the item as it was at `version` as its newest version,
+    /// restoring it if it was deleted since. Returns the current version.
+    pub fn revert(&self, id: &str, version: u64) -> Result<u64, DbError> {
+        self.revert_scoped(id, version, None)
+    }
+
+    fn history_values(&self, id: &str) -> Result<Vec<HistoryEntry>, DbError> {
+        let prefix = history_prefix(id);
+        let mut entries = Vec::new();
+
+        for entry in self.tree.scan_prefix(&prefix) {
+            let (key, data) = entry?;
+
+            // Versions of other items whose IDs start with `{id}/`.
+            if key.len() != prefix.len() + 20 {
+                continue;
+            }
+
+            entries.push(read_entry(&data)?);
+        }
+
+        Ok(self
+            .metadata
+            .history
+            .retain(entries, millis_since_epoch(SystemTime::now())))
+    }
+
+    // `scope` is the dependencies hash of the subcollection both the
+    // reverted version and the current item must belong to, if any.
+    fn revert_scoped(&self, id: &str, version: u64, scope: Option<&str>) -> Result<u64, DbError> {
+        self.check_writable()?;
+
+        let message = message_at(self.history_values(id)?, At::Version(version))?;
+        let message = self.prepare_message(&to_json(&message)?)?;
+
+        if scope.is_some_and(|deps_hash| deps_hash != message.deps_hash) {
+            return Err(DbError::NotFound);
+        }
+
+        let (written, current) = self.tree.transaction(|tx_tree| {
+            let written = if self.exists_in_scope(tx_tree, id, scope)? {
+                self.update_prepared(tx_tree, id, &message)?
+            } else {
+                self.insert_prepared(tx_tree, id, &message)?;
+                true
+            };
+
+            Ok((written, self.version_in(tx_tree, id)?))
+        })?;
+
+        if written {
+            self.flusher.commit()?;
+        }
+
+        Ok(current)
+    }
+
+    fn version_in(
+        &self,
+        tx_tree: &TransactionalTree,
+        id: &str,
+    ) -> ConflictableTransactionResult<u64, DbError> {
+        let item_data = match tx_tree.get(item_key(id))? {
+            Some(data) => data,
+            None => return Err(ConflictableTransactionError::Abort(DbError::NotFound)),
+        };
+
+        let storage_value =
+            parse_storage_value(&item_data).map_err(ConflictableTransactionError::Abort)?;
+
+        Ok(storage_version(&storage_value))
+    }
+
+    // Adds a version to the history of an item in the transaction writing
+    // it; `message` is `None` for a delete.
+    pub(crate) fn record_history(
+        &self,
+        tx_tree: &TransactionalTree,
+        id: &str,
+        version: u64,
+        message: Option<Value>,
+    ) -> ConflictableTransactionResult<(), DbError> {
+        if self.metadata.history == HistoryRetention::Off {
+            return Ok(());
+        }
+
+        let now = millis_since_epoch(SystemTime::now());
+        let entry = HistoryEntry {
+            version,
+            timestamp: now,
+            message,
+        };
+        let data = serde_json::to_vec(&entry).map_err(|e| {
+            ConflictableTransactionError::Abort(DbError::SerializationError(format!(
+                "Failed to serialize history: {}",
+                e
+            )))
+        })?;
+
+        tx_tree.insert(history_key(id, version), data)?;
+
+        let first = match tx_tree.get(history_first_key(id))? {
+            Some(data) => {
+                let bytes: [u8; 8] = data.as_ref().try_into().map_err(|_| {
+                    ConflictableTransactionError::Abort(DbError::DatabaseError(format!(
+                        "Corrupted history of item {}",
+                        id
+                    )))
+                })?;
+                Some(u64::from_be_bytes(bytes))
+            }
+            None => None,
+        };
+
+        let mut retained = first.unwrap_or(version);
+        while retained < version && !self.keeps_in(tx_tree, id, retained, version, now)? {
+            tx_tree.remove(history_key(id, retained))?;
+            retained += 1;
+        }
+
+        if first != Some(retained) {
+            tx_tree.insert(history_first_key(id), &retained.to_be_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    // Whether the retention policy keeps version `retained` of an item once
+    // `version`, written at `now`, is the newest one. Only the version after
+    // it is read, for the time it replaced `retained`.
+    fn keeps_in(
+        &self,
+        tx_tree: &TransactionalTree,
+        id: &str,
+        retained: u64,
+        version: u64,
+        now: u64,
+    ) -> ConflictableTransactionResult<bool, DbError> {
+        match self.metadata.history {
+            HistoryRetention::Off | HistoryRetention::All => Ok(true),
+            HistoryRetention::Versions(versions) => Ok(version - retained < versions.max(1) as u64),
+            HistoryRetention::Seconds(seconds) => {
+                let cutoff = now.saturating_sub(seconds.saturating_mul(1000));
+
+                match tx_tree.get(history_key(id, retained + 1))? {
+                    Some(data) => {
+                        let next =
+                            read_entry(&data).map_err(ConflictableTransactionError::Abort)?;
+                        Ok(next.timestamp >= cutoff)
+                    }
+                    None => Ok(true),
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Subcollection<'a> {
+    /// The history of the item with bodies; versions in which it belonged to
+    /// other dependencies have none, like deletions.
+    pub fn history<T: DeserializeOwned>(&self, id: &str) -> Result<Vec<HistoryEntry<T>>, DbError> {
+        self.history_values(id)?.into_iter().map(decode).collect()
+    }
+
+    pub fn get_at<T: DeserializeOwned>(&self, id: &str, at: At) -> Result<T, DbError> {
+        from_value(message_at(self.history_values(id)?, at)?)
+    }
+
+    pub fn get_json_at(&self, id: &str, at: At) -> Result<String, DbError> {
+        to_json(&message_at(self.history_values(id)?, at)?)
+    }
+
+    /// Fails with `DbError::NotFound` if the item did not belong to this
+    /// subcollection at `version`, and with `DbError::AlreadyExists` if it
+    /// now belongs to another one.
+    pub fn revert(&self, id: &str, version: u64) -> Result<u64, DbError> {
+        self.collection
+            .revert_scoped(id, version, Some(&self.dependencies_hash))
+    }
+
+    fn history_values(&self, id: &str) -> Result<Vec<HistoryEntry>, DbError> {
+        Ok(self
+            .collection
+            .history_values(id)?
+            .into_iter()
+            .map(|entry| HistoryEntry {
+                version: entry.version,
+                timestamp: entry.timestamp,
+                message: body_in(entry.message, &self.dependencies),
+            })
+            .collect())
+    }
+}
+
+// The message of the entry in effect at `at`: the one with that version, or
+// the last one written by that time.
+fn message_at(entries: Vec<HistoryEntry>, at: At) -> Result<Value, DbError> {
+    let entry = match at {
+        At::Version(version) => entries.into_iter().find(|entry| entry.version == version),
+        At::Time(time) => entries
+            .into_iter()
+            .rev()
+            .find(|entry| entry.timestamp <= time),
+    };
+
+    entry
+        .and_then(|entry| entry.message)
+        .ok_or(DbError::NotFound)
+}
+
+fn read_entry(data: &[u8]) -> Result<HistoryEntry, DbError> {
+    serde_json::from_slice(data)
+        .map_err(|e| DbError::DeserializationError(format!("Invalid history record: {}", e)))
+}
+
+fn decode<T: DeserializeOwned>(entry: HistoryEntry) -> Result<HistoryEntry<T>, DbError> {
+    Ok(HistoryEntry {
+        version: entry.version,
+        timestamp: entry.timestamp,
+        message: entry.message.map(from_value).transpose()?,
+    })
+}
+
+fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, DbError> {
+    serde_json::from_value(value).map_err(|e| DbError::DeserializationError(e.to_string()))
+}
+
+fn to_json(value: &Value) -> Result<String, DbError> {
+    serde_json::to_string(value).map_err(|e| DbError::SerializationError(e.to_string()))
+}
@@ -0,0 +1,190 @@
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionalTree,
+};
+use uuid::Uuid;
+
+use crate::helper::{canonical_json, get_json_hash};
+use crate::keys::{item_key, ID_COUNTER_KEY};
+use crate::{Collection, DbError, PreparedMessage, Subcollection};
+
+/// How a collection generates the IDs of inserted items, fixed when it is
+/// created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdStrategy {
+    /// 16 random alphanumeric characters.
+    #[default]
+    Random,
+    UuidV4,
+    /// Time-ordered UUIDs: items inserted later sort after earlier ones, so
+    /// key order and range scans follow insertion time.
+    UuidV7,
+    /// A per-collection counter starting at 1, zero-padded to 20 digits so
+    /// that IDs sort numerically.
+    Counter,
+    /// The SHA-256 of the canonical JSON of the message. Inserting a message
+    /// equal to a stored one fails with `DbError::AlreadyExists`.
+    ContentHash,
+}
+
+const MAX_RANDOM_ATTEMPTS: usize = 10;
+
+impl Collection {
+    pub fn get_id_strategy(&self) -> IdStrategy {
+        self.metadata.id_strategy
+    }
+
+    // Generates the ID of a new item inside the inserting transaction, so
+    // that counters and collision checks see concurrent inserts.
+    pub(crate) fn generate_id_in(
+        &self,
+        tx_tree: &TransactionalTree,
+        message: &PreparedMessage,
+    ) -> ConflictableTransactionResult<String, DbError> {
+        match self.metadata.id_strategy {
+            IdStrategy::Random => {
+                for _ in 0..MAX_RANDOM_ATTEMPTS {
+                    let id: String = thread_rng()
+                        .sample_iter(&Alphanumeric)
+                        .take(16)
+                        .map(char::from)
+                        .collect();
+
+                    if tx_tree.get(item_key(&id))?.is_none() {
+                        return Ok(id);
+                    }
+                }
+
+                Err(ConflictableTransactionError::Abort(DbError::DatabaseError(
+                    format!(
+                        "Failed to generate unique ID after {} attempts",
+                        MAX_RANDOM_ATTEMPTS
+                    ),
+                )))
+            }
+            IdStrategy::UuidV4 => Ok(Uuid::new_v4().to_string()),
+            IdStrategy::UuidV7 => Ok(Uuid::now_v7().to_string()),
+            IdStrategy::Counter => {
+                let last = match tx_tree.get(ID_COUNTER_KEY)? {
+                    Some(data) => {
+                        let bytes: [u8; 8] = data.as_ref().try_into().map_err(|_| {
+                            ConflictableTransactionError::Abort(DbError::DatabaseError(
+                                "Corrupted ID counter".to_string(),
+                            ))
+                        })?;
+                        u64::from_be_bytes(bytes)
+                    }
+                    None => 0,
+                };
+
+                let next = last + 1;
+                tx_tree.insert(ID_COUNTER_KEY, &next.to_be_bytes())?;
+                Ok(format!("{:020}", next))
+            }
+            IdStrategy::ContentHash => {
+                // The canonical JSON of the whole message, keys being sorted.
+                let body_json =
+                    canonical_json(&message.body).map_err(ConflictableTransactionError::Abort)?;
+                let message_json = format!(
+                    "{{\"body\":{},\"dependencies\":{}}}",
+                    body_json, message.dependencies_json
+                );
+
+                Ok(get_json_hash(&message_json))
+            }
+        }
+    }
+
+    // Inserts a message under a newly generated ID.
+    pub(crate) fn insert_new(
+        &self,
+        tx_tree: &TransactionalTree,
+        message: &PreparedMessage,
+    ) -> ConflictableTransactionResult<String, DbError> {
+        let id = self.generate_id_in(tx_tree, message)?;
+        self.insert_prepared(tx_tree, &id, message)?;
+        Ok(id)
+    }
+
+    /// Inserts a message under an ID chosen by the caller instead of one
+    /// from the ID strategy. Fails with `DbError::AlreadyExists` if the ID
+    /// is taken.
+    pub fn insert_with_id<T: Serialize>(&self, id: &str, value: &T) -> Result<(), DbError> {
+        let json =
+            serde_json::to_string(value).map_err(|e| DbError::SerializationError(e.to_string()))?;
+
+        self.insert_json_with_id(id, json)
+    }
+
+    pub fn insert_json_with_id(&self, id: &str, json: String) -> Result<(), DbError> {
+        self.check_writable()?;
+        check_id(id)?;
+
+        let message = self.prepare_message(&json)?;
+
+        self.tree
+            .transaction(|tx_tree| self.insert_prepared(tx_tree, id, &message))?;
+
+        self.flusher.commit()
+    }
+}
+
+/// Longest ID, in bytes, that callers may choose.
+pub const MAX_ID_LEN: usize = 256;
+
+/// IDs callers may not choose, as they name the fixed routes next to items
+/// in the server (`/collections/{name}/query` and so on), or path segments
+/// that URLs normalize away.
+pub const RESERVED_IDS: &[&str] = &[
+    ".",
+    "..",
+    "batch",
+    "changes",
+    "codec",
+    "constraints",
+    "exists",
+    "indexes",
+    "keys",
+    "query",
+    "subcollections",
+    "trash",
+];
+
+// Rejects IDs chosen by callers that can never be valid: empty or too long
+// IDs, reserved names, and IDs with `/` or control characters, which would
+// not fit in one URL path segment.
+pub(crate) fn check_id(id: &str) -> Result<(), DbError> {
+    let problem = if id.is_empty() {
+        "must not be empty"
+    } else if id.len() > MAX_ID_LEN {
+        "is too long"
+    } else if RESERVED_IDS.contains(&id) {
+        "is reserved"
+    } else if id.chars().any(|c| c == '/' || c.is_control()) {
+        "must not contain '/' or control characters"
+    } else {
+        return Ok(());
+    };
+
+    Err(DbError::DeserializationError(format!(
+        "Item ID {:?} {}",
+        id, problem
+    )))
+}
+
+impl<'a> Subcollection<'a> {
+    pub fn insert_with_id<T: Serialize>(&self, id: &str, body: &T) -> Result<(), DbError> {
+        let body_json = serde_json::to_string(body)
+            .map_err(|e| DbError::SerializationError(format!("Failed to serialize body: {}", e)))?;
+
+        self.insert_json_with_id(id, body_json)
+    }
+
+    pub fn insert_json_with_id(&self, id: &str, body_json: String) -> Result<(), DbError> {
+        self.collection
+            .insert_json_with_id(id, self.message_json(&body_json)?)
+    }
+}
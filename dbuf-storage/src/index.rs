@@ -0,0 +1,708 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sled::transaction::TransactionalTree;
+use sled::transaction::{ConflictableTransactionError, ConflictableTransactionResult};
+
+use crate::dependencies;
+use crate::keys::{self, index_prefix, item_key, member_prefix, unique_prefix};
+use crate::query::{lookup, Filter, Query};
+use crate::{parse_storage_value, Collection, DbError};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IndexOptions {
+    /// Rejects writes that would give two items the same value. Items
+    /// without the field are not constrained.
+    #[serde(default)]
+    pub unique: bool,
+}
+
+/// A secondary index over one body field. `path` uses the query syntax and
+/// starts at the message, e.g. `body.price`. An index is `ready` once it
+/// covers all items that existed when it was created; until then queries
+/// do not use it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexDefinition {
+    pub name: String,
+    pub path: String,
+    #[serde(default)]
+    pub options: IndexOptions,
+    #[serde(default)]
+    pub ready: bool,
+}
+
+impl IndexDefinition {
+    fn value<'v>(&self, body: &'v Value) -> Option<&'v Value> {
+        match self.path.strip_prefix("body") {
+            Some("") => Some(body),
+            Some(rest) => lookup(body, rest.strip_prefix('.')?),
+            None => None,
+        }
+    }
+
+    fn entry_key(&self, value: Option<&Value>, id: &str) -> Vec<u8> {
+        let mut key = index_prefix(&self.name);
+        encode_value(value, &mut key);
+        key.extend_from_slice(id.as_bytes());
+        key
+    }
+
+    // Only present values take part in uniqueness, so any number of items
+    // may lack the field.
+    fn unique_key(&self, value: Option<&Value>) -> Option<Vec<u8>> {
+        if !self.options.unique {
+            return None;
+        }
+
+        let value = value?;
+        let mut key = unique_prefix(&self.name);
+        encode_value(Some(value), &mut key);
+        Some(key)
+    }
+}
+
+// Order-preserving encoding of indexed values. Byte-wise order of encoded
+// values matches the sort order of queries: missing < null < bool < number
+// < string < array < object. Arrays and objects are not ordered among
+// themselves, so only their kind is encoded.
+const TAG_MISSING: u8 = 0x01;
+const TAG_NULL: u8 = 0x02;
+const TAG_BOOL: u8 = 0x03;
+const TAG_NUMBER: u8 = 0x04;
+const TAG_STRING: u8 = 0x05;
+const TAG_ARRAY: u8 = 0x06;
+const TAG_OBJECT: u8 = 0x07;
+
+fn tag(value: Option<&Value>) -> u8 {
+    match value {
+        None => TAG_MISSING,
+        Some(Value::Null) => TAG_NULL,
+        Some(Value::Bool(_)) => TAG_BOOL,
+        Some(Value::Number(_)) => TAG_NUMBER,
+        Some(Value::String(_)) => TAG_STRING,
+        Some(Value::Array(_)) => TAG_ARRAY,
+        Some(Value::Object(_)) => TAG_OBJECT,
+    }
+}
+
+pub(crate) fn encode_value(value: Option<&Value>, out: &mut Vec<u8>) {
+    out.push(tag(value));
+
+    match value {
+        Some(Value::Bool(b)) => out.push(*b as u8),
+        Some(Value::Number(n)) => {
+            // Flipping the sign bit of positive floats and every bit of
+            // negative ones makes their big-endian bytes sort numerically.
+            let f = n.as_f64().unwrap_or_default();
+            let f = if f == 0.0 { 0.0 } else { f };
+            let bits = f.to_bits();
+            let bits = if f.is_sign_negative() {
+                !bits
+            } else {
+                bits ^ (1 << 63)
+            };
+            out.extend_from_slice(&bits.to_be_bytes());
+
+            // Integers beyond the precision of floats share their rounded
+            // value with their neighbours, so their distance to it follows,
+            // sign bit flipped. It is 0 for every other number.
+            let exact = n
+                .as_i64()
+                .map(i128::from)
+                .or_else(|| n.as_u64().map(i128::from));
+            let offset = exact.map_or(0, |exact| (exact - f as i128) as i32);
+            out.extend_from_slice(&((offset as u32) ^ (1 << 31)).to_be_bytes());
+        }
+        Some(Value::String(s)) => {
+            // 0x00 is escaped as 0x00 0xFF and the string ends with 0x00
+            // 0x01, so a string sorts before every string it prefixes.
+            for byte in s.bytes() {
+                out.push(byte);
+                if byte == 0 {
+                    out.push(0xFF);
+                }
+            }
+            out.extend_from_slice(&[0x00, 0x01]);
+        }
+        _ => {}
+    }
+}
+
+fn encoded_len(data: &[u8]) -> Option<usize> {
+    match *data.first()? {
+        TAG_BOOL => Some(2),
+        TAG_NUMBER => Some(13),
+        TAG_STRING => {
+            let mut i = 1;
+            loop {
+                match (data.get(i)?, data.get(i + 1)?) {
+                    (0x00, 0x01) => return Some(i + 2),
+                    (0x00, _) => i += 2,
+                    _ => i += 1,
+                }
+            }
+        }
+        _ => Some(1),
+    }
+}
+
+fn parse_definitions(data: Option<&[u8]>) -> Result<Vec<IndexDefinition>, DbError> {
+    match data {
+        Some(data) => serde_json::from_slice(data).map_err(|e| {
+            DbError::DeserializationError(format!("Failed to deserialize indexes: {}", e))
+        }),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn serialize_definitions(definitions: &[IndexDefinition]) -> Result<Vec<u8>, DbError> {
+    serde_json::to_vec(definitions)
+        .map_err(|e| DbError::SerializationError(format!("Failed to serialize indexes: {}", e)))
+}
+
+pub(crate) fn load_definitions(tree: &sled::Tree) -> Result<Vec<IndexDefinition>, DbError> {
+    parse_definitions(tree.get(keys::INDEXES_KEY)?.as_deref())
+}
+
+// Writers read the definitions inside their transaction, so an index
+// created through one `Collection` is maintained by every other handle too.
+pub(crate) fn load(
+    tx_tree: &TransactionalTree,
+) -> ConflictableTransactionResult<Vec<IndexDefinition>, DbError> {
+    parse_definitions(tx_tree.get(keys::INDEXES_KEY)?.as_deref())
+        .map_err(ConflictableTransactionError::Abort)
+}
+
+fn store(
+    tx_tree: &TransactionalTree,
+    definitions: &[IndexDefinition],
+) -> ConflictableTransactionResult<(), DbError> {
+    let data = serialize_definitions(definitions).map_err(ConflictableTransactionError::Abort)?;
+    tx_tree.insert(keys::INDEXES_KEY, data)?;
+    Ok(())
+}
+
+pub(crate) fn add_entries(
+    tx_tree: &TransactionalTree,
+    definitions: &[IndexDefinition],
+    id: &str,
+    body: &Value,
+) -> ConflictableTransactionResult<(), DbError> {
+    for definition in definitions {
+        let value = definition.value(body);
+
+        if let Some(unique_key) = definition.unique_key(value) {
+            if let Some(owner) = tx_tree.get(&unique_key)? {
+                if owner.as_ref() != id.as_bytes() {
+                    return Err(ConflictableTransactionError::Abort(DbError::AlreadyExists(
+                        format!(
+                            "Index {} already contains the value {}",
+                            definition.name,
+                            value.cloned().unwrap_or_default()
+                        ),
+                    )));
+                }
+            }
+            tx_tree.insert(unique_key, id.as_bytes())?;
+        }
+
+        tx_tree.insert(definition.entry_key(value, id), &[])?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn remove_entries(
+    tx_tree: &TransactionalTree,
+    definitions: &[IndexDefinition],
+    id: &str,
+    body: &Value,
+) -> ConflictableTransactionResult<(), DbError> {
+    for definition in definitions {
+        let value = definition.value(body);
+
+        if let Some(unique_key) = definition.unique_key(value) {
+            if tx_tree.get(&unique_key)?.as_deref() == Some(id.as_bytes()) {
+                tx_tree.remove(unique_key)?;
+            }
+        }
+
+        tx_tree.remove(definition.entry_key(value, id))?;
+    }
+
+    Ok(())
+}
+
+// Entries of one item for a non-transactional rebuild, see `migration`.
+pub(crate) fn entries(
+    definitions: &[IndexDefinition],
+    id: &str,
+    body: &Value,
+) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut entries = Vec::new();
+
+    for definition in definitions {
+        let value = definition.value(body);
+
+        if let Some(unique_key) = definition.unique_key(value) {
+            entries.push((unique_key, id.as_bytes().to_vec()));
+        }
+        entries.push((definition.entry_key(value, id), Vec::new()));
+    }
+
+    entries
+}
+
+// Start (inclusive) and end (exclusive) of a key range.
+type KeyRange = (Vec<u8>, Vec<u8>);
+
+// Entries of secondary indexes name items; entries of the field index of
+// dependency records name subcollections.
+enum Source {
+    Index,
+    Dependencies,
+}
+
+// Key ranges to walk and whether walking them yields items in the order the
+// query asks for.
+pub(crate) struct IndexScan {
+    prefix: Vec<u8>,
+    ranges: Vec<KeyRange>,
+    source: Source,
+    pub sorted: bool,
+    descending: bool,
+}
+
+fn upper_bound(prefix: &[u8]) -> Vec<u8> {
+    let mut key = prefix.to_vec();
+    if let Some(last) = key.last_mut() {
+        *last += 1;
+    }
+    key
+}
+
+// The conditions that all have to hold for `filter` to match.
+fn conditions(filter: Option<&Filter>) -> Vec<&Filter> {
+    match filter {
+        Some(Filter::And(filters)) => filters.iter().collect(),
+        Some(filter) => vec![filter],
+        None => Vec::new(),
+    }
+}
+
+// Field index prefixes for the conditions on dependency fields.
+fn dependency_candidates<'f>(conditions: &[&'f Filter]) -> Vec<(Vec<u8>, &'f str)> {
+    let mut candidates: Vec<(Vec<u8>, &str)> = Vec::new();
+
+    for condition in conditions {
+        let path = match condition {
+            Filter::Eq(path, _)
+            | Filter::Gt(path, _)
+            | Filter::Gte(path, _)
+            | Filter::Lt(path, _)
+            | Filter::Lte(path, _)
+            | Filter::In(path, _) => path.as_str(),
+            _ => continue,
+        };
+
+        if let Some(field) = path.strip_prefix("dependencies.") {
+            if !candidates.iter().any(|(_, p)| *p == path) {
+                candidates.push((dependencies::field_prefix(field), path));
+            }
+        }
+    }
+
+    candidates
+}
+
+// Narrows the entries under `prefix`, indexing the values at `path`, by the
+// conditions on that path. Returns `None` if the filter does not constrain
+// the field, and otherwise the ranges and whether they were pinned to
+// single values by `eq`/`in`. Candidates are a superset of the matches:
+// the filter is still applied to every item.
+fn constrain(prefix: &[u8], path: &str, conditions: &[&Filter]) -> Option<(Vec<KeyRange>, bool)> {
+    let bound = |value: &Value, suffix: &[u8]| {
+        let mut key = prefix.to_vec();
+        encode_value(Some(value), &mut key);
+        key.extend_from_slice(suffix);
+        key
+    };
+    let tag_bound = |tag: u8| {
+        let mut key = prefix.to_vec();
+        key.push(tag);
+        key
+    };
+
+    let mut points: Option<Vec<&Value>> = None;
+    let mut lower = prefix.to_vec();
+    let mut upper = upper_bound(prefix);
+    let mut ranged = false;
+
+    for condition in conditions {
+        match condition {
+            Filter::Eq(p, value) if p == path => {
+                points.get_or_insert_with(|| vec![value]);
+            }
+            Filter::In(p, values) if p == path => {
+                points.get_or_insert_with(|| values.iter().collect());
+            }
+            Filter::Gt(p, value) | Filter::Gte(p, value) if p == path => {
+                lower = lower.max(bound(value, &[]));
+                upper = upper.min(tag_bound(tag(Some(value)) + 1));
+                ranged = true;
+            }
+            Filter::Lt(p, value) | Filter::Lte(p, value) if p == path => {
+                lower = lower.max(tag_bound(tag(Some(value))));
+                upper = upper.min(bound(value, &[0xFF]));
+                ranged = true;
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(points) = points {
+        let mut ranges: Vec<_> = points
+            .into_iter()
+            .map(|value| (bound(value, &[]), bound(value, &[0xFF])))
+            .collect();
+        ranges.sort();
+        ranges.dedup();
+        return Some((ranges, true));
+    }
+
+    if !ranged {
+        return None;
+    }
+
+    if lower >= upper {
+        return Some((Vec::new(), false));
+    }
+
+    Some((vec![(lower, upper)], false))
+}
+
+// Picks the candidate narrowed best by `conditions`: preferably one pinned
+// to single values, otherwise the first one bounded by a range.
+fn best_constraint(
+    candidates: &[(Vec<u8>, &str)],
+    conditions: &[&Filter],
+) -> Option<(usize, Vec<KeyRange>)> {
+    let mut best: Option<(usize, Vec<KeyRange>, bool)> = None;
+
+    for (i, (prefix, path)) in candidates.iter().enumerate() {
+        if let Some((ranges, points)) = constrain(prefix, path, conditions) {
+            if best
+                .as_ref()
+                .is_none_or(|(_, _, best_points)| points && !best_points)
+            {
+                best = Some((i, ranges, points));
+            }
+        }
+    }
+
+    best.map(|(i, ranges, _)| (i, ranges))
+}
+
+// The part of each key within `ranges` following its encoded value, i.e.
+// an item ID or a dependency hash. Walking backwards, keys with equal
+// values still come in ascending order, just like after the stable sort of
+// a full scan.
+fn scan_entries(
+    tree: &sled::Tree,
+    prefix: &[u8],
+    ranges: &[KeyRange],
+    descending: bool,
+) -> Result<Vec<String>, DbError> {
+    let mut suffixes = Vec::new();
+
+    let mut ranges: Vec<&KeyRange> = ranges.iter().collect();
+    if descending {
+        ranges.reverse();
+    }
+
+    for (start, end) in ranges {
+        let entries = tree.range(start.as_slice()..end.as_slice()).keys();
+        let entries: Box<dyn Iterator<Item = _>> = if descending {
+            Box::new(entries.rev())
+        } else {
+            Box::new(entries)
+        };
+
+        let mut group: Vec<String> = Vec::new();
+        let mut group_value: Vec<u8> = Vec::new();
+
+        for key in entries {
+            let key = key?;
+            let rest = &key[prefix.len()..];
+            let len = encoded_len(rest)
+                .ok_or_else(|| DbError::DeserializationError("Invalid index entry".to_string()))?;
+            let suffix = String::from_utf8(rest[len..].to_vec()).map_err(|e| {
+                DbError::DeserializationError(format!("Invalid index entry: {}", e))
+            })?;
+
+            if !descending {
+                suffixes.push(suffix);
+                continue;
+            }
+
+            if rest[..len] != group_value[..] {
+                suffixes.extend(group.drain(..).rev());
+                group_value = rest[..len].to_vec();
+            }
+            group.push(suffix);
+        }
+
+        suffixes.extend(group.drain(..).rev());
+    }
+
+    Ok(suffixes)
+}
+
+impl Collection {
+    /// Builds a secondary index over `json_path` for all existing items and
+    /// maintains it in the transactions of every later insert, update and
+    /// delete. Queries filtering or sorting on `json_path` then read the
+    /// index instead of scanning the collection.
+    pub fn create_index(
+        &self,
+        name: &str,
+        json_path: &str,
+        options: IndexOptions,
+    ) -> Result<(), DbError> {
+        self.check_writable()?;
+
+        if name.is_empty() || name.contains('/') {
+            return Err(DbError::SchemaError(format!(
+                "Invalid index name: {:?}",
+                name
+            )));
+        }
+
+        if json_path != "body" && !json_path.starts_with("body.") {
+            return Err(DbError::SchemaError(format!(
+                "Index path must point into the body: {}",
+                json_path
+            )));
+        }
+
+        let definition = IndexDefinition {
+            name: name.to_string(),
+            path: json_path.to_string(),
+            options,
+            ready: false,
+        };
+
+        // Registering the index first makes concurrent writers maintain it
+        // while the existing items are added below. An unfinished build
+        // with the same definition is resumed.
+        self.tree.transaction(|tx_tree| {
+            let mut definitions = load(tx_tree)?;
+
+            match definitions.iter().find(|d| d.name == definition.name) {
+                Some(existing)
+                    if !existing.ready
+                        && existing.path == definition.path
+                        && existing.options == definition.options =>
+                {
+                    Ok(())
+                }
+                Some(_) => Err(ConflictableTransactionError::Abort(DbError::AlreadyExists(
+                    format!("Index {} already exists", definition.name),
+                ))),
+                None => {
+                    definitions.push(definition.clone());
+                    store(tx_tree, &definitions)
+                }
+            }
+        })?;
+
+        if let Err(e) = self.build_index(&definition) {
+            self.drop_index(name)?;
+            return Err(e);
+        }
+
+        self.tree.transaction(|tx_tree| {
+            let mut definitions = load(tx_tree)?;
+            for existing in definitions.iter_mut() {
+                if existing.name == definition.name {
+                    existing.ready = true;
+                }
+            }
+            store(tx_tree, &definitions)
+        })?;
+
+        self.flusher.commit()?;
+        Ok(())
+    }
+
+    // Each item is indexed in its own transaction, re-reading it there so
+    // that items changed or removed since the scan are indexed as they are
+    // now. Entries written by concurrent writers are simply rewritten.
+    fn build_index(&self, definition: &IndexDefinition) -> Result<(), DbError> {
+        let definitions = std::slice::from_ref(definition);
+
+        for id in self.keys() {
+            let id = id?;
+
+            self.tree.transaction(|tx_tree| {
+                let item_data = match tx_tree.get(item_key(&id))? {
+                    Some(data) => data,
+                    None => return Ok(()),
+                };
+
+                let storage_value =
+                    parse_storage_value(&item_data).map_err(ConflictableTransactionError::Abort)?;
+
+                add_entries(tx_tree, definitions, &id, &storage_value["body"])
+            })?;
+        }
+
+        Ok(())
+    }
+
+    pub fn drop_index(&self, name: &str) -> Result<(), DbError> {
+        self.check_writable()?;
+
+        self.tree.transaction(|tx_tree| {
+            let mut definitions = load(tx_tree)?;
+            let count = definitions.len();
+            definitions.retain(|d| d.name != name);
+
+            if definitions.len() == count {
+                return Err(ConflictableTransactionError::Abort(DbError::NotFound));
+            }
+
+            store(tx_tree, &definitions)
+        })?;
+
+        // Writers stop touching the index as soon as its definition is gone,
+        // so the entries can be removed outside of a transaction.
+        let mut batch = sled::Batch::default();
+        for prefix in [index_prefix(name), unique_prefix(name)] {
+            for key in self.tree.scan_prefix(prefix).keys() {
+                batch.remove(key?);
+            }
+        }
+        self.tree.apply_batch(batch)?;
+
+        self.flusher.commit()?;
+        Ok(())
+    }
+
+    pub fn list_indexes(&self) -> Result<Vec<IndexDefinition>, DbError> {
+        load_definitions(&self.tree)
+    }
+
+    // Picks the ranges to read for `query`: from a ready index or from the
+    // field index of dependency records, see `best_constraint`, and
+    // otherwise from an index providing the requested order when the query
+    // sorts by a single field.
+    pub(crate) fn plan_query(&self, query: &Query) -> Result<Option<IndexScan>, DbError> {
+        let definitions: Vec<IndexDefinition> = load_definitions(&self.tree)?
+            .into_iter()
+            .filter(|definition| definition.ready)
+            .collect();
+
+        let conditions = conditions(query.filter.as_ref());
+
+        let sort_key = match query.sort.as_slice() {
+            [key] => Some(key),
+            _ => None,
+        };
+
+        let mut candidates: Vec<(Vec<u8>, &str)> = definitions
+            .iter()
+            .map(|definition| (index_prefix(&definition.name), definition.path.as_str()))
+            .collect();
+        let indexes = candidates.len();
+        candidates.extend(dependency_candidates(&conditions));
+
+        let (i, ranges) = match best_constraint(&candidates, &conditions) {
+            Some(best) => best,
+            None => {
+                let sort_index = candidates[..indexes]
+                    .iter()
+                    .position(|(_, path)| sort_key.is_some_and(|key| key.path == *path));
+
+                match sort_index {
+                    Some(i) => {
+                        let prefix = candidates[i].0.clone();
+                        let upper = upper_bound(&prefix);
+                        (i, vec![(prefix, upper)])
+                    }
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        let (prefix, path) = candidates.swap_remove(i);
+        let source = if i < indexes {
+            Source::Index
+        } else {
+            Source::Dependencies
+        };
+
+        let sorted =
+            matches!(source, Source::Index) && sort_key.is_some_and(|key| key.path == path);
+        let descending = sorted && sort_key.is_some_and(|key| key.descending);
+
+        Ok(Some(IndexScan {
+            prefix,
+            ranges,
+            source,
+            sorted,
+            descending,
+        }))
+    }
+
+    // IDs of the items selected by `scan`, in the order of the index if it
+    // provides the sort and in ID order otherwise, as a full scan would.
+    pub(crate) fn scan_index(&self, scan: &IndexScan) -> Result<Vec<String>, DbError> {
+        let entries = scan_entries(&self.tree, &scan.prefix, &scan.ranges, scan.descending)?;
+
+        let mut ids = match scan.source {
+            Source::Index => entries,
+            Source::Dependencies => {
+                let mut hashes = entries;
+                hashes.sort();
+                hashes.dedup();
+
+                let mut ids = Vec::new();
+                for hash in hashes {
+                    let prefix = member_prefix(&hash);
+                    for key in self.tree.scan_prefix(&prefix).keys() {
+                        ids.extend(keys::strip(&key?, &prefix));
+                    }
+                }
+                ids
+            }
+        };
+
+        if !scan.sorted {
+            ids.sort();
+        }
+
+        Ok(ids)
+    }
+
+    // Hashes of the dependency records possibly matching `filter`, narrowed
+    // by the field index where the filter allows it.
+    pub(crate) fn scan_dependencies(&self, filter: &Filter) -> Result<Vec<String>, DbError> {
+        let conditions = conditions(Some(filter));
+        let candidates = dependency_candidates(&conditions);
+
+        let mut hashes = match best_constraint(&candidates, &conditions) {
+            Some((i, ranges)) => scan_entries(&self.tree, &candidates[i].0, &ranges, false)?,
+            None => {
+                let mut hashes = Vec::new();
+                for key in self.tree.scan_prefix(keys::DEPENDENCY_PREFIX).keys() {
+                    hashes.extend(keys::strip(&key?, keys::DEPENDENCY_PREFIX));
+                }
+                hashes
+            }
+        };
+
+        hashes.sort();
+        hashes.dedup();
+        Ok(hashes)
+    }
+}
@@ -0,0 +1,262 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::ops::Bound;
+
+use crate::helper::{from_hex, to_hex};
+use crate::keys::{self, item_key, member_prefix};
+use crate::{body_json, codec, is_expired, Collection, DbError, Subcollection};
+
+/// One page of a paginated listing. `next_cursor` is `None` on the last page;
+/// otherwise passing it to the next `page` call continues right after the
+/// last returned item, regardless of items inserted or deleted meanwhile.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<(String, T)>,
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    fn try_map<U>(
+        self,
+        mut f: impl FnMut(&str, T) -> Result<Option<U>, DbError>,
+    ) -> Result<Page<U>, DbError> {
+        let mut items = Vec::with_capacity(self.items.len());
+        for (id, value) in self.items {
+            if let Some(mapped) = f(&id, value)? {
+                items.push((id, mapped));
+            }
+        }
+
+        Ok(Page {
+            items,
+            next_cursor: self.next_cursor,
+        })
+    }
+}
+
+// Cursors are the hex-encoded ID of the last returned item, so listing
+// resumes by key order instead of by offset.
+fn decode_cursor(cursor: &str) -> Result<String, DbError> {
+    from_hex(cursor)
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(|| DbError::DeserializationError(format!("Invalid cursor: {}", cursor)))
+}
+
+// An empty page could only hand back the cursor it was given, so a limit of
+// 0 is rejected instead of making a paging loop spin forever.
+fn scan_page(
+    tree: &sled::Tree,
+    prefix: &[u8],
+    limit: usize,
+    cursor: Option<&str>,
+) -> Result<Page<sled::IVec>, DbError> {
+    if limit == 0 {
+        return Err(DbError::DeserializationError(
+            "Page limit must be at least 1".to_string(),
+        ));
+    }
+
+    let start = match cursor {
+        Some(cursor) => {
+            let mut key = prefix.to_vec();
+            key.extend_from_slice(decode_cursor(cursor)?.as_bytes());
+            Bound::Excluded(key)
+        }
+        None => Bound::Included(prefix.to_vec()),
+    };
+
+    let mut items: Vec<(String, sled::IVec)> = Vec::new();
+    let mut next_cursor = None;
+
+    for entry in tree.range::<Vec<u8>, _>((start, Bound::Unbounded)) {
+        let (key, value) = entry?;
+        let id = match keys::strip(&key, prefix) {
+            Some(id) => id,
+            None => break,
+        };
+
+        if items.len() == limit {
+            next_cursor = items.last().map(|(last, _)| to_hex(last.as_bytes()));
+            break;
+        }
+
+        items.push((id, value));
+    }
+
+    Ok(Page { items, next_cursor })
+}
+
+impl Collection {
+    // Expired items are skipped here and in every listing below.
+    pub fn keys(&self) -> impl Iterator<Item = Result<String, DbError>> + '_ {
+        self.tree
+            .scan_prefix(keys::ITEM_PREFIX)
+            .filter_map(|entry| {
+                let (key, item_data) = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => return Some(Err(DbError::from(e))),
+                };
+
+                match codec::decode(&item_data) {
+                    Ok(storage_value) if is_expired(&storage_value) => None,
+                    Ok(_) => keys::strip(&key, keys::ITEM_PREFIX).map(Ok),
+                    Err(e) => Some(Err(e)),
+                }
+            })
+    }
+
+    pub fn len(&self) -> Result<usize, DbError> {
+        let mut len = 0;
+        for key in self.keys() {
+            key?;
+            len += 1;
+        }
+
+        Ok(len)
+    }
+
+    pub fn is_empty(&self) -> Result<bool, DbError> {
+        Ok(self.keys().next().transpose()?.is_none())
+    }
+
+    pub fn iter<T: DeserializeOwned>(
+        &self,
+    ) -> impl Iterator<Item = Result<(String, T), DbError>> + '_ {
+        self.iter_json().map(|entry| {
+            let (id, json) = entry?;
+            let value = serde_json::from_str(&json)
+                .map_err(|e| DbError::DeserializationError(e.to_string()))?;
+            Ok((id, value))
+        })
+    }
+
+    pub fn iter_json(&self) -> impl Iterator<Item = Result<(String, String), DbError>> + '_ {
+        self.tree
+            .scan_prefix(keys::ITEM_PREFIX)
+            .filter_map(move |entry| {
+                let (key, item_data) = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => return Some(Err(DbError::from(e))),
+                };
+                let id = keys::strip(&key, keys::ITEM_PREFIX)?;
+
+                self.message_json(&item_data)
+                    .transpose()
+                    .map(|json| json.map(|json| (id, json)))
+            })
+    }
+
+    /// Lists at most `limit` items after `cursor`, in key order. Fails with
+    /// `DbError::DeserializationError` if `limit` is 0.
+    pub fn page<T: DeserializeOwned>(
+        &self,
+        limit: usize,
+        cursor: Option<&str>,
+    ) -> Result<Page<T>, DbError> {
+        self.page_json(limit, cursor)?.try_map(|_, json| {
+            serde_json::from_str(&json)
+                .map(Some)
+                .map_err(|e| DbError::DeserializationError(e.to_string()))
+        })
+    }
+
+    pub fn page_json(&self, limit: usize, cursor: Option<&str>) -> Result<Page<String>, DbError> {
+        scan_page(&self.tree, keys::ITEM_PREFIX, limit, cursor)?
+            .try_map(|_, item_data| self.message_json(&item_data))
+    }
+}
+
+impl<'a> Subcollection<'a> {
+    pub fn keys(&self) -> impl Iterator<Item = Result<String, DbError>> + '_ {
+        let prefix = member_prefix(&self.dependencies_hash);
+
+        self.collection
+            .tree
+            .scan_prefix(prefix.clone())
+            .keys()
+            .filter_map(move |key| {
+                let id = match key {
+                    Ok(key) => keys::strip(&key, &prefix)?,
+                    Err(e) => return Some(Err(DbError::from(e))),
+                };
+
+                match self.collection.tree.get(item_key(&id)) {
+                    Ok(Some(item_data)) => match codec::decode(&item_data) {
+                        Ok(storage_value) if is_expired(&storage_value) => None,
+                        Ok(_) => Some(Ok(id)),
+                        Err(e) => Some(Err(e)),
+                    },
+                    Ok(None) => None,
+                    Err(e) => Some(Err(DbError::from(e))),
+                }
+            })
+    }
+
+    pub fn len(&self) -> Result<usize, DbError> {
+        let mut len = 0;
+        for key in self.keys() {
+            key?;
+            len += 1;
+        }
+
+        Ok(len)
+    }
+
+    pub fn is_empty(&self) -> Result<bool, DbError> {
+        Ok(self.keys().next().transpose()?.is_none())
+    }
+
+    pub fn iter<T: DeserializeOwned>(
+        &self,
+    ) -> impl Iterator<Item = Result<(String, T), DbError>> + '_ {
+        self.iter_json().map(|entry| {
+            let (id, json) = entry?;
+            let body = serde_json::from_str(&json).map_err(|e| {
+                DbError::DeserializationError(format!("Failed to deserialize body: {}", e))
+            })?;
+            Ok((id, body))
+        })
+    }
+
+    // Yields the bodies of the subcollection. Items removed between reading
+    // the membership key and the item itself are skipped.
+    pub fn iter_json(&self) -> impl Iterator<Item = Result<(String, String), DbError>> + '_ {
+        self.keys().filter_map(move |id| {
+            let id = match id {
+                Ok(id) => id,
+                Err(e) => return Some(Err(e)),
+            };
+
+            match self.collection.tree.get(item_key(&id)) {
+                Ok(Some(item_data)) => body_json(&item_data)
+                    .transpose()
+                    .map(|json| json.map(|json| (id, json))),
+                Ok(None) => None,
+                Err(e) => Some(Err(DbError::from(e))),
+            }
+        })
+    }
+
+    pub fn page<T: DeserializeOwned>(
+        &self,
+        limit: usize,
+        cursor: Option<&str>,
+    ) -> Result<Page<T>, DbError> {
+        self.page_json(limit, cursor)?.try_map(|_, json| {
+            serde_json::from_str(&json).map(Some).map_err(|e| {
+                DbError::DeserializationError(format!("Failed to deserialize body: {}", e))
+            })
+        })
+    }
+
+    pub fn page_json(&self, limit: usize, cursor: Option<&str>) -> Result<Page<String>, DbError> {
+        let prefix = member_prefix(&self.dependencies_hash);
+
+        scan_page(&self.collection.tree, &prefix, limit, cursor)?.try_map(|id, _| {
+            match self.collection.tree.get(item_key(id))? {
+                Some(item_data) => body_json(&item_data),
+                None => Ok(None),
+            }
+        })
+    }
+}
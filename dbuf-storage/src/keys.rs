@@ -0,0 +1,146 @@
+// Key encoding of a collection tree. Every kind of record lives under its own
+// prefix, so a user-chosen ID can never collide with an internal key and a
+// prefix scan only ever sees one kind of record:
+//
+//   i/{id}            item: `{"deps": hash, "body": ..., "version": n}`
+//   d/{hash}          dependency record shared by all items with that hash
+//   s/{hash}/{id}     membership of item `id` in subcollection `hash`
+//   v/{path}{v}{hash} field `path` of dependency record `hash` has value `v`,
+//                     both encoded like index values
+//   x/{name}/{v}{id}  entry of secondary index `name`, `v` being the indexed
+//                     value in the order-preserving encoding of `index`
+//   u/{name}/{v}      ID owning value `v` of the unique index `name`
+//   c/{seq}           entry `seq` of the change feed, `seq` in big-endian
+//   h/{id}/{n}        retained version `n` of item `id`, `n` zero-padded to
+//                     20 digits so that versions sort numerically
+//   f/{id}            oldest retained version of item `id`, in big-endian
+//   t/{id}            deleted item `id` in the trash, with its full message
+//   e/{t}{id}         item `id` expires at `t` milliseconds since the Unix
+//                     epoch, `t` in big-endian
+//   r/{id}            last version of deleted item `id`, in big-endian, for
+//                     a new item with that ID to carry on from; only kept
+//                     with history on
+//   *metadata*        collection metadata, including the format version
+//   *indexes*         definitions of the secondary indexes
+//   *constraints*     constraints every message must satisfy
+//   *codec*           codec new items are written with, JSON if absent
+//   *id_counter*      last ID handed out by `IdStrategy::Counter`
+//   *change_seq*      sequence number of the last change
+//   *retired_version* highest version of a deleted item, in big-endian, for
+//                     new items to carry on from
+
+pub const ITEM_PREFIX: &[u8] = b"i/";
+pub const DEPENDENCY_PREFIX: &[u8] = b"d/";
+pub const MEMBER_PREFIX: &[u8] = b"s/";
+pub const FIELD_PREFIX: &[u8] = b"v/";
+pub const INDEX_PREFIX: &[u8] = b"x/";
+pub const UNIQUE_PREFIX: &[u8] = b"u/";
+pub const CHANGE_PREFIX: &[u8] = b"c/";
+pub const HISTORY_PREFIX: &[u8] = b"h/";
+pub const HISTORY_FIRST_PREFIX: &[u8] = b"f/";
+pub const TRASH_PREFIX: &[u8] = b"t/";
+pub const EXPIRY_PREFIX: &[u8] = b"e/";
+pub const RETIRED_PREFIX: &[u8] = b"r/";
+pub const INDEXES_KEY: &[u8] = b"*indexes*";
+pub const CONSTRAINTS_KEY: &[u8] = b"*constraints*";
+pub const CODEC_KEY: &[u8] = b"*codec*";
+pub const ID_COUNTER_KEY: &[u8] = b"*id_counter*";
+pub const CHANGE_SEQ_KEY: &[u8] = b"*change_seq*";
+pub const RETIRED_VERSION_KEY: &[u8] = b"*retired_version*";
+
+fn prefixed(prefix: &[u8], suffix: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(prefix.len() + suffix.len());
+    key.extend_from_slice(prefix);
+    key.extend_from_slice(suffix.as_bytes());
+    key
+}
+
+pub fn item_key(id: &str) -> Vec<u8> {
+    prefixed(ITEM_PREFIX, id)
+}
+
+pub fn dependency_key(hash: &str) -> Vec<u8> {
+    prefixed(DEPENDENCY_PREFIX, hash)
+}
+
+pub fn member_prefix(hash: &str) -> Vec<u8> {
+    let mut key = prefixed(MEMBER_PREFIX, hash);
+    key.push(b'/');
+    key
+}
+
+pub fn member_key(hash: &str, id: &str) -> Vec<u8> {
+    let mut key = member_prefix(hash);
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+pub fn index_prefix(name: &str) -> Vec<u8> {
+    let mut key = prefixed(INDEX_PREFIX, name);
+    key.push(b'/');
+    key
+}
+
+pub fn unique_prefix(name: &str) -> Vec<u8> {
+    let mut key = prefixed(UNIQUE_PREFIX, name);
+    key.push(b'/');
+    key
+}
+
+pub fn history_prefix(id: &str) -> Vec<u8> {
+    let mut key = prefixed(HISTORY_PREFIX, id);
+    key.push(b'/');
+    key
+}
+
+pub fn history_key(id: &str, version: u64) -> Vec<u8> {
+    let mut key = history_prefix(id);
+    key.extend_from_slice(format!("{:020}", version).as_bytes());
+    key
+}
+
+pub fn history_first_key(id: &str) -> Vec<u8> {
+    prefixed(HISTORY_FIRST_PREFIX, id)
+}
+
+pub fn trash_key(id: &str) -> Vec<u8> {
+    prefixed(TRASH_PREFIX, id)
+}
+
+pub fn expiry_key(expires_at: u64, id: &str) -> Vec<u8> {
+    let mut key = EXPIRY_PREFIX.to_vec();
+    key.extend_from_slice(&expires_at.to_be_bytes());
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+// Returns the expiry time and the ID of an expiry key.
+pub fn parse_expiry_key(key: &[u8]) -> Option<(u64, String)> {
+    let rest = key.strip_prefix(EXPIRY_PREFIX)?;
+    if rest.len() < 8 {
+        return None;
+    }
+
+    let (time, id) = rest.split_at(8);
+    let expires_at = u64::from_be_bytes(time.try_into().ok()?);
+
+    String::from_utf8(id.to_vec())
+        .ok()
+        .map(|id| (expires_at, id))
+}
+
+pub fn retired_key(id: &str) -> Vec<u8> {
+    prefixed(RETIRED_PREFIX, id)
+}
+
+pub fn change_key(seq: u64) -> Vec<u8> {
+    let mut key = CHANGE_PREFIX.to_vec();
+    key.extend_from_slice(&seq.to_be_bytes());
+    key
+}
+
+// Returns the part of `key` following `prefix`, e.g. the ID of an item key.
+pub fn strip(key: &[u8], prefix: &[u8]) -> Option<String> {
+    key.strip_prefix(prefix)
+        .and_then(|rest| String::from_utf8(rest.to_vec()).ok())
+}
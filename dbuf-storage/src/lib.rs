@@ -1,23 +1,97 @@
 use jsonschema::{Draft, JSONSchema};
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
 use schemars::{schema_for, JsonSchema};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{json, Value};
-use sled;
-use sled::transaction::TransactionError;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree,
+};
 use std::error::Error;
 use std::fmt;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+mod batch;
+pub use batch::{BatchMode, BatchResults};
+
+mod durability;
+pub use durability::Durability;
+use durability::Flusher;
 
 mod helper;
-use helper::get_json_hash;
+
+mod history;
+pub use history::{At, HistoryEntry, HistoryRetention};
+
+mod id;
+pub use id::{IdStrategy, MAX_ID_LEN, RESERVED_IDS};
+
+mod index;
+pub use index::{IndexDefinition, IndexOptions};
+
+mod iter;
+pub use iter::Page;
+
+mod keys;
+use helper::{canonical_json, canonicalize, get_json_hash, millis_since_epoch};
+use keys::{dependency_key, expiry_key, item_key, member_key, member_prefix};
+
+mod changes;
+pub use changes::{ChangeEvent, Watcher, DEFAULT_CHANGES_RETAINED};
+
+mod codec;
+pub use codec::StorageCodec;
+
+mod conditional;
+pub use conditional::{CompareAndSwapError, CompareAndSwapResult};
+
+mod constraint;
+pub use constraint::Constraint;
+
+mod config;
+pub use config::DatabaseConfig;
+
+mod dependencies;
+use dependencies::DependencyRecord;
+
+mod message;
+pub use dbuf_storage_derive::DbufMessage;
+pub use message::DbufMessage;
+
+mod migration;
+
+mod options;
+pub use options::CollectionOptions;
+
+mod query;
+pub use query::{Filter, Query, SortKey};
 
 mod schema;
 use schema::Schema;
 
+mod trash;
+pub use trash::DeletedItem;
+
+mod transaction;
+pub use transaction::{Transaction, TransactionCollection, TransactionSubcollection};
+
+mod ttl;
+
+mod typed;
+pub use typed::{Id, Message, TypedCollection, TypedSubcollection};
+
+mod validator;
+use validator::Validators;
+pub use validator::{ValidationError, Validator};
+
+mod version;
+
 const METADATA_KEY: &str = "*metadata*";
 
+// Version of the on-disk layout written by this build. Collections created
+// with an older version are rewritten by `migration` when they are opened.
+const FORMAT_VERSION: u32 = 5;
+
 #[derive(Debug)]
 pub enum DbError {
     SerializationError(String),
@@ -28,6 +102,11 @@ pub enum DbError {
     SchemaError(String),
     SchemaValidationError(String),
     SchemaCompilationError(String),
+    HashCollision(String),
+    ReadOnly,
+    ValidationError(ValidationError),
+    Conflict(String),
+    VersionMismatch(String),
 }
 
 impl fmt::Display for DbError {
@@ -41,6 +120,11 @@ impl fmt::Display for DbError {
             DbError::SchemaError(msg) => write!(f, "Schema error: {}", msg),
             DbError::SchemaValidationError(msg) => write!(f, "Schema validation error: {}", msg),
             DbError::SchemaCompilationError(msg) => write!(f, "Schema compilation error: {}", msg),
+            DbError::HashCollision(msg) => write!(f, "Hash collision: {}", msg),
+            DbError::ReadOnly => write!(f, "Database is open read-only"),
+            DbError::ValidationError(e) => write!(f, "Validation error: {}", e),
+            DbError::Conflict(msg) => write!(f, "Conflict: {}", msg),
+            DbError::VersionMismatch(msg) => write!(f, "Version mismatch: {}", msg),
         }
     }
 }
@@ -53,60 +137,66 @@ impl From<sled::Error> for DbError {
     }
 }
 
+impl From<TransactionError<DbError>> for DbError {
+    fn from(err: TransactionError<DbError>) -> Self {
+        match err {
+            TransactionError::Abort(err) => err,
+            TransactionError::Storage(err) => DbError::DatabaseError(err.to_string()),
+        }
+    }
+}
+
 pub struct Collection {
     tree: sled::Tree,
     metadata: CollectionMetadata,
+    flusher: Arc<Flusher>,
+    validators: Arc<Validators>,
+    read_only: bool,
 }
 
 pub struct Database {
     db: sled::Db,
+    flusher: Arc<Flusher>,
+    validators: Arc<Validators>,
+    read_only: bool,
+    sweeper: Option<ttl::Sweeper>,
 }
 
 impl Database {
     pub fn new(path: Option<&str>) -> Result<Self, DbError> {
-        let db_path = path.unwrap_or("./dbuf_db");
-        let db = sled::open(db_path).map_err(|e| DbError::DatabaseError(e.to_string()))?;
+        DatabaseConfig::new(path.unwrap_or("./dbuf_db")).open()
+    }
 
-        Ok(Database { db })
+    pub fn open(config: &DatabaseConfig) -> Result<Self, DbError> {
+        config.open()
     }
 
-    pub fn create_collection(&self, name: &str) -> Result<Collection, DbError> {
-        if self
-            .db
-            .tree_names()
-            .iter()
-            .any(|tree_name| tree_name == name.as_bytes())
-        {
-            return Err(DbError::AlreadyExists(format!(
-                "Collection {} already exists",
-                name
-            )));
+    fn from_sled(db: sled::Db, durability: Durability, read_only: bool) -> Self {
+        let flusher = Arc::new(Flusher::new(db.clone(), durability));
+
+        Database {
+            db,
+            flusher,
+            validators: Arc::new(Validators::default()),
+            read_only,
+            sweeper: None,
         }
+    }
 
-        let tree = self
-            .db
-            .open_tree(name.as_bytes())
-            .map_err(|e| DbError::DatabaseError(e.to_string()))?;
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
 
-        let metadata = CollectionMetadata {
-            name: name.to_string(),
-            body_schema: None,
-            dependencies_schema: None,
-            created_at: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs(),
-        };
-        let metadata_json = serde_json::to_string(&metadata).map_err(|e| {
-            DbError::SerializationError(format!("Failed to serialize metadata: {}", e))
-        })?;
+    fn check_writable(&self) -> Result<(), DbError> {
+        if self.read_only {
+            return Err(DbError::ReadOnly);
+        }
 
-        tree.insert(METADATA_KEY.as_bytes(), metadata_json.as_bytes())
-            .map_err(|e| DbError::DatabaseError(e.to_string()))?;
-        tree.flush()
-            .map_err(|e| DbError::DatabaseError(e.to_string()))?;
+        Ok(())
+    }
 
-        Ok(Collection { tree, metadata })
+    pub fn create_collection(&self, name: &str) -> Result<Collection, DbError> {
+        self.create_collection_with_options(name, CollectionOptions::new())
     }
 
     pub fn create_collection_with_schema<B, D>(&self, name: &str) -> Result<Collection, DbError>
@@ -134,6 +224,19 @@ impl Database {
         body_schema_json: &str,
         deps_schema_json: &str,
     ) -> Result<Collection, DbError> {
+        self.create_collection_with_options(
+            name,
+            CollectionOptions::new().schema_json(body_schema_json, deps_schema_json),
+        )
+    }
+
+    pub fn create_collection_with_options(
+        &self,
+        name: &str,
+        options: CollectionOptions,
+    ) -> Result<Collection, DbError> {
+        self.check_writable()?;
+
         if self
             .db
             .tree_names()
@@ -146,42 +249,19 @@ impl Database {
             )));
         }
 
-        let body_schema_value: Value = serde_json::from_str(body_schema_json).map_err(|e| {
-            DbError::DeserializationError(format!("Body schema JSON parsing error: {}", e))
-        })?;
-
-        let deps_schema_value: Value = serde_json::from_str(deps_schema_json).map_err(|e| {
-            DbError::DeserializationError(format!("Dependencies schema JSON parsing error: {}", e))
-        })?;
-
-        let compiled_body_schema = JSONSchema::options()
-            .with_draft(Draft::Draft7)
-            .compile(&body_schema_value)
-            .map_err(|e| {
-                DbError::SchemaCompilationError(format!("Failed to compile body schema: {}", e))
-            })?;
-
-        let compiled_deps_schema = JSONSchema::options()
-            .with_draft(Draft::Draft7)
-            .compile(&deps_schema_value)
-            .map_err(|e| {
-                DbError::SchemaCompilationError(format!(
-                    "Failed to compile dependencies schema: {}",
-                    e
-                ))
-            })?;
-
-        let body_schema = Schema {
-            schema_json: body_schema_json.to_string(),
-            schema_hash: crate::helper::get_json_hash(body_schema_json),
-            compiled: Some(Arc::new(compiled_body_schema)),
+        let (body_schema, deps_schema) = match &options.schemas {
+            Some((body_schema_json, deps_schema_json)) => (
+                Some(compile_schema(body_schema_json, "body")?),
+                Some(compile_schema(deps_schema_json, "dependencies")?),
+            ),
+            None => (None, None),
         };
 
-        let deps_schema = Schema {
-            schema_json: deps_schema_json.to_string(),
-            schema_hash: crate::helper::get_json_hash(deps_schema_json),
-            compiled: Some(Arc::new(compiled_deps_schema)),
-        };
+        let constraints = options
+            .constraints
+            .iter()
+            .map(|constraint| Constraint::parse(constraint))
+            .collect::<Result<Vec<_>, _>>()?;
 
         let tree = self
             .db
@@ -190,24 +270,47 @@ impl Database {
 
         let metadata = CollectionMetadata {
             name: name.to_string(),
-            body_schema: Some(body_schema),
-            dependencies_schema: Some(deps_schema),
+            body_schema,
+            dependencies_schema: deps_schema,
+            format_version: FORMAT_VERSION,
             created_at: std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap_or_default()
                 .as_secs(),
+            id_strategy: options.id_strategy,
+            changes_retained: options.changes_retained.unwrap_or(DEFAULT_CHANGES_RETAINED),
+            history: options.history,
+            soft_delete: options.soft_delete,
+            default_ttl: options.default_ttl,
         };
 
         let metadata_json = serde_json::to_string(&metadata).map_err(|e| {
             DbError::SerializationError(format!("Failed to serialize metadata: {}", e))
         })?;
 
+        if options.codec != StorageCodec::default() {
+            tree.insert(keys::CODEC_KEY, codec::serialize_codec(options.codec)?)?;
+        }
+
+        if !constraints.is_empty() {
+            tree.insert(
+                keys::CONSTRAINTS_KEY,
+                constraint::serialize_constraints(&constraints)?,
+            )?;
+        }
+
         tree.insert(METADATA_KEY.as_bytes(), metadata_json.as_bytes())
             .map_err(|e| DbError::DatabaseError(e.to_string()))?;
         tree.flush()
             .map_err(|e| DbError::DatabaseError(e.to_string()))?;
 
-        Ok(Collection { tree, metadata })
+        Ok(Collection {
+            tree,
+            metadata,
+            flusher: self.flusher.clone(),
+            validators: self.validators.clone(),
+            read_only: self.read_only,
+        })
     }
 
     pub fn get_collection(&self, name: &str) -> Result<Collection, DbError> {
@@ -225,7 +328,7 @@ impl Database {
             .open_tree(name.as_bytes())
             .map_err(|e| DbError::DatabaseError(e.to_string()))?;
 
-        let metadata = match tree.get(METADATA_KEY.as_bytes())? {
+        let mut metadata = match tree.get(METADATA_KEY.as_bytes())? {
             Some(metadata_bytes) => {
                 let metadata: CollectionMetadata = serde_json::from_slice(&metadata_bytes)
                     .map_err(|e| {
@@ -244,7 +347,18 @@ impl Database {
             }
         };
 
-        let mut collection = Collection { tree, metadata };
+        if metadata.format_version < FORMAT_VERSION {
+            self.check_writable()?;
+            migration::migrate_collection(&tree, &mut metadata)?;
+        }
+
+        let mut collection = Collection {
+            tree,
+            metadata,
+            flusher: self.flusher.clone(),
+            validators: self.validators.clone(),
+            read_only: self.read_only,
+        };
 
         collection.compile_schemas()?;
 
@@ -267,9 +381,38 @@ impl Database {
     }
 
     pub fn drop_collection(&self, name: &str) -> Result<(), DbError> {
+        self.check_writable()?;
+
         self.db.drop_tree(name.as_bytes())?;
+        self.validators.clear(name);
         Ok(())
     }
+
+    /// Rewrites every collection stored in an older on-disk format and
+    /// returns the names of the collections that were migrated.
+    pub fn migrate(&self) -> Result<Vec<String>, DbError> {
+        let mut migrated = Vec::new();
+
+        for name in self.list_collections() {
+            let tree = self.db.open_tree(name.as_bytes())?;
+
+            let mut metadata: CollectionMetadata = match tree.get(METADATA_KEY.as_bytes())? {
+                Some(metadata_bytes) => match serde_json::from_slice(&metadata_bytes) {
+                    Ok(metadata) => metadata,
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+
+            if metadata.format_version < FORMAT_VERSION {
+                self.check_writable()?;
+                migration::migrate_collection(&tree, &mut metadata)?;
+                migrated.push(name);
+            }
+        }
+
+        Ok(migrated)
+    }
 }
 
 pub struct Subcollection<'a> {
@@ -278,6 +421,24 @@ pub struct Subcollection<'a> {
     dependencies_hash: String,
 }
 
+// A message checked against the collection schemas, so that only the
+// writes are left for the transaction.
+struct PreparedMessage {
+    body: Value,
+    dependencies: Value,
+    dependencies_json: String,
+    deps_hash: String,
+}
+
+impl PreparedMessage {
+    fn to_value(&self) -> Value {
+        json!({
+            "body": self.body,
+            "dependencies": self.dependencies
+        })
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 struct CollectionMetadata {
     name: String,
@@ -285,37 +446,32 @@ struct CollectionMetadata {
     body_schema: Option<Schema>,
     #[serde(skip_serializing_if = "Option::is_none")]
     dependencies_schema: Option<Schema>,
+    #[serde(default)]
+    format_version: u32,
     created_at: u64,
+    #[serde(default)]
+    id_strategy: IdStrategy,
+    #[serde(default = "default_changes_retained")]
+    changes_retained: usize,
+    #[serde(default)]
+    history: HistoryRetention,
+    #[serde(default)]
+    soft_delete: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default_ttl: Option<Duration>,
 }
 
-impl Collection {
-    fn generate_id(&self) -> String {
-        let rand_string: String = thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(16)
-            .map(char::from)
-            .collect();
-
-        rand_string
-    }
-
-    fn generate_unique_id(&self) -> Result<String, DbError> {
-        const MAX_ATTEMPTS: usize = 10;
-
-        for attempt in 0..MAX_ATTEMPTS {
-            let id = self.generate_id();
-
-            if !self.tree.contains_key(id.as_bytes())? {
-                return Ok(id);
-            }
+fn default_changes_retained() -> usize {
+    DEFAULT_CHANGES_RETAINED
+}
 
-            println!("ID collision detected (attempt {}): {}", attempt + 1, id);
+impl Collection {
+    fn check_writable(&self) -> Result<(), DbError> {
+        if self.read_only {
+            return Err(DbError::ReadOnly);
         }
 
-        Err(DbError::DatabaseError(format!(
-            "Failed to generate unique ID after {} attempts",
-            MAX_ATTEMPTS
-        )))
+        Ok(())
     }
 
     fn compile_schemas(&mut self) -> Result<(), DbError> {
@@ -391,7 +547,22 @@ impl Collection {
     }
 
     pub fn insert_json(&self, json: String) -> Result<String, DbError> {
-        let value: Value = serde_json::from_str(&json)
+        self.check_writable()?;
+
+        let message = self.prepare_message(&json)?;
+
+        let id = self
+            .tree
+            .transaction(|tx_tree| self.insert_new(tx_tree, &message))?;
+
+        self.flusher.commit()?;
+
+        Ok(id)
+    }
+
+    // Parses and validates a full message.
+    fn prepare_message(&self, json: &str) -> Result<PreparedMessage, DbError> {
+        let value: Value = serde_json::from_str(json)
             .map_err(|e| DbError::DeserializationError(format!("JSON parsing error: {}", e)))?;
 
         let obj = match value.as_object() {
@@ -414,44 +585,98 @@ impl Collection {
 
         self.validate_body(body)?;
         self.validate_dependencies(dependencies)?;
+        self.run_validators(body, dependencies)?;
 
-        let dependencies_json = serde_json::to_string(dependencies).map_err(|e| {
-            DbError::SerializationError(format!("Failed to serialize dependencies: {}", e))
-        })?;
+        let dependencies_json = canonical_json(dependencies)?;
 
         let deps_hash = get_json_hash(&dependencies_json);
 
-        let id = self.generate_unique_id()?;
+        Ok(PreparedMessage {
+            body: body.clone(),
+            dependencies: canonicalize(dependencies),
+            dependencies_json,
+            deps_hash,
+        })
+    }
 
-        let storage_value = json!({
-            "deps": deps_hash,
-            "body": body
+    // Encodes the stored form of an item at the given version, expiring at
+    // `expires_at` milliseconds since the Unix epoch if set.
+    fn storage_data(
+        &self,
+        tx_tree: &TransactionalTree,
+        message: &PreparedMessage,
+        version: u64,
+        expires_at: Option<u64>,
+    ) -> ConflictableTransactionResult<Vec<u8>, DbError> {
+        let mut storage_value = json!({
+            "deps": message.deps_hash,
+            "body": message.body,
+            "version": version
         });
+        if let Some(expires_at) = expires_at {
+            storage_value["expires_at"] = json!(expires_at);
+        }
 
-        let storage_json = serde_json::to_string(&storage_value).map_err(|e| {
-            DbError::SerializationError(format!("Failed to serialize storage value: {}", e))
-        })?;
+        codec::load(tx_tree)?
+            .encode(&storage_value)
+            .map_err(ConflictableTransactionError::Abort)
+    }
 
-        let deps_exists = self.tree.contains_key(deps_hash.as_bytes())?;
+    // Inserts a message expiring after the default TTL of the collection.
+    fn insert_prepared(
+        &self,
+        tx_tree: &TransactionalTree,
+        id: &str,
+        message: &PreparedMessage,
+    ) -> ConflictableTransactionResult<(), DbError> {
+        let expires_at = self.metadata.default_ttl.map(ttl::expiry);
 
-        let result = self.tree.transaction(|tx_tree| {
-            tx_tree.insert(id.as_bytes(), storage_json.as_bytes())?;
+        self.insert_expiring(tx_tree, id, message, expires_at)
+    }
 
-            if !deps_exists {
-                tx_tree.insert(deps_hash.as_bytes(), dependencies_json.as_bytes())?;
+    fn insert_expiring(
+        &self,
+        tx_tree: &TransactionalTree,
+        id: &str,
+        message: &PreparedMessage,
+        expires_at: Option<u64>,
+    ) -> ConflictableTransactionResult<(), DbError> {
+        constraint::check_in(tx_tree, &message.to_value())?;
+
+        if let Some(item_data) = tx_tree.get(item_key(id))? {
+            let storage_value =
+                parse_storage_value(&item_data).map_err(ConflictableTransactionError::Abort)?;
+            if !is_expired(&storage_value) {
+                return Err(ConflictableTransactionError::Abort(DbError::AlreadyExists(
+                    format!("Item {} already exists", id),
+                )));
             }
 
-            let marker_key = format!("{}_{}", deps_hash, id);
-            tx_tree.insert(marker_key.as_bytes(), &[])?;
+            // An expired item not swept yet makes way for the new one.
+            self.remove_in(tx_tree, id, &storage_value)?;
+        }
 
-            Ok(())
-        });
+        let version = version::next_version_in(tx_tree, id)?;
+        tx_tree.insert(
+            item_key(id),
+            self.storage_data(tx_tree, message, version, expires_at)?,
+        )?;
 
-        result.map_err(|e: TransactionError| DbError::DatabaseError(e.to_string()))?;
+        if let Some(expires_at) = expires_at {
+            tx_tree.insert(expiry_key(expires_at, id), &[])?;
+        }
 
-        self.tree.flush()?;
+        dependencies::acquire(tx_tree, &message.deps_hash, &message.dependencies_json)?;
 
-        Ok(id)
+        tx_tree.insert(member_key(&message.deps_hash, id), &[])?;
+
+        let indexes = index::load(tx_tree)?;
+        index::add_entries(tx_tree, &indexes, id, &message.body)?;
+
+        self.record_change(tx_tree, id, None, Some(message.to_value()))?;
+        self.record_history(tx_tree, id, version, Some(message.to_value()))?;
+
+        Ok(())
     }
 
     pub fn get<T: DeserializeOwned>(&self, id: &str) -> Result<T, DbError> {
@@ -461,50 +686,61 @@ impl Collection {
     }
 
     pub fn get_json(&self, id: &str) -> Result<String, DbError> {
-        let item_data = match self.tree.get(id.as_bytes())? {
+        let item_data = match self.tree.get(item_key(id))? {
             Some(data) => data,
             None => return Err(DbError::NotFound),
         };
 
-        let storage_value: Value = serde_json::from_slice(&item_data).map_err(|e| {
-            DbError::DeserializationError(format!("Failed to deserialize storage value: {}", e))
-        })?;
+        self.message_json(&item_data)?.ok_or(DbError::NotFound)
+    }
 
-        if !storage_value.is_object()
-            || !storage_value.as_object().unwrap().contains_key("deps")
-            || !storage_value.as_object().unwrap().contains_key("body")
-        {
-            return Err(DbError::DeserializationError(
-                "Invalid storage structure: missing 'deps' or 'body'".to_string(),
-            ));
+    // The full message of a stored item, `None` if it expired.
+    fn message_json(&self, item_data: &[u8]) -> Result<Option<String>, DbError> {
+        let storage_value = parse_storage_value(item_data)?;
+        if is_expired(&storage_value) {
+            return Ok(None);
         }
 
-        let body = &storage_value["body"];
-        let deps_hash = storage_value["deps"]
-            .as_str()
-            .ok_or_else(|| DbError::DeserializationError("Invalid deps_hash format".to_string()))?;
+        let deps_hash = storage_deps_hash(&storage_value)?;
+        let deps_data = self.tree.get(dependency_key(deps_hash))?;
+
+        render_message(&storage_value, deps_data.as_deref()).map(Some)
+    }
 
-        let deps_data = match self.tree.get(deps_hash.as_bytes())? {
+    // The storage value of an item, `None` if there is none or it expired.
+    fn live_item_in(
+        &self,
+        tx_tree: &TransactionalTree,
+        id: &str,
+    ) -> ConflictableTransactionResult<Option<Value>, DbError> {
+        let item_data = match tx_tree.get(item_key(id))? {
             Some(data) => data,
-            None => {
-                return Err(DbError::DeserializationError(format!(
-                    "Dependencies with hash {} not found",
-                    deps_hash
-                )))
-            }
+            None => return Ok(None),
         };
 
-        let dependencies: Value = serde_json::from_slice(&deps_data).map_err(|e| {
-            DbError::DeserializationError(format!("Failed to deserialize dependencies: {}", e))
-        })?;
+        let storage_value =
+            parse_storage_value(&item_data).map_err(ConflictableTransactionError::Abort)?;
 
-        let result = json!({
-            "body": body,
-            "dependencies": dependencies
-        });
+        Ok(Some(storage_value).filter(|storage_value| !is_expired(storage_value)))
+    }
+
+    fn get_json_in(
+        &self,
+        tx_tree: &TransactionalTree,
+        id: &str,
+    ) -> ConflictableTransactionResult<String, DbError> {
+        let storage_value = match self.live_item_in(tx_tree, id)? {
+            Some(storage_value) => storage_value,
+            None => return Err(ConflictableTransactionError::Abort(DbError::NotFound)),
+        };
 
-        serde_json::to_string(&result)
-            .map_err(|e| DbError::SerializationError(format!("Failed to serialize result: {}", e)))
+        let deps_hash =
+            storage_deps_hash(&storage_value).map_err(ConflictableTransactionError::Abort)?;
+
+        let deps_data = tx_tree.get(dependency_key(deps_hash))?;
+
+        render_message(&storage_value, deps_data.as_deref())
+            .map_err(ConflictableTransactionError::Abort)
     }
 
     pub fn update<T: Serialize>(&self, id: &str, value: &T) -> Result<(), DbError> {
@@ -517,162 +753,224 @@ impl Collection {
     }
 
     pub fn update_json(&self, id: &str, json: String) -> Result<(), DbError> {
-        let old_item_data = match self.tree.get(id.as_bytes())? {
-            Some(data) => data,
-            None => return Err(DbError::NotFound),
-        };
+        self.check_writable()?;
 
-        let old_storage_value: Value = serde_json::from_slice(&old_item_data).map_err(|e| {
-            DbError::DeserializationError(format!("Failed to deserialize old item: {}", e))
-        })?;
+        let message = self.prepare_message(&json)?;
 
-        if !old_storage_value.is_object()
-            || !old_storage_value.as_object().unwrap().contains_key("deps")
-            || !old_storage_value.as_object().unwrap().contains_key("body")
-        {
-            return Err(DbError::DeserializationError(
-                "Invalid storage structure: missing 'deps' or 'body'".to_string(),
-            ));
-        }
+        let updated = self
+            .tree
+            .transaction(|tx_tree| self.update_prepared(tx_tree, id, &message))?;
 
-        let old_body = &old_storage_value["body"];
-        let old_deps_hash = old_storage_value["deps"]
-            .as_str()
-            .ok_or_else(|| DbError::DeserializationError("Invalid deps_hash format".to_string()))?;
+        if updated {
+            self.flusher.commit()?;
+        }
 
-        let new_value: Value = serde_json::from_str(&json)
-            .map_err(|e| DbError::DeserializationError(format!("JSON parsing error: {}", e)))?;
+        Ok(())
+    }
 
-        let obj = match new_value.as_object() {
-            Some(obj) => obj,
-            None => {
-                return Err(DbError::DeserializationError(
-                    "Invalid JSON structure: Not an object".to_string(),
-                ))
-            }
+    // Returns whether anything changed.
+    fn update_prepared(
+        &self,
+        tx_tree: &TransactionalTree,
+        id: &str,
+        message: &PreparedMessage,
+    ) -> ConflictableTransactionResult<bool, DbError> {
+        constraint::check_in(tx_tree, &message.to_value())?;
+
+        let old_storage_value = match self.live_item_in(tx_tree, id)? {
+            Some(storage_value) => storage_value,
+            None => return Err(ConflictableTransactionError::Abort(DbError::NotFound)),
         };
 
-        if obj.len() != 2 || !obj.contains_key("body") || !obj.contains_key("dependencies") {
-            return Err(DbError::DeserializationError(
-                "Invalid JSON structure: Message must contains exactly 2 keys: 'body' and 'dependencies'".to_string()
-            ));
+        let old_deps_hash =
+            storage_deps_hash(&old_storage_value).map_err(ConflictableTransactionError::Abort)?;
+
+        let body_changed = old_storage_value["body"] != message.body;
+        let deps_changed = old_deps_hash != message.deps_hash;
+
+        if !body_changed && !deps_changed {
+            return Ok(false);
         }
 
-        let new_body = &new_value["body"];
-        let new_dependencies = &new_value["dependencies"];
+        let old_message = self.logged_message_in(tx_tree, &old_storage_value)?;
 
-        self.validate_body(new_body)?;
-        self.validate_dependencies(new_dependencies)?;
+        if deps_changed {
+            tx_tree.remove(member_key(old_deps_hash, id))?;
+            dependencies::release(tx_tree, old_deps_hash)?;
 
-        let new_dependencies_json = serde_json::to_string(new_dependencies).map_err(|e| {
-            DbError::SerializationError(format!("Failed to serialize dependencies: {}", e))
-        })?;
+            dependencies::acquire(tx_tree, &message.deps_hash, &message.dependencies_json)?;
+            tx_tree.insert(member_key(&message.deps_hash, id), &[])?;
+        }
 
-        let new_deps_hash = get_json_hash(&new_dependencies_json);
+        if body_changed {
+            let indexes = index::load(tx_tree)?;
+            index::remove_entries(tx_tree, &indexes, id, &old_storage_value["body"])?;
+            index::add_entries(tx_tree, &indexes, id, &message.body)?;
+        }
 
-        let body_changed = serde_json::to_string(old_body)
-            .map_err(|e| DbError::SerializationError(e.to_string()))?
-            != serde_json::to_string(new_body)
-                .map_err(|e| DbError::SerializationError(e.to_string()))?;
+        // Updates keep the expiry of the item.
+        let version = storage_version(&old_storage_value) + 1;
+        let expires_at = storage_expiry(&old_storage_value);
+        tx_tree.insert(
+            item_key(id),
+            self.storage_data(tx_tree, message, version, expires_at)?,
+        )?;
 
-        let deps_changed = old_deps_hash != new_deps_hash;
+        self.record_change(tx_tree, id, old_message, Some(message.to_value()))?;
+        self.record_history(tx_tree, id, version, Some(message.to_value()))?;
 
-        if !body_changed && !deps_changed {
-            return Ok(());
-        }
+        Ok(true)
+    }
 
-        let mut new_storage_value = old_storage_value.clone();
+    pub fn delete(&self, id: &str) -> Result<(), DbError> {
+        self.delete_json(id)
+    }
 
-        if body_changed {
-            new_storage_value["body"] = new_body.clone();
-        }
+    pub fn delete_json(&self, id: &str) -> Result<(), DbError> {
+        self.check_writable()?;
 
-        if deps_changed {
-            new_storage_value["deps"] = json!(new_deps_hash);
-        }
+        self.tree
+            .transaction(|tx_tree| self.delete_in(tx_tree, id))?;
 
-        let updated_storage_json = serde_json::to_string(&new_storage_value).map_err(|e| {
-            DbError::SerializationError(format!("Failed to serialize updated item: {}", e))
-        })?;
+        self.flusher.commit()?;
+        Ok(())
+    }
+
+    fn delete_in(
+        &self,
+        tx_tree: &TransactionalTree,
+        id: &str,
+    ) -> ConflictableTransactionResult<(), DbError> {
+        let storage_value = match self.live_item_in(tx_tree, id)? {
+            Some(storage_value) => storage_value,
+            None => return Err(ConflictableTransactionError::Abort(DbError::NotFound)),
+        };
 
-        let new_deps_exists = self.tree.contains_key(new_deps_hash.as_bytes())?;
+        self.trash_in(tx_tree, id, &storage_value)?;
+        self.remove_in(tx_tree, id, &storage_value)
+    }
 
-        let result = self.tree.transaction(|tx_tree| {
-            if deps_changed {
-                let old_marker_key = format!("{}_{}", old_deps_hash, id);
-                tx_tree.remove(old_marker_key.as_bytes())?;
+    // Removes a stored item along with everything referring to it. Expired
+    // items are removed this way too, bypassing the trash.
+    fn remove_in(
+        &self,
+        tx_tree: &TransactionalTree,
+        id: &str,
+        storage_value: &Value,
+    ) -> ConflictableTransactionResult<(), DbError> {
+        let deps_hash =
+            storage_deps_hash(storage_value).map_err(ConflictableTransactionError::Abort)?;
+
+        let old_message = self.logged_message_in(tx_tree, storage_value)?;
+
+        tx_tree.remove(item_key(id))?;
+        if let Some(expires_at) = storage_expiry(storage_value) {
+            tx_tree.remove(expiry_key(expires_at, id))?;
+        }
 
-                if !new_deps_exists {
-                    tx_tree.insert(new_deps_hash.as_bytes(), new_dependencies_json.as_bytes())?;
-                }
+        tx_tree.remove(member_key(deps_hash, id))?;
 
-                let new_marker_key = format!("{}_{}", new_deps_hash, id);
-                tx_tree.insert(new_marker_key.as_bytes(), &[])?;
-            }
+        dependencies::release(tx_tree, deps_hash)?;
 
-            tx_tree.insert(id.as_bytes(), updated_storage_json.as_bytes())?;
+        let indexes = index::load(tx_tree)?;
+        index::remove_entries(tx_tree, &indexes, id, &storage_value["body"])?;
 
-            Ok(())
-        });
+        let version = storage_version(storage_value) + 1;
+        self.retire_in(tx_tree, id, version)?;
 
-        result.map_err(|e: TransactionError| DbError::DatabaseError(e.to_string()))?;
+        self.record_change(tx_tree, id, old_message, None)?;
+        self.record_history(tx_tree, id, version, None)?;
 
-        self.tree.flush()?;
         Ok(())
     }
 
-    pub fn delete(&self, id: &str) -> Result<(), DbError> {
-        self.delete_json(id)
-    }
+    // The full message of a stored item for the change log, read before its
+    // dependency record may be released. `None` if changes are not logged.
+    fn logged_message_in(
+        &self,
+        tx_tree: &TransactionalTree,
+        storage_value: &Value,
+    ) -> ConflictableTransactionResult<Option<Value>, DbError> {
+        if self.metadata.changes_retained == 0 {
+            return Ok(None);
+        }
 
-    pub fn delete_json(&self, id: &str) -> Result<(), DbError> {
-        let item_data = match self.tree.get(id.as_bytes())? {
-            Some(data) => data,
-            None => return Err(DbError::NotFound),
-        };
+        let deps_hash =
+            storage_deps_hash(storage_value).map_err(ConflictableTransactionError::Abort)?;
+        let deps_data = tx_tree.get(dependency_key(deps_hash))?;
 
-        let storage_value: Value = serde_json::from_slice(&item_data).map_err(|e| {
-            DbError::DeserializationError(format!("Failed to deserialize item: {}", e))
-        })?;
+        let message_json = render_message(storage_value, deps_data.as_deref())
+            .map_err(ConflictableTransactionError::Abort)?;
 
-        if !storage_value.is_object() || !storage_value.as_object().unwrap().contains_key("deps") {
-            return Err(DbError::DeserializationError(
-                "Invalid storage structure: missing 'deps'".to_string(),
-            ));
-        }
+        serde_json::from_str(&message_json).map(Some).map_err(|e| {
+            ConflictableTransactionError::Abort(DbError::DeserializationError(e.to_string()))
+        })
+    }
 
-        let deps_hash = storage_value["deps"]
-            .as_str()
-            .ok_or_else(|| DbError::DeserializationError("Invalid deps_hash format".to_string()))?;
-
-        let prefix = format!("{}_", deps_hash);
-        let mut items_cnt = 0;
-        for result in self.tree.scan_prefix(prefix.as_bytes()) {
-            if result.is_ok() {
-                items_cnt += 1;
-                if items_cnt >= 2 {
-                    break;
-                }
+    /// Removes dependency records that no item refers to anymore and repairs
+    /// reference counts that disagree with the stored items. Returns the
+    /// number of removed records.
+    pub fn gc_dependencies(&self) -> Result<usize, DbError> {
+        self.check_writable()?;
+
+        let mut removed = 0;
+
+        for entry in self.tree.scan_prefix(keys::DEPENDENCY_PREFIX) {
+            let (key, data) = entry?;
+            let hash = match keys::strip(&key, keys::DEPENDENCY_PREFIX) {
+                Some(hash) => hash,
+                None => continue,
+            };
+            let seen_refs = DependencyRecord::from_slice(&data)?.refs;
+
+            let mut refs = 0;
+            for marker in self.tree.scan_prefix(member_prefix(&hash)) {
+                marker?;
+                refs += 1;
             }
-        }
 
-        let result = self.tree.transaction(|tx_tree| {
-            tx_tree.remove(id.as_bytes())?;
+            if refs == seen_refs {
+                continue;
+            }
 
-            let marker_key = format!("{}_{}", deps_hash, id);
-            tx_tree.remove(marker_key.as_bytes())?;
+            // Items added or removed since the scan changed the count, so
+            // the record is left for the next sweep.
+            let swept = self.tree.transaction(|tx_tree| {
+                let data = match tx_tree.get(dependency_key(&hash))? {
+                    Some(data) => data,
+                    None => return Ok(false),
+                };
+
+                let mut record = DependencyRecord::from_slice(&data)
+                    .map_err(ConflictableTransactionError::Abort)?;
+                if record.refs != seen_refs {
+                    return Ok(false);
+                }
 
-            if items_cnt >= 2 {
-                tx_tree.remove(deps_hash.as_bytes())?;
-            }
+                if refs == 0 {
+                    tx_tree.remove(dependency_key(&hash))?;
+                    for key in dependencies::field_keys(&hash, &record.dependencies) {
+                        tx_tree.remove(key)?;
+                    }
+                    return Ok(true);
+                }
 
-            Ok(())
-        });
+                record.refs = refs;
+                let data = record
+                    .to_vec()
+                    .map_err(ConflictableTransactionError::Abort)?;
+                tx_tree.insert(dependency_key(&hash), data)?;
 
-        result.map_err(|e: TransactionError| DbError::DatabaseError(e.to_string()))?;
+                Ok(false)
+            })?;
 
-        self.tree.flush()?;
-        Ok(())
+            if swept {
+                removed += 1;
+            }
+        }
+
+        self.flusher.commit()?;
+        Ok(removed)
     }
 
     pub fn has_schema(&self) -> bool {
@@ -698,10 +996,13 @@ impl Collection {
     }
 
     pub fn get_name(&self) -> String {
-        return self.metadata.name.clone();
+        self.metadata.name.clone()
     }
 
-    pub fn subcollection<T: Serialize>(&self, dependencies: &T) -> Result<Subcollection, DbError> {
+    pub fn subcollection<T: Serialize>(
+        &self,
+        dependencies: &T,
+    ) -> Result<Subcollection<'_>, DbError> {
         let deps_json = serde_json::to_string(dependencies).map_err(|e| {
             DbError::SerializationError(format!("Failed to serialize dependencies: {}", e))
         })?;
@@ -709,16 +1010,19 @@ impl Collection {
         self.subcollection_json(deps_json)
     }
 
-    pub fn subcollection_json(&self, dependencies_json: String) -> Result<Subcollection, DbError> {
+    pub fn subcollection_json(
+        &self,
+        dependencies_json: String,
+    ) -> Result<Subcollection<'_>, DbError> {
         let dependencies: Value = serde_json::from_str(&dependencies_json)
             .map_err(|e| DbError::DeserializationError(format!("JSON parsing error: {}", e)))?;
 
-        let dependencies_hash = crate::helper::get_json_hash(&dependencies_json);
+        let dependencies = canonicalize(&dependencies);
+        let dependencies_json = canonical_json(&dependencies)?;
+        let dependencies_hash = get_json_hash(&dependencies_json);
 
-        if !self.tree.contains_key(dependencies_hash.as_bytes())? {
-            self.tree
-                .insert(dependencies_hash.as_bytes(), dependencies_json.as_bytes())?;
-            self.tree.flush()?;
+        if let Some(data) = self.tree.get(dependency_key(&dependencies_hash))? {
+            DependencyRecord::from_slice(&data)?.verify(&dependencies_hash, &dependencies_json)?;
         }
 
         Ok(Subcollection {
@@ -730,6 +1034,16 @@ impl Collection {
 }
 
 impl<'a> Subcollection<'a> {
+    pub fn get_dependencies<T: DeserializeOwned>(&self) -> Result<T, DbError> {
+        serde_json::from_value(self.dependencies.clone()).map_err(|e| {
+            DbError::DeserializationError(format!("Failed to deserialize dependencies: {}", e))
+        })
+    }
+
+    pub fn get_dependencies_json(&self) -> Result<String, DbError> {
+        canonical_json(&self.dependencies)
+    }
+
     pub fn insert<T: Serialize>(&self, body: &T) -> Result<String, DbError> {
         let body_json = serde_json::to_string(body)
             .map_err(|e| DbError::SerializationError(format!("Failed to serialize body: {}", e)))?;
@@ -738,19 +1052,7 @@ impl<'a> Subcollection<'a> {
     }
 
     pub fn insert_json(&self, body_json: String) -> Result<String, DbError> {
-        let body: Value = serde_json::from_str(&body_json)
-            .map_err(|e| DbError::DeserializationError(format!("JSON parsing error: {}", e)))?;
-
-        let full_object = serde_json::json!({
-            "body": body,
-            "dependencies": self.dependencies
-        });
-
-        let full_json = serde_json::to_string(&full_object).map_err(|e| {
-            DbError::SerializationError(format!("Failed to serialize full object: {}", e))
-        })?;
-
-        self.collection.insert_json(full_json)
+        self.collection.insert_json(self.message_json(&body_json)?)
     }
 
     pub fn get<T: DeserializeOwned>(&self, id: &str) -> Result<T, DbError> {
@@ -764,24 +1066,12 @@ impl<'a> Subcollection<'a> {
     pub fn get_json(&self, id: &str) -> Result<String, DbError> {
         self.check_belongs_to_subcollection(id)?;
 
-        let item_data = match self.collection.tree.get(id.as_bytes())? {
+        let item_data = match self.collection.tree.get(item_key(id))? {
             Some(data) => data,
             None => return Err(DbError::NotFound),
         };
 
-        let storage_value: Value = serde_json::from_slice(&item_data).map_err(|e| {
-            DbError::DeserializationError(format!("Failed to deserialize storage value: {}", e))
-        })?;
-
-        if !storage_value.is_object() || !storage_value.as_object().unwrap().contains_key("body") {
-            return Err(DbError::DeserializationError(
-                "Invalid storage structure: missing 'body'".to_string(),
-            ));
-        }
-
-        let body = &storage_value["body"];
-        serde_json::to_string(body)
-            .map_err(|e| DbError::SerializationError(format!("Failed to serialize body: {}", e)))
+        body_json(&item_data)?.ok_or(DbError::NotFound)
     }
 
     pub fn update<T: Serialize>(&self, id: &str, body: &T) -> Result<(), DbError> {
@@ -794,51 +1084,8 @@ impl<'a> Subcollection<'a> {
     pub fn update_json(&self, id: &str, body_json: String) -> Result<(), DbError> {
         self.check_belongs_to_subcollection(id)?;
 
-        let item_data = match self.collection.tree.get(id.as_bytes())? {
-            Some(data) => data,
-            None => return Err(DbError::NotFound),
-        };
-
-        let mut storage_value: Value = serde_json::from_slice(&item_data).map_err(|e| {
-            DbError::DeserializationError(format!("Failed to deserialize storage value: {}", e))
-        })?;
-
-        if !storage_value.is_object()
-            || !storage_value.as_object().unwrap().contains_key("deps")
-            || !storage_value.as_object().unwrap().contains_key("body")
-        {
-            return Err(DbError::DeserializationError(
-                "Invalid storage structure: missing 'deps' or 'body'".to_string(),
-            ));
-        }
-
-        let old_body = &storage_value["body"];
-
-        let new_body: Value = serde_json::from_str(&body_json).map_err(|e| {
-            DbError::DeserializationError(format!("JSON parsing error for new body: {}", e))
-        })?;
-
-        let body_changed = serde_json::to_string(old_body)
-            .map_err(|e| DbError::SerializationError(e.to_string()))?
-            != serde_json::to_string(&new_body)
-                .map_err(|e| DbError::SerializationError(e.to_string()))?;
-
-        if !body_changed {
-            return Ok(());
-        }
-
-        storage_value["body"] = new_body;
-
-        let updated_json = serde_json::to_string(&storage_value).map_err(|e| {
-            DbError::SerializationError(format!("Failed to serialize updated storage value: {}", e))
-        })?;
-
         self.collection
-            .tree
-            .insert(id.as_bytes(), updated_json.as_bytes())?;
-        self.collection.tree.flush()?;
-
-        Ok(())
+            .update_json(id, self.message_json(&body_json)?)
     }
 
     pub fn delete(&self, id: &str) -> Result<(), DbError> {
@@ -852,34 +1099,131 @@ impl<'a> Subcollection<'a> {
     }
 
     pub fn get_keys(&self) -> Result<Vec<String>, DbError> {
-        let mut keys = Vec::new();
-        let prefix = format!("{}_", self.dependencies_hash);
-
-        for result in self.collection.tree.scan_prefix(prefix.as_bytes()) {
-            match result {
-                Ok((key_bytes, _)) => {
-                    if let Ok(marker_key) = String::from_utf8(key_bytes.to_vec()) {
-                        let parts: Vec<&str> = marker_key.split('_').collect();
-                        if parts.len() > 1 {
-                            let id = parts[1..].join("_");
-                            keys.push(id);
-                        }
-                    }
-                }
-                Err(e) => return Err(DbError::from(e)),
-            }
-        }
+        self.keys().collect()
+    }
+
+    // Builds the full message for a body of this subcollection.
+    fn message_json(&self, body_json: &str) -> Result<String, DbError> {
+        let body: Value = serde_json::from_str(body_json)
+            .map_err(|e| DbError::DeserializationError(format!("JSON parsing error: {}", e)))?;
 
-        Ok(keys)
+        let full_object = serde_json::json!({
+            "body": body,
+            "dependencies": self.dependencies
+        });
+
+        serde_json::to_string(&full_object).map_err(|e| {
+            DbError::SerializationError(format!("Failed to serialize full object: {}", e))
+        })
     }
 
     fn check_belongs_to_subcollection(&self, id: &str) -> Result<(), DbError> {
-        let marker_key = format!("{}_{}", self.dependencies_hash, id);
+        let marker_key = member_key(&self.dependencies_hash, id);
 
-        if !self.collection.tree.contains_key(marker_key.as_bytes())? {
+        if !self.collection.tree.contains_key(marker_key)? {
             return Err(DbError::NotFound);
         }
 
         Ok(())
     }
 }
+
+// Parses and compiles a schema given as JSON; `kind` names it in errors.
+fn compile_schema(schema_json: &str, kind: &str) -> Result<Schema, DbError> {
+    let schema_value: Value = serde_json::from_str(schema_json).map_err(|e| {
+        DbError::DeserializationError(format!("Invalid {} schema JSON: {}", kind, e))
+    })?;
+
+    let compiled = JSONSchema::options()
+        .with_draft(Draft::Draft7)
+        .compile(&schema_value)
+        .map_err(|e| {
+            DbError::SchemaCompilationError(format!("Failed to compile {} schema: {}", kind, e))
+        })?;
+
+    Ok(Schema {
+        schema_json: schema_json.to_string(),
+        schema_hash: get_json_hash(schema_json),
+        compiled: Some(Arc::new(compiled)),
+    })
+}
+
+fn parse_storage_value(item_data: &[u8]) -> Result<Value, DbError> {
+    let storage_value = codec::decode(item_data)?;
+
+    if !storage_value.is_object()
+        || !storage_value.as_object().unwrap().contains_key("deps")
+        || !storage_value.as_object().unwrap().contains_key("body")
+    {
+        return Err(DbError::DeserializationError(
+            "Invalid storage structure: missing 'deps' or 'body'".to_string(),
+        ));
+    }
+
+    Ok(storage_value)
+}
+
+// Items written before versions were introduced are at version 1.
+fn storage_version(storage_value: &Value) -> u64 {
+    storage_value["version"].as_u64().unwrap_or(1)
+}
+
+fn storage_expiry(storage_value: &Value) -> Option<u64> {
+    storage_value["expires_at"].as_u64()
+}
+
+// Expired items read as missing until the sweeper removes them.
+fn is_expired(storage_value: &Value) -> bool {
+    storage_expiry(storage_value)
+        .is_some_and(|expires_at| expires_at <= millis_since_epoch(SystemTime::now()))
+}
+
+fn storage_deps_hash(storage_value: &Value) -> Result<&str, DbError> {
+    storage_value["deps"]
+        .as_str()
+        .ok_or_else(|| DbError::DeserializationError("Invalid deps_hash format".to_string()))
+}
+
+// Builds the full message of an item from its storage value and the data of
+// the dependency record it points at.
+fn render_message(storage_value: &Value, deps_data: Option<&[u8]>) -> Result<String, DbError> {
+    let deps_data = match deps_data {
+        Some(data) => data,
+        None => {
+            return Err(DbError::DeserializationError(format!(
+                "Dependencies with hash {} not found",
+                storage_deps_hash(storage_value)?
+            )))
+        }
+    };
+
+    let record = DependencyRecord::from_slice(deps_data)?;
+
+    let result = json!({
+        "body": storage_value["body"],
+        "dependencies": record.dependencies
+    });
+
+    serde_json::to_string(&result)
+        .map_err(|e| DbError::SerializationError(format!("Failed to serialize result: {}", e)))
+}
+
+// The body of a stored item, `None` if it expired.
+fn body_json(item_data: &[u8]) -> Result<Option<String>, DbError> {
+    let storage_value = codec::decode(item_data)?;
+
+    if !storage_value.is_object() || !storage_value.as_object().unwrap().contains_key("body") {
+        return Err(DbError::DeserializationError(
+            "Invalid storage structure: missing 'body'".to_string(),
+        ));
+    }
+
+    if is_expired(&storage_value) {
+        return Ok(None);
+    }
+
+    let body = &storage_value["body"];
+    serde_json::to_string(body)
+        .map(Some)
+        .map_err(|e| DbError::SerializationError(format!("Failed to serialize body: {}", e)))
+}
@@ -0,0 +1,103 @@
+use schemars::{schema::RootSchema, schema_for, JsonSchema};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::typed::check_schema;
+use crate::{Collection, CollectionOptions, Database, DbError};
+
+/// A message type that knows its collection, so it can be stored with
+/// `Database::store` and read with `Database::load`.
+///
+/// DependoBuf codegen output can derive it:
+///
+/// ```
+/// # use dbuf_storage::DbufMessage;
+/// # use serde::{Deserialize, Serialize};
+/// #[derive(Serialize, Deserialize, schemars::JsonSchema)]
+/// pub struct Body {
+///     pub name: String,
+/// }
+///
+/// #[derive(Serialize, Deserialize, schemars::JsonSchema)]
+/// pub struct Dependencies {
+///     pub age: u32,
+/// }
+///
+/// #[derive(Serialize, Deserialize, DbufMessage)]
+/// #[dbuf(collection = "users")]
+/// pub struct User {
+///     pub body: Body,
+///     pub dependencies: Dependencies,
+/// }
+///
+/// assert_eq!(User::COLLECTION, "users");
+/// ```
+pub trait DbufMessage: Serialize + DeserializeOwned {
+    type Body: Serialize + DeserializeOwned + JsonSchema + 'static;
+    type Dependencies: Serialize + DeserializeOwned + JsonSchema + 'static;
+
+    /// Name of the collection messages of this type are stored in.
+    const COLLECTION: &'static str;
+
+    fn body_schema() -> RootSchema {
+        schema_for!(Self::Body)
+    }
+
+    fn dependencies_schema() -> RootSchema {
+        schema_for!(Self::Dependencies)
+    }
+}
+
+impl Database {
+    /// Inserts `message` into the collection of its type, creating the
+    /// collection with the schemas of the type if it does not exist yet.
+    pub fn store<M: DbufMessage>(&self, message: &M) -> Result<String, DbError> {
+        self.message_collection::<M>(true)?.insert(message)
+    }
+
+    pub fn load<M: DbufMessage>(&self, id: &str) -> Result<M, DbError> {
+        self.message_collection::<M>(false)?.get(id)
+    }
+
+    // The collection of `M`, checked to have the schemas of `M`.
+    fn message_collection<M: DbufMessage>(&self, create: bool) -> Result<Collection, DbError> {
+        let body_schema = M::body_schema();
+        let deps_schema = M::dependencies_schema();
+
+        let collection = match self.get_collection(M::COLLECTION) {
+            Err(DbError::NotFound) if create => {
+                let options = CollectionOptions::new().schema_json(
+                    &serialize_schema(&body_schema, "body")?,
+                    &serialize_schema(&deps_schema, "dependencies")?,
+                );
+
+                match self.create_collection_with_options(M::COLLECTION, options) {
+                    // Created concurrently by another writer.
+                    Err(DbError::AlreadyExists(_)) => self.get_collection(M::COLLECTION)?,
+                    result => return result,
+                }
+            }
+            result => result?,
+        };
+
+        check_schema(
+            &collection,
+            "body",
+            collection.get_body_schema_json(),
+            &body_schema,
+        )?;
+        check_schema(
+            &collection,
+            "dependencies",
+            collection.get_dependencies_schema_json(),
+            &deps_schema,
+        )?;
+
+        Ok(collection)
+    }
+}
+
+fn serialize_schema(schema: &RootSchema, kind: &str) -> Result<String, DbError> {
+    serde_json::to_string(schema).map_err(|e| {
+        DbError::SerializationError(format!("Failed to serialize {} schema: {}", kind, e))
+    })
+}
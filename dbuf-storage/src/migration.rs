@@ -0,0 +1,234 @@
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+use crate::codec;
+use crate::dependencies::{self, DependencyRecord};
+use crate::helper::{canonical_json, canonicalize, get_json_hash};
+use crate::index;
+use crate::keys::{self, dependency_key, expiry_key, item_key, member_key};
+use crate::{
+    storage_expiry, storage_version, CollectionMetadata, DbError, FORMAT_VERSION, METADATA_KEY,
+};
+
+// A message recovered from an older on-disk layout.
+struct StoredMessage {
+    id: String,
+    body: Value,
+    dependencies: Value,
+    dependencies_json: String,
+    version: u64,
+    expires_at: Option<u64>,
+}
+
+// Rewrites the whole tree in the current format. All old keys are removed and
+// the items are written again from scratch in a single atomic batch, so a
+// crash in the middle leaves the collection in its previous state. Index
+// definitions are kept and their entries rebuilt along with the items.
+pub(crate) fn migrate_collection(
+    tree: &sled::Tree,
+    metadata: &mut CollectionMetadata,
+) -> Result<(), DbError> {
+    let messages = read_messages(tree, metadata.format_version)?;
+    let indexes = index::load_definitions(tree)?;
+    let codec = codec::load_codec(tree)?;
+
+    let mut batch = sled::Batch::default();
+
+    for entry in tree.iter() {
+        let (key, _) = entry?;
+        if !is_kept(&key) {
+            batch.remove(key);
+        }
+    }
+
+    let mut records: HashMap<String, DependencyRecord> = HashMap::new();
+
+    for message in &messages {
+        let deps_hash = get_json_hash(&message.dependencies_json);
+
+        let mut storage_value = json!({
+            "deps": deps_hash,
+            "body": message.body,
+            "version": message.version
+        });
+        if let Some(expires_at) = message.expires_at {
+            storage_value["expires_at"] = json!(expires_at);
+            batch.insert(expiry_key(expires_at, &message.id), &[]);
+        }
+        let storage_data = codec.encode(&storage_value)?;
+
+        batch.insert(item_key(&message.id), storage_data);
+        batch.insert(member_key(&deps_hash, &message.id), &[]);
+
+        for (key, value) in index::entries(&indexes, &message.id, &message.body) {
+            batch.insert(key, value);
+        }
+
+        records
+            .entry(deps_hash)
+            .or_insert_with(|| DependencyRecord {
+                refs: 0,
+                dependencies: message.dependencies.clone(),
+            })
+            .refs += 1;
+    }
+
+    for (deps_hash, record) in &records {
+        batch.insert(dependency_key(deps_hash), record.to_vec()?);
+
+        for key in dependencies::field_keys(deps_hash, &record.dependencies) {
+            batch.insert(key, &[]);
+        }
+    }
+
+    metadata.format_version = FORMAT_VERSION;
+    let metadata_json = serde_json::to_string(&metadata)
+        .map_err(|e| DbError::SerializationError(format!("Failed to serialize metadata: {}", e)))?;
+    batch.insert(METADATA_KEY.as_bytes(), metadata_json.as_bytes());
+
+    tree.apply_batch(batch)?;
+    tree.flush()?;
+
+    Ok(())
+}
+
+// Records that do not depend on the layout of items survive a migration.
+fn is_kept(key: &[u8]) -> bool {
+    key == METADATA_KEY.as_bytes()
+        || key == keys::INDEXES_KEY
+        || key == keys::CONSTRAINTS_KEY
+        || key == keys::CODEC_KEY
+        || key == keys::ID_COUNTER_KEY
+        || key == keys::CHANGE_SEQ_KEY
+        || key == keys::RETIRED_VERSION_KEY
+        || key.starts_with(keys::CHANGE_PREFIX)
+        || key.starts_with(keys::HISTORY_PREFIX)
+        || key.starts_with(keys::HISTORY_FIRST_PREFIX)
+        || key.starts_with(keys::TRASH_PREFIX)
+        || key.starts_with(keys::RETIRED_PREFIX)
+}
+
+// ID, body, dependencies, version and expiry of an item.
+type LoadedMessage = (String, Value, Value, u64, Option<u64>);
+
+fn read_messages(tree: &sled::Tree, version: u32) -> Result<Vec<StoredMessage>, DbError> {
+    let messages = if version >= 4 {
+        read_prefixed_messages(tree)?
+    } else {
+        read_legacy_messages(tree, version)?
+    };
+
+    let mut canonical = Vec::with_capacity(messages.len());
+    for (id, body, dependencies, version, expires_at) in messages {
+        let dependencies = canonicalize(&dependencies);
+        let dependencies_json = canonical_json(&dependencies)?;
+
+        canonical.push(StoredMessage {
+            id,
+            body,
+            dependencies,
+            dependencies_json,
+            version,
+            expires_at,
+        });
+    }
+
+    Ok(canonical)
+}
+
+// Collects the items of a tree using the prefixed keyspaces of `keys`.
+fn read_prefixed_messages(tree: &sled::Tree) -> Result<Vec<LoadedMessage>, DbError> {
+    let mut messages = Vec::new();
+
+    for entry in tree.scan_prefix(keys::ITEM_PREFIX) {
+        let (key, value) = entry?;
+        let id = match keys::strip(&key, keys::ITEM_PREFIX) {
+            Some(id) => id,
+            None => continue,
+        };
+
+        let storage_value = codec::decode(&value)?;
+        let deps_hash = storage_value["deps"].as_str().ok_or_else(|| {
+            DbError::DeserializationError(format!("Invalid deps_hash format in item {}", id))
+        })?;
+
+        let record = match tree.get(dependency_key(deps_hash))? {
+            Some(data) => DependencyRecord::from_slice(&data)?,
+            None => {
+                return Err(DbError::DatabaseError(format!(
+                    "Cannot migrate item {}: dependencies with hash {} not found",
+                    id, deps_hash
+                )))
+            }
+        };
+
+        messages.push((
+            id,
+            storage_value["body"].clone(),
+            record.dependencies,
+            storage_version(&storage_value),
+            storage_expiry(&storage_value),
+        ));
+    }
+
+    Ok(messages)
+}
+
+// Collects every item of a tree written before version 4, where items,
+// dependency records and `{hash}_{id}` markers share one keyspace. Items are
+// recognized as `{"deps", "body"}` objects whose `deps` points at another
+// key of the tree; everything else is an internal record. Since version 3
+// dependency records carry a reference count next to the dependencies.
+fn read_legacy_messages(tree: &sled::Tree, version: u32) -> Result<Vec<LoadedMessage>, DbError> {
+    let mut records = HashMap::new();
+    let mut candidates = Vec::new();
+
+    for entry in tree.iter() {
+        let (key, value) = entry?;
+        if key.as_ref() == METADATA_KEY.as_bytes() || value.is_empty() {
+            continue;
+        }
+
+        let key = String::from_utf8_lossy(&key).to_string();
+
+        if let Ok(Value::Object(obj)) = serde_json::from_slice::<Value>(&value) {
+            if let (2, Some(Value::String(deps)), Some(body)) =
+                (obj.len(), obj.get("deps"), obj.get("body"))
+            {
+                candidates.push((key.clone(), deps.clone(), body.clone()));
+            }
+        }
+
+        records.insert(key, value);
+    }
+
+    let referenced: HashSet<&String> = candidates.iter().map(|(_, deps, _)| deps).collect();
+
+    let mut messages = Vec::new();
+    for (id, deps_hash, body) in &candidates {
+        if referenced.contains(id) {
+            continue;
+        }
+
+        let dependencies = match records.get(deps_hash) {
+            Some(dependencies) => dependencies,
+            None => {
+                return Err(DbError::DatabaseError(format!(
+                    "Cannot migrate item {}: dependencies with hash {} not found",
+                    id, deps_hash
+                )))
+            }
+        };
+
+        let dependencies = if version >= 3 {
+            DependencyRecord::from_slice(dependencies)?.dependencies
+        } else {
+            serde_json::from_slice(dependencies).map_err(|e| {
+                DbError::DeserializationError(format!("Invalid dependencies record: {}", e))
+            })?
+        };
+        messages.push((id.clone(), body.clone(), dependencies, 1, None));
+    }
+
+    Ok(messages)
+}
@@ -0,0 +1,90 @@
+use schemars::{schema_for, JsonSchema};
+use std::time::Duration;
+
+use crate::{HistoryRetention, IdStrategy, StorageCodec};
+
+/// Settings fixed when a collection is created, for
+/// `Database::create_collection_with_options`.
+#[derive(Clone, Debug, Default)]
+pub struct CollectionOptions {
+    pub(crate) schemas: Option<(String, String)>,
+    pub(crate) codec: StorageCodec,
+    pub(crate) constraints: Vec<String>,
+    pub(crate) id_strategy: IdStrategy,
+    pub(crate) changes_retained: Option<usize>,
+    pub(crate) history: HistoryRetention,
+    pub(crate) soft_delete: bool,
+    pub(crate) default_ttl: Option<Duration>,
+}
+
+impl CollectionOptions {
+    pub fn new() -> Self {
+        CollectionOptions::default()
+    }
+
+    /// Validates bodies and dependencies against the JSON schemas of `B`
+    /// and `D`.
+    pub fn schema<B, D>(self) -> Self
+    where
+        B: JsonSchema + 'static,
+        D: JsonSchema + 'static,
+    {
+        // Generated schemas always serialize; an empty string would still
+        // be reported when the schema is compiled.
+        let body_schema_json = serde_json::to_string(&schema_for!(B)).unwrap_or_default();
+        let deps_schema_json = serde_json::to_string(&schema_for!(D)).unwrap_or_default();
+
+        self.schema_json(&body_schema_json, &deps_schema_json)
+    }
+
+    pub fn schema_json(mut self, body_schema_json: &str, deps_schema_json: &str) -> Self {
+        self.schemas = Some((body_schema_json.to_string(), deps_schema_json.to_string()));
+        self
+    }
+
+    pub fn codec(mut self, codec: StorageCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn id_strategy(mut self, id_strategy: IdStrategy) -> Self {
+        self.id_strategy = id_strategy;
+        self
+    }
+
+    /// Turns the change feed on, keeping this many past changes for
+    /// `Collection::watch_from`. By default `DEFAULT_CHANGES_RETAINED`, 0,
+    /// the feed is off and nothing can be watched.
+    pub fn changes_retained(mut self, changes_retained: usize) -> Self {
+        self.changes_retained = Some(changes_retained);
+        self
+    }
+
+    /// Keeps past versions of items for `Collection::history`, `get_at` and
+    /// `revert`; off by default.
+    pub fn history(mut self, history: HistoryRetention) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Moves deleted items to a trash, from which `Collection::restore`
+    /// brings them back until `Collection::purge` removes them.
+    pub fn soft_delete(mut self, soft_delete: bool) -> Self {
+        self.soft_delete = soft_delete;
+        self
+    }
+
+    /// Lets inserted items expire after `ttl` unless they are inserted with
+    /// a TTL of their own.
+    pub fn default_ttl(mut self, ttl: Duration) -> Self {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    /// Adds a `Constraint` every message must satisfy, e.g.
+    /// `body.sum.dependencies.a == dependencies.b - dependencies.a`.
+    pub fn constraint(mut self, constraint: &str) -> Self {
+        self.constraints.push(constraint.to_string());
+        self
+    }
+}
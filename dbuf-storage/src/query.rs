@@ -0,0 +1,432 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+
+use crate::{Collection, DbError, Subcollection};
+
+/// A predicate over stored messages. Paths are dot separated and start at the
+/// message, e.g. `body.sum.dependencies.a` or `dependencies.b`; numeric
+/// segments index into arrays.
+///
+/// In JSON a filter is written as `{"eq": ["body.name", "Alice"]}`,
+/// `{"in": ["dependencies.a", [1, 2]]}`, `{"exists": "body.x"}` or
+/// `{"and": [...]}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    Eq(String, Value),
+    Ne(String, Value),
+    Gt(String, Value),
+    Gte(String, Value),
+    Lt(String, Value),
+    Lte(String, Value),
+    In(String, Vec<Value>),
+    Exists(String),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    pub fn eq(path: &str, value: impl Into<Value>) -> Self {
+        Filter::Eq(path.to_string(), value.into())
+    }
+
+    pub fn ne(path: &str, value: impl Into<Value>) -> Self {
+        Filter::Ne(path.to_string(), value.into())
+    }
+
+    pub fn gt(path: &str, value: impl Into<Value>) -> Self {
+        Filter::Gt(path.to_string(), value.into())
+    }
+
+    pub fn gte(path: &str, value: impl Into<Value>) -> Self {
+        Filter::Gte(path.to_string(), value.into())
+    }
+
+    pub fn lt(path: &str, value: impl Into<Value>) -> Self {
+        Filter::Lt(path.to_string(), value.into())
+    }
+
+    pub fn lte(path: &str, value: impl Into<Value>) -> Self {
+        Filter::Lte(path.to_string(), value.into())
+    }
+
+    pub fn is_in(path: &str, values: Vec<Value>) -> Self {
+        Filter::In(path.to_string(), values)
+    }
+
+    pub fn exists(path: &str) -> Self {
+        Filter::Exists(path.to_string())
+    }
+
+    pub fn and(filters: Vec<Filter>) -> Self {
+        Filter::And(filters)
+    }
+
+    pub fn or(filters: Vec<Filter>) -> Self {
+        Filter::Or(filters)
+    }
+
+    pub fn matches(&self, message: &Value) -> bool {
+        match self {
+            Filter::Eq(path, value) => lookup(message, path).is_some_and(|v| equal(v, value)),
+            Filter::Ne(path, value) => !lookup(message, path).is_some_and(|v| equal(v, value)),
+            Filter::Gt(path, value) => compare_at(message, path, value) == Some(Ordering::Greater),
+            Filter::Gte(path, value) => matches!(
+                compare_at(message, path, value),
+                Some(Ordering::Greater | Ordering::Equal)
+            ),
+            Filter::Lt(path, value) => compare_at(message, path, value) == Some(Ordering::Less),
+            Filter::Lte(path, value) => matches!(
+                compare_at(message, path, value),
+                Some(Ordering::Less | Ordering::Equal)
+            ),
+            Filter::In(path, values) => {
+                lookup(message, path).is_some_and(|v| values.iter().any(|value| equal(v, value)))
+            }
+            Filter::Exists(path) => lookup(message, path).is_some(),
+            Filter::And(filters) => filters.iter().all(|filter| filter.matches(message)),
+            Filter::Or(filters) => filters.iter().any(|filter| filter.matches(message)),
+            Filter::Not(filter) => !filter.matches(message),
+        }
+    }
+}
+
+impl std::ops::Not for Filter {
+    type Output = Filter;
+
+    fn not(self) -> Filter {
+        Filter::Not(Box::new(self))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SortKey {
+    pub path: String,
+    #[serde(default)]
+    pub descending: bool,
+}
+
+/// Filter, sort, limit and projection applied by `Collection::query_json`.
+/// Deserializes from `{"filter": ..., "sort": [{"path": ..., "descending":
+/// true}], "limit": 10, "projection": ["body.name"]}`, every field optional.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Query {
+    #[serde(default)]
+    pub filter: Option<Filter>,
+    #[serde(default)]
+    pub sort: Vec<SortKey>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub projection: Option<Vec<String>>,
+}
+
+impl Query {
+    pub fn new() -> Self {
+        Query::default()
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn sort_by(mut self, path: &str, descending: bool) -> Self {
+        self.sort.push(SortKey {
+            path: path.to_string(),
+            descending,
+        });
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn project(mut self, paths: &[&str]) -> Self {
+        self.projection = Some(paths.iter().map(|path| path.to_string()).collect());
+        self
+    }
+
+    fn matches(&self, message: &Value) -> bool {
+        self.filter
+            .as_ref()
+            .is_none_or(|filter| filter.matches(message))
+    }
+
+    // Runs the query over `(id, message)` pairs and returns the projected
+    // messages in result order. `presorted` messages already arrive in the
+    // order of `sort`, e.g. from an index scan.
+    fn execute(
+        &self,
+        messages: impl Iterator<Item = Result<(String, Value), DbError>>,
+        presorted: bool,
+    ) -> Result<Vec<(String, Value)>, DbError> {
+        let sorted = presorted || self.sort.is_empty();
+
+        let mut results = Vec::new();
+
+        for message in messages {
+            let (id, message) = message?;
+            if !self.matches(&message) {
+                continue;
+            }
+
+            results.push((id, message));
+
+            if sorted && self.limit == Some(results.len()) {
+                break;
+            }
+        }
+
+        if !sorted {
+            results.sort_by(|(_, a), (_, b)| self.compare(a, b));
+        }
+
+        if let Some(limit) = self.limit {
+            results.truncate(limit);
+        }
+
+        if let Some(ref paths) = self.projection {
+            for (_, message) in results.iter_mut() {
+                *message = project(message, paths);
+            }
+        }
+
+        Ok(results)
+    }
+
+    fn compare(&self, a: &Value, b: &Value) -> Ordering {
+        for key in &self.sort {
+            let ordering = sort_order(lookup(a, &key.path), lookup(b, &key.path));
+            let ordering = if key.descending {
+                ordering.reverse()
+            } else {
+                ordering
+            };
+
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+
+        Ordering::Equal
+    }
+}
+
+pub(crate) fn lookup<'v>(value: &'v Value, path: &str) -> Option<&'v Value> {
+    if path.is_empty() {
+        return Some(value);
+    }
+
+    path.split('.')
+        .try_fold(value, |current, segment| match current {
+            Value::Object(obj) => obj.get(segment),
+            Value::Array(items) => segment.parse::<usize>().ok().and_then(|i| items.get(i)),
+            _ => None,
+        })
+}
+
+fn compare_at(message: &Value, path: &str, value: &Value) -> Option<Ordering> {
+    lookup(message, path).and_then(|v| compare(v, value))
+}
+
+// Orders values of the same kind; numbers compare by value regardless of
+// their integer or float representation.
+pub(crate) fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => {
+            if let (Some(x), Some(y)) = (x.as_i64(), y.as_i64()) {
+                Some(x.cmp(&y))
+            } else if let (Some(x), Some(y)) = (x.as_u64(), y.as_u64()) {
+                Some(x.cmp(&y))
+            } else {
+                x.as_f64()?.partial_cmp(&y.as_f64()?)
+            }
+        }
+        (Value::String(x), Value::String(y)) => Some(x.cmp(y)),
+        (Value::Bool(x), Value::Bool(y)) => Some(x.cmp(y)),
+        (Value::Null, Value::Null) => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+pub(crate) fn equal(a: &Value, b: &Value) -> bool {
+    match compare(a, b) {
+        Some(ordering) => ordering == Ordering::Equal,
+        None => a == b,
+    }
+}
+
+// Total order used for sorting: missing < null < bool < number < string <
+// array < object.
+fn sort_order(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    fn rank(value: Option<&Value>) -> u8 {
+        match value {
+            None => 0,
+            Some(Value::Null) => 1,
+            Some(Value::Bool(_)) => 2,
+            Some(Value::Number(_)) => 3,
+            Some(Value::String(_)) => 4,
+            Some(Value::Array(_)) => 5,
+            Some(Value::Object(_)) => 6,
+        }
+    }
+
+    match (a, b) {
+        (Some(x), Some(y)) => compare(x, y).unwrap_or_else(|| rank(a).cmp(&rank(b))),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+// Keeps only the given paths of `message`, rebuilding the nesting around
+// them. Paths that do not resolve are left out.
+fn project(message: &Value, paths: &[String]) -> Value {
+    let mut projected = Value::Object(Map::new());
+
+    for path in paths {
+        let value = match lookup(message, path) {
+            Some(value) => value.clone(),
+            None => continue,
+        };
+
+        let mut current = &mut projected;
+        let segments: Vec<&str> = path.split('.').collect();
+        for (i, segment) in segments.iter().enumerate() {
+            let obj = match current {
+                Value::Object(obj) => obj,
+                _ => break,
+            };
+
+            if i + 1 == segments.len() {
+                obj.insert(segment.to_string(), value);
+                break;
+            }
+
+            current = obj
+                .entry(segment.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+        }
+    }
+
+    projected
+}
+
+fn parse_message(entry: Result<(String, String), DbError>) -> Result<(String, Value), DbError> {
+    let (id, json) = entry?;
+    let message = serde_json::from_str(&json)
+        .map_err(|e| DbError::DeserializationError(format!("JSON parsing error: {}", e)))?;
+    Ok((id, message))
+}
+
+fn into_json(results: Vec<(String, Value)>) -> Result<Vec<(String, String)>, DbError> {
+    results
+        .into_iter()
+        .map(|(id, message)| {
+            let json = serde_json::to_string(&message).map_err(|e| {
+                DbError::SerializationError(format!("Failed to serialize result: {}", e))
+            })?;
+            Ok((id, json))
+        })
+        .collect()
+}
+
+fn into_typed<T: DeserializeOwned>(
+    results: Vec<(String, Value)>,
+) -> Result<Vec<(String, T)>, DbError> {
+    results
+        .into_iter()
+        .map(|(id, message)| {
+            let value = serde_json::from_value(message)
+                .map_err(|e| DbError::DeserializationError(e.to_string()))?;
+            Ok((id, value))
+        })
+        .collect()
+}
+
+impl Collection {
+    fn run_query(&self, query: &Query) -> Result<Vec<(String, Value)>, DbError> {
+        let scan = match self.plan_query(query)? {
+            Some(scan) => scan,
+            None => return query.execute(self.iter_json().map(parse_message), false),
+        };
+
+        let messages =
+            self.scan_index(&scan)?
+                .into_iter()
+                .filter_map(|id| match self.get_json(&id) {
+                    Ok(json) => Some(parse_message(Ok((id, json)))),
+                    Err(DbError::NotFound) => None,
+                    Err(e) => Some(Err(e)),
+                });
+
+        query.execute(messages, scan.sorted)
+    }
+
+    pub fn query<T: DeserializeOwned>(&self, query: &Query) -> Result<Vec<(String, T)>, DbError> {
+        into_typed(self.run_query(query)?)
+    }
+
+    pub fn query_json(&self, query: &Query) -> Result<Vec<(String, String)>, DbError> {
+        into_json(self.run_query(query)?)
+    }
+
+    pub fn query_ids(&self, query: &Query) -> Result<Vec<String>, DbError> {
+        let query = Query {
+            projection: None,
+            ..query.clone()
+        };
+
+        Ok(self
+            .run_query(&query)?
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect())
+    }
+}
+
+impl<'a> Subcollection<'a> {
+    // Subcollection queries see the same full messages as collection
+    // queries, restricted to the members of the subcollection.
+    fn run_query(&self, query: &Query) -> Result<Vec<(String, Value)>, DbError> {
+        let messages = self.keys().filter_map(|id| {
+            let id = match id {
+                Ok(id) => id,
+                Err(e) => return Some(Err(e)),
+            };
+
+            match self.collection.get_json(&id) {
+                Ok(json) => Some(parse_message(Ok((id, json)))),
+                Err(DbError::NotFound) => None,
+                Err(e) => Some(Err(e)),
+            }
+        });
+
+        query.execute(messages, false)
+    }
+
+    pub fn query<T: DeserializeOwned>(&self, query: &Query) -> Result<Vec<(String, T)>, DbError> {
+        into_typed(self.run_query(query)?)
+    }
+
+    pub fn query_json(&self, query: &Query) -> Result<Vec<(String, String)>, DbError> {
+        into_json(self.run_query(query)?)
+    }
+
+    pub fn query_ids(&self, query: &Query) -> Result<Vec<String>, DbError> {
+        let query = Query {
+            projection: None,
+            ..query.clone()
+        };
+
+        Ok(self
+            .run_query(&query)?
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect())
+    }
+}
@@ -0,0 +1,335 @@
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionalTree,
+    UnabortableTransactionError,
+};
+use sled::Transactional;
+use std::cell::RefCell;
+
+use crate::helper::{canonical_json, canonicalize, get_json_hash};
+use crate::keys::{dependency_key, item_key, member_key};
+use crate::{body_json, Collection, Database, DbError, DependencyRecord};
+
+/// Access to the collections of a database inside `Database::transaction`.
+/// Reads see the writes made earlier in the same transaction; nothing is
+/// visible to others before the transaction commits.
+pub struct Transaction<'a> {
+    database: &'a Database,
+    collections: &'a [Collection],
+    trees: &'a [TransactionalTree],
+    // Conflicts and storage failures hit by an operation. They are reported
+    // to sled instead of the error returned by the closure, so that sled
+    // retries the closure or fails the whole transaction.
+    failure: RefCell<Option<UnabortableTransactionError>>,
+    // A collection asked for that is not part of the transaction yet. The
+    // transaction is then run again with it.
+    missing: &'a RefCell<Option<String>>,
+}
+
+pub struct TransactionCollection<'t> {
+    collection: &'t Collection,
+    tx_tree: &'t TransactionalTree,
+    failure: &'t RefCell<Option<UnabortableTransactionError>>,
+}
+
+pub struct TransactionSubcollection<'t> {
+    collection: TransactionCollection<'t>,
+    dependencies: Value,
+    dependencies_hash: String,
+}
+
+impl Database {
+    /// Runs `f` as one atomic transaction over the collections it uses. If
+    /// `f` returns an error nothing is written. On a conflict with a
+    /// concurrent writer, or when `f` uses a collection for the first time,
+    /// `f` is run again, so it may be called more than once and should not
+    /// have other side effects.
+    pub fn transaction<F, R>(&self, f: F) -> Result<R, DbError>
+    where
+        F: Fn(&Transaction<'_>) -> Result<R, DbError>,
+    {
+        self.check_writable()?;
+
+        // Collections are opened as `f` asks for them, so that only those
+        // are loaded and locked.
+        let mut collections: Vec<Collection> = Vec::new();
+
+        loop {
+            let missing = RefCell::new(None);
+
+            let result = if collections.is_empty() {
+                // Nothing can be written before a collection is asked for.
+                let transaction = Transaction {
+                    database: self,
+                    collections: &[],
+                    trees: &[],
+                    failure: RefCell::new(None),
+                    missing: &missing,
+                };
+
+                f(&transaction)
+            } else {
+                let trees: Vec<&sled::Tree> = collections
+                    .iter()
+                    .map(|collection| &collection.tree)
+                    .collect();
+
+                trees
+                    .as_slice()
+                    .transaction(|tx_trees| {
+                        let transaction = Transaction {
+                            database: self,
+                            collections: &collections,
+                            trees: tx_trees,
+                            failure: RefCell::new(None),
+                            missing: &missing,
+                        };
+
+                        // A failure stands even if the closure went on after
+                        // the error, so that no partial work is committed.
+                        let result = f(&transaction);
+                        if missing.borrow().is_some() {
+                            return Err(ConflictableTransactionError::Abort(DbError::NotFound));
+                        }
+                        if let Some(failure) = transaction.failure.take() {
+                            return Err(failure.into());
+                        }
+
+                        result.map_err(ConflictableTransactionError::Abort)
+                    })
+                    .map_err(DbError::from)
+            };
+
+            if let Some(name) = missing.take() {
+                collections.push(self.get_collection(&name)?);
+                continue;
+            }
+
+            let result = result?;
+            if !collections.is_empty() {
+                self.flusher.commit()?;
+            }
+
+            return Ok(result);
+        }
+    }
+}
+
+impl<'a> Transaction<'a> {
+    /// Fails with `DbError::NotFound` if there is no such collection.
+    pub fn collection(&self, name: &str) -> Result<TransactionCollection<'_>, DbError> {
+        let position = self
+            .collections
+            .iter()
+            .position(|collection| collection.metadata.name == name);
+
+        let position = match position {
+            Some(position) => position,
+            None => {
+                if self.database.collection_exists(name)? {
+                    self.missing.borrow_mut().get_or_insert(name.to_string());
+                }
+                return Err(DbError::NotFound);
+            }
+        };
+
+        Ok(TransactionCollection {
+            collection: &self.collections[position],
+            tx_tree: &self.trees[position],
+            failure: &self.failure,
+        })
+    }
+}
+
+impl<'t> TransactionCollection<'t> {
+    // Hands the error of an operation to the caller, remembering conflicts
+    // and storage failures for `Database::transaction`.
+    fn run<T>(&self, result: ConflictableTransactionResult<T, DbError>) -> Result<T, DbError> {
+        match result {
+            Ok(value) => Ok(value),
+            Err(ConflictableTransactionError::Abort(e)) => Err(e),
+            Err(ConflictableTransactionError::Conflict) => {
+                self.failure
+                    .replace(Some(UnabortableTransactionError::Conflict));
+                Err(DbError::DatabaseError(
+                    "Conflict during transaction".to_string(),
+                ))
+            }
+            Err(ConflictableTransactionError::Storage(e)) => {
+                self.failure
+                    .replace(Some(UnabortableTransactionError::Storage(e.clone())));
+                Err(DbError::from(e))
+            }
+        }
+    }
+
+    pub fn insert<T: Serialize>(&self, value: &T) -> Result<String, DbError> {
+        let json =
+            serde_json::to_string(value).map_err(|e| DbError::SerializationError(e.to_string()))?;
+
+        self.insert_json(json)
+    }
+
+    pub fn insert_json(&self, json: String) -> Result<String, DbError> {
+        let message = self.collection.prepare_message(&json)?;
+
+        self.run(self.collection.insert_new(self.tx_tree, &message))
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, id: &str) -> Result<T, DbError> {
+        let json = self.get_json(id)?;
+
+        serde_json::from_str(&json).map_err(|e| DbError::DeserializationError(e.to_string()))
+    }
+
+    pub fn get_json(&self, id: &str) -> Result<String, DbError> {
+        self.run(self.collection.get_json_in(self.tx_tree, id))
+    }
+
+    pub fn update<T: Serialize>(&self, id: &str, value: &T) -> Result<(), DbError> {
+        let json =
+            serde_json::to_string(value).map_err(|e| DbError::SerializationError(e.to_string()))?;
+
+        self.update_json(id, json)
+    }
+
+    pub fn update_json(&self, id: &str, json: String) -> Result<(), DbError> {
+        let message = self.collection.prepare_message(&json)?;
+
+        self.run(self.collection.update_prepared(self.tx_tree, id, &message))?;
+
+        Ok(())
+    }
+
+    pub fn delete(&self, id: &str) -> Result<(), DbError> {
+        self.run(self.collection.delete_in(self.tx_tree, id))
+    }
+
+    pub fn subcollection<T: Serialize>(
+        &self,
+        dependencies: &T,
+    ) -> Result<TransactionSubcollection<'t>, DbError> {
+        let deps_json = serde_json::to_string(dependencies).map_err(|e| {
+            DbError::SerializationError(format!("Failed to serialize dependencies: {}", e))
+        })?;
+
+        self.subcollection_json(deps_json)
+    }
+
+    pub fn subcollection_json(
+        &self,
+        dependencies_json: String,
+    ) -> Result<TransactionSubcollection<'t>, DbError> {
+        let dependencies: Value = serde_json::from_str(&dependencies_json)
+            .map_err(|e| DbError::DeserializationError(format!("JSON parsing error: {}", e)))?;
+
+        let dependencies = canonicalize(&dependencies);
+        let dependencies_json = canonical_json(&dependencies)?;
+        let dependencies_hash = get_json_hash(&dependencies_json);
+
+        if let Some(data) = self.run(
+            self.tx_tree
+                .get(dependency_key(&dependencies_hash))
+                .map_err(ConflictableTransactionError::from),
+        )? {
+            DependencyRecord::from_slice(&data)?.verify(&dependencies_hash, &dependencies_json)?;
+        }
+
+        Ok(TransactionSubcollection {
+            collection: TransactionCollection {
+                collection: self.collection,
+                tx_tree: self.tx_tree,
+                failure: self.failure,
+            },
+            dependencies,
+            dependencies_hash,
+        })
+    }
+}
+
+impl<'t> TransactionSubcollection<'t> {
+    pub fn insert<T: Serialize>(&self, body: &T) -> Result<String, DbError> {
+        let body_json = serde_json::to_string(body)
+            .map_err(|e| DbError::SerializationError(format!("Failed to serialize body: {}", e)))?;
+
+        self.insert_json(body_json)
+    }
+
+    pub fn insert_json(&self, body_json: String) -> Result<String, DbError> {
+        self.collection.insert_json(self.message_json(&body_json)?)
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, id: &str) -> Result<T, DbError> {
+        let body_json = self.get_json(id)?;
+
+        serde_json::from_str(&body_json).map_err(|e| {
+            DbError::DeserializationError(format!("Failed to deserialize body: {}", e))
+        })
+    }
+
+    pub fn get_json(&self, id: &str) -> Result<String, DbError> {
+        self.check_belongs_to_subcollection(id)?;
+
+        let item_data = self
+            .collection
+            .run(
+                self.collection
+                    .tx_tree
+                    .get(item_key(id))
+                    .map_err(ConflictableTransactionError::from),
+            )?
+            .ok_or(DbError::NotFound)?;
+
+        body_json(&item_data)?.ok_or(DbError::NotFound)
+    }
+
+    pub fn update<T: Serialize>(&self, id: &str, body: &T) -> Result<(), DbError> {
+        let body_json = serde_json::to_string(body)
+            .map_err(|e| DbError::SerializationError(format!("Failed to serialize body: {}", e)))?;
+
+        self.update_json(id, body_json)
+    }
+
+    pub fn update_json(&self, id: &str, body_json: String) -> Result<(), DbError> {
+        self.check_belongs_to_subcollection(id)?;
+
+        self.collection
+            .update_json(id, self.message_json(&body_json)?)
+    }
+
+    pub fn delete(&self, id: &str) -> Result<(), DbError> {
+        self.check_belongs_to_subcollection(id)?;
+
+        self.collection.delete(id)
+    }
+
+    fn message_json(&self, body_json: &str) -> Result<String, DbError> {
+        let body: Value = serde_json::from_str(body_json)
+            .map_err(|e| DbError::DeserializationError(format!("JSON parsing error: {}", e)))?;
+
+        let full_object = serde_json::json!({
+            "body": body,
+            "dependencies": self.dependencies
+        });
+
+        serde_json::to_string(&full_object).map_err(|e| {
+            DbError::SerializationError(format!("Failed to serialize full object: {}", e))
+        })
+    }
+
+    fn check_belongs_to_subcollection(&self, id: &str) -> Result<(), DbError> {
+        let marker = self.collection.run(
+            self.collection
+                .tx_tree
+                .get(member_key(&self.dependencies_hash, id))
+                .map_err(ConflictableTransactionError::from),
+        )?;
+
+        if marker.is_none() {
+            return Err(DbError::NotFound);
+        }
+
+        Ok(())
+    }
+}
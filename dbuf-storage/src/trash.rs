@@ -0,0 +1,206 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionalTree,
+};
+use std::time::{Duration, SystemTime};
+
+use crate::helper::millis_since_epoch;
+use crate::keys::{self, dependency_key, trash_key, TRASH_PREFIX};
+use crate::{render_message, storage_deps_hash, Collection, DbError, Subcollection};
+
+/// An item in the trash of a collection with soft delete, from
+/// `Collection::list_deleted`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeletedItem<T = Value> {
+    pub id: String,
+    /// When the item was deleted, in milliseconds since the Unix epoch.
+    pub deleted_at: u64,
+    /// The full message, or the body when listed from a subcollection.
+    pub message: T,
+}
+
+// Stored form of a deleted item. It keeps the whole message rather than a
+// reference to the dependency record, which the delete released.
+#[derive(Serialize, Deserialize)]
+struct TrashRecord {
+    deleted_at: u64,
+    deps: String,
+    message: Value,
+}
+
+impl TrashRecord {
+    fn from_slice(data: &[u8]) -> Result<Self, DbError> {
+        serde_json::from_slice(data)
+            .map_err(|e| DbError::DeserializationError(format!("Invalid deleted item: {}", e)))
+    }
+}
+
+// With soft delete on, every delete moves the item to the trash, where it
+// stays until it is restored or purged. A new item may take the ID of a
+// deleted one meanwhile; deleting it again replaces the one in the trash.
+impl Collection {
+    pub fn has_soft_delete(&self) -> bool {
+        self.metadata.soft_delete
+    }
+
+    /// The items in the trash, in ID order; `T` is the message type.
+    pub fn list_deleted<T: DeserializeOwned>(&self) -> Result<Vec<DeletedItem<T>>, DbError> {
+        self.deleted_items(None)
+    }
+
+    /// Moves a deleted item back into the collection and the subcollection it
+    /// belonged to. Fails with `DbError::NotFound` if it is not in the trash
+    /// and with `DbError::AlreadyExists` if its ID was taken since.
+    pub fn restore(&self, id: &str) -> Result<(), DbError> {
+        self.restore_scoped(id, None)
+    }
+
+    /// Removes for good the items deleted at least `older_than` ago, all of
+    /// them for `Duration::ZERO`. Returns how many were removed.
+    pub fn purge(&self, older_than: Duration) -> Result<usize, DbError> {
+        self.check_writable()?;
+
+        let cutoff = SystemTime::now()
+            .checked_sub(older_than)
+            .map_or(0, millis_since_epoch);
+        let mut purged = 0;
+
+        for entry in self.tree.scan_prefix(TRASH_PREFIX) {
+            let (key, data) = entry?;
+            if TrashRecord::from_slice(&data)?.deleted_at > cutoff {
+                continue;
+            }
+
+            // An item deleted again meanwhile is left for the next purge.
+            if self
+                .tree
+                .compare_and_swap(&key, Some(&data), None as Option<&[u8]>)?
+                .is_ok()
+            {
+                purged += 1;
+            }
+        }
+
+        if purged > 0 {
+            self.flusher.commit()?;
+        }
+
+        Ok(purged)
+    }
+
+    // `scope` is the dependencies hash of the subcollection to list, if any.
+    fn deleted_items<T: DeserializeOwned>(
+        &self,
+        scope: Option<&str>,
+    ) -> Result<Vec<DeletedItem<T>>, DbError> {
+        let mut items = Vec::new();
+
+        for entry in self.tree.scan_prefix(TRASH_PREFIX) {
+            let (key, data) = entry?;
+            let id = match keys::strip(&key, TRASH_PREFIX) {
+                Some(id) => id,
+                None => continue,
+            };
+
+            let mut record = TrashRecord::from_slice(&data)?;
+            let message = match scope {
+                None => record.message,
+                Some(deps_hash) if deps_hash == record.deps => record.message["body"].take(),
+                Some(_) => continue,
+            };
+
+            items.push(DeletedItem {
+                id,
+                deleted_at: record.deleted_at,
+                message: serde_json::from_value(message)
+                    .map_err(|e| DbError::DeserializationError(e.to_string()))?,
+            });
+        }
+
+        Ok(items)
+    }
+
+    fn restore_scoped(&self, id: &str, scope: Option<&str>) -> Result<(), DbError> {
+        self.check_writable()?;
+
+        let data = self.tree.get(trash_key(id))?.ok_or(DbError::NotFound)?;
+        let record = TrashRecord::from_slice(&data)?;
+
+        if scope.is_some_and(|deps_hash| deps_hash != record.deps) {
+            return Err(DbError::NotFound);
+        }
+
+        let json = serde_json::to_string(&record.message)
+            .map_err(|e| DbError::SerializationError(e.to_string()))?;
+        let message = self.prepare_message(&json)?;
+
+        self.tree.transaction(|tx_tree| {
+            match tx_tree.remove(trash_key(id))? {
+                Some(current) if current == data => {}
+                Some(_) => {
+                    return Err(ConflictableTransactionError::Abort(DbError::Conflict(
+                        format!("Item {} was deleted again while being restored", id),
+                    )))
+                }
+                None => return Err(ConflictableTransactionError::Abort(DbError::NotFound)),
+            }
+
+            self.insert_prepared(tx_tree, id, &message)
+        })?;
+
+        self.flusher.commit()
+    }
+
+    // Moves a deleted item to the trash in the transaction deleting it,
+    // before its dependency record may be released.
+    pub(crate) fn trash_in(
+        &self,
+        tx_tree: &TransactionalTree,
+        id: &str,
+        storage_value: &Value,
+    ) -> ConflictableTransactionResult<(), DbError> {
+        if !self.metadata.soft_delete {
+            return Ok(());
+        }
+
+        let deps_hash =
+            storage_deps_hash(storage_value).map_err(ConflictableTransactionError::Abort)?;
+        let deps_data = tx_tree.get(dependency_key(deps_hash))?;
+
+        let message_json = render_message(storage_value, deps_data.as_deref())
+            .map_err(ConflictableTransactionError::Abort)?;
+
+        let record = TrashRecord {
+            deleted_at: millis_since_epoch(SystemTime::now()),
+            deps: deps_hash.to_string(),
+            message: serde_json::from_str(&message_json).map_err(|e| {
+                ConflictableTransactionError::Abort(DbError::DeserializationError(e.to_string()))
+            })?,
+        };
+        let data = serde_json::to_vec(&record).map_err(|e| {
+            ConflictableTransactionError::Abort(DbError::SerializationError(format!(
+                "Failed to serialize deleted item: {}",
+                e
+            )))
+        })?;
+
+        tx_tree.insert(trash_key(id), data)?;
+
+        Ok(())
+    }
+}
+
+impl<'a> Subcollection<'a> {
+    /// The deleted items of this subcollection, with their bodies.
+    pub fn list_deleted<T: DeserializeOwned>(&self) -> Result<Vec<DeletedItem<T>>, DbError> {
+        self.collection.deleted_items(Some(&self.dependencies_hash))
+    }
+
+    /// Fails with `DbError::NotFound` unless the deleted item belonged to
+    /// this subcollection.
+    pub fn restore(&self, id: &str) -> Result<(), DbError> {
+        self.collection
+            .restore_scoped(id, Some(&self.dependencies_hash))
+    }
+}
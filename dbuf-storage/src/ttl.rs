@@ -0,0 +1,205 @@
+use serde::Serialize;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use crate::helper::millis_since_epoch;
+use crate::keys::{expiry_key, item_key, parse_expiry_key, EXPIRY_PREFIX};
+use crate::{
+    parse_storage_value, storage_expiry, Collection, Database, DbError, Subcollection, METADATA_KEY,
+};
+use sled::transaction::ConflictableTransactionError;
+
+// Expiry time of an item written now with the given TTL, in milliseconds
+// since the Unix epoch.
+pub(crate) fn expiry(ttl: Duration) -> u64 {
+    let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+
+    millis_since_epoch(SystemTime::now()).saturating_add(ttl)
+}
+
+// An item with a TTL carries its expiry time and has a key in the expiry
+// keyspace, ordered by time, so that sweeping only visits expired items.
+// Reads treat an expired item as missing right away; the sweeper removes it
+// later, like a delete that bypasses the trash.
+impl Collection {
+    pub fn get_default_ttl(&self) -> Option<Duration> {
+        self.metadata.default_ttl
+    }
+
+    /// Inserts a message that expires after `ttl`, overriding the default
+    /// TTL of the collection. Updates keep the expiry of an item.
+    pub fn insert_with_ttl<T: Serialize>(
+        &self,
+        value: &T,
+        ttl: Duration,
+    ) -> Result<String, DbError> {
+        let json =
+            serde_json::to_string(value).map_err(|e| DbError::SerializationError(e.to_string()))?;
+
+        self.insert_json_with_ttl(json, ttl)
+    }
+
+    pub fn insert_json_with_ttl(&self, json: String, ttl: Duration) -> Result<String, DbError> {
+        self.check_writable()?;
+
+        let message = self.prepare_message(&json)?;
+        let expires_at = expiry(ttl);
+
+        let id = self.tree.transaction(|tx_tree| {
+            let id = self.generate_id_in(tx_tree, &message)?;
+            self.insert_expiring(tx_tree, &id, &message, Some(expires_at))?;
+            Ok(id)
+        })?;
+
+        self.flusher.commit()?;
+        Ok(id)
+    }
+
+    /// Removes the expired items, with their subcollection markers and the
+    /// dependency records no other item refers to. Returns how many were
+    /// removed. The database sweeper calls this for every collection.
+    pub fn sweep_expired(&self) -> Result<usize, DbError> {
+        self.check_writable()?;
+
+        let now = millis_since_epoch(SystemTime::now());
+        let mut swept = 0;
+
+        for entry in self
+            .tree
+            .range(EXPIRY_PREFIX.to_vec()..expiry_key(now.saturating_add(1), ""))
+        {
+            let (key, _) = entry?;
+            let (expires_at, id) = match parse_expiry_key(&key) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+
+            let removed = self.tree.transaction(|tx_tree| {
+                let item_data = match tx_tree.get(item_key(&id))? {
+                    Some(data) => data,
+                    None => {
+                        tx_tree.remove(&key)?;
+                        return Ok(false);
+                    }
+                };
+
+                let storage_value =
+                    parse_storage_value(&item_data).map_err(ConflictableTransactionError::Abort)?;
+
+                // A key left behind by an item replaced since.
+                if storage_expiry(&storage_value) != Some(expires_at) {
+                    tx_tree.remove(&key)?;
+                    return Ok(false);
+                }
+
+                self.remove_in(tx_tree, &id, &storage_value)?;
+                Ok(true)
+            })?;
+
+            if removed {
+                swept += 1;
+            }
+        }
+
+        if swept > 0 {
+            self.flusher.commit()?;
+        }
+
+        Ok(swept)
+    }
+}
+
+impl<'a> Subcollection<'a> {
+    pub fn insert_with_ttl<T: Serialize>(
+        &self,
+        body: &T,
+        ttl: Duration,
+    ) -> Result<String, DbError> {
+        let body_json = serde_json::to_string(body)
+            .map_err(|e| DbError::SerializationError(format!("Failed to serialize body: {}", e)))?;
+
+        self.insert_json_with_ttl(body_json, ttl)
+    }
+
+    pub fn insert_json_with_ttl(
+        &self,
+        body_json: String,
+        ttl: Duration,
+    ) -> Result<String, DbError> {
+        self.collection
+            .insert_json_with_ttl(self.message_json(&body_json)?, ttl)
+    }
+}
+
+impl Database {
+    /// Removes the expired items of every collection; see
+    /// `Collection::sweep_expired`. Only collections with expired items are
+    /// opened.
+    pub fn sweep_expired(&self) -> Result<usize, DbError> {
+        let now = millis_since_epoch(SystemTime::now());
+        let mut swept = 0;
+
+        for name in self.list_collections() {
+            let tree = self.db.open_tree(name.as_bytes())?;
+            let mut expired =
+                tree.range(EXPIRY_PREFIX.to_vec()..expiry_key(now.saturating_add(1), ""));
+            if expired.next().is_some() && tree.contains_key(METADATA_KEY.as_bytes())? {
+                swept += self.get_collection(&name)?.sweep_expired()?;
+            }
+        }
+
+        Ok(swept)
+    }
+
+    // Sweeps every `interval` on a thread of its own until the database is
+    // dropped.
+    pub(crate) fn start_sweeper(&mut self, interval: Duration) {
+        let db = Database {
+            db: self.db.clone(),
+            flusher: self.flusher.clone(),
+            validators: self.validators.clone(),
+            read_only: self.read_only,
+            sweeper: None,
+        };
+
+        self.sweeper = Some(Sweeper::start(db, interval));
+    }
+}
+
+pub(crate) struct Sweeper {
+    // Dropping the sender stops the thread.
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    fn start(db: Database, interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        let thread = thread::spawn(move || loop {
+            match stopped.recv_timeout(interval) {
+                // A failed sweep is tried again at the next interval.
+                Err(RecvTimeoutError::Timeout) => {
+                    let _ = db.sweep_expired();
+                }
+                Ok(()) | Err(RecvTimeoutError::Disconnected) => return,
+            }
+        });
+
+        Sweeper {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        self.stop.take();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+
+use schemars::{schema::RootSchema, schema_for, JsonSchema};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use crate::{Collection, Database, DbError, Subcollection};
+
+/// A DependoBuf message: a body together with the dependencies it was
+/// constructed with.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Message<B, D> {
+    pub body: B,
+    pub dependencies: D,
+}
+
+/// The id of a `Message<B, D>` stored in a `TypedCollection<B, D>`.
+pub struct Id<B, D> {
+    id: String,
+    _message: PhantomData<fn() -> Message<B, D>>,
+}
+
+impl<B, D> Id<B, D> {
+    /// Wraps an id obtained elsewhere, e.g. from the untyped API. The id is
+    /// not checked to exist.
+    pub fn new(id: impl Into<String>) -> Self {
+        Id {
+            id: id.into(),
+            _message: PhantomData,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.id
+    }
+
+    pub fn into_string(self) -> String {
+        self.id
+    }
+}
+
+// Implemented by hand so that `B` and `D` need none of these traits.
+impl<B, D> Clone for Id<B, D> {
+    fn clone(&self) -> Self {
+        Id::new(self.id.clone())
+    }
+}
+
+impl<B, D> PartialEq for Id<B, D> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<B, D> Eq for Id<B, D> {}
+
+impl<B, D> Hash for Id<B, D> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+impl<B, D> fmt::Debug for Id<B, D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Id").field(&self.id).finish()
+    }
+}
+
+impl<B, D> fmt::Display for Id<B, D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.id)
+    }
+}
+
+impl<B, D> Serialize for Id<B, D> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.id)
+    }
+}
+
+impl<'de, B, D> Deserialize<'de> for Id<B, D> {
+    fn deserialize<De: Deserializer<'de>>(deserializer: De) -> Result<Self, De::Error> {
+        String::deserialize(deserializer).map(Id::new)
+    }
+}
+
+/// A collection bound to one message type. Obtained from
+/// `Database::typed_collection`, which checks that the schemas stored with
+/// the collection are the ones generated for `B` and `D`.
+pub struct TypedCollection<B, D> {
+    collection: Collection,
+    _message: PhantomData<fn() -> Message<B, D>>,
+}
+
+impl Database {
+    /// Creates a collection validated against the schemas of `B` and `D`.
+    pub fn create_typed_collection<B, D>(
+        &self,
+        name: &str,
+    ) -> Result<TypedCollection<B, D>, DbError>
+    where
+        B: Serialize + DeserializeOwned + JsonSchema + 'static,
+        D: Serialize + DeserializeOwned + JsonSchema + 'static,
+    {
+        let collection = self.create_collection_with_schema::<B, D>(name)?;
+
+        Ok(TypedCollection {
+            collection,
+            _message: PhantomData,
+        })
+    }
+
+    /// Opens an existing collection as a `TypedCollection<B, D>`. Fails with
+    /// `DbError::SchemaError` unless the collection was created with the
+    /// schemas of exactly these types.
+    pub fn typed_collection<B, D>(&self, name: &str) -> Result<TypedCollection<B, D>, DbError>
+    where
+        B: Serialize + DeserializeOwned + JsonSchema + 'static,
+        D: Serialize + DeserializeOwned + JsonSchema + 'static,
+    {
+        let collection = self.get_collection(name)?;
+
+        check_schema(
+            &collection,
+            "body",
+            collection.get_body_schema_json(),
+            &schema_for!(B),
+        )?;
+        check_schema(
+            &collection,
+            "dependencies",
+            collection.get_dependencies_schema_json(),
+            &schema_for!(D),
+        )?;
+
+        Ok(TypedCollection {
+            collection,
+            _message: PhantomData,
+        })
+    }
+}
+
+// Schemas are compared as JSON values, so a schema given as text with other
+// formatting still matches.
+pub(crate) fn check_schema(
+    collection: &Collection,
+    kind: &str,
+    stored_schema_json: Option<&str>,
+    expected: &RootSchema,
+) -> Result<(), DbError> {
+    let stored_schema_json = stored_schema_json.ok_or_else(|| {
+        DbError::SchemaError(format!(
+            "Collection {} has no {} schema",
+            collection.get_name(),
+            kind
+        ))
+    })?;
+
+    let stored: Value = serde_json::from_str(stored_schema_json).map_err(|e| {
+        DbError::DeserializationError(format!("Invalid {} schema JSON: {}", kind, e))
+    })?;
+    let expected = serde_json::to_value(expected).map_err(|e| {
+        DbError::SerializationError(format!("Failed to serialize {} schema: {}", kind, e))
+    })?;
+
+    if stored != expected {
+        return Err(DbError::SchemaError(format!(
+            "The {} schema of collection {} does not match the {} type",
+            kind,
+            collection.get_name(),
+            kind
+        )));
+    }
+
+    Ok(())
+}
+
+type Entry<B, D> = (Id<B, D>, Message<B, D>);
+
+impl<B, D> TypedCollection<B, D>
+where
+    B: Serialize + DeserializeOwned,
+    D: Serialize + DeserializeOwned,
+{
+    /// The untyped collection, for the parts of the API without a typed
+    /// counterpart.
+    pub fn collection(&self) -> &Collection {
+        &self.collection
+    }
+
+    pub fn insert(&self, message: &Message<B, D>) -> Result<Id<B, D>, DbError> {
+        self.collection.insert(message).map(Id::new)
+    }
+
+    pub fn get(&self, id: &Id<B, D>) -> Result<Message<B, D>, DbError> {
+        self.collection.get(id.as_str())
+    }
+
+    pub fn update(&self, id: &Id<B, D>, message: &Message<B, D>) -> Result<(), DbError> {
+        self.collection.update(id.as_str(), message)
+    }
+
+    pub fn delete(&self, id: &Id<B, D>) -> Result<(), DbError> {
+        self.collection.delete(id.as_str())
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = Result<Id<B, D>, DbError>> + '_ {
+        self.collection.keys().map(|id| id.map(Id::new))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Result<Entry<B, D>, DbError>> + '_ {
+        self.collection
+            .iter()
+            .map(|item| item.map(|(id, message)| (Id::new(id), message)))
+    }
+
+    pub fn subcollection(&self, dependencies: &D) -> Result<TypedSubcollection<'_, B, D>, DbError> {
+        Ok(TypedSubcollection {
+            subcollection: self.collection.subcollection(dependencies)?,
+            _message: PhantomData,
+        })
+    }
+}
+
+/// The bodies of a `TypedCollection<B, D>` that share one value of the
+/// dependencies. Ids are those of the whole collection.
+pub struct TypedSubcollection<'a, B, D> {
+    subcollection: Subcollection<'a>,
+    _message: PhantomData<fn() -> Message<B, D>>,
+}
+
+impl<'a, B, D> TypedSubcollection<'a, B, D>
+where
+    B: Serialize + DeserializeOwned,
+    D: Serialize + DeserializeOwned,
+{
+    pub fn subcollection(&self) -> &Subcollection<'a> {
+        &self.subcollection
+    }
+
+    pub fn dependencies(&self) -> Result<D, DbError> {
+        self.subcollection.get_dependencies()
+    }
+
+    pub fn insert(&self, body: &B) -> Result<Id<B, D>, DbError> {
+        self.subcollection.insert(body).map(Id::new)
+    }
+
+    pub fn get(&self, id: &Id<B, D>) -> Result<B, DbError> {
+        self.subcollection.get(id.as_str())
+    }
+
+    pub fn update(&self, id: &Id<B, D>, body: &B) -> Result<(), DbError> {
+        self.subcollection.update(id.as_str(), body)
+    }
+
+    pub fn delete(&self, id: &Id<B, D>) -> Result<(), DbError> {
+        self.subcollection.delete(id.as_str())
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = Result<Id<B, D>, DbError>> + '_ {
+        self.subcollection.keys().map(|id| id.map(Id::new))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Result<(Id<B, D>, B), DbError>> + '_ {
+        self.subcollection
+            .iter()
+            .map(|item| item.map(|(id, body)| (Id::new(id), body)))
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{Collection, DbError};
+
+/// A check of messages that JSON Schema cannot express, such as equalities
+/// between the body and the dependencies. Runs after schema validation on
+/// every insert and update of the collection it is registered on.
+///
+/// Any `Fn(&Value, &Value) -> Result<(), ValidationError>` taking the body
+/// and the dependencies is a validator.
+pub trait Validator: Send + Sync {
+    fn validate(&self, body: &Value, dependencies: &Value) -> Result<(), ValidationError>;
+}
+
+impl<F> Validator for F
+where
+    F: Fn(&Value, &Value) -> Result<(), ValidationError> + Send + Sync,
+{
+    fn validate(&self, body: &Value, dependencies: &Value) -> Result<(), ValidationError> {
+        self(body, dependencies)
+    }
+}
+
+/// A message rejected by a `Validator`, reported as
+/// `DbError::ValidationError`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationError {
+    /// Name the validator was registered under; filled in by the collection.
+    pub validator: String,
+    /// Location of the offending value in the message, e.g.
+    /// `body.sum.dependencies.a`.
+    pub path: Option<String>,
+    pub message: String,
+}
+
+impl ValidationError {
+    pub fn new(message: impl Into<String>) -> Self {
+        ValidationError {
+            validator: String::new(),
+            path: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn at(path: impl Into<String>, message: impl Into<String>) -> Self {
+        ValidationError {
+            path: Some(path.into()),
+            ..ValidationError::new(message)
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.validator, self.message)?;
+
+        if let Some(path) = &self.path {
+            write!(f, " at path: {}", path)?;
+        }
+
+        Ok(())
+    }
+}
+
+type NamedValidator = (String, Arc<dyn Validator>);
+
+// Validators of all collections of a database, shared by every handle so
+// that collections opened elsewhere, e.g. in a transaction, apply them too.
+#[derive(Default)]
+pub(crate) struct Validators {
+    by_collection: RwLock<HashMap<String, Vec<NamedValidator>>>,
+}
+
+impl Validators {
+    // The map stays consistent even if a validator panicked while it was
+    // locked, so poisoning is ignored.
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, HashMap<String, Vec<NamedValidator>>> {
+        self.by_collection
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, HashMap<String, Vec<NamedValidator>>> {
+        self.by_collection
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn clear(&self, collection: &str) {
+        self.write().remove(collection);
+    }
+
+    pub(crate) fn validate(
+        &self,
+        collection: &str,
+        body: &Value,
+        dependencies: &Value,
+    ) -> Result<(), DbError> {
+        let validators = match self.read().get(collection) {
+            Some(validators) => validators.clone(),
+            None => return Ok(()),
+        };
+
+        for (name, validator) in validators {
+            if let Err(mut e) = validator.validate(body, dependencies) {
+                e.validator = name;
+                return Err(DbError::ValidationError(e));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Collection {
+    /// Registers `validator` under `name` for this collection in every
+    /// handle of the database, replacing a validator with the same name.
+    /// Validators run in registration order and are not persisted.
+    pub fn add_validator<V: Validator + 'static>(&self, name: &str, validator: V) {
+        let mut by_collection = self.validators.write();
+        let validators = by_collection.entry(self.metadata.name.clone()).or_default();
+        let validator: Arc<dyn Validator> = Arc::new(validator);
+
+        match validators.iter_mut().find(|(existing, _)| existing == name) {
+            Some(entry) => entry.1 = validator,
+            None => validators.push((name.to_string(), validator)),
+        }
+    }
+
+    /// Registers a validator receiving the body and dependencies
+    /// deserialized as `B` and `D`. Messages that do not deserialize are
+    /// rejected.
+    pub fn add_typed_validator<B, D, F>(&self, name: &str, validator: F)
+    where
+        B: DeserializeOwned,
+        D: DeserializeOwned,
+        F: Fn(&B, &D) -> Result<(), ValidationError> + Send + Sync + 'static,
+    {
+        self.add_validator(name, move |body: &Value, dependencies: &Value| {
+            let body: B = B::deserialize(body)
+                .map_err(|e| ValidationError::at("body", format!("Unexpected body: {}", e)))?;
+            let dependencies: D = D::deserialize(dependencies).map_err(|e| {
+                ValidationError::at("dependencies", format!("Unexpected dependencies: {}", e))
+            })?;
+
+            validator(&body, &dependencies)
+        });
+    }
+
+    /// Returns whether a validator with this name was registered.
+    pub fn remove_validator(&self, name: &str) -> bool {
+        let mut by_collection = self.validators.write();
+
+        match by_collection.get_mut(&self.metadata.name) {
+            Some(validators) => {
+                let count = validators.len();
+                validators.retain(|(existing, _)| existing != name);
+                validators.len() != count
+            }
+            None => false,
+        }
+    }
+
+    pub fn validator_names(&self) -> Vec<String> {
+        self.validators
+            .read()
+            .get(&self.metadata.name)
+            .map(|validators| validators.iter().map(|(name, _)| name.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn run_validators(&self, body: &Value, dependencies: &Value) -> Result<(), DbError> {
+        self.validators
+            .validate(&self.metadata.name, body, dependencies)
+    }
+}
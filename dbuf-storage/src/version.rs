@@ -0,0 +1,239 @@
+use serde::{de::DeserializeOwned, Serialize};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionalTree,
+};
+use sled::IVec;
+
+use crate::keys::{dependency_key, item_key, retired_key, RETIRED_VERSION_KEY};
+use crate::{
+    is_expired, parse_storage_value, render_message, storage_deps_hash, storage_version,
+    Collection, DbError, HistoryRetention, Subcollection,
+};
+
+// Every item carries a version, incremented by each update that changes it.
+// Updating or deleting "if version" fails with `DbError::VersionMismatch`
+// when another writer got there first. A delete counts as a version too, and
+// items inserted later carry on after the highest version deleted from the
+// collection, so that no version read before a delete matches a new item
+// under the same ID.
+impl Collection {
+    pub fn get_with_version<T: DeserializeOwned>(&self, id: &str) -> Result<(T, u64), DbError> {
+        let (json, version) = self.get_json_with_version(id)?;
+
+        let value = serde_json::from_str(&json)
+            .map_err(|e| DbError::DeserializationError(e.to_string()))?;
+
+        Ok((value, version))
+    }
+
+    pub fn get_json_with_version(&self, id: &str) -> Result<(String, u64), DbError> {
+        let item_data = self.tree.get(item_key(id))?.ok_or(DbError::NotFound)?;
+
+        let storage_value = parse_storage_value(&item_data)?;
+        if is_expired(&storage_value) {
+            return Err(DbError::NotFound);
+        }
+
+        let deps_data = self
+            .tree
+            .get(dependency_key(storage_deps_hash(&storage_value)?))?;
+
+        Ok((
+            render_message(&storage_value, deps_data.as_deref())?,
+            storage_version(&storage_value),
+        ))
+    }
+
+    /// Updates the item only if it is still at `version`. Returns the new
+    /// version.
+    pub fn update_if_version<T: Serialize>(
+        &self,
+        id: &str,
+        value: &T,
+        version: u64,
+    ) -> Result<u64, DbError> {
+        let json =
+            serde_json::to_string(value).map_err(|e| DbError::SerializationError(e.to_string()))?;
+
+        self.update_json_if_version(id, json, version)
+    }
+
+    pub fn update_json_if_version(
+        &self,
+        id: &str,
+        json: String,
+        version: u64,
+    ) -> Result<u64, DbError> {
+        self.check_writable()?;
+
+        let message = self.prepare_message(&json)?;
+
+        let (updated, new_version) = self.tree.transaction(|tx_tree| {
+            self.check_version_in(tx_tree, id, version)?;
+
+            match self.update_prepared(tx_tree, id, &message)? {
+                true => Ok((true, version + 1)),
+                false => Ok((false, version)),
+            }
+        })?;
+
+        if updated {
+            self.flusher.commit()?;
+        }
+
+        Ok(new_version)
+    }
+
+    /// Deletes the item only if it is still at `version`.
+    pub fn delete_if_version(&self, id: &str, version: u64) -> Result<(), DbError> {
+        self.check_writable()?;
+
+        self.tree.transaction(|tx_tree| {
+            self.check_version_in(tx_tree, id, version)?;
+            self.delete_in(tx_tree, id)
+        })?;
+
+        self.flusher.commit()
+    }
+
+    fn check_version_in(
+        &self,
+        tx_tree: &TransactionalTree,
+        id: &str,
+        version: u64,
+    ) -> ConflictableTransactionResult<(), DbError> {
+        let storage_value = match self.live_item_in(tx_tree, id)? {
+            Some(storage_value) => storage_value,
+            None => return Err(ConflictableTransactionError::Abort(DbError::NotFound)),
+        };
+
+        let current = storage_version(&storage_value);
+
+        if current != version {
+            return Err(ConflictableTransactionError::Abort(
+                DbError::VersionMismatch(format!(
+                    "Item {} is at version {}, not {}",
+                    id, current, version
+                )),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> Subcollection<'a> {
+    pub fn get_with_version<T: DeserializeOwned>(&self, id: &str) -> Result<(T, u64), DbError> {
+        let (body_json, version) = self.get_json_with_version(id)?;
+
+        let body = serde_json::from_str(&body_json).map_err(|e| {
+            DbError::DeserializationError(format!("Failed to deserialize body: {}", e))
+        })?;
+
+        Ok((body, version))
+    }
+
+    pub fn get_json_with_version(&self, id: &str) -> Result<(String, u64), DbError> {
+        self.check_belongs_to_subcollection(id)?;
+
+        let item_data = self
+            .collection
+            .tree
+            .get(item_key(id))?
+            .ok_or(DbError::NotFound)?;
+
+        let storage_value = parse_storage_value(&item_data)?;
+        if is_expired(&storage_value) {
+            return Err(DbError::NotFound);
+        }
+
+        let body_json = serde_json::to_string(&storage_value["body"])
+            .map_err(|e| DbError::SerializationError(format!("Failed to serialize body: {}", e)))?;
+
+        Ok((body_json, storage_version(&storage_value)))
+    }
+
+    pub fn update_if_version<T: Serialize>(
+        &self,
+        id: &str,
+        body: &T,
+        version: u64,
+    ) -> Result<u64, DbError> {
+        let body_json = serde_json::to_string(body)
+            .map_err(|e| DbError::SerializationError(format!("Failed to serialize body: {}", e)))?;
+
+        self.update_json_if_version(id, body_json, version)
+    }
+
+    pub fn update_json_if_version(
+        &self,
+        id: &str,
+        body_json: String,
+        version: u64,
+    ) -> Result<u64, DbError> {
+        self.check_belongs_to_subcollection(id)?;
+
+        self.collection
+            .update_json_if_version(id, self.message_json(&body_json)?, version)
+    }
+
+    pub fn delete_if_version(&self, id: &str, version: u64) -> Result<(), DbError> {
+        self.check_belongs_to_subcollection(id)?;
+
+        self.collection.delete_if_version(id, version)
+    }
+}
+
+// Version of an item about to be inserted: 1, unless items were deleted
+// from the collection before. The record of the last version of a deleted
+// item with the same ID is taken over if there is one, else the highest
+// version any deleted item reached.
+pub(crate) fn next_version_in(
+    tx_tree: &TransactionalTree,
+    id: &str,
+) -> ConflictableTransactionResult<u64, DbError> {
+    if let Some(version) = read_version(tx_tree.remove(retired_key(id))?)? {
+        return Ok(version + 1);
+    }
+
+    Ok(read_version(tx_tree.get(RETIRED_VERSION_KEY)?)?.map_or(1, |version| version + 1))
+}
+
+impl Collection {
+    // Records the version of a delete in the transaction making it. With
+    // history on, the item keeps a record of its own, so that its versions
+    // stay consecutive across deletes; otherwise only the highest version of
+    // the collection is kept, and the tree does not grow with every ID ever
+    // deleted.
+    pub(crate) fn retire_in(
+        &self,
+        tx_tree: &TransactionalTree,
+        id: &str,
+        version: u64,
+    ) -> ConflictableTransactionResult<(), DbError> {
+        if self.metadata.history != HistoryRetention::Off {
+            tx_tree.insert(retired_key(id), &version.to_be_bytes())?;
+            return Ok(());
+        }
+
+        let highest = read_version(tx_tree.get(RETIRED_VERSION_KEY)?)?;
+        if highest.is_none_or(|highest| highest < version) {
+            tx_tree.insert(RETIRED_VERSION_KEY, &version.to_be_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+fn read_version(data: Option<IVec>) -> ConflictableTransactionResult<Option<u64>, DbError> {
+    data.map(|data| {
+        let bytes: [u8; 8] = data.as_ref().try_into().map_err(|_| {
+            ConflictableTransactionError::Abort(DbError::DatabaseError(
+                "Corrupted version of a deleted item".to_string(),
+            ))
+        })?;
+
+        Ok(u64::from_be_bytes(bytes))
+    })
+    .transpose()
+}
@@ -1,4 +1,8 @@
-use dbuf_storage::{Database, DbError};
+use dbuf_storage::{
+    At, BatchMode, ChangeEvent, CollectionOptions, Constraint, Database, DatabaseConfig, DbError,
+    DbufMessage, DeletedItem, Durability, Filter, HistoryRetention, Id, IdStrategy, IndexOptions,
+    Query, StorageCodec, ValidationError, MAX_ID_LEN,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::fs;
@@ -152,6 +156,37 @@ pub mod nat {
     }
 }
 
+pub mod point {
+    use dbuf_storage::DbufMessage;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, schemars::JsonSchema)]
+    pub struct Body {
+        pub x: i32,
+        pub y: i32,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, schemars::JsonSchema)]
+    pub struct Dependencies {
+        pub dim: i32,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, DbufMessage)]
+    #[dbuf(collection = "points")]
+    pub struct Point {
+        pub body: Body,
+        pub dependencies: Dependencies,
+    }
+}
+
+// Messages defined through the generic `Message` implement the trait by hand.
+impl DbufMessage for foo::Foo {
+    type Body = foo::Body;
+    type Dependencies = foo::Dependencies;
+
+    const COLLECTION: &'static str = "foo";
+}
+
 #[test]
 fn test_database_creation() {
     let temp_dir = tempdir().unwrap();
@@ -1025,3 +1060,2606 @@ fn test_schema_consistency() {
     let result = collection.insert_json(invalid_json.to_string());
     assert!(matches!(result, Err(DbError::SchemaValidationError(_))));
 }
+
+// Opens the underlying sled database directly. The lock of a database that
+// was just dropped may still be held by its background flusher for a moment.
+fn open_raw(db_path: &str) -> sled::Db {
+    for _ in 0..50 {
+        if let Ok(db) = sled::open(db_path) {
+            return db;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    sled::open(db_path).unwrap()
+}
+
+#[test]
+fn test_legacy_database_migration() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+
+    {
+        // Layout written by versions hashing dependencies with DefaultHasher.
+        let sled_db = open_raw(db_path);
+        let tree = sled_db.open_tree("legacy").unwrap();
+        tree.insert("*metadata*", r#"{"name":"legacy","created_at":0}"#)
+            .unwrap();
+        tree.insert("4kPb2Lq", r#"{"a":42}"#).unwrap();
+        tree.insert("AAAAAAAAAAAAAAA1", r#"{"deps":"4kPb2Lq","body":{}}"#)
+            .unwrap();
+        tree.insert("AAAAAAAAAAAAAAA2", r#"{"deps":"4kPb2Lq","body":{}}"#)
+            .unwrap();
+        tree.insert("4kPb2Lq_AAAAAAAAAAAAAAA1", &[]).unwrap();
+        tree.insert("4kPb2Lq_AAAAAAAAAAAAAAA2", &[]).unwrap();
+        sled_db.flush().unwrap();
+    }
+
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+    assert_eq!(db.migrate().unwrap(), vec!["legacy".to_string()]);
+    assert!(db.migrate().unwrap().is_empty());
+
+    let collection = db
+        .get_collection("legacy")
+        .expect("Failed to get migrated collection");
+
+    let retrieved: sum::Sum = collection
+        .get("AAAAAAAAAAAAAAA1")
+        .expect("Failed to get migrated item");
+    assert_eq!(retrieved.dependencies.a, 42);
+
+    let subcollection = collection
+        .subcollection(&sum::Dependencies { a: 42 })
+        .expect("Failed to create subcollection");
+    let keys = subcollection.get_keys().expect("Failed to get keys");
+    assert_eq!(keys.len(), 2);
+    assert!(keys.contains(&"AAAAAAAAAAAAAAA1".to_string()));
+    assert!(keys.contains(&"AAAAAAAAAAAAAAA2".to_string()));
+
+    let found = collection
+        .find_subcollections(&Filter::eq("dependencies.a", 42))
+        .expect("Failed to find subcollections");
+    assert_eq!(found.len(), 1);
+}
+
+#[test]
+fn test_dependency_hash_collision() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+
+    {
+        let db = Database::new(Some(db_path)).expect("Failed to open database");
+        let collection = db
+            .create_collection("collision")
+            .expect("Failed to create collection");
+        collection
+            .insert(&sum::Sum::new(sum::Dependencies { a: 1 }).unwrap())
+            .expect("Failed to insert item");
+    }
+
+    {
+        // Simulate a collision by overwriting the stored dependency record.
+        let sled_db = open_raw(db_path);
+        let tree = sled_db.open_tree("collision").unwrap();
+        let deps_key = tree
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .find(|(_, value)| value.as_ref() == br#"{"refs":1,"dependencies":{"a":1}}"#)
+            .map(|(key, _)| key)
+            .expect("Dependency record not found");
+        tree.insert(deps_key, r#"{"refs":1,"dependencies":{"a":2}}"#)
+            .unwrap();
+        sled_db.flush().unwrap();
+    }
+
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+    let collection = db
+        .get_collection("collision")
+        .expect("Failed to get collection");
+
+    let result = collection.insert(&sum::Sum::new(sum::Dependencies { a: 1 }).unwrap());
+    assert!(matches!(result, Err(DbError::HashCollision(_))));
+
+    let result = collection.subcollection(&sum::Dependencies { a: 1 });
+    assert!(matches!(result, Err(DbError::HashCollision(_))));
+}
+
+#[test]
+fn test_canonical_dependencies() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+    let collection = db
+        .create_collection("test_canonical")
+        .expect("Failed to create collection");
+
+    let id1 = collection
+        .insert_json(
+            r#"{"body":{"sum":{"body":{},"dependencies":{"a":1}}},"dependencies":{"a":1,"b":2}}"#
+                .to_string(),
+        )
+        .expect("Failed to insert first item");
+    let id2 = collection
+        .insert_json(r#"{"body":{"sum":{"body":{},"dependencies":{"a":1}}},"dependencies":{ "b": 2.0, "a": 1 }}"#.to_string())
+        .expect("Failed to insert second item");
+
+    let subcollection = collection
+        .subcollection_json(r#"{"b":2,"a":1.0}"#.to_string())
+        .expect("Failed to create subcollection");
+    let keys = subcollection.get_keys().expect("Failed to get keys");
+    assert_eq!(keys.len(), 2);
+    assert!(keys.contains(&id1) && keys.contains(&id2));
+
+    let retrieved: foo::Foo = collection.get(&id2).expect("Failed to get item");
+    assert_eq!(retrieved.dependencies, foo::Dependencies { a: 1, b: 2 });
+
+    let retrieved_json = collection.get_json(&id2).expect("Failed to get JSON");
+    let retrieved_value: Value = serde_json::from_str(&retrieved_json).unwrap();
+    assert_eq!(retrieved_value["dependencies"], json!({"a": 1, "b": 2}));
+}
+
+fn count_dependency_records(db_path: &str, collection: &str) -> usize {
+    let sled_db = open_raw(db_path);
+    let tree = sled_db.open_tree(collection).unwrap();
+    tree.iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|(key, _)| key.starts_with(b"d/"))
+        .count()
+}
+
+#[test]
+fn test_dependency_reference_counting() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+
+    {
+        let db = Database::new(Some(db_path)).expect("Failed to open database");
+        let collection = db
+            .create_collection("test_refs")
+            .expect("Failed to create collection");
+
+        let subcollection = collection
+            .subcollection(&sum::Dependencies { a: 5 })
+            .expect("Failed to create subcollection");
+        let id1 = subcollection
+            .insert(&sum::Body {})
+            .expect("Failed to insert body1");
+        let id2 = subcollection
+            .insert(&sum::Body {})
+            .expect("Failed to insert body2");
+
+        let _unused = collection
+            .subcollection(&sum::Dependencies { a: 6 })
+            .expect("Failed to create unused subcollection");
+
+        collection.delete(&id1).expect("Failed to delete item");
+        let remaining: sum::Sum = collection.get(&id2).expect("Failed to get remaining item");
+        assert_eq!(remaining.dependencies.a, 5);
+
+        collection
+            .update(&id2, &sum::Sum::new(sum::Dependencies { a: 7 }).unwrap())
+            .expect("Failed to update item");
+        assert!(subcollection.get_keys().unwrap().is_empty());
+        assert_eq!(collection.gc_dependencies().unwrap(), 0);
+    }
+
+    assert_eq!(count_dependency_records(db_path, "test_refs"), 1);
+}
+
+#[test]
+fn test_gc_dependencies() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+
+    let id = {
+        let db = Database::new(Some(db_path)).expect("Failed to open database");
+        let collection = db
+            .create_collection("test_gc")
+            .expect("Failed to create collection");
+        collection
+            .insert(&sum::Sum::new(sum::Dependencies { a: 1 }).unwrap())
+            .expect("Failed to insert item")
+    };
+
+    {
+        // Orphaned record and a stale reference count.
+        let sled_db = open_raw(db_path);
+        let tree = sled_db.open_tree("test_gc").unwrap();
+        tree.insert(
+            format!("d/{}", "f".repeat(64)),
+            r#"{"refs":1,"dependencies":{"a":2}}"#,
+        )
+        .unwrap();
+        let deps_key = tree
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .find(|(_, value)| value.as_ref() == br#"{"refs":1,"dependencies":{"a":1}}"#)
+            .map(|(key, _)| key)
+            .expect("Dependency record not found");
+        tree.insert(deps_key, r#"{"refs":4,"dependencies":{"a":1}}"#)
+            .unwrap();
+        sled_db.flush().unwrap();
+    }
+
+    {
+        let db = Database::new(Some(db_path)).expect("Failed to open database");
+        let collection = db
+            .get_collection("test_gc")
+            .expect("Failed to get collection");
+
+        assert_eq!(collection.gc_dependencies().unwrap(), 1);
+        assert_eq!(collection.gc_dependencies().unwrap(), 0);
+
+        collection.delete(&id).expect("Failed to delete item");
+    }
+
+    assert_eq!(count_dependency_records(db_path, "test_gc"), 0);
+}
+
+#[test]
+fn test_separate_keyspaces() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+
+    {
+        let db = Database::new(Some(db_path)).expect("Failed to open database");
+        let collection = db
+            .create_collection("test_keyspaces")
+            .expect("Failed to create collection");
+        let subcollection = collection
+            .subcollection(&foo::Dependencies { a: 1, b: 2 })
+            .expect("Failed to create subcollection");
+        subcollection
+            .insert(&foo::Body {
+                sum: sum::Sum::new(sum::Dependencies { a: 1 }).unwrap(),
+            })
+            .expect("Failed to insert item");
+    }
+
+    let sled_db = open_raw(db_path);
+    let tree = sled_db.open_tree("test_keyspaces").unwrap();
+    let mut prefixes: Vec<Vec<u8>> = tree
+        .iter()
+        .filter_map(|entry| entry.ok())
+        .map(|(key, _)| key.split(|b| *b == b'/').next().unwrap().to_vec())
+        .collect();
+    prefixes.dedup();
+
+    assert_eq!(
+        prefixes,
+        vec![
+            b"*metadata*".to_vec(),
+            b"d".to_vec(),
+            b"i".to_vec(),
+            b"s".to_vec(),
+            b"v".to_vec()
+        ]
+    );
+}
+
+#[test]
+fn test_collection_iteration() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+    let collection = db
+        .create_collection("test_iteration")
+        .expect("Failed to create collection");
+
+    assert!(collection.is_empty().unwrap());
+
+    let mut ids = Vec::new();
+    for a in 0..5 {
+        let sum = sum::Sum::new(sum::Dependencies { a: a % 2 }).unwrap();
+        ids.push(collection.insert(&sum).expect("Failed to insert item"));
+    }
+    ids.sort();
+
+    assert_eq!(collection.len().unwrap(), 5);
+    assert!(!collection.is_empty().unwrap());
+
+    let keys: Vec<String> = collection.keys().map(|key| key.unwrap()).collect();
+    assert_eq!(keys, ids);
+
+    let items: Vec<(String, sum::Sum)> = collection.iter().map(|item| item.unwrap()).collect();
+    assert_eq!(items.len(), 5);
+    assert_eq!(
+        items
+            .iter()
+            .filter(|(_, sum)| sum.dependencies.a == 1)
+            .count(),
+        2
+    );
+
+    let subcollection = collection
+        .subcollection(&sum::Dependencies { a: 0 })
+        .expect("Failed to create subcollection");
+    assert_eq!(subcollection.len().unwrap(), 3);
+
+    let bodies: Vec<(String, sum::Body)> = subcollection.iter().map(|item| item.unwrap()).collect();
+    assert_eq!(bodies.len(), 3);
+    for (id, body) in &bodies {
+        assert_eq!(body, &sum::Body {});
+        assert!(ids.contains(id));
+    }
+
+    let json_items: Vec<(String, String)> =
+        collection.iter_json().map(|item| item.unwrap()).collect();
+    let first: Value = serde_json::from_str(&json_items[0].1).unwrap();
+    assert!(first["dependencies"]["a"].is_number());
+}
+
+#[test]
+fn test_cursor_pagination() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+    let collection = db
+        .create_collection("test_pagination")
+        .expect("Failed to create collection");
+
+    let subcollection = collection
+        .subcollection(&user::Dependencies {})
+        .expect("Failed to create subcollection");
+
+    for a in 0..7 {
+        subcollection
+            .insert(&user::Body { a, b: 0, c: 0 })
+            .expect("Failed to insert item");
+    }
+
+    let mut seen = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let page = collection
+            .page::<user::User>(3, cursor.as_deref())
+            .expect("Failed to get page");
+        assert!(page.items.len() <= 3);
+        seen.extend(page.items.into_iter().map(|(id, _)| id));
+
+        if seen.len() == 3 {
+            // Inserted while paginating: listed exactly once if it sorts
+            // after the cursor, never duplicated.
+            subcollection
+                .insert(&user::Body { a: 100, b: 0, c: 0 })
+                .expect("Failed to insert item during pagination");
+        }
+
+        match page.next_cursor {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+
+    let mut unique = seen.clone();
+    unique.sort();
+    unique.dedup();
+    assert_eq!(unique.len(), seen.len());
+    assert!(seen.len() == 7 || seen.len() == 8);
+
+    let first = subcollection
+        .page::<user::Body>(4, None)
+        .expect("Failed to get first page");
+    assert_eq!(first.items.len(), 4);
+    let rest = subcollection
+        .page::<user::Body>(10, first.next_cursor.as_deref())
+        .expect("Failed to get second page");
+    assert_eq!(rest.items.len(), 4);
+    assert!(rest.next_cursor.is_none());
+
+    let invalid = collection.page_json(3, Some("not a cursor"));
+    assert!(matches!(invalid, Err(DbError::DeserializationError(_))));
+
+    // An empty page would return its own cursor forever.
+    for cursor in [None, first.next_cursor.as_deref()] {
+        assert!(matches!(
+            collection.page_json(0, cursor),
+            Err(DbError::DeserializationError(_))
+        ));
+        assert!(matches!(
+            subcollection.page::<user::Body>(0, cursor),
+            Err(DbError::DeserializationError(_))
+        ));
+    }
+}
+
+#[test]
+fn test_query_filters() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+    let collection = db
+        .create_collection("test_query")
+        .expect("Failed to create collection");
+
+    let mut ids = Vec::new();
+    for a in 0..5 {
+        let subcollection = collection
+            .subcollection(&foo::Dependencies { a, b: a % 2 })
+            .expect("Failed to create subcollection");
+        let foo = foo::Foo::new(foo::Dependencies { a, b: a % 2 }).unwrap();
+        ids.push(
+            subcollection
+                .insert(&foo.body)
+                .expect("Failed to insert item"),
+        );
+    }
+
+    let found = collection
+        .query_ids(&Query::new().filter(Filter::eq("dependencies.a", 3)))
+        .expect("Failed to query");
+    assert_eq!(found, vec![ids[3].clone()]);
+
+    let found = collection
+        .query::<foo::Foo>(
+            &Query::new()
+                .filter(Filter::and(vec![
+                    Filter::gte("dependencies.a", 1),
+                    Filter::lt("dependencies.a", 4),
+                ]))
+                .sort_by("dependencies.a", true),
+        )
+        .expect("Failed to query");
+    let values: Vec<i32> = found.iter().map(|(_, foo)| foo.dependencies.a).collect();
+    assert_eq!(values, vec![3, 2, 1]);
+
+    let found = collection
+        .query_ids(&Query::new().filter(Filter::or(vec![
+            Filter::is_in("dependencies.a", vec![json!(0), json!(4)]),
+            Filter::eq("body.sum.dependencies.a", -2),
+        ])))
+        .expect("Failed to query");
+    assert_eq!(found.len(), 4);
+
+    let found = collection
+        .query_ids(&Query::new().filter(!Filter::eq("dependencies.b", 1)))
+        .expect("Failed to query");
+    assert_eq!(found.len(), 3);
+
+    let found = collection
+        .query_ids(&Query::new().filter(Filter::exists("body.missing")))
+        .expect("Failed to query");
+    assert!(found.is_empty());
+
+    let found = collection
+        .query_json(
+            &Query::new()
+                .sort_by("dependencies.a", false)
+                .limit(2)
+                .project(&["dependencies.a"]),
+        )
+        .expect("Failed to query");
+    assert_eq!(found.len(), 2);
+    let projected: Value = serde_json::from_str(&found[1].1).unwrap();
+    assert_eq!(projected, json!({"dependencies": {"a": 1}}));
+
+    let subcollection = collection
+        .subcollection(&foo::Dependencies { a: 2, b: 0 })
+        .expect("Failed to create subcollection");
+    let found = subcollection
+        .query_ids(&Query::new().filter(Filter::gt("dependencies.a", 0)))
+        .expect("Failed to query subcollection");
+    assert_eq!(found, vec![ids[2].clone()]);
+
+    let parsed: Query = serde_json::from_value(json!({
+        "filter": {"and": [{"gt": ["dependencies.a", 0]}, {"not": {"eq": ["dependencies.b", 0]}}]},
+        "sort": [{"path": "dependencies.a"}]
+    }))
+    .expect("Failed to parse query");
+    let found = collection
+        .query::<foo::Foo>(&parsed)
+        .expect("Failed to query");
+    let values: Vec<i32> = found.iter().map(|(_, foo)| foo.dependencies.a).collect();
+    assert_eq!(values, vec![1, 3]);
+}
+
+#[test]
+fn test_secondary_index() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+    let collection = db
+        .create_collection("test_index")
+        .expect("Failed to create collection");
+    let subcollection = collection
+        .subcollection(&user::Dependencies {})
+        .expect("Failed to create subcollection");
+
+    let mut ids = Vec::new();
+    for a in [5, -3, 8, 0, 5, 12, -7, 3] {
+        ids.push(
+            subcollection
+                .insert(&user::Body { a, b: a % 3, c: 0 })
+                .expect("Failed to insert item"),
+        );
+    }
+
+    collection
+        .create_index("by_a", "body.a", IndexOptions::default())
+        .expect("Failed to create index");
+
+    let indexes = collection.list_indexes().expect("Failed to list indexes");
+    assert_eq!(indexes.len(), 1);
+    assert_eq!(indexes[0].path, "body.a");
+    assert!(indexes[0].ready);
+
+    // Maintained by a handle opened before the index existed as well.
+    let other = db
+        .get_collection("test_index")
+        .expect("Failed to get collection");
+    let other_subcollection = other
+        .subcollection(&user::Dependencies {})
+        .expect("Failed to create subcollection");
+    other_subcollection
+        .insert(&user::Body { a: 4, b: 1, c: 0 })
+        .expect("Failed to insert item");
+    subcollection
+        .update(&ids[2], &user::Body { a: -1, b: 0, c: 0 })
+        .expect("Failed to update item");
+    collection.delete(&ids[5]).expect("Failed to delete item");
+
+    let a_values = |results: Vec<(String, user::User)>| -> Vec<i32> {
+        results.iter().map(|(_, user)| user.body.a).collect()
+    };
+
+    let found = collection
+        .query::<user::User>(
+            &Query::new()
+                .filter(Filter::and(vec![
+                    Filter::gte("body.a", 0),
+                    Filter::lt("body.a", 6),
+                ]))
+                .sort_by("body.a", false),
+        )
+        .expect("Failed to query");
+    assert_eq!(a_values(found), vec![0, 3, 4, 5, 5]);
+
+    let found = collection
+        .query::<user::User>(&Query::new().filter(Filter::eq("body.a", -1)))
+        .expect("Failed to query");
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].0, ids[2]);
+
+    let found = collection
+        .query::<user::User>(&Query::new().filter(Filter::eq("body.a", 12)))
+        .expect("Failed to query");
+    assert!(found.is_empty());
+
+    let queries = [
+        Query::new().sort_by("body.a", true).limit(3),
+        Query::new().sort_by("body.a", false),
+        Query::new()
+            .filter(Filter::is_in(
+                "body.a",
+                vec![json!(5), json!(-7), json!(100)],
+            ))
+            .sort_by("body.a", true),
+        Query::new().filter(Filter::and(vec![
+            Filter::gt("body.a", -5),
+            Filter::eq("body.b", 0),
+        ])),
+        Query::new().filter(Filter::lte("body.a", "text")),
+    ];
+
+    let indexed: Vec<Vec<String>> = queries
+        .iter()
+        .map(|query| collection.query_ids(query).expect("Failed to query"))
+        .collect();
+
+    assert_eq!(
+        a_values(collection.query(&queries[0]).unwrap()),
+        vec![5, 5, 4]
+    );
+    assert!(indexed[4].is_empty());
+
+    assert!(matches!(
+        collection.create_index("by_a", "body.b", IndexOptions::default()),
+        Err(DbError::AlreadyExists(_))
+    ));
+    assert!(matches!(
+        collection.create_index("by_deps", "dependencies", IndexOptions::default()),
+        Err(DbError::SchemaError(_))
+    ));
+
+    collection.drop_index("by_a").expect("Failed to drop index");
+    assert!(collection.list_indexes().unwrap().is_empty());
+    assert!(matches!(
+        collection.drop_index("by_a"),
+        Err(DbError::NotFound)
+    ));
+
+    let scanned: Vec<Vec<String>> = queries
+        .iter()
+        .map(|query| collection.query_ids(query).expect("Failed to query"))
+        .collect();
+    assert_eq!(indexed, scanned);
+}
+
+#[test]
+fn test_unique_index() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+    let collection = db
+        .create_collection("test_unique_index")
+        .expect("Failed to create collection");
+    let subcollection = collection
+        .subcollection(&user::Dependencies {})
+        .expect("Failed to create subcollection");
+
+    let first = subcollection
+        .insert(&user::Body { a: 1, b: 0, c: 0 })
+        .expect("Failed to insert item");
+    let duplicate = subcollection
+        .insert(&user::Body { a: 1, b: 1, c: 0 })
+        .expect("Failed to insert item");
+
+    let unique = IndexOptions { unique: true };
+
+    assert!(matches!(
+        collection.create_index("unique_a", "body.a", unique.clone()),
+        Err(DbError::AlreadyExists(_))
+    ));
+    assert!(collection.list_indexes().unwrap().is_empty());
+
+    collection
+        .delete(&duplicate)
+        .expect("Failed to delete item");
+    collection
+        .create_index("unique_a", "body.a", unique)
+        .expect("Failed to create index");
+
+    let result = subcollection.insert(&user::Body { a: 1, b: 2, c: 0 });
+    assert!(matches!(result, Err(DbError::AlreadyExists(_))));
+    assert_eq!(collection.len().unwrap(), 1);
+
+    subcollection
+        .update(&first, &user::Body { a: 1, b: 5, c: 0 })
+        .expect("Failed to update item without changing the indexed value");
+    subcollection
+        .update(&first, &user::Body { a: 2, b: 5, c: 0 })
+        .expect("Failed to update item");
+
+    let second = subcollection
+        .insert(&user::Body { a: 1, b: 2, c: 0 })
+        .expect("Failed to insert freed value");
+
+    let result = subcollection.update(&second, &user::Body { a: 2, b: 2, c: 0 });
+    assert!(matches!(result, Err(DbError::AlreadyExists(_))));
+
+    collection.delete(&first).expect("Failed to delete item");
+    subcollection
+        .update(&second, &user::Body { a: 2, b: 2, c: 0 })
+        .expect("Failed to update item");
+
+    let found = collection
+        .query_ids(&Query::new().filter(Filter::eq("body.a", 2)))
+        .expect("Failed to query");
+    assert_eq!(found, vec![second]);
+
+    // Integers that differ only past the precision of floats are distinct
+    // values, ordered exactly.
+    let large = db
+        .create_collection("test_unique_large")
+        .expect("Failed to create collection");
+    large
+        .create_index("unique_n", "body.n", IndexOptions { unique: true })
+        .expect("Failed to create index");
+    let message = |n: Value| json!({"body": {"n": n}, "dependencies": {}});
+    let big = 1u64 << 60;
+    let mut ids = Vec::new();
+    for n in [
+        json!(big + 1),
+        json!(big),
+        json!(u64::MAX),
+        json!(-(big as i64) - 1),
+    ] {
+        ids.push(large.insert(&message(n)).expect("Failed to insert item"));
+    }
+    assert!(matches!(
+        large.insert(&message(json!(big))),
+        Err(DbError::AlreadyExists(_))
+    ));
+
+    let found = large
+        .query_ids(&Query::new().filter(Filter::gt("body.n", big)))
+        .expect("Failed to query");
+    let mut expected = vec![ids[0].clone(), ids[2].clone()];
+    expected.sort();
+    assert_eq!(found, expected);
+    let found = large
+        .query_ids(&Query::new().filter(Filter::eq("body.n", big + 1)))
+        .expect("Failed to query");
+    assert_eq!(found, vec![ids[0].clone()]);
+}
+
+#[test]
+fn test_find_subcollections() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+
+    {
+        let db = Database::new(Some(db_path)).expect("Failed to open database");
+        let collection = db
+            .create_collection("test_find")
+            .expect("Failed to create collection");
+
+        let mut ids = Vec::new();
+        for a in 0..5 {
+            let dependencies = foo::Dependencies { a, b: a * 10 };
+            let subcollection = collection
+                .subcollection(&dependencies)
+                .expect("Failed to create subcollection");
+            let foo = foo::Foo::new(dependencies).unwrap();
+            for _ in 0..2 {
+                ids.push(
+                    subcollection
+                        .insert(&foo.body)
+                        .expect("Failed to insert item"),
+                );
+            }
+        }
+
+        let found = collection
+            .find_subcollections(&Filter::eq("dependencies.a", 3))
+            .expect("Failed to find subcollections");
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            found[0]
+                .get_dependencies::<foo::Dependencies>()
+                .expect("Failed to get dependencies"),
+            foo::Dependencies { a: 3, b: 30 }
+        );
+        assert_eq!(found[0].len().unwrap(), 2);
+
+        let found = collection
+            .find_subcollections(&Filter::gt("dependencies.b", 10))
+            .expect("Failed to find subcollections");
+        let mut values: Vec<i32> = found
+            .iter()
+            .map(|s| s.get_dependencies::<foo::Dependencies>().unwrap().a)
+            .collect();
+        values.sort();
+        assert_eq!(values, vec![2, 3, 4]);
+
+        let found = collection
+            .find_subcollections(&Filter::and(vec![
+                Filter::is_in("dependencies.a", vec![json!(1), json!(4)]),
+                Filter::lt("dependencies.b", 40),
+            ]))
+            .expect("Failed to find subcollections");
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            found[0].get_dependencies_json().unwrap(),
+            r#"{"a":1,"b":10}"#
+        );
+
+        let found = collection
+            .find_subcollections(&Filter::exists("dependencies.a"))
+            .expect("Failed to find subcollections");
+        assert_eq!(found.len(), 5);
+
+        let found = collection
+            .query_ids(&Query::new().filter(Filter::gte("dependencies.b", 30)))
+            .expect("Failed to query");
+        let mut expected = ids[6..].to_vec();
+        expected.sort();
+        assert_eq!(found, expected);
+
+        collection.delete(&ids[6]).expect("Failed to delete item");
+        collection.delete(&ids[7]).expect("Failed to delete item");
+
+        let found = collection
+            .find_subcollections(&Filter::eq("dependencies.a", 3))
+            .expect("Failed to find subcollections");
+        assert!(found.is_empty());
+
+        for id in ids.iter().filter(|id| *id != &ids[6] && *id != &ids[7]) {
+            collection.delete(id).expect("Failed to delete item");
+        }
+    }
+
+    let sled_db = open_raw(db_path);
+    let tree = sled_db.open_tree("test_find").unwrap();
+    let fields = tree
+        .iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|(key, _)| key.starts_with(b"v/"))
+        .count();
+    assert_eq!(fields, 0);
+}
+
+#[test]
+fn test_database_transaction() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+    let sums = db
+        .create_collection_with_schema::<sum::Body, sum::Dependencies>("sums")
+        .expect("Failed to create collection");
+    let foos = db
+        .create_collection("foos")
+        .expect("Failed to create collection");
+
+    let dependencies = foo::Dependencies { a: 2, b: 7 };
+    let foo = foo::Foo::new(dependencies).unwrap();
+
+    let (sum_id, foo_id) = db
+        .transaction(|tx| {
+            let sum_id = tx.collection("sums")?.insert(&foo.body.sum)?;
+            let foo_id = tx
+                .collection("foos")?
+                .subcollection(&foo.dependencies)?
+                .insert(&foo.body)?;
+
+            let stored: sum::Sum = tx.collection("sums")?.get(&sum_id)?;
+            assert_eq!(stored, foo.body.sum);
+
+            Ok((sum_id, foo_id))
+        })
+        .expect("Failed to run transaction");
+
+    let stored: sum::Sum = sums.get(&sum_id).expect("Failed to get sum");
+    assert_eq!(stored.dependencies.a, 5);
+    let stored: foo::Foo = foos.get(&foo_id).expect("Failed to get foo");
+    assert_eq!(
+        stored,
+        foo::Foo::new(foo::Dependencies { a: 2, b: 7 }).unwrap()
+    );
+
+    // Everything is rolled back if any step fails.
+    let result = db.transaction(|tx| {
+        tx.collection("foos")?.delete(&foo_id)?;
+        tx.collection("sums")?.delete(&sum_id)?;
+        tx.collection("sums")?
+            .insert_json(r#"{"body": {}, "dependencies": {"a": "one"}}"#.to_string())
+    });
+    assert!(matches!(result, Err(DbError::SchemaValidationError(_))));
+    assert_eq!(sums.len().unwrap(), 1);
+    assert_eq!(foos.len().unwrap(), 1);
+
+    let result = db.transaction(|tx| tx.collection("missing").map(|_| ()));
+    assert!(matches!(result, Err(DbError::NotFound)));
+
+    db.transaction(|tx| {
+        let subcollection = tx.collection("foos")?.subcollection(&foo.dependencies)?;
+        assert!(matches!(
+            tx.collection("foos")?
+                .subcollection(&foo::Dependencies { a: 0, b: 0 })?
+                .get::<foo::Body>(&foo_id),
+            Err(DbError::NotFound)
+        ));
+        subcollection.delete(&foo_id)?;
+        tx.collection("sums")?.delete(&sum_id)
+    })
+    .expect("Failed to run transaction");
+
+    assert!(sums.is_empty().unwrap());
+    assert!(foos.is_empty().unwrap());
+
+    // Only the collections a transaction uses are opened.
+    drop((sums, foos, db));
+    {
+        let raw = open_raw(db_path);
+        raw.open_tree("broken")
+            .unwrap()
+            .insert("*metadata*", "not metadata")
+            .unwrap();
+        raw.flush().unwrap();
+    }
+    let db = Database::new(Some(db_path)).expect("Failed to reopen database");
+    let id = db
+        .transaction(|tx| tx.collection("sums")?.insert(&foo.body.sum))
+        .expect("Failed to run transaction");
+    assert!(db.get_collection("sums").unwrap().get_json(&id).is_ok());
+    assert!(db
+        .transaction(|tx| tx.collection("broken").map(|_| ()))
+        .is_err());
+}
+
+#[test]
+fn test_transaction_retry() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+    let counters = db
+        .create_collection("counters")
+        .expect("Failed to create collection");
+    let id = counters
+        .insert(&user::User::new(user::Body { a: 0, b: 0, c: 0 }).unwrap())
+        .expect("Failed to insert counter");
+
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for _ in 0..10 {
+                    db.transaction(|tx| {
+                        let counters = tx.collection("counters")?;
+                        let mut counter: user::User = counters.get(&id)?;
+                        counter.body.a += 1;
+                        counters.update(&id, &counter)
+                    })
+                    .expect("Failed to run transaction");
+                }
+            });
+        }
+    });
+
+    let counter: user::User = counters.get(&id).expect("Failed to get counter");
+    assert_eq!(counter.body.a, 40);
+
+    // Conflicts are retried even if the closure ignores the error.
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for _ in 0..10 {
+                    db.transaction(|tx| {
+                        let counters = tx.collection("counters")?;
+                        if let Ok(mut counter) = counters.get::<user::User>(&id) {
+                            counter.body.a += 1;
+                            let _ = counters.update(&id, &counter);
+                        }
+                        Ok(())
+                    })
+                    .expect("Failed to run transaction");
+                }
+            });
+        }
+    });
+
+    let counter: user::User = counters.get(&id).expect("Failed to get counter");
+    assert_eq!(counter.body.a, 80);
+}
+
+#[test]
+fn test_batch_writes() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+    let collection = db
+        .create_collection_with_schema::<user::Body, user::Dependencies>("test_batch_writes")
+        .expect("Failed to create collection");
+    collection
+        .create_index("unique_a", "body.a", IndexOptions { unique: true })
+        .expect("Failed to create index");
+
+    let users: Vec<user::User> = (0..5)
+        .map(|a| user::User::new(user::Body { a, b: 0, c: 0 }).unwrap())
+        .collect();
+    let ids: Vec<String> = collection
+        .insert_many(&users, BatchMode::Atomic)
+        .expect("Failed to insert batch")
+        .into_iter()
+        .map(|result| result.unwrap())
+        .collect();
+    assert_eq!(ids.len(), 5);
+    assert_eq!(collection.len().unwrap(), 5);
+    let stored: user::User = collection.get(&ids[3]).expect("Failed to get item");
+    assert_eq!(stored, users[3]);
+
+    // An invalid message or a unique violation fails the whole atomic batch.
+    let result = collection.insert_many_json(
+        vec![
+            r#"{"body": {"a": 10, "b": 0, "c": 0}, "dependencies": {}}"#.to_string(),
+            r#"{"body": {"a": "ten"}, "dependencies": {}}"#.to_string(),
+        ],
+        BatchMode::Atomic,
+    );
+    assert!(matches!(result, Err(DbError::SchemaValidationError(_))));
+    let result = collection.insert_many(
+        &[
+            user::User::new(user::Body { a: 10, b: 0, c: 0 }).unwrap(),
+            user::User::new(user::Body { a: 1, b: 0, c: 0 }).unwrap(),
+        ],
+        BatchMode::Atomic,
+    );
+    assert!(matches!(result, Err(DbError::AlreadyExists(_))));
+    assert_eq!(collection.len().unwrap(), 5);
+
+    // Per item, only the failing items are skipped.
+    let results = collection
+        .insert_many_json(
+            vec![
+                r#"{"body": {"a": 10, "b": 0, "c": 0}, "dependencies": {}}"#.to_string(),
+                r#"{"body": {"a": "ten"}, "dependencies": {}}"#.to_string(),
+                r#"{"body": {"a": 1, "b": 0, "c": 0}, "dependencies": {}}"#.to_string(),
+                r#"{"body": {"a": 11, "b": 0, "c": 0}, "dependencies": {}}"#.to_string(),
+            ],
+            BatchMode::PerItem,
+        )
+        .expect("Failed to insert batch");
+    assert!(results[0].is_ok());
+    assert!(matches!(results[1], Err(DbError::SchemaValidationError(_))));
+    assert!(matches!(results[2], Err(DbError::AlreadyExists(_))));
+    assert!(results[3].is_ok());
+    assert_eq!(collection.len().unwrap(), 7);
+
+    let updates = vec![
+        (
+            ids[0].clone(),
+            user::User::new(user::Body { a: 20, b: 1, c: 0 }).unwrap(),
+        ),
+        (
+            "missing".to_string(),
+            user::User::new(user::Body { a: 21, b: 1, c: 0 }).unwrap(),
+        ),
+    ];
+    let result = collection.update_many(&updates, BatchMode::Atomic);
+    assert!(matches!(result, Err(DbError::NotFound)));
+    let stored: user::User = collection.get(&ids[0]).expect("Failed to get item");
+    assert_eq!(stored.body.a, 0);
+
+    let results = collection
+        .update_many(&updates, BatchMode::PerItem)
+        .expect("Failed to update batch");
+    assert!(results[0].is_ok());
+    assert!(matches!(results[1], Err(DbError::NotFound)));
+    let stored: user::User = collection.get(&ids[0]).expect("Failed to get item");
+    assert_eq!(stored.body.a, 20);
+
+    let subcollection = collection
+        .subcollection(&user::Dependencies {})
+        .expect("Failed to create subcollection");
+    let sub_ids: Vec<String> = subcollection
+        .insert_many(
+            &[
+                user::Body { a: 30, b: 0, c: 0 },
+                user::Body { a: 31, b: 0, c: 0 },
+            ],
+            BatchMode::Atomic,
+        )
+        .expect("Failed to insert batch")
+        .into_iter()
+        .map(|result| result.unwrap())
+        .collect();
+    let results = subcollection
+        .update_many(
+            &[(sub_ids[0].clone(), user::Body { a: 32, b: 0, c: 0 })],
+            BatchMode::Atomic,
+        )
+        .expect("Failed to update batch");
+    assert!(results[0].is_ok());
+    let stored: user::Body = subcollection.get(&sub_ids[0]).expect("Failed to get item");
+    assert_eq!(stored.a, 32);
+
+    let other = db
+        .create_collection("test_batch_other")
+        .expect("Failed to create collection");
+    let other_subcollection = other
+        .subcollection(&json!({"x": 1}))
+        .expect("Failed to create subcollection");
+    let results = other_subcollection
+        .delete_many(&sub_ids, BatchMode::PerItem)
+        .expect("Failed to delete batch");
+    assert!(results
+        .iter()
+        .all(|result| matches!(result, Err(DbError::NotFound))));
+
+    let mut all_ids: Vec<String> = collection
+        .keys()
+        .collect::<Result<_, _>>()
+        .expect("Failed to get keys");
+    all_ids.push("missing".to_string());
+    let result = collection.delete_many(&all_ids, BatchMode::Atomic);
+    assert!(matches!(result, Err(DbError::NotFound)));
+    assert_eq!(collection.len().unwrap(), 9);
+
+    let results = collection
+        .delete_many(&all_ids, BatchMode::PerItem)
+        .expect("Failed to delete batch");
+    assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 9);
+    assert!(collection.is_empty().unwrap());
+    assert!(subcollection.get_keys().unwrap().is_empty());
+}
+
+#[test]
+fn test_durability() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+
+    {
+        let db = Database::with_durability(Some(db_path), Durability::OnDemand)
+            .expect("Failed to open database");
+        assert_eq!(db.durability(), Durability::OnDemand);
+        let collection = db
+            .create_collection("test_durability")
+            .expect("Failed to create collection");
+
+        std::thread::scope(|scope| {
+            for a in 0..4 {
+                let collection = &collection;
+                scope.spawn(move || {
+                    for b in 0..25 {
+                        collection
+                            .insert(&user::User::new(user::Body { a, b, c: 0 }).unwrap())
+                            .expect("Failed to insert item");
+                    }
+                });
+            }
+        });
+
+        db.flush().expect("Failed to flush database");
+    }
+
+    let db = Database::with_durability(
+        Some(db_path),
+        Durability::Periodic(std::time::Duration::from_millis(10)),
+    )
+    .expect("Failed to reopen database");
+    let collection = db
+        .get_collection("test_durability")
+        .expect("Failed to get collection");
+    assert_eq!(collection.len().unwrap(), 100);
+    drop(collection);
+    drop(db);
+
+    // Concurrent synchronous writers share flushes.
+    let db = Database::new(Some(db_path)).expect("Failed to reopen database");
+    assert_eq!(db.durability(), Durability::Sync);
+    let collection = db
+        .get_collection("test_durability")
+        .expect("Failed to get collection");
+    std::thread::scope(|scope| {
+        for _ in 0..8 {
+            scope.spawn(|| {
+                for _ in 0..10 {
+                    collection
+                        .insert(&user::User::new(user::Body { a: 0, b: 0, c: 1 }).unwrap())
+                        .expect("Failed to insert item");
+                }
+            });
+        }
+    });
+    assert_eq!(collection.len().unwrap(), 180);
+}
+
+#[test]
+fn test_database_config() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().join("db");
+
+    assert!(DatabaseConfig::new(&db_path)
+        .create_if_missing(false)
+        .open()
+        .is_err());
+    assert!(DatabaseConfig::new(&db_path)
+        .read_only(true)
+        .open()
+        .is_err());
+    assert!(!db_path.exists());
+
+    let id = {
+        let db = DatabaseConfig::new(&db_path)
+            .cache_capacity(16 * 1024 * 1024)
+            .segment_size(1 << 20)
+            .open()
+            .expect("Failed to open database");
+        let collection = db
+            .create_collection("test_config")
+            .expect("Failed to create collection");
+        collection
+            .insert(&user::User::new(user::Body { a: 1, b: 2, c: 3 }).unwrap())
+            .expect("Failed to insert item")
+    };
+
+    let db = Database::open(
+        &DatabaseConfig::new(&db_path)
+            .segment_size(1 << 20)
+            .create_if_missing(false)
+            .read_only(true),
+    )
+    .expect("Failed to open database read-only");
+    assert!(db.is_read_only());
+    let collection = db
+        .get_collection("test_config")
+        .expect("Failed to get collection");
+    let stored: user::User = collection.get(&id).expect("Failed to get item");
+    assert_eq!(stored.body.c, 3);
+
+    let user = user::User::new(user::Body { a: 4, b: 5, c: 6 }).unwrap();
+    assert!(matches!(collection.insert(&user), Err(DbError::ReadOnly)));
+    assert!(matches!(
+        collection.update(&id, &user),
+        Err(DbError::ReadOnly)
+    ));
+    assert!(matches!(collection.delete(&id), Err(DbError::ReadOnly)));
+    assert!(matches!(
+        collection.insert_many(&[user], BatchMode::PerItem),
+        Err(DbError::ReadOnly)
+    ));
+    assert!(matches!(
+        collection.create_index("by_a", "body.a", IndexOptions::default()),
+        Err(DbError::ReadOnly)
+    ));
+    assert!(matches!(
+        db.create_collection("other"),
+        Err(DbError::ReadOnly)
+    ));
+    assert!(matches!(
+        db.transaction(|tx| tx.collection("test_config")?.delete(&id)),
+        Err(DbError::ReadOnly)
+    ));
+    assert_eq!(collection.len().unwrap(), 1);
+
+    let temporary = DatabaseConfig::temporary()
+        .open()
+        .expect("Failed to open temporary database");
+    let collection = temporary
+        .create_collection("test_temporary")
+        .expect("Failed to create collection");
+    collection
+        .insert(&user::User::new(user::Body { a: 1, b: 2, c: 3 }).unwrap())
+        .expect("Failed to insert item");
+    assert_eq!(collection.len().unwrap(), 1);
+
+    if !cfg!(feature = "compression") {
+        assert!(DatabaseConfig::temporary().compression(3).open().is_err());
+    }
+}
+
+#[test]
+fn test_storage_codecs() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let codecs = [
+        StorageCodec::Json,
+        StorageCodec::Cbor,
+        StorageCodec::MessagePack,
+        StorageCodec::Bincode,
+    ];
+
+    let mut expected = Vec::new();
+
+    {
+        let db = Database::new(Some(db_path)).expect("Failed to open database");
+
+        for codec in codecs {
+            let name = format!("test_codec_{:?}", codec);
+            let collection = db
+                .create_collection_with_options(
+                    &name,
+                    CollectionOptions::new()
+                        .schema::<user::Body, user::Dependencies>()
+                        .codec(codec),
+                )
+                .expect("Failed to create collection");
+            assert_eq!(collection.get_codec().unwrap(), codec);
+            assert!(collection.has_schema());
+            collection
+                .create_index("by_a", "body.a", IndexOptions::default())
+                .expect("Failed to create index");
+
+            let ids: Vec<String> = collection
+                .insert_many(
+                    &[
+                        user::User::new(user::Body { a: -3, b: 1, c: 0 }).unwrap(),
+                        user::User::new(user::Body { a: 7, b: 2, c: 0 }).unwrap(),
+                    ],
+                    BatchMode::Atomic,
+                )
+                .expect("Failed to insert batch")
+                .into_iter()
+                .map(|result| result.unwrap())
+                .collect();
+            assert_eq!(ids.len(), 2);
+
+            let stored: user::User = collection.get(&ids[0]).expect("Failed to get item");
+            assert_eq!(stored.body.a, -3);
+            let json: Value = serde_json::from_str(&collection.get_json(&ids[1]).unwrap()).unwrap();
+            assert_eq!(
+                json,
+                json!({"body": {"a": 7, "b": 2, "c": 0}, "dependencies": {}})
+            );
+
+            collection
+                .update(
+                    &ids[1],
+                    &user::User::new(user::Body { a: 8, b: 2, c: 0 }).unwrap(),
+                )
+                .expect("Failed to update item");
+
+            let found = collection
+                .query_ids(&Query::new().filter(Filter::gt("body.a", 0)))
+                .expect("Failed to query");
+            assert_eq!(found, vec![ids[1].clone()]);
+
+            let subcollection = collection
+                .subcollection(&user::Dependencies {})
+                .expect("Failed to create subcollection");
+            let bodies: Vec<(String, user::Body)> = subcollection
+                .iter()
+                .collect::<Result<_, _>>()
+                .expect("Failed to iterate");
+            assert_eq!(bodies.len(), 2);
+
+            expected.push((name, ids));
+        }
+
+        let collection = db
+            .get_collection("test_codec_Json")
+            .expect("Failed to get collection");
+        let other = db
+            .get_collection("test_codec_Json")
+            .expect("Failed to get collection");
+        assert_eq!(collection.reencode(StorageCodec::Bincode).unwrap(), 2);
+        assert_eq!(collection.reencode(StorageCodec::Bincode).unwrap(), 0);
+        collection
+            .insert(&user::User::new(user::Body { a: 9, b: 9, c: 9 }).unwrap())
+            .expect("Failed to insert item");
+
+        // Handles opened before the switch write with the new codec too.
+        assert_eq!(other.get_codec().unwrap(), StorageCodec::Bincode);
+        other
+            .insert(&user::User::new(user::Body { a: 10, b: 9, c: 9 }).unwrap())
+            .expect("Failed to insert item");
+
+        let collection = db
+            .get_collection("test_codec_Cbor")
+            .expect("Failed to get collection");
+        assert_eq!(collection.reencode(StorageCodec::Json).unwrap(), 2);
+    }
+
+    {
+        let raw = open_raw(db_path);
+        for (name, tag) in [
+            ("test_codec_Cbor", b'{'),
+            ("test_codec_MessagePack", 2),
+            ("test_codec_Json", 3),
+        ] {
+            let tree = raw.open_tree(name).unwrap();
+            for entry in tree.scan_prefix("i/") {
+                let (_, value) = entry.unwrap();
+                assert_eq!(value[0], tag);
+            }
+        }
+    }
+
+    let db = Database::new(Some(db_path)).expect("Failed to reopen database");
+    for (name, ids) in expected {
+        let collection = db.get_collection(&name).expect("Failed to get collection");
+        let stored: user::User = collection.get(&ids[1]).expect("Failed to get item");
+        assert_eq!(stored.body.a, 8);
+    }
+
+    let collection = db
+        .get_collection("test_codec_Json")
+        .expect("Failed to get collection");
+    assert_eq!(collection.get_codec().unwrap(), StorageCodec::Bincode);
+    assert_eq!(collection.len().unwrap(), 4);
+}
+
+#[test]
+fn test_typed_collection() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+
+    let foos = db
+        .create_typed_collection::<foo::Body, foo::Dependencies>("typed_foo")
+        .expect("Failed to create typed collection");
+
+    let foo = foo::Foo::new(foo::Dependencies { a: 1, b: 4 }).unwrap();
+    let message = dbuf_storage::Message {
+        body: foo.body,
+        dependencies: foo.dependencies,
+    };
+    let id = foos.insert(&message).expect("Failed to insert message");
+    assert_eq!(foos.get(&id).expect("Failed to get message"), message);
+
+    let subcollection = foos
+        .subcollection(&foo::Dependencies { a: 1, b: 4 })
+        .expect("Failed to create subcollection");
+    let body = foo::Body {
+        sum: Sum::new(sum::Dependencies { a: 7 }).unwrap(),
+    };
+    let other_id = subcollection.insert(&body).expect("Failed to insert body");
+    assert_eq!(subcollection.get(&other_id).unwrap(), body);
+    assert_eq!(subcollection.dependencies().unwrap(), message.dependencies);
+
+    let mut ids: Vec<Id<foo::Body, foo::Dependencies>> =
+        foos.ids().collect::<Result<_, _>>().unwrap();
+    ids.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    let mut expected = vec![id.clone(), other_id.clone()];
+    expected.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    assert_eq!(ids, expected);
+
+    // Typed ids round-trip through serde as plain strings.
+    let id_json = serde_json::to_string(&id).unwrap();
+    assert_eq!(id_json, format!("\"{}\"", id));
+    let parsed: Id<foo::Body, foo::Dependencies> = serde_json::from_str(&id_json).unwrap();
+    assert_eq!(parsed, id);
+
+    subcollection
+        .delete(&other_id)
+        .expect("Failed to delete body");
+    assert!(matches!(foos.get(&other_id), Err(DbError::NotFound)));
+    drop(subcollection);
+    drop(foos);
+
+    let reopened = db
+        .typed_collection::<foo::Body, foo::Dependencies>("typed_foo")
+        .expect("Failed to open typed collection");
+    assert_eq!(reopened.get(&id).unwrap(), message);
+
+    assert!(matches!(
+        db.typed_collection::<user::Body, user::Dependencies>("typed_foo"),
+        Err(DbError::SchemaError(_))
+    ));
+    assert!(matches!(
+        db.typed_collection::<foo::Body, sum::Dependencies>("typed_foo"),
+        Err(DbError::SchemaError(_))
+    ));
+
+    db.create_collection("untyped").unwrap();
+    assert!(matches!(
+        db.typed_collection::<user::Body, user::Dependencies>("untyped"),
+        Err(DbError::SchemaError(_))
+    ));
+    assert!(matches!(
+        db.typed_collection::<user::Body, user::Dependencies>("missing"),
+        Err(DbError::NotFound)
+    ));
+}
+
+#[test]
+fn test_store_and_load_messages() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+
+    let point = point::Point {
+        body: point::Body { x: 1, y: 2 },
+        dependencies: point::Dependencies { dim: 2 },
+    };
+    assert_eq!(point::Point::COLLECTION, "points");
+    assert!(!db.collection_exists("points").unwrap());
+
+    let id = db.store(&point).expect("Failed to store message");
+    assert!(db.collection_exists("points").unwrap());
+    assert_eq!(db.load::<point::Point>(&id).unwrap(), point);
+
+    let other_id = db.store(&point).expect("Failed to store message");
+    assert_ne!(id, other_id);
+    assert_eq!(db.get_collection("points").unwrap().len().unwrap(), 2);
+
+    // The created collection is validated like one created with its schemas.
+    let typed = db
+        .typed_collection::<point::Body, point::Dependencies>("points")
+        .expect("Failed to open typed collection");
+    assert_eq!(typed.ids().count(), 2);
+    drop(typed);
+
+    let foo = foo::Foo::new(foo::Dependencies { a: 1, b: 4 }).unwrap();
+    let foo_id = db.store(&foo).expect("Failed to store message");
+    assert_eq!(db.load::<foo::Foo>(&foo_id).unwrap(), foo);
+    assert!(matches!(
+        db.load::<foo::Foo>("missing"),
+        Err(DbError::NotFound)
+    ));
+
+    // An existing collection with other schemas is not written to.
+    db.drop_collection("points").unwrap();
+    db.create_collection_with_schema::<user::Body, user::Dependencies>("points")
+        .unwrap();
+    assert!(matches!(db.store(&point), Err(DbError::SchemaError(_))));
+    assert!(matches!(
+        db.load::<point::Point>(&id),
+        Err(DbError::SchemaError(_))
+    ));
+
+    let other_db = Database::new(Some(temp_dir.path().join("other").to_str().unwrap())).unwrap();
+    assert!(matches!(
+        other_db.load::<point::Point>(&id),
+        Err(DbError::NotFound)
+    ));
+}
+
+#[test]
+fn test_validators() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+    let collection = db
+        .create_collection_with_schema::<foo::Body, foo::Dependencies>("foos")
+        .expect("Failed to create collection");
+
+    // The dependent typing of `foo` that the schema cannot express.
+    collection.add_typed_validator(
+        "sum_matches",
+        |body: &foo::Body, dependencies: &foo::Dependencies| {
+            if body.sum.dependencies.a != dependencies.b - dependencies.a {
+                return Err(ValidationError::at(
+                    "body.sum.dependencies.a",
+                    "must equal dependencies.b - dependencies.a",
+                ));
+            }
+            Ok(())
+        },
+    );
+    assert_eq!(collection.validator_names(), vec!["sum_matches"]);
+
+    let valid = foo::Foo::new(foo::Dependencies { a: 1, b: 4 }).unwrap();
+    let id = collection
+        .insert(&valid)
+        .expect("Failed to insert valid message");
+
+    let mut invalid = foo::Foo::new(foo::Dependencies { a: 1, b: 4 }).unwrap();
+    invalid.body.sum.dependencies.a = 5;
+
+    match collection.insert(&invalid) {
+        Err(DbError::ValidationError(e)) => {
+            assert_eq!(e.validator, "sum_matches");
+            assert_eq!(e.path.as_deref(), Some("body.sum.dependencies.a"));
+        }
+        other => panic!("Expected a validation error, got {:?}", other),
+    }
+    assert!(matches!(
+        collection.update(&id, &invalid),
+        Err(DbError::ValidationError(_))
+    ));
+
+    let subcollection = collection.subcollection(&valid.dependencies).unwrap();
+    assert!(matches!(
+        subcollection.insert(&invalid.body),
+        Err(DbError::ValidationError(_))
+    ));
+    assert!(matches!(
+        subcollection.update(&id, &invalid.body),
+        Err(DbError::ValidationError(_))
+    ));
+    assert!(subcollection.insert(&valid.body).is_ok());
+
+    let results = collection
+        .insert_many(&[&valid, &invalid], BatchMode::PerItem)
+        .unwrap();
+    assert!(results[0].is_ok());
+    assert!(matches!(results[1], Err(DbError::ValidationError(_))));
+
+    // Validators apply to every handle of the collection.
+    let result = db.transaction(|tx| tx.collection("foos")?.insert(&invalid));
+    assert!(matches!(result, Err(DbError::ValidationError(_))));
+
+    // Plain closures see the raw JSON.
+    db.get_collection("foos").unwrap().add_validator(
+        "positive_a",
+        |_: &serde_json::Value, dependencies: &serde_json::Value| {
+            if dependencies["a"].as_i64().unwrap_or(0) <= 0 {
+                return Err(ValidationError::new("a must be positive"));
+            }
+            Ok(())
+        },
+    );
+    assert_eq!(
+        collection.validator_names(),
+        vec!["sum_matches", "positive_a"]
+    );
+
+    let negative = foo::Foo::new(foo::Dependencies { a: -1, b: 4 }).unwrap();
+    match collection.insert(&negative) {
+        Err(DbError::ValidationError(e)) => {
+            assert_eq!(e.validator, "positive_a");
+            assert_eq!(e.path, None);
+        }
+        other => panic!("Expected a validation error, got {:?}", other),
+    }
+
+    assert!(collection.remove_validator("positive_a"));
+    assert!(!collection.remove_validator("positive_a"));
+    assert!(collection.insert(&negative).is_ok());
+
+    drop(subcollection);
+    drop(collection);
+    db.drop_collection("foos").unwrap();
+    let collection = db
+        .create_collection_with_schema::<foo::Body, foo::Dependencies>("foos")
+        .unwrap();
+    assert!(collection.validator_names().is_empty());
+    assert!(collection.insert(&invalid).is_ok());
+}
+
+#[test]
+fn test_constraints() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+
+    let valid = foo::Foo::new(foo::Dependencies { a: 1, b: 4 }).unwrap();
+    let mut invalid = foo::Foo::new(foo::Dependencies { a: 1, b: 4 }).unwrap();
+    invalid.body.sum.dependencies.a = 5;
+
+    {
+        let db = Database::new(Some(db_path)).expect("Failed to open database");
+        let collection = db
+            .create_collection_with_options(
+                "foos",
+                CollectionOptions::new()
+                    .schema::<foo::Body, foo::Dependencies>()
+                    .constraint("body.sum.dependencies.a == dependencies.b - dependencies.a"),
+            )
+            .expect("Failed to create collection");
+
+        collection
+            .insert(&valid)
+            .expect("Failed to insert valid message");
+        match collection.insert(&invalid) {
+            Err(DbError::ValidationError(e)) => {
+                assert_eq!(e.path.as_deref(), Some("body.sum.dependencies.a"));
+                assert!(e.message.contains("5 == 3"), "{}", e.message);
+            }
+            other => panic!("Expected a validation error, got {:?}", other),
+        }
+
+        assert!(matches!(
+            db.create_collection_with_options(
+                "broken",
+                CollectionOptions::new().constraint("body.a ==")
+            ),
+            Err(DbError::SchemaError(_))
+        ));
+        assert!(!db.collection_exists("broken").unwrap());
+    }
+
+    // Constraints are stored with the collection and survive reopening.
+    let db = Database::new(Some(db_path)).expect("Failed to reopen database");
+    let collection = db.get_collection("foos").unwrap();
+    assert_eq!(
+        collection.get_constraints().unwrap()[0].as_str(),
+        "body.sum.dependencies.a == dependencies.b - dependencies.a"
+    );
+    assert!(matches!(
+        collection.insert(&invalid),
+        Err(DbError::ValidationError(_))
+    ));
+    let subcollection = collection.subcollection(&valid.dependencies).unwrap();
+    assert!(matches!(
+        subcollection.insert(&invalid.body),
+        Err(DbError::ValidationError(_))
+    ));
+    drop(subcollection);
+
+    // New constraints are checked against the stored messages, and taken
+    // back if one violates them.
+    let other = db.get_collection("foos").unwrap();
+    assert!(matches!(
+        collection.add_constraint("dependencies.a > 1"),
+        Err(DbError::ValidationError(_))
+    ));
+    assert_eq!(collection.get_constraints().unwrap().len(), 1);
+    collection
+        .add_constraint("dependencies.a * 2 <= dependencies.b")
+        .expect("Failed to add constraint");
+    assert!(matches!(
+        collection.add_constraint("dependencies.a * 2 <= dependencies.b"),
+        Err(DbError::AlreadyExists(_))
+    ));
+    assert_eq!(collection.get_constraints().unwrap().len(), 2);
+
+    // Handles opened earlier enforce it too.
+    let too_large_a = foo::Foo::new(foo::Dependencies { a: 3, b: 4 }).unwrap();
+    assert!(matches!(
+        collection.insert(&too_large_a),
+        Err(DbError::ValidationError(_))
+    ));
+    assert!(matches!(
+        other.insert(&too_large_a),
+        Err(DbError::ValidationError(_))
+    ));
+
+    assert!(collection
+        .remove_constraint("dependencies.a * 2 <= dependencies.b")
+        .unwrap());
+    assert!(!collection
+        .remove_constraint("dependencies.a > 100")
+        .unwrap());
+    collection.insert(&too_large_a).expect("Failed to insert");
+    assert_eq!(
+        db.get_collection("foos")
+            .unwrap()
+            .get_constraints()
+            .unwrap()
+            .len(),
+        1
+    );
+
+    // Lengths, strings and literals.
+    let lists = db
+        .create_collection_with_options(
+            "lists",
+            CollectionOptions::new()
+                .constraint("len(body.items) == dependencies.n")
+                .constraint(r#"body.kind != "unknown""#)
+                .constraint("-(dependencies.n) < 1 + 2 * (dependencies.n / 2)"),
+        )
+        .unwrap();
+
+    let list = |items: Value, kind: &str, n: i64| {
+        json!({"body": {"items": items, "kind": kind}, "dependencies": {"n": n}}).to_string()
+    };
+    assert!(lists.insert_json(list(json!([1, 2, 3]), "a", 3)).is_ok());
+    assert!(matches!(
+        lists.insert_json(list(json!([1, 2]), "a", 3)),
+        Err(DbError::ValidationError(_))
+    ));
+    assert!(matches!(
+        lists.insert_json(list(json!([1]), "unknown", 1)),
+        Err(DbError::ValidationError(_))
+    ));
+    assert!(matches!(
+        lists.insert_json(json!({"body": {"kind": "a"}, "dependencies": {"n": 0}}).to_string()),
+        Err(DbError::ValidationError(_))
+    ));
+
+    for invalid in [
+        "body.a",
+        "body.a == 1 == 2",
+        "other.a == 1",
+        "body..a == 1",
+        "len(1) == 1",
+        "body.a == (1",
+        r#"body.a == "open"#,
+        "body.a @ 1",
+    ] {
+        assert!(
+            matches!(Constraint::parse(invalid), Err(DbError::SchemaError(_))),
+            "{} should not parse",
+            invalid
+        );
+    }
+
+    let constraint = Constraint::parse("body.price * 1.5 >= 3").unwrap();
+    assert!(constraint
+        .check(&json!({"body": {"price": 2}, "dependencies": {}}))
+        .is_ok());
+    assert!(constraint
+        .check(&json!({"body": {"price": 1}, "dependencies": {}}))
+        .is_err());
+}
+
+#[test]
+fn test_id_strategies() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+
+    let create = |name: &str, id_strategy: IdStrategy| {
+        db.create_collection_with_options(name, CollectionOptions::new().id_strategy(id_strategy))
+            .expect("Failed to create collection")
+    };
+    let message = |n: i32| json!({"body": {"n": n}, "dependencies": {"a": 1}}).to_string();
+
+    let random = create("random", IdStrategy::default());
+    let id = random.insert_json(message(1)).unwrap();
+    assert_eq!(id.len(), 16);
+    assert!(id.chars().all(|c| c.is_ascii_alphanumeric()));
+
+    let uuid_v4 = create("uuid_v4", IdStrategy::UuidV4);
+    let id = uuid_v4.insert_json(message(1)).unwrap();
+    assert_eq!(id.len(), 36);
+    assert_eq!(&id[14..15], "4");
+
+    // Time-ordered and counter IDs iterate in insertion order.
+    for (name, id_strategy) in [
+        ("uuid_v7", IdStrategy::UuidV7),
+        ("counter", IdStrategy::Counter),
+    ] {
+        let collection = create(name, id_strategy);
+        let mut ids = Vec::new();
+        for n in 0..20 {
+            ids.push(collection.insert_json(message(n)).unwrap());
+        }
+        let keys: Vec<String> = collection.keys().collect::<Result<_, _>>().unwrap();
+        assert_eq!(keys, ids, "{:?} IDs are not ordered", id_strategy);
+    }
+
+    let counter = db.get_collection("counter").unwrap();
+    assert_eq!(counter.get_id_strategy(), IdStrategy::Counter);
+    assert_eq!(
+        counter.keys().next().unwrap().unwrap(),
+        "00000000000000000001"
+    );
+    let ids = counter
+        .insert_many_json(vec![message(1), message(2)], BatchMode::Atomic)
+        .unwrap();
+    assert_eq!(ids[0].as_ref().unwrap(), "00000000000000000021");
+    assert_eq!(ids[1].as_ref().unwrap(), "00000000000000000022");
+    let id = db
+        .transaction(|tx| tx.collection("counter")?.insert_json(message(3)))
+        .unwrap();
+    assert_eq!(id, "00000000000000000023");
+
+    // Equal messages get the same ID however their JSON is written.
+    let content = create("content", IdStrategy::ContentHash);
+    let id = content
+        .insert_json(r#"{"dependencies": {"a": 1.0}, "body": {"n": 1, "m": 2}}"#.to_string())
+        .unwrap();
+    assert_eq!(id.len(), 64);
+    assert!(matches!(
+        content.insert_json(r#"{"body": {"m": 2, "n": 1}, "dependencies": {"a": 1}}"#.to_string()),
+        Err(DbError::AlreadyExists(_))
+    ));
+    assert_ne!(content.insert_json(message(2)).unwrap(), id);
+
+    // Caller-chosen IDs.
+    random
+        .insert_json_with_id("custom", message(5))
+        .expect("Failed to insert with ID");
+    assert_eq!(
+        random.get_json("custom").unwrap(),
+        json!({"body": {"n": 5}, "dependencies": {"a": 1}}).to_string()
+    );
+    assert!(matches!(
+        random.insert_json_with_id("custom", message(6)),
+        Err(DbError::AlreadyExists(_))
+    ));
+    // IDs that could not be told apart from the other server routes, or
+    // would not fit in one path segment, are rejected.
+    let too_long = "x".repeat(MAX_ID_LEN + 1);
+    for id in ["", "query", "trash", "..", "a/b", "a\nb", too_long.as_str()] {
+        assert!(
+            matches!(
+                random.insert_json_with_id(id, message(6)),
+                Err(DbError::DeserializationError(_))
+            ),
+            "ID {:?} was accepted",
+            id
+        );
+    }
+    assert!(matches!(
+        random.upsert_json("changes", message(6)),
+        Err(DbError::DeserializationError(_))
+    ));
+    random
+        .insert_json_with_id(&"x".repeat(MAX_ID_LEN), message(6))
+        .unwrap();
+    random.insert_json_with_id("query-2", message(6)).unwrap();
+
+    let subcollection = counter.subcollection(&json!({"a": 1})).unwrap();
+    subcollection
+        .insert_with_id("sub", &json!({"n": 7}))
+        .expect("Failed to insert with ID");
+    assert_eq!(subcollection.get::<Value>("sub").unwrap(), json!({"n": 7}));
+    assert!(matches!(
+        subcollection.insert_with_id("sub", &json!({"n": 8})),
+        Err(DbError::AlreadyExists(_))
+    ));
+    assert!(subcollection
+        .get_keys()
+        .unwrap()
+        .contains(&"sub".to_string()));
+}
+
+#[test]
+fn test_conditional_writes() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+    let collection = db
+        .create_collection("conditional")
+        .expect("Failed to create collection");
+
+    let message = |n: i64| json!({"body": {"n": n}, "dependencies": {"a": 1}});
+
+    // Upsert creates, then replaces.
+    assert!(collection.upsert("item", &message(1)).unwrap());
+    assert!(!collection.upsert("item", &message(2)).unwrap());
+    assert_eq!(collection.get::<Value>("item").unwrap(), message(2));
+
+    // Insert if absent never overwrites.
+    assert!(!collection.insert_if_absent("item", &message(3)).unwrap());
+    assert_eq!(collection.get::<Value>("item").unwrap(), message(2));
+    assert!(collection.insert_if_absent("other", &message(3)).unwrap());
+    assert_eq!(collection.get::<Value>("other").unwrap(), message(3));
+    assert!(matches!(
+        collection.upsert("", &message(1)),
+        Err(DbError::DeserializationError(_))
+    ));
+
+    // Compare and swap only writes over the expected item.
+    let mismatch = collection
+        .compare_and_swap("item", Some(&message(1)), Some(&message(4)))
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(mismatch.current, Some(message(2).to_string()));
+    assert_eq!(collection.get::<Value>("item").unwrap(), message(2));
+
+    collection
+        .compare_and_swap("item", Some(&message(2)), Some(&message(4)))
+        .unwrap()
+        .expect("Compare and swap failed");
+    assert_eq!(collection.get::<Value>("item").unwrap(), message(4));
+
+    // `None` expects a missing item and deletes one.
+    collection
+        .compare_and_swap("item", Some(&message(4)), None)
+        .unwrap()
+        .expect("Compare and swap failed");
+    assert!(matches!(
+        collection.get::<Value>("item"),
+        Err(DbError::NotFound)
+    ));
+    collection
+        .compare_and_swap("item", None, Some(&message(5)))
+        .unwrap()
+        .expect("Compare and swap failed");
+    let mismatch = collection
+        .compare_and_swap("item", None, Some(&message(6)))
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(mismatch.current, Some(message(5).to_string()));
+
+    // Validation applies to the new message.
+    assert!(collection
+        .compare_and_swap_json(
+            "item",
+            Some(message(5).to_string()),
+            Some(r#"{"body": {}}"#.to_string())
+        )
+        .is_err());
+
+    // Concurrent increments through compare and swap lose no update.
+    collection.upsert("counter", &message(0)).unwrap();
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for _ in 0..25 {
+                    loop {
+                        let current: Value = collection.get("counter").unwrap();
+                        let n = current["body"]["n"].as_i64().unwrap();
+                        let result = collection
+                            .compare_and_swap("counter", Some(&current), Some(&message(n + 1)))
+                            .unwrap();
+                        if result.is_ok() {
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(collection.get::<Value>("counter").unwrap(), message(100));
+
+    // Subcollections work with bodies and keep to their own items.
+    let subcollection = collection.subcollection(&json!({"a": 2})).unwrap();
+    assert!(subcollection.upsert("sub", &json!({"n": 1})).unwrap());
+    assert!(!subcollection.upsert("sub", &json!({"n": 2})).unwrap());
+    assert!(!subcollection
+        .insert_if_absent("sub", &json!({"n": 3}))
+        .unwrap());
+    assert_eq!(subcollection.get::<Value>("sub").unwrap(), json!({"n": 2}));
+
+    let mismatch = subcollection
+        .compare_and_swap("sub", Some(&json!({"n": 1})), Some(&json!({"n": 3})))
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(mismatch.current, Some(json!({"n": 2}).to_string()));
+    subcollection
+        .compare_and_swap("sub", Some(&json!({"n": 2})), Some(&json!({"n": 3})))
+        .unwrap()
+        .expect("Compare and swap failed");
+    assert_eq!(subcollection.get::<Value>("sub").unwrap(), json!({"n": 3}));
+
+    assert!(matches!(
+        subcollection.upsert("other", &json!({"n": 1})),
+        Err(DbError::AlreadyExists(_))
+    ));
+    assert!(!subcollection
+        .insert_if_absent("other", &json!({"n": 1}))
+        .unwrap());
+    assert_eq!(collection.get::<Value>("other").unwrap(), message(3));
+}
+
+#[test]
+fn test_item_versions() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+    let collection = db
+        .create_collection("versioned")
+        .expect("Failed to create collection");
+
+    let message = |n: i64| json!({"body": {"n": n}, "dependencies": {"a": 1}});
+
+    let id = collection.insert(&message(1)).unwrap();
+    let (value, version) = collection.get_with_version::<Value>(&id).unwrap();
+    assert_eq!(value, message(1));
+    assert_eq!(version, 1);
+
+    // Every kind of update bumps the version, unchanged writes do not.
+    collection.update(&id, &message(2)).unwrap();
+    collection.update(&id, &message(2)).unwrap();
+    collection.upsert(&id, &message(3)).unwrap();
+    assert_eq!(collection.get_with_version::<Value>(&id).unwrap().1, 3);
+
+    assert_eq!(
+        collection.update_if_version(&id, &message(4), 3).unwrap(),
+        4
+    );
+    assert_eq!(
+        collection.update_if_version(&id, &message(4), 4).unwrap(),
+        4
+    );
+
+    // A writer holding an old version loses.
+    assert!(matches!(
+        collection.update_if_version(&id, &message(5), 3),
+        Err(DbError::VersionMismatch(_))
+    ));
+    assert!(matches!(
+        collection.delete_if_version(&id, 3),
+        Err(DbError::VersionMismatch(_))
+    ));
+    assert_eq!(collection.get::<Value>(&id).unwrap(), message(4));
+    assert!(matches!(
+        collection.update_if_version("missing", &message(5), 1),
+        Err(DbError::NotFound)
+    ));
+
+    // Versions survive a change of codec.
+    let collection = db.get_collection("versioned").unwrap();
+    collection.reencode(StorageCodec::Cbor).unwrap();
+    assert_eq!(collection.get_with_version::<Value>(&id).unwrap().1, 4);
+
+    collection.delete_if_version(&id, 4).unwrap();
+    assert!(matches!(
+        collection.get_with_version::<Value>(&id),
+        Err(DbError::NotFound)
+    ));
+
+    // An item inserted again under the ID carries on after the delete, so a
+    // version read before the delete never matches it.
+    collection.insert_with_id(&id, &message(6)).unwrap();
+    assert_eq!(collection.get_with_version::<Value>(&id).unwrap().1, 6);
+    assert!(matches!(
+        collection.update_if_version(&id, &message(7), 1),
+        Err(DbError::VersionMismatch(_))
+    ));
+    collection.delete(&id).unwrap();
+    collection.insert_with_id(&id, &message(8)).unwrap();
+    assert_eq!(collection.get_with_version::<Value>(&id).unwrap().1, 8);
+
+    // New IDs carry on after the highest version deleted from the
+    // collection too.
+    let subcollection = collection.subcollection(&json!({"a": 2})).unwrap();
+    let id = subcollection.insert(&json!({"n": 1})).unwrap();
+    assert_eq!(
+        subcollection.get_with_version::<Value>(&id).unwrap(),
+        (json!({"n": 1}), 8)
+    );
+    assert_eq!(
+        subcollection
+            .update_if_version(&id, &json!({"n": 2}), 8)
+            .unwrap(),
+        9
+    );
+    assert!(matches!(
+        subcollection.update_if_version(&id, &json!({"n": 3}), 8),
+        Err(DbError::VersionMismatch(_))
+    ));
+    assert!(matches!(
+        collection
+            .subcollection(&json!({"a": 3}))
+            .unwrap()
+            .get_with_version::<Value>(&id),
+        Err(DbError::NotFound)
+    ));
+    subcollection.delete_if_version(&id, 9).unwrap();
+    assert!(subcollection.get_keys().unwrap().is_empty());
+
+    // Deletes leave no record per ID behind.
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    {
+        let db = Database::new(Some(db_path)).expect("Failed to open database");
+        let collection = db
+            .create_collection("versioned")
+            .expect("Failed to create collection");
+        for n in 0..10 {
+            let id = collection.insert(&message(n)).unwrap();
+            assert_eq!(
+                collection.get_with_version::<Value>(&id).unwrap().1,
+                1 + 2 * n as u64
+            );
+            collection.delete(&id).unwrap();
+        }
+    }
+
+    let sled_db = open_raw(db_path);
+    let tree = sled_db.open_tree("versioned").unwrap();
+    assert_eq!(tree.scan_prefix(b"r/").count(), 0);
+    assert_eq!(tree.scan_prefix(b"i/").count(), 0);
+}
+
+#[test]
+fn test_watch_changes() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+    let message = |n: i64, a: i64| json!({"body": {"n": n}, "dependencies": {"a": a}});
+    let timeout = std::time::Duration::from_secs(5);
+
+    // The change feed is off unless asked for.
+    let plain = db
+        .create_collection("plain")
+        .expect("Failed to create collection");
+    assert_eq!(plain.get_changes_retained(), 0);
+    plain.insert(&message(1, 1)).unwrap();
+    assert_eq!(plain.last_change_seq().unwrap(), 0);
+    assert!(matches!(plain.watch::<Value>(), Err(DbError::NotFound)));
+
+    let collection = db
+        .create_collection_with_options("watched", CollectionOptions::new().changes_retained(100))
+        .expect("Failed to create collection");
+
+    let first = collection.insert(&message(1, 1)).unwrap();
+    assert_eq!(collection.last_change_seq().unwrap(), 1);
+
+    let mut watcher = collection.watch::<Value>().unwrap();
+    let subcollection = collection.subcollection(&json!({"a": 1})).unwrap();
+    let mut sub_watcher = subcollection.watch::<Value>().unwrap();
+
+    collection.update(&first, &message(2, 1)).unwrap();
+    let second = collection.insert(&message(3, 2)).unwrap();
+    // Moving an item out of the subcollection.
+    collection.update(&first, &message(2, 2)).unwrap();
+    db.transaction(|tx| tx.collection("watched")?.delete(&second))
+        .unwrap();
+
+    assert_eq!(
+        watcher.next_timeout(timeout).unwrap(),
+        Some(ChangeEvent::Update {
+            seq: 2,
+            id: first.clone(),
+            old: message(1, 1),
+            new: message(2, 1)
+        })
+    );
+    assert_eq!(
+        watcher.next_timeout(timeout).unwrap(),
+        Some(ChangeEvent::Insert {
+            seq: 3,
+            id: second.clone(),
+            new: message(3, 2)
+        })
+    );
+    let event = watcher.next().unwrap().unwrap();
+    assert!(matches!(event, ChangeEvent::Update { seq: 4, .. }));
+    assert_eq!(
+        watcher.next().unwrap().unwrap(),
+        ChangeEvent::Delete {
+            seq: 5,
+            id: second.clone(),
+            old: message(3, 2)
+        }
+    );
+    assert_eq!(
+        watcher
+            .next_timeout(std::time::Duration::from_millis(50))
+            .unwrap(),
+        None
+    );
+    assert_eq!(watcher.next_seq(), 6);
+
+    // A subcollection sees bodies, and items leaving it as deletes.
+    assert_eq!(
+        sub_watcher.next_timeout(timeout).unwrap(),
+        Some(ChangeEvent::Update {
+            seq: 2,
+            id: first.clone(),
+            old: json!({"n": 1}),
+            new: json!({"n": 2})
+        })
+    );
+    assert_eq!(
+        sub_watcher.next_timeout(timeout).unwrap(),
+        Some(ChangeEvent::Delete {
+            seq: 4,
+            id: first.clone(),
+            old: json!({"n": 2})
+        })
+    );
+
+    // Resuming replays the retained changes, then follows new ones.
+    let mut resumed = collection.watch_from::<Value>(3).unwrap();
+    let seqs: Vec<u64> = (0..3)
+        .map(|_| resumed.next_timeout(timeout).unwrap().unwrap().seq())
+        .collect();
+    assert_eq!(seqs, vec![3, 4, 5]);
+    collection.delete(&first).unwrap();
+    let event = resumed.next_timeout(timeout).unwrap().unwrap();
+    assert_eq!((event.seq(), event.id()), (6, first.as_str()));
+
+    // Typed events.
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Body {
+        n: i64,
+    }
+    let mut typed = subcollection.watch_from::<Body>(1).unwrap();
+    assert_eq!(
+        typed.next_timeout(timeout).unwrap(),
+        Some(ChangeEvent::Insert {
+            seq: 1,
+            id: first.clone(),
+            new: Body { n: 1 }
+        })
+    );
+
+    // Only the last changes are retained.
+    let limited = db
+        .create_collection_with_options("limited", CollectionOptions::new().changes_retained(2))
+        .unwrap();
+    for n in 0..5 {
+        limited.insert(&message(n, 1)).unwrap();
+    }
+    assert!(matches!(
+        limited.watch_from::<Value>(3),
+        Err(DbError::NotFound)
+    ));
+    let mut watcher = limited.watch_from::<Value>(4).unwrap();
+    assert_eq!(watcher.next_timeout(timeout).unwrap().unwrap().seq(), 4);
+    assert_eq!(watcher.next_timeout(timeout).unwrap().unwrap().seq(), 5);
+
+    // Concurrent writers are seen in order, without gaps.
+    let mut watcher = limited.watch::<Value>().unwrap();
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for n in 0..10 {
+                    limited.insert(&message(n, 1)).unwrap();
+                }
+            });
+        }
+    });
+    let seqs: Vec<u64> = (0..40)
+        .map(|_| watcher.next_timeout(timeout).unwrap().unwrap().seq())
+        .collect();
+    assert_eq!(seqs, (6..46).collect::<Vec<u64>>());
+}
+
+#[test]
+fn test_item_history() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+
+    let message = |n: i64| json!({"body": {"n": n}, "dependencies": {"a": 1}});
+
+    // Without history only the current version is readable.
+    let plain = db.create_collection("plain").unwrap();
+    let id = plain.insert(&message(1)).unwrap();
+    plain.update(&id, &message(2)).unwrap();
+    assert!(plain.history::<Value>(&id).unwrap().is_empty());
+    assert!(matches!(
+        plain.get_at::<Value>(&id, At::Version(1)),
+        Err(DbError::NotFound)
+    ));
+
+    let collection = db
+        .create_collection_with_options(
+            "audited",
+            CollectionOptions::new().history(HistoryRetention::All),
+        )
+        .unwrap();
+    assert_eq!(collection.get_history_retention(), HistoryRetention::All);
+
+    let id = collection.insert(&message(1)).unwrap();
+    collection.update(&id, &message(2)).unwrap();
+    collection.update(&id, &message(2)).unwrap();
+    let before_third = std::time::SystemTime::now();
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    collection.update(&id, &message(3)).unwrap();
+
+    let history = collection.history::<Value>(&id).unwrap();
+    let versions: Vec<u64> = history.iter().map(|entry| entry.version).collect();
+    assert_eq!(versions, vec![1, 2, 3]);
+    assert_eq!(history[0].message, Some(message(1)));
+    assert!(history[1].timestamp <= history[2].timestamp);
+
+    assert_eq!(
+        collection.get_at::<Value>(&id, At::Version(2)).unwrap(),
+        message(2)
+    );
+    assert_eq!(
+        collection
+            .get_at::<Value>(&id, At::from(before_third))
+            .unwrap(),
+        message(2)
+    );
+    assert!(matches!(
+        collection.get_at::<Value>(&id, At::Time(0)),
+        Err(DbError::NotFound)
+    ));
+
+    // Reverting writes the old message as a new version.
+    assert_eq!(collection.revert(&id, 1).unwrap(), 4);
+    assert_eq!(
+        collection.get_with_version::<Value>(&id).unwrap(),
+        (message(1), 4)
+    );
+    assert!(matches!(collection.revert(&id, 9), Err(DbError::NotFound)));
+
+    // Deleted items keep their history and can be brought back, their
+    // versions going on from there.
+    collection.delete(&id).unwrap();
+    let history = collection.history::<Value>(&id).unwrap();
+    assert_eq!(history.last().unwrap().version, 5);
+    assert_eq!(history.last().unwrap().message, None);
+    assert!(matches!(
+        collection.get_at::<Value>(&id, At::Version(5)),
+        Err(DbError::NotFound)
+    ));
+
+    assert_eq!(collection.revert(&id, 3).unwrap(), 6);
+    assert_eq!(collection.get::<Value>(&id).unwrap(), message(3));
+    collection.delete(&id).unwrap();
+    collection.insert_with_id(&id, &message(7)).unwrap();
+    assert_eq!(collection.get_with_version::<Value>(&id).unwrap().1, 8);
+
+    // Subcollections see bodies, and only the versions they contained.
+    collection
+        .update(&id, &json!({"body": {"n": 9}, "dependencies": {"a": 2}}))
+        .unwrap();
+    let subcollection = collection.subcollection(&json!({"a": 1})).unwrap();
+    let history = subcollection.history::<Value>(&id).unwrap();
+    assert_eq!(history[0].message, Some(json!({"n": 1})));
+    assert_eq!(history.last().unwrap().message, None);
+    assert_eq!(
+        subcollection.get_at::<Value>(&id, At::Version(8)).unwrap(),
+        json!({"n": 7})
+    );
+    assert!(matches!(
+        subcollection.revert(&id, 8),
+        Err(DbError::AlreadyExists(_))
+    ));
+    let other = collection.subcollection(&json!({"a": 2})).unwrap();
+    assert!(matches!(other.revert(&id, 8), Err(DbError::NotFound)));
+
+    drop(collection);
+    let collection = db.get_collection("audited").unwrap();
+    assert_eq!(collection.history::<Value>(&id).unwrap().len(), 9);
+
+    // Retention bounds the versions kept per item.
+    let bounded = db
+        .create_collection_with_options(
+            "bounded",
+            CollectionOptions::new().history(HistoryRetention::Versions(2)),
+        )
+        .unwrap();
+    let bounded_id = bounded.insert(&message(1)).unwrap();
+    for n in 2..=5 {
+        bounded.update(&bounded_id, &message(n)).unwrap();
+    }
+    let versions: Vec<u64> = bounded
+        .history::<Value>(&bounded_id)
+        .unwrap()
+        .iter()
+        .map(|entry| entry.version)
+        .collect();
+    assert_eq!(versions, vec![4, 5]);
+    assert!(matches!(
+        bounded.revert(&bounded_id, 1),
+        Err(DbError::NotFound)
+    ));
+
+    let expiring = db
+        .create_collection_with_options(
+            "expiring",
+            CollectionOptions::new().history(HistoryRetention::Seconds(0)),
+        )
+        .unwrap();
+    let id = expiring.insert(&message(1)).unwrap();
+    expiring.update(&id, &message(2)).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    let history = expiring.history::<Value>(&id).unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].message, Some(message(2)));
+
+    // Each version has its own key, and writes remove the versions they
+    // leave out of the retention.
+    expiring.update(&id, &message(3)).unwrap();
+    drop((bounded, expiring, collection, plain));
+    drop(db);
+
+    let raw = open_raw(db_path);
+    for (name, id, versions) in [
+        ("bounded", &bounded_id, vec![4, 5]),
+        ("expiring", &id, vec![2, 3]),
+    ] {
+        let prefix = format!("h/{}/", id);
+        let stored: Vec<u64> = raw
+            .open_tree(name)
+            .unwrap()
+            .scan_prefix(&prefix)
+            .map(|entry| {
+                let (key, _) = entry.unwrap();
+                std::str::from_utf8(&key[prefix.len()..])
+                    .unwrap()
+                    .parse()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(stored, versions, "versions stored in {}", name);
+    }
+}
+
+#[test]
+fn test_soft_delete() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let db = Database::new(Some(db_path)).expect("Failed to open database");
+
+    let collection = db
+        .create_collection_with_options("notes", CollectionOptions::new().soft_delete(true))
+        .unwrap();
+    assert!(collection.has_soft_delete());
+
+    let message = |n: i64, a: i64| json!({"body": {"n": n}, "dependencies": {"a": a}});
+
+    let first = collection.insert(&message(1, 1)).unwrap();
+    let second = collection.insert(&message(2, 2)).unwrap();
+    let subcollection = collection.subcollection(&json!({"a": 1})).unwrap();
+
+    collection.delete(&first).unwrap();
+    collection.delete(&second).unwrap();
+
+    // Deleted items are gone for every read.
+    assert!(matches!(
+        collection.get::<Value>(&first),
+        Err(DbError::NotFound)
+    ));
+    assert_eq!(collection.iter::<Value>().count(), 0);
+    assert!(subcollection.get_keys().unwrap().is_empty());
+    assert_eq!(collection.gc_dependencies().unwrap(), 0);
+
+    let deleted: Vec<DeletedItem> = collection.list_deleted().unwrap();
+    assert_eq!(deleted.len(), 2);
+    let first_deleted = deleted.iter().find(|item| item.id == first).unwrap();
+    assert_eq!(first_deleted.message, message(1, 1));
+    assert!(first_deleted.deleted_at > 0);
+
+    let deleted: Vec<DeletedItem> = subcollection.list_deleted().unwrap();
+    assert_eq!(deleted.len(), 1);
+    assert_eq!(deleted[0].message, json!({"n": 1}));
+
+    // Restoring puts the item back into its subcollection.
+    assert!(matches!(
+        subcollection.restore(&second),
+        Err(DbError::NotFound)
+    ));
+    subcollection.restore(&first).unwrap();
+    assert_eq!(subcollection.get::<Value>(&first).unwrap(), json!({"n": 1}));
+    assert_eq!(subcollection.get_keys().unwrap(), vec![first.clone()]);
+    assert!(matches!(collection.restore(&first), Err(DbError::NotFound)));
+
+    // An ID taken since cannot be restored.
+    collection.insert_with_id(&second, &message(3, 2)).unwrap();
+    assert!(matches!(
+        collection.restore(&second),
+        Err(DbError::AlreadyExists(_))
+    ));
+
+    assert_eq!(
+        collection
+            .purge(std::time::Duration::from_secs(3600))
+            .unwrap(),
+        0
+    );
+    assert_eq!(collection.purge(std::time::Duration::ZERO).unwrap(), 1);
+    assert!(collection.list_deleted::<Value>().unwrap().is_empty());
+
+    // Without soft delete, deleted items are gone for good.
+    let plain = db.create_collection("plain").unwrap();
+    let id = plain.insert(&message(1, 1)).unwrap();
+    plain.delete(&id).unwrap();
+    assert!(plain.list_deleted::<Value>().unwrap().is_empty());
+    assert!(matches!(plain.restore(&id), Err(DbError::NotFound)));
+}
+
+#[test]
+fn test_item_ttl() {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().to_str().unwrap();
+    let message = |n: i64, a: i64| json!({"body": {"n": n}, "dependencies": {"a": a}});
+
+    {
+        let db = Database::new(Some(db_path)).expect("Failed to open database");
+        let collection = db.create_collection("sessions").unwrap();
+        assert_eq!(collection.get_default_ttl(), None);
+
+        let short = collection
+            .insert_with_ttl(&message(1, 1), Duration::from_millis(300))
+            .unwrap();
+        let long = collection
+            .insert_with_ttl(&message(2, 1), Duration::from_secs(3600))
+            .unwrap();
+        let subcollection = collection.subcollection(&json!({"a": 2})).unwrap();
+        let body_only = subcollection
+            .insert_with_ttl(&json!({"n": 3}), Duration::from_millis(300))
+            .unwrap();
+
+        assert_eq!(collection.get::<Value>(&short).unwrap(), message(1, 1));
+        assert_eq!(subcollection.get_keys().unwrap(), vec![body_only.clone()]);
+
+        sleep(Duration::from_millis(400));
+
+        // Expired items are gone for every read before they are swept.
+        assert!(matches!(
+            collection.get::<Value>(&short),
+            Err(DbError::NotFound)
+        ));
+        assert!(matches!(
+            collection.update(&short, &message(4, 1)),
+            Err(DbError::NotFound)
+        ));
+        assert!(matches!(
+            subcollection.get::<Value>(&body_only),
+            Err(DbError::NotFound)
+        ));
+        let keys: Vec<String> = collection.keys().collect::<Result<_, _>>().unwrap();
+        assert_eq!(keys, vec![long.clone()]);
+        assert_eq!(collection.iter::<Value>().count(), 1);
+        assert!(subcollection.get_keys().unwrap().is_empty());
+
+        // The ID of an expired item is free again.
+        collection.insert_with_id(&short, &message(5, 1)).unwrap();
+        assert_eq!(collection.get::<Value>(&short).unwrap(), message(5, 1));
+
+        assert_eq!(db.sweep_expired().unwrap(), 1);
+        assert_eq!(collection.sweep_expired().unwrap(), 0);
+        assert_eq!(collection.keys().count(), 2);
+    }
+
+    // Sweeping released the dependencies only the expired item used.
+    assert_eq!(count_dependency_records(db_path, "sessions"), 1);
+
+    // Items of a collection with a default TTL expire without asking, and
+    // the background sweeper removes them.
+    {
+        let db = DatabaseConfig::new(db_path)
+            .sweep_interval(Duration::from_millis(50))
+            .open()
+            .unwrap();
+        let collection = db
+            .create_collection_with_options(
+                "tokens",
+                CollectionOptions::new().default_ttl(Duration::from_millis(300)),
+            )
+            .unwrap();
+        assert_eq!(
+            collection.get_default_ttl(),
+            Some(Duration::from_millis(300))
+        );
+
+        let id = collection.insert(&message(1, 1)).unwrap();
+        collection.update(&id, &message(2, 1)).unwrap();
+        assert_eq!(collection.get::<Value>(&id).unwrap(), message(2, 1));
+
+        sleep(Duration::from_millis(800));
+        assert_eq!(collection.keys().count(), 0);
+        assert_eq!(collection.sweep_expired().unwrap(), 0);
+    }
+
+    assert_eq!(count_dependency_records(db_path, "tokens"), 0);
+
+    // The default TTL is kept across reopening.
+    {
+        let db = Database::new(Some(db_path)).unwrap();
+        let collection = db.get_collection("tokens").unwrap();
+        assert_eq!(
+            collection.get_default_ttl(),
+            Some(Duration::from_millis(300))
+        );
+    }
+
+    // Sweeping only opens collections with expired items.
+    {
+        let raw = open_raw(db_path);
+        raw.open_tree("broken")
+            .unwrap()
+            .insert("*metadata*", "not metadata")
+            .unwrap();
+        raw.flush().unwrap();
+    }
+    let db = Database::new(Some(db_path)).unwrap();
+    assert_eq!(db.sweep_expired().unwrap(), 0);
+}
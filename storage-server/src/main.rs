@@ -1,11 +1,19 @@
+use actix_web::http::header::{self, EntityTag, Header, IfMatch};
 use actix_web::http::StatusCode;
-use actix_web::{middleware, web, App, HttpResponse, HttpServer, Responder, ResponseError};
-use clap::{Arg, Command};
-use dbuf_storage::{Database, DbError};
+use actix_web::{
+    middleware, web, App, HttpRequest, HttpResponse, HttpServer, Responder, ResponseError,
+};
+use clap::{Arg, ArgAction, Command};
+use dbuf_storage::{
+    At, BatchMode, ChangeEvent, CollectionOptions, CompareAndSwapError, Database, DatabaseConfig,
+    DbError, DeletedItem, Durability, Filter, HistoryEntry, HistoryRetention, IdStrategy,
+    IndexOptions, Query, StorageCodec, Watcher, DEFAULT_CHANGES_RETAINED,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::fmt;
 use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Serialize, Deserialize)]
 struct ApiResponse<T> {
@@ -21,9 +29,89 @@ struct InsertResponse {
     id: String,
 }
 
+#[derive(Serialize, Deserialize)]
+struct UpsertResponse {
+    id: String,
+    created: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct InsertIfAbsentResponse {
+    id: String,
+    inserted: bool,
+}
+
+// Messages for collections, bodies for subcollections; `null` stands for a
+// missing item.
+#[derive(Serialize, Deserialize)]
+struct CompareAndSwapRequest {
+    #[serde(default)]
+    expected: Option<Value>,
+    #[serde(default)]
+    new: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompareAndSwapConflict {
+    current: Option<Value>,
+}
+
+// Exactly one of the version or the time, in milliseconds since the Unix
+// epoch, of the item to read.
+#[derive(Serialize, Deserialize)]
+struct AtQuery {
+    version: Option<u64>,
+    time: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RevertRequest {
+    version: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RevertResponse {
+    id: String,
+    version: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PurgeQuery {
+    // Age in seconds of the deleted items to remove, all of them by default.
+    #[serde(default)]
+    older_than: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TtlQuery {
+    // Seconds after which the inserted item expires, overriding the default
+    // TTL of the collection.
+    ttl: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PurgeResponse {
+    purged: usize,
+}
+
 #[derive(Serialize, Deserialize)]
 struct CollectionRequest {
     name: String,
+    #[serde(default)]
+    codec: StorageCodec,
+    #[serde(default)]
+    constraints: Vec<String>,
+    #[serde(default)]
+    id_strategy: IdStrategy,
+    #[serde(default = "default_changes_retained")]
+    changes_retained: usize,
+    #[serde(default)]
+    history: HistoryRetention,
+    #[serde(default)]
+    soft_delete: bool,
+    // Seconds after which items expire unless inserted with a TTL of their
+    // own.
+    default_ttl: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -31,6 +119,43 @@ struct CollectionWithSchemaRequest {
     name: String,
     body_schema: String,
     dependencies_schema: String,
+    #[serde(default)]
+    codec: StorageCodec,
+    #[serde(default)]
+    constraints: Vec<String>,
+    #[serde(default)]
+    id_strategy: IdStrategy,
+    #[serde(default = "default_changes_retained")]
+    changes_retained: usize,
+    #[serde(default)]
+    history: HistoryRetention,
+    #[serde(default)]
+    soft_delete: bool,
+    // Seconds after which items expire unless inserted with a TTL of their
+    // own.
+    default_ttl: Option<u64>,
+}
+
+fn default_changes_retained() -> usize {
+    DEFAULT_CHANGES_RETAINED
+}
+
+#[derive(Serialize, Deserialize)]
+struct CodecRequest {
+    codec: StorageCodec,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ConstraintRequest {
+    constraint: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexRequest {
+    name: String,
+    path: String,
+    #[serde(default)]
+    unique: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -39,6 +164,24 @@ struct SubcollectionRequest {
     dependencies: Value,
 }
 
+// Query string form of `SubcollectionRequest`, carrying the dependencies as
+// JSON text.
+#[derive(Serialize, Deserialize)]
+struct SubcollectionQuery {
+    collection: String,
+    dependencies: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WatchQuery {
+    // Sequence number of the first change to send.
+    since: Option<u64>,
+}
+
+// How often an idle change stream sends a comment, so that proxies keep the
+// connection open and a closed one is noticed.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
 struct AppState {
     db: Arc<Database>,
 }
@@ -77,21 +220,173 @@ impl ResponseError for AppError {
         match self.0 {
             DbError::NotFound => StatusCode::NOT_FOUND,
             DbError::AlreadyExists(_) => StatusCode::CONFLICT,
+            DbError::VersionMismatch(_) => StatusCode::PRECONDITION_FAILED,
+            DbError::Conflict(_) => StatusCode::CONFLICT,
+            DbError::ReadOnly => StatusCode::FORBIDDEN,
             DbError::DeserializationError(_)
             | DbError::SerializationError(_)
             | DbError::SchemaValidationError(_)
             | DbError::SchemaError(_)
-            | DbError::SchemaCompilationError(_) => StatusCode::BAD_REQUEST,
+            | DbError::SchemaCompilationError(_)
+            | DbError::ValidationError(_) => StatusCode::BAD_REQUEST,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
 
+fn etag(version: u64) -> header::ETag {
+    header::ETag(EntityTag::new_strong(version.to_string()))
+}
+
+// The item version required by an `If-Match` header, `None` if any version
+// will do. Tags that are not versions of this server never match.
+fn if_match(req: &HttpRequest) -> Result<Option<u64>, DbError> {
+    if !req.headers().contains_key(header::IF_MATCH) {
+        return Ok(None);
+    }
+
+    let tags = match IfMatch::parse(req) {
+        Ok(IfMatch::Any) => return Ok(None),
+        Ok(IfMatch::Items(tags)) => tags,
+        Err(e) => return Err(DbError::DeserializationError(e.to_string())),
+    };
+
+    tags.iter()
+        .filter(|tag| !tag.weak)
+        .find_map(|tag| tag.tag().parse().ok())
+        .map(Some)
+        .ok_or_else(|| DbError::VersionMismatch("If-Match names no item version".to_string()))
+}
+
+// A message or body as returned by item gets, tagged with its version.
+fn item_response(json: &str, version: u64) -> Result<HttpResponse, DbError> {
+    let json_value: Value =
+        serde_json::from_str(json).map_err(|e| DbError::DeserializationError(e.to_string()))?;
+
+    let response = ApiResponse {
+        success: true,
+        data: Some(json_value),
+        error: None,
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header(etag(version))
+        .json(response))
+}
+
+fn to_json_string(value: &Option<Value>) -> Result<Option<String>, DbError> {
+    value
+        .as_ref()
+        .map(|value| {
+            serde_json::to_string(value).map_err(|e| DbError::SerializationError(e.to_string()))
+        })
+        .transpose()
+}
+
+// A failed compare and swap is a conflict reporting the current item.
+fn compare_and_swap_response(
+    result: Result<(), CompareAndSwapError>,
+) -> Result<HttpResponse, AppError> {
+    let e = match result {
+        Ok(()) => {
+            return Ok(HttpResponse::Ok().json(ApiResponse::<()> {
+                success: true,
+                data: None,
+                error: None,
+            }))
+        }
+        Err(e) => e,
+    };
+
+    let current = e
+        .current
+        .as_deref()
+        .map(serde_json::from_str)
+        .transpose()
+        .map_err(|e| DbError::DeserializationError(e.to_string()))?;
+
+    Ok(HttpResponse::Conflict().json(ApiResponse {
+        success: false,
+        data: Some(CompareAndSwapConflict { current }),
+        error: Some(e.to_string()),
+    }))
+}
+
+fn at_query(query: &AtQuery) -> Result<At, DbError> {
+    match (query.version, query.time) {
+        (Some(version), None) => Ok(At::Version(version)),
+        (None, Some(time)) => Ok(At::Time(time)),
+        _ => Err(DbError::DeserializationError(
+            "Expected either a version or a time".to_string(),
+        )),
+    }
+}
+
+fn history_response(history: Vec<HistoryEntry>) -> HttpResponse {
+    HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        data: Some(history),
+        error: None,
+    })
+}
+
+fn at_response(json: &str) -> Result<HttpResponse, DbError> {
+    let json_value: Value =
+        serde_json::from_str(json).map_err(|e| DbError::DeserializationError(e.to_string()))?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        data: Some(json_value),
+        error: None,
+    }))
+}
+
+fn revert_response(id: String, version: u64) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(etag(version))
+        .json(ApiResponse {
+            success: true,
+            data: Some(RevertResponse { id, version }),
+            error: None,
+        })
+}
+
+fn deleted_response(items: Vec<DeletedItem>) -> HttpResponse {
+    HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        data: Some(items),
+        error: None,
+    })
+}
+
+fn json_payload(payload: &[u8]) -> Result<String, DbError> {
+    let value: Value = serde_json::from_slice(payload)
+        .map_err(|e| DbError::DeserializationError(format!("JSON parsing error: {}", e)))?;
+
+    serde_json::to_string(&value).map_err(|e| DbError::SerializationError(e.to_string()))
+}
+
 async fn create_collection(
     app_state: web::Data<AppState>,
     req: web::Json<CollectionRequest>,
 ) -> Result<impl Responder, AppError> {
-    app_state.db.create_collection(&req.name)?;
+    let options = req.constraints.iter().fold(
+        CollectionOptions::new()
+            .codec(req.codec)
+            .id_strategy(req.id_strategy)
+            .changes_retained(req.changes_retained)
+            .history(req.history)
+            .soft_delete(req.soft_delete),
+        |options, constraint| options.constraint(constraint),
+    );
+    let options = match req.default_ttl {
+        Some(secs) => options.default_ttl(Duration::from_secs(secs)),
+        None => options,
+    };
+
+    app_state
+        .db
+        .create_collection_with_options(&req.name, options)?;
 
     let response = ApiResponse {
         success: true,
@@ -106,11 +401,24 @@ async fn create_collection_with_schema(
     app_state: web::Data<AppState>,
     req: web::Json<CollectionWithSchemaRequest>,
 ) -> Result<impl Responder, AppError> {
-    app_state.db.create_collection_with_schema_json(
-        &req.name,
-        &req.body_schema,
-        &req.dependencies_schema,
-    )?;
+    let options = req.constraints.iter().fold(
+        CollectionOptions::new()
+            .schema_json(&req.body_schema, &req.dependencies_schema)
+            .codec(req.codec)
+            .id_strategy(req.id_strategy)
+            .changes_retained(req.changes_retained)
+            .history(req.history)
+            .soft_delete(req.soft_delete),
+        |options, constraint| options.constraint(constraint),
+    );
+    let options = match req.default_ttl {
+        Some(secs) => options.default_ttl(Duration::from_secs(secs)),
+        None => options,
+    };
+
+    app_state
+        .db
+        .create_collection_with_options(&req.name, options)?;
 
     let response = ApiResponse {
         success: true,
@@ -161,6 +469,14 @@ async fn get_collection_info(
         name: String,
         has_schema: bool,
         created_at: u64,
+        codec: StorageCodec,
+        id_strategy: IdStrategy,
+        changes_retained: usize,
+        history: HistoryRetention,
+        soft_delete: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        default_ttl: Option<u64>,
+        constraints: Vec<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         body_schema: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
@@ -171,6 +487,17 @@ async fn get_collection_info(
         name: collection_name,
         has_schema: collection.has_schema(),
         created_at: collection.get_created_at(),
+        codec: collection.get_codec()?,
+        id_strategy: collection.get_id_strategy(),
+        changes_retained: collection.get_changes_retained(),
+        history: collection.get_history_retention(),
+        soft_delete: collection.has_soft_delete(),
+        default_ttl: collection.get_default_ttl().map(|ttl| ttl.as_secs()),
+        constraints: collection
+            .get_constraints()?
+            .iter()
+            .map(|constraint| constraint.to_string())
+            .collect(),
         body_schema: collection.get_body_schema_json().map(|s| s.to_string()),
         dependencies_schema: collection
             .get_dependencies_schema_json()
@@ -186,6 +513,74 @@ async fn get_collection_info(
     Ok(web::Json(response))
 }
 
+async fn reencode_collection(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    req: web::Json<CodecRequest>,
+) -> Result<impl Responder, AppError> {
+    let collection_name = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let reencoded = collection.reencode(req.codec)?;
+
+    #[derive(Serialize)]
+    struct ReencodeResponse {
+        codec: StorageCodec,
+        reencoded: usize,
+    }
+
+    let response = ApiResponse {
+        success: true,
+        data: Some(ReencodeResponse {
+            codec: req.codec,
+            reencoded,
+        }),
+        error: None,
+    };
+
+    Ok(web::Json(response))
+}
+
+async fn add_constraint(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    req: web::Json<ConstraintRequest>,
+) -> Result<impl Responder, AppError> {
+    let collection_name = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    collection.add_constraint(&req.constraint)?;
+
+    let response = ApiResponse {
+        success: true,
+        data: Some(req.into_inner()),
+        error: None,
+    };
+
+    Ok(web::Json(response))
+}
+
+async fn remove_constraint(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    req: web::Json<ConstraintRequest>,
+) -> Result<impl Responder, AppError> {
+    let collection_name = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    if !collection.remove_constraint(&req.constraint)? {
+        return Err(AppError(DbError::NotFound));
+    }
+
+    let response = ApiResponse {
+        success: true,
+        data: Some(req.into_inner()),
+        error: None,
+    };
+
+    Ok(web::Json(response))
+}
+
 async fn drop_collection(
     app_state: web::Data<AppState>,
     req: web::Json<CollectionRequest>,
@@ -204,15 +599,35 @@ async fn drop_collection(
 async fn insert_to_collection(
     app_state: web::Data<AppState>,
     path: web::Path<String>,
-    json_data: web::Json<Value>,
+    ttl_query: web::Query<TtlQuery>,
+    payload: web::Bytes,
 ) -> Result<impl Responder, AppError> {
     let collection_name = path.into_inner();
     let collection = app_state.db.get_collection(&collection_name)?;
 
-    let json_string = serde_json::to_string(&json_data.into_inner())
-        .map_err(|e| DbError::SerializationError(e.to_string()))?;
+    let id = match ttl_query.ttl.map(Duration::from_secs) {
+        Some(ttl) => collection.insert_json_with_ttl(json_payload(&payload)?, ttl)?,
+        None => collection.insert_json(json_payload(&payload)?)?,
+    };
+
+    let response = ApiResponse {
+        success: true,
+        data: Some(InsertResponse { id }),
+        error: None,
+    };
+
+    Ok(web::Json(response))
+}
+
+async fn insert_to_collection_with_id(
+    app_state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    payload: web::Bytes,
+) -> Result<impl Responder, AppError> {
+    let (collection_name, id) = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
 
-    let id = collection.insert_json(json_string)?;
+    collection.insert_json_with_id(&id, json_payload(&payload)?)?;
 
     let response = ApiResponse {
         success: true,
@@ -231,16 +646,19 @@ async fn batch_insert_to_collection(
     let collection_name = path.into_inner();
     let collection = app_state.db.get_collection(&collection_name)?;
 
-    let mut ids = Vec::with_capacity(json_data.len());
+    let mut jsons = Vec::with_capacity(json_data.len());
 
     for item in json_data.iter() {
         let json_string =
             serde_json::to_string(item).map_err(|e| DbError::SerializationError(e.to_string()))?;
-
-        let id = collection.insert_json(json_string)?;
-        ids.push(id);
+        jsons.push(json_string);
     }
 
+    let ids = collection
+        .insert_many_json(jsons, BatchMode::Atomic)?
+        .into_iter()
+        .collect::<Result<Vec<String>, DbError>>()?;
+
     #[derive(Serialize)]
     struct BatchInsertResponse {
         count: usize,
@@ -262,21 +680,13 @@ async fn batch_insert_to_collection(
 async fn get_from_collection(
     app_state: web::Data<AppState>,
     path: web::Path<(String, String)>,
-) -> Result<impl Responder, AppError> {
+) -> Result<HttpResponse, AppError> {
     let (collection_name, id) = path.into_inner();
     let collection = app_state.db.get_collection(&collection_name)?;
 
-    let json_string = collection.get_json(&id)?;
-    let json_value: Value = serde_json::from_str(&json_string)
-        .map_err(|e| DbError::DeserializationError(e.to_string()))?;
-
-    let response = ApiResponse {
-        success: true,
-        data: Some(json_value),
-        error: None,
-    };
+    let (json_string, version) = collection.get_json_with_version(&id)?;
 
-    Ok(web::Json(response))
+    Ok(item_response(&json_string, version)?)
 }
 
 async fn batch_get_from_collection(
@@ -324,162 +734,696 @@ async fn batch_get_from_collection(
 async fn update_in_collection(
     app_state: web::Data<AppState>,
     path: web::Path<(String, String)>,
-    json_data: web::Json<Value>,
-) -> Result<impl Responder, AppError> {
+    req: HttpRequest,
+    payload: web::Bytes,
+) -> Result<HttpResponse, AppError> {
     let (collection_name, id) = path.into_inner();
     let collection = app_state.db.get_collection(&collection_name)?;
 
-    let json_string = serde_json::to_string(&json_data.into_inner())
-        .map_err(|e| DbError::SerializationError(e.to_string()))?;
-
-    collection.update_json(&id, json_string)?;
-
     let response = ApiResponse::<()> {
         success: true,
         data: None,
         error: None,
     };
 
-    Ok(web::Json(response))
+    if let Some(version) = if_match(&req)? {
+        let version = collection.update_json_if_version(&id, json_payload(&payload)?, version)?;
+
+        return Ok(HttpResponse::Ok()
+            .insert_header(etag(version))
+            .json(response));
+    }
+
+    collection.update_json(&id, json_payload(&payload)?)?;
+
+    Ok(HttpResponse::Ok().json(response))
 }
 
-async fn delete_from_collection(
+async fn upsert_in_collection(
     app_state: web::Data<AppState>,
     path: web::Path<(String, String)>,
+    payload: web::Bytes,
 ) -> Result<impl Responder, AppError> {
     let (collection_name, id) = path.into_inner();
     let collection = app_state.db.get_collection(&collection_name)?;
 
-    collection.delete_json(&id)?;
+    let created = collection.upsert_json(&id, json_payload(&payload)?)?;
 
-    let response = ApiResponse::<()> {
+    let response = ApiResponse {
         success: true,
-        data: None,
+        data: Some(UpsertResponse { id, created }),
         error: None,
     };
 
     Ok(web::Json(response))
 }
 
-async fn batch_delete_from_collection(
+async fn insert_if_absent_to_collection(
     app_state: web::Data<AppState>,
-    path: web::Path<String>,
-    ids: web::Json<Vec<String>>,
+    path: web::Path<(String, String)>,
+    payload: web::Bytes,
 ) -> Result<impl Responder, AppError> {
-    let collection_name = path.into_inner();
+    let (collection_name, id) = path.into_inner();
     let collection = app_state.db.get_collection(&collection_name)?;
 
-    let mut deleted = Vec::new();
-    let mut errors = Vec::new();
-
-    for id in ids.iter() {
-        match collection.delete_json(id) {
-            Ok(_) => deleted.push(id.clone()),
-            Err(e) => errors.push((id.clone(), e.to_string())),
-        }
-    }
-
-    #[derive(Serialize)]
-    struct BatchDeleteResponse {
-        deleted: Vec<String>,
-        failed: Vec<(String, String)>,
-    }
+    let inserted = collection.insert_if_absent_json(&id, json_payload(&payload)?)?;
 
     let response = ApiResponse {
         success: true,
-        data: Some(BatchDeleteResponse {
-            deleted,
-            failed: errors,
-        }),
+        data: Some(InsertIfAbsentResponse { id, inserted }),
         error: None,
     };
 
     Ok(web::Json(response))
 }
 
-async fn create_subcollection(
+async fn compare_and_swap_in_collection(
     app_state: web::Data<AppState>,
-    req: web::Json<SubcollectionRequest>,
-) -> Result<impl Responder, AppError> {
-    let collection = app_state.db.get_collection(&req.collection)?;
+    path: web::Path<(String, String)>,
+    req: web::Json<CompareAndSwapRequest>,
+) -> Result<HttpResponse, AppError> {
+    let (collection_name, id) = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
 
-    let dependencies_json = serde_json::to_string(&req.dependencies)
-        .map_err(|e| DbError::SerializationError(e.to_string()))?;
+    let result = collection.compare_and_swap_json(
+        &id,
+        to_json_string(&req.expected)?,
+        to_json_string(&req.new)?,
+    )?;
 
-    collection.subcollection_json(dependencies_json)?;
+    compare_and_swap_response(result)
+}
 
-    let response = ApiResponse {
-        success: true,
-        data: Some(req.into_inner()),
-        error: None,
-    };
+async fn get_collection_item_history(
+    app_state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, AppError> {
+    let (collection_name, id) = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
 
-    Ok(web::Json(response))
+    Ok(history_response(collection.history(&id)?))
 }
 
-async fn insert_to_subcollection(
+async fn get_from_collection_at(
     app_state: web::Data<AppState>,
-    path: web::Path<String>,
-    query: web::Query<SubcollectionRequest>,
-    json_data: web::Json<Value>,
-) -> Result<impl Responder, AppError> {
-    let collection_name = path.into_inner();
+    path: web::Path<(String, String)>,
+    query: web::Query<AtQuery>,
+) -> Result<HttpResponse, AppError> {
+    let (collection_name, id) = path.into_inner();
     let collection = app_state.db.get_collection(&collection_name)?;
 
-    let dependencies_json = serde_json::to_string(&query.dependencies)
-        .map_err(|e| DbError::SerializationError(e.to_string()))?;
+    let json_string = collection.get_json_at(&id, at_query(&query)?)?;
 
-    let subcollection = collection.subcollection_json(dependencies_json)?;
+    Ok(at_response(&json_string)?)
+}
 
-    let body_json = serde_json::to_string(&json_data.into_inner())
+async fn revert_in_collection(
+    app_state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    req: web::Json<RevertRequest>,
+) -> Result<HttpResponse, AppError> {
+    let (collection_name, id) = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let version = collection.revert(&id, req.version)?;
+
+    Ok(revert_response(id, version))
+}
+
+async fn batch_update_in_collection(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    json_data: web::Json<Vec<(String, Value)>>,
+) -> Result<impl Responder, AppError> {
+    let collection_name = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let mut items = Vec::with_capacity(json_data.len());
+
+    for (id, item) in json_data.iter() {
+        let json_string =
+            serde_json::to_string(item).map_err(|e| DbError::SerializationError(e.to_string()))?;
+        items.push((id.clone(), json_string));
+    }
+
+    let mut updated = Vec::new();
+    let mut errors = Vec::new();
+
+    let results = collection.update_many_json(items, BatchMode::PerItem)?;
+
+    for ((id, _), result) in json_data.iter().zip(results) {
+        match result {
+            Ok(_) => updated.push(id.clone()),
+            Err(e) => errors.push((id.clone(), e.to_string())),
+        }
+    }
+
+    #[derive(Serialize)]
+    struct BatchUpdateResponse {
+        updated: Vec<String>,
+        failed: Vec<(String, String)>,
+    }
+
+    let response = ApiResponse {
+        success: true,
+        data: Some(BatchUpdateResponse {
+            updated,
+            failed: errors,
+        }),
+        error: None,
+    };
+
+    Ok(web::Json(response))
+}
+
+async fn delete_from_collection(
+    app_state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    req: HttpRequest,
+) -> Result<impl Responder, AppError> {
+    let (collection_name, id) = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    match if_match(&req)? {
+        Some(version) => collection.delete_if_version(&id, version)?,
+        None => collection.delete_json(&id)?,
+    }
+
+    let response = ApiResponse::<()> {
+        success: true,
+        data: None,
+        error: None,
+    };
+
+    Ok(web::Json(response))
+}
+
+async fn batch_delete_from_collection(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    ids: web::Json<Vec<String>>,
+) -> Result<impl Responder, AppError> {
+    let collection_name = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let mut deleted = Vec::new();
+    let mut errors = Vec::new();
+
+    let results = collection.delete_many(&ids, BatchMode::PerItem)?;
+
+    for (id, result) in ids.iter().zip(results) {
+        match result {
+            Ok(_) => deleted.push(id.clone()),
+            Err(e) => errors.push((id.clone(), e.to_string())),
+        }
+    }
+
+    #[derive(Serialize)]
+    struct BatchDeleteResponse {
+        deleted: Vec<String>,
+        failed: Vec<(String, String)>,
+    }
+
+    let response = ApiResponse {
+        success: true,
+        data: Some(BatchDeleteResponse {
+            deleted,
+            failed: errors,
+        }),
+        error: None,
+    };
+
+    Ok(web::Json(response))
+}
+
+#[derive(Serialize)]
+struct QueryResponse {
+    count: usize,
+    items: Vec<(String, Value)>,
+}
+
+fn query_response(results: Vec<(String, String)>) -> Result<QueryResponse, AppError> {
+    let mut items = Vec::with_capacity(results.len());
+
+    for (id, json_string) in results {
+        let json_value: Value = serde_json::from_str(&json_string)
+            .map_err(|e| DbError::DeserializationError(e.to_string()))?;
+        items.push((id, json_value));
+    }
+
+    Ok(QueryResponse {
+        count: items.len(),
+        items,
+    })
+}
+
+async fn query_collection(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Json<Query>,
+) -> Result<impl Responder, AppError> {
+    let collection_name = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let results = collection.query_json(&query)?;
+
+    let response = ApiResponse {
+        success: true,
+        data: Some(query_response(results)?),
+        error: None,
+    };
+
+    Ok(web::Json(response))
+}
+
+async fn create_index(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    req: web::Json<IndexRequest>,
+) -> Result<impl Responder, AppError> {
+    let collection_name = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    collection.create_index(&req.name, &req.path, IndexOptions { unique: req.unique })?;
+
+    let response = ApiResponse {
+        success: true,
+        data: Some(req.into_inner()),
+        error: None,
+    };
+
+    Ok(web::Json(response))
+}
+
+async fn list_indexes(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<impl Responder, AppError> {
+    let collection_name = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let response = ApiResponse {
+        success: true,
+        data: Some(collection.list_indexes()?),
+        error: None,
+    };
+
+    Ok(web::Json(response))
+}
+
+async fn drop_index(
+    app_state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+) -> Result<impl Responder, AppError> {
+    let (collection_name, index_name) = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    collection.drop_index(&index_name)?;
+
+    let response = ApiResponse::<()> {
+        success: true,
+        data: None,
+        error: None,
+    };
+
+    Ok(web::Json(response))
+}
+
+async fn find_subcollections(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    filter: web::Json<Filter>,
+) -> Result<impl Responder, AppError> {
+    let collection_name = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let mut dependencies = Vec::new();
+    for subcollection in collection.find_subcollections(&filter)? {
+        dependencies.push(subcollection.get_dependencies::<Value>()?);
+    }
+
+    let response = ApiResponse {
+        success: true,
+        data: Some(dependencies),
+        error: None,
+    };
+
+    Ok(web::Json(response))
+}
+
+async fn create_subcollection(
+    app_state: web::Data<AppState>,
+    req: web::Json<SubcollectionRequest>,
+) -> Result<impl Responder, AppError> {
+    let collection = app_state.db.get_collection(&req.collection)?;
+
+    let dependencies_json = serde_json::to_string(&req.dependencies)
         .map_err(|e| DbError::SerializationError(e.to_string()))?;
 
-    let id = subcollection.insert_json(body_json)?;
+    collection.subcollection_json(dependencies_json)?;
+
+    let response = ApiResponse {
+        success: true,
+        data: Some(req.into_inner()),
+        error: None,
+    };
+
+    Ok(web::Json(response))
+}
+
+async fn insert_to_subcollection(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<SubcollectionQuery>,
+    ttl_query: web::Query<TtlQuery>,
+    payload: web::Bytes,
+) -> Result<impl Responder, AppError> {
+    let collection_name = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
+
+    let id = match ttl_query.ttl.map(Duration::from_secs) {
+        Some(ttl) => subcollection.insert_json_with_ttl(json_payload(&payload)?, ttl)?,
+        None => subcollection.insert_json(json_payload(&payload)?)?,
+    };
+
+    let response = ApiResponse {
+        success: true,
+        data: Some(InsertResponse { id }),
+        error: None,
+    };
+
+    Ok(web::Json(response))
+}
+
+async fn insert_to_subcollection_with_id(
+    app_state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<SubcollectionQuery>,
+    payload: web::Bytes,
+) -> Result<impl Responder, AppError> {
+    let (collection_name, id) = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
+
+    subcollection.insert_json_with_id(&id, json_payload(&payload)?)?;
+
+    let response = ApiResponse {
+        success: true,
+        data: Some(InsertResponse { id }),
+        error: None,
+    };
+
+    Ok(web::Json(response))
+}
+
+async fn batch_insert_to_subcollection(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<SubcollectionQuery>,
+    json_data: web::Json<Vec<Value>>,
+) -> Result<impl Responder, AppError> {
+    let collection_name = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
+
+    let mut body_jsons = Vec::with_capacity(json_data.len());
+
+    for item in json_data.iter() {
+        let body_json =
+            serde_json::to_string(item).map_err(|e| DbError::SerializationError(e.to_string()))?;
+        body_jsons.push(body_json);
+    }
+
+    let ids = subcollection
+        .insert_many_json(body_jsons, BatchMode::Atomic)?
+        .into_iter()
+        .collect::<Result<Vec<String>, DbError>>()?;
+
+    #[derive(Serialize)]
+    struct BatchInsertResponse {
+        count: usize,
+        ids: Vec<String>,
+    }
+
+    let response = ApiResponse {
+        success: true,
+        data: Some(BatchInsertResponse {
+            count: ids.len(),
+            ids,
+        }),
+        error: None,
+    };
+
+    Ok(web::Json(response))
+}
+
+async fn get_from_subcollection(
+    app_state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<SubcollectionQuery>,
+) -> Result<HttpResponse, AppError> {
+    let (collection_name, id) = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
+
+    let (json_string, version) = subcollection.get_json_with_version(&id)?;
+
+    Ok(item_response(&json_string, version)?)
+}
+
+async fn batch_get_from_subcollection(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<SubcollectionQuery>,
+    ids: web::Json<Vec<String>>,
+) -> Result<impl Responder, AppError> {
+    let collection_name = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
+
+    let mut results = Vec::with_capacity(ids.len());
+    let mut errors = Vec::new();
+
+    for id in ids.iter() {
+        match subcollection.get_json(id) {
+            Ok(json_string) => {
+                let json_value: Value = serde_json::from_str(&json_string)
+                    .map_err(|e| DbError::DeserializationError(e.to_string()))?;
+                results.push((id.clone(), json_value));
+            }
+            Err(e) => {
+                errors.push((id.clone(), e.to_string()));
+            }
+        }
+    }
+
+    #[derive(Serialize)]
+    struct BatchGetResponse {
+        found: Vec<(String, Value)>,
+        not_found: Vec<(String, String)>,
+    }
+
+    let response = ApiResponse {
+        success: true,
+        data: Some(BatchGetResponse {
+            found: results,
+            not_found: errors,
+        }),
+        error: None,
+    };
+
+    Ok(web::Json(response))
+}
+
+async fn update_in_subcollection(
+    app_state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<SubcollectionQuery>,
+    req: HttpRequest,
+    payload: web::Bytes,
+) -> Result<HttpResponse, AppError> {
+    let (collection_name, id) = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
+
+    let response = ApiResponse::<()> {
+        success: true,
+        data: None,
+        error: None,
+    };
+
+    if let Some(version) = if_match(&req)? {
+        let version =
+            subcollection.update_json_if_version(&id, json_payload(&payload)?, version)?;
+
+        return Ok(HttpResponse::Ok()
+            .insert_header(etag(version))
+            .json(response));
+    }
+
+    subcollection.update_json(&id, json_payload(&payload)?)?;
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+async fn upsert_in_subcollection(
+    app_state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<SubcollectionQuery>,
+    payload: web::Bytes,
+) -> Result<impl Responder, AppError> {
+    let (collection_name, id) = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
+
+    let created = subcollection.upsert_json(&id, json_payload(&payload)?)?;
+
+    let response = ApiResponse {
+        success: true,
+        data: Some(UpsertResponse { id, created }),
+        error: None,
+    };
+
+    Ok(web::Json(response))
+}
+
+async fn insert_if_absent_to_subcollection(
+    app_state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<SubcollectionQuery>,
+    payload: web::Bytes,
+) -> Result<impl Responder, AppError> {
+    let (collection_name, id) = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
+
+    let inserted = subcollection.insert_if_absent_json(&id, json_payload(&payload)?)?;
+
+    let response = ApiResponse {
+        success: true,
+        data: Some(InsertIfAbsentResponse { id, inserted }),
+        error: None,
+    };
+
+    Ok(web::Json(response))
+}
+
+async fn compare_and_swap_in_subcollection(
+    app_state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<SubcollectionQuery>,
+    req: web::Json<CompareAndSwapRequest>,
+) -> Result<HttpResponse, AppError> {
+    let (collection_name, id) = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
+
+    let result = subcollection.compare_and_swap_json(
+        &id,
+        to_json_string(&req.expected)?,
+        to_json_string(&req.new)?,
+    )?;
+
+    compare_and_swap_response(result)
+}
+
+async fn get_subcollection_item_history(
+    app_state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<SubcollectionQuery>,
+) -> Result<HttpResponse, AppError> {
+    let (collection_name, id) = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
+
+    Ok(history_response(subcollection.history(&id)?))
+}
+
+async fn get_from_subcollection_at(
+    app_state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<SubcollectionQuery>,
+    at: web::Query<AtQuery>,
+) -> Result<HttpResponse, AppError> {
+    let (collection_name, id) = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
+
+    let json_string = subcollection.get_json_at(&id, at_query(&at)?)?;
+
+    Ok(at_response(&json_string)?)
+}
 
-    let response = ApiResponse {
-        success: true,
-        data: Some(InsertResponse { id }),
-        error: None,
-    };
+async fn revert_in_subcollection(
+    app_state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<SubcollectionQuery>,
+    req: web::Json<RevertRequest>,
+) -> Result<HttpResponse, AppError> {
+    let (collection_name, id) = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
 
-    Ok(web::Json(response))
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
+
+    let version = subcollection.revert(&id, req.version)?;
+
+    Ok(revert_response(id, version))
 }
 
-async fn batch_insert_to_subcollection(
+async fn batch_update_in_subcollection(
     app_state: web::Data<AppState>,
     path: web::Path<String>,
-    query: web::Query<SubcollectionRequest>,
-    json_data: web::Json<Vec<Value>>,
+    query: web::Query<SubcollectionQuery>,
+    json_data: web::Json<Vec<(String, Value)>>,
 ) -> Result<impl Responder, AppError> {
     let collection_name = path.into_inner();
     let collection = app_state.db.get_collection(&collection_name)?;
 
-    let dependencies_json = serde_json::to_string(&query.dependencies)
-        .map_err(|e| DbError::SerializationError(e.to_string()))?;
-
-    let subcollection = collection.subcollection_json(dependencies_json)?;
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
 
-    let mut ids = Vec::with_capacity(json_data.len());
+    let mut items = Vec::with_capacity(json_data.len());
 
-    for item in json_data.iter() {
+    for (id, item) in json_data.iter() {
         let body_json =
             serde_json::to_string(item).map_err(|e| DbError::SerializationError(e.to_string()))?;
+        items.push((id.clone(), body_json));
+    }
+
+    let mut updated = Vec::new();
+    let mut errors = Vec::new();
+
+    let results = subcollection.update_many_json(items, BatchMode::PerItem)?;
 
-        let id = subcollection.insert_json(body_json)?;
-        ids.push(id);
+    for ((id, _), result) in json_data.iter().zip(results) {
+        match result {
+            Ok(_) => updated.push(id.clone()),
+            Err(e) => errors.push((id.clone(), e.to_string())),
+        }
     }
 
     #[derive(Serialize)]
-    struct BatchInsertResponse {
-        count: usize,
-        ids: Vec<String>,
+    struct BatchUpdateResponse {
+        updated: Vec<String>,
+        failed: Vec<(String, String)>,
     }
 
     let response = ApiResponse {
         success: true,
-        data: Some(BatchInsertResponse {
-            count: ids.len(),
-            ids,
+        data: Some(BatchUpdateResponse {
+            updated,
+            failed: errors,
         }),
         error: None,
     };
@@ -487,73 +1431,65 @@ async fn batch_insert_to_subcollection(
     Ok(web::Json(response))
 }
 
-async fn get_from_subcollection(
+async fn delete_from_subcollection(
     app_state: web::Data<AppState>,
     path: web::Path<(String, String)>,
-    query: web::Query<SubcollectionRequest>,
+    query: web::Query<SubcollectionQuery>,
+    req: HttpRequest,
 ) -> Result<impl Responder, AppError> {
     let (collection_name, id) = path.into_inner();
     let collection = app_state.db.get_collection(&collection_name)?;
 
-    let dependencies_json = serde_json::to_string(&query.dependencies)
-        .map_err(|e| DbError::SerializationError(e.to_string()))?;
-
-    let subcollection = collection.subcollection_json(dependencies_json)?;
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
 
-    let json_string = subcollection.get_json(&id)?;
-    let json_value: Value = serde_json::from_str(&json_string)
-        .map_err(|e| DbError::DeserializationError(e.to_string()))?;
+    match if_match(&req)? {
+        Some(version) => subcollection.delete_if_version(&id, version)?,
+        None => subcollection.delete_json(&id)?,
+    }
 
-    let response = ApiResponse {
+    let response = ApiResponse::<()> {
         success: true,
-        data: Some(json_value),
+        data: None,
         error: None,
     };
 
     Ok(web::Json(response))
 }
 
-async fn batch_get_from_subcollection(
+async fn batch_delete_from_subcollection(
     app_state: web::Data<AppState>,
     path: web::Path<String>,
-    query: web::Query<SubcollectionRequest>,
+    query: web::Query<SubcollectionQuery>,
     ids: web::Json<Vec<String>>,
 ) -> Result<impl Responder, AppError> {
     let collection_name = path.into_inner();
     let collection = app_state.db.get_collection(&collection_name)?;
 
-    let dependencies_json = serde_json::to_string(&query.dependencies)
-        .map_err(|e| DbError::SerializationError(e.to_string()))?;
-
-    let subcollection = collection.subcollection_json(dependencies_json)?;
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
 
-    let mut results = Vec::with_capacity(ids.len());
+    let mut deleted = Vec::new();
     let mut errors = Vec::new();
 
-    for id in ids.iter() {
-        match subcollection.get_json(id) {
-            Ok(json_string) => {
-                let json_value: Value = serde_json::from_str(&json_string)
-                    .map_err(|e| DbError::DeserializationError(e.to_string()))?;
-                results.push((id.clone(), json_value));
-            }
-            Err(e) => {
-                errors.push((id.clone(), e.to_string()));
-            }
+    let results = subcollection.delete_many(&ids, BatchMode::PerItem)?;
+
+    for (id, result) in ids.iter().zip(results) {
+        match result {
+            Ok(_) => deleted.push(id.clone()),
+            Err(e) => errors.push((id.clone(), e.to_string())),
         }
     }
 
     #[derive(Serialize)]
-    struct BatchGetResponse {
-        found: Vec<(String, Value)>,
-        not_found: Vec<(String, String)>,
+    struct BatchDeleteResponse {
+        deleted: Vec<String>,
+        failed: Vec<(String, String)>,
     }
 
     let response = ApiResponse {
         success: true,
-        data: Some(BatchGetResponse {
-            found: results,
-            not_found: errors,
+        data: Some(BatchDeleteResponse {
+            deleted,
+            failed: errors,
         }),
         error: None,
     };
@@ -561,112 +1497,221 @@ async fn batch_get_from_subcollection(
     Ok(web::Json(response))
 }
 
-async fn update_in_subcollection(
+async fn query_subcollection(
     app_state: web::Data<AppState>,
-    path: web::Path<(String, String)>,
-    query: web::Query<SubcollectionRequest>,
-    json_data: web::Json<Value>,
+    path: web::Path<String>,
+    query: web::Query<SubcollectionQuery>,
+    filter: web::Json<Query>,
 ) -> Result<impl Responder, AppError> {
-    let (collection_name, id) = path.into_inner();
+    let collection_name = path.into_inner();
     let collection = app_state.db.get_collection(&collection_name)?;
 
-    let dependencies_json = serde_json::to_string(&query.dependencies)
-        .map_err(|e| DbError::SerializationError(e.to_string()))?;
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
 
-    let subcollection = collection.subcollection_json(dependencies_json)?;
+    let results = subcollection.query_json(&filter)?;
 
-    let body_json = serde_json::to_string(&json_data.into_inner())
-        .map_err(|e| DbError::SerializationError(e.to_string()))?;
+    let response = ApiResponse {
+        success: true,
+        data: Some(query_response(results)?),
+        error: None,
+    };
 
-    subcollection.update_json(&id, body_json)?;
+    Ok(web::Json(response))
+}
 
-    let response = ApiResponse::<()> {
+async fn list_deleted_from_collection(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let collection_name = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    Ok(deleted_response(collection.list_deleted()?))
+}
+
+async fn purge_collection(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<PurgeQuery>,
+) -> Result<impl Responder, AppError> {
+    let collection_name = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let purged = collection.purge(Duration::from_secs(query.older_than))?;
+
+    let response = ApiResponse {
         success: true,
-        data: None,
+        data: Some(PurgeResponse { purged }),
         error: None,
     };
 
     Ok(web::Json(response))
 }
 
-async fn delete_from_subcollection(
+async fn restore_to_collection(
     app_state: web::Data<AppState>,
     path: web::Path<(String, String)>,
-    query: web::Query<SubcollectionRequest>,
 ) -> Result<impl Responder, AppError> {
     let (collection_name, id) = path.into_inner();
     let collection = app_state.db.get_collection(&collection_name)?;
 
-    let dependencies_json = serde_json::to_string(&query.dependencies)
-        .map_err(|e| DbError::SerializationError(e.to_string()))?;
-
-    let subcollection = collection.subcollection_json(dependencies_json)?;
+    collection.restore(&id)?;
 
-    subcollection.delete_json(&id)?;
-
-    let response = ApiResponse::<()> {
+    let response = ApiResponse {
         success: true,
-        data: None,
+        data: Some(InsertResponse { id }),
         error: None,
     };
 
     Ok(web::Json(response))
 }
 
-async fn batch_delete_from_subcollection(
+async fn list_deleted_from_subcollection(
     app_state: web::Data<AppState>,
     path: web::Path<String>,
-    query: web::Query<SubcollectionRequest>,
-    ids: web::Json<Vec<String>>,
-) -> Result<impl Responder, AppError> {
+    query: web::Query<SubcollectionQuery>,
+) -> Result<HttpResponse, AppError> {
     let collection_name = path.into_inner();
     let collection = app_state.db.get_collection(&collection_name)?;
 
-    let dependencies_json = serde_json::to_string(&query.dependencies)
-        .map_err(|e| DbError::SerializationError(e.to_string()))?;
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
 
-    let subcollection = collection.subcollection_json(dependencies_json)?;
+    Ok(deleted_response(subcollection.list_deleted()?))
+}
 
-    let mut deleted = Vec::new();
-    let mut errors = Vec::new();
+async fn restore_to_subcollection(
+    app_state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<SubcollectionQuery>,
+) -> Result<impl Responder, AppError> {
+    let (collection_name, id) = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
 
-    for id in ids.iter() {
-        match subcollection.delete_json(id) {
-            Ok(_) => deleted.push(id.clone()),
-            Err(e) => errors.push((id.clone(), e.to_string())),
-        }
-    }
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
 
-    #[derive(Serialize)]
-    struct BatchDeleteResponse {
-        deleted: Vec<String>,
-        failed: Vec<(String, String)>,
-    }
+    subcollection.restore(&id)?;
 
     let response = ApiResponse {
         success: true,
-        data: Some(BatchDeleteResponse {
-            deleted,
-            failed: errors,
-        }),
+        data: Some(InsertResponse { id }),
         error: None,
     };
 
     Ok(web::Json(response))
 }
 
+async fn watch_collection(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<WatchQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let collection_name = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let watcher = match watch_start(&req, &query)? {
+        Some(seq) => collection.watch_from(seq)?,
+        None => collection.watch()?,
+    };
+
+    Ok(change_stream(watcher))
+}
+
+async fn watch_subcollection(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<SubcollectionQuery>,
+    watch_query: web::Query<WatchQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let collection_name = path.into_inner();
+    let collection = app_state.db.get_collection(&collection_name)?;
+
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
+
+    let watcher = match watch_start(&req, &watch_query)? {
+        Some(seq) => subcollection.watch_from(seq)?,
+        None => subcollection.watch()?,
+    };
+
+    Ok(change_stream(watcher))
+}
+
+// Where a change stream starts: after the `Last-Event-ID` an EventSource
+// sends when it reconnects, else at `since`, else with the next change.
+fn watch_start(req: &HttpRequest, query: &WatchQuery) -> Result<Option<u64>, DbError> {
+    if let Some(value) = req.headers().get("Last-Event-ID") {
+        let next_seq = value
+            .to_str()
+            .ok()
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .and_then(|last_seq| last_seq.checked_add(1))
+            .ok_or_else(|| DbError::DeserializationError("Invalid Last-Event-ID".to_string()))?;
+
+        return Ok(Some(next_seq));
+    }
+
+    Ok(query.since)
+}
+
+// Streams changes as Server-Sent Events. Watchers block, so each stream is
+// fed by a blocking task that ends once the client has gone.
+fn change_stream(mut watcher: Watcher) -> HttpResponse {
+    let (sender, receiver) = tokio::sync::mpsc::channel::<Result<web::Bytes, AppError>>(16);
+
+    actix_web::rt::task::spawn_blocking(move || {
+        let mut chunk = Ok(web::Bytes::from_static(b": watching\n\n"));
+
+        loop {
+            let failed = chunk.is_err();
+            if sender.blocking_send(chunk).is_err() || failed {
+                return;
+            }
+
+            chunk = match watcher.next_timeout(KEEP_ALIVE_INTERVAL) {
+                Ok(Some(event)) => sse_event(&event).map_err(AppError),
+                Ok(None) => Ok(web::Bytes::from_static(b": keep-alive\n\n")),
+                Err(e) => Err(AppError(e)),
+            };
+        }
+    });
+
+    let stream = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(header::CacheControl(vec![header::CacheDirective::NoCache]))
+        .streaming(stream)
+}
+
+fn sse_event(event: &ChangeEvent) -> Result<web::Bytes, DbError> {
+    let kind = match event {
+        ChangeEvent::Insert { .. } => "insert",
+        ChangeEvent::Update { .. } => "update",
+        ChangeEvent::Delete { .. } => "delete",
+    };
+    let data =
+        serde_json::to_string(event).map_err(|e| DbError::SerializationError(e.to_string()))?;
+
+    Ok(web::Bytes::from(format!(
+        "id: {}\nevent: {}\ndata: {}\n\n",
+        event.seq(),
+        kind,
+        data
+    )))
+}
+
 async fn get_subcollection_keys(
     app_state: web::Data<AppState>,
     path: web::Path<String>,
-    query: web::Query<SubcollectionRequest>,
+    query: web::Query<SubcollectionQuery>,
 ) -> Result<impl Responder, AppError> {
     let collection_name = path.into_inner();
     let collection = app_state.db.get_collection(&collection_name)?;
 
-    let dependencies_json = serde_json::to_string(&query.dependencies)
-        .map_err(|e| DbError::SerializationError(e.to_string()))?;
-
-    let subcollection = collection.subcollection_json(dependencies_json)?;
+    let subcollection = collection.subcollection_json(query.dependencies.clone())?;
 
     let keys = subcollection.get_keys()?;
 
@@ -701,44 +1746,141 @@ async fn main() -> std::io::Result<()> {
                 .help("Address to bind the server (format: host:port)")
                 .value_name("ADDRESS"),
         )
+        .arg(
+            Arg::new("flush-every-ms")
+                .long("flush-every-ms")
+                .help(
+                    "Flush writes in the background every N milliseconds instead of on every write",
+                )
+                .value_name("MS")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("cache-capacity")
+                .long("cache-capacity")
+                .help("Size of the page cache in bytes")
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("compression")
+                .long("compression")
+                .help("Compress data on disk with zstd at the given level (requires the `compression` feature)")
+                .value_name("LEVEL")
+                .value_parser(clap::value_parser!(i32)),
+        )
+        .arg(
+            Arg::new("segment-size")
+                .long("segment-size")
+                .help("Size of the log segments in bytes (a power of two)")
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("temporary")
+                .long("temporary")
+                .help("Use a temporary database that is deleted on shutdown; ignores --db-path")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("read-only")
+                .long("read-only")
+                .help("Reject all writes")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("must-exist")
+                .long("must-exist")
+                .help("Fail instead of creating a database if none exists at --db-path")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("sweep-interval")
+                .long("sweep-interval")
+                .help("Remove expired items every N seconds (default: 60, 0 disables)")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64)),
+        )
         .get_matches();
 
     let db_path = matches
         .get_one::<String>("db-path")
-        .map(|s| s.clone())
+        .cloned()
         .or_else(|| std::env::var("DB_PATH").ok())
         .unwrap_or_else(|| "./data".to_string());
 
     let bind_address = matches
         .get_one::<String>("bind-address")
-        .map(|s| s.clone())
+        .cloned()
         .or_else(|| std::env::var("BIND_ADDRESS").ok())
         .unwrap_or_else(|| "127.0.0.1:8080".to_string());
 
+    let durability = match matches
+        .get_one::<u64>("flush-every-ms")
+        .copied()
+        .or_else(|| std::env::var("FLUSH_EVERY_MS").ok()?.parse().ok())
+    {
+        Some(ms) => Durability::Periodic(Duration::from_millis(ms)),
+        None => Durability::Sync,
+    };
+
+    let mut config = if matches.get_flag("temporary") {
+        DatabaseConfig::temporary()
+    } else {
+        DatabaseConfig::new(&db_path)
+    };
+
+    config = config
+        .durability(durability)
+        .read_only(matches.get_flag("read-only"))
+        .create_if_missing(!matches.get_flag("must-exist"));
+
+    let sweep_interval = matches
+        .get_one::<u64>("sweep-interval")
+        .copied()
+        .or_else(|| std::env::var("SWEEP_INTERVAL").ok()?.parse().ok())
+        .unwrap_or(60);
+
+    if sweep_interval > 0 {
+        config = config.sweep_interval(Duration::from_secs(sweep_interval));
+    }
+
+    if let Some(&bytes) = matches.get_one::<u64>("cache-capacity") {
+        config = config.cache_capacity(bytes);
+    }
+
+    if let Some(&level) = matches.get_one::<i32>("compression") {
+        config = config.compression(level);
+    }
+
+    if let Some(&bytes) = matches.get_one::<usize>("segment-size") {
+        config = config.segment_size(bytes);
+    }
+
     println!("Database path: {}", db_path);
     println!("Binding to: {}", bind_address);
+    println!("Durability: {:?}", durability);
 
-    let db = match Database::new(Some(&db_path)) {
+    let db = match Database::open(&config) {
         Ok(db) => {
             println!("Successfully opened database at: {}", db_path);
             Arc::new(db)
         }
         Err(e) => {
             eprintln!("Failed to open database: {}", e);
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                e.to_string(),
-            ));
+            return Err(std::io::Error::other(e.to_string()));
         }
     };
 
-    let app_state = web::Data::new(AppState { db });
+    let app_state = web::Data::new(AppState { db: db.clone() });
 
     println!("Starting server at: {}", bind_address);
 
     HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
+            // Raw message bodies get the same limit as JSON ones.
+            .app_data(web::PayloadConfig::new(2 * 1024 * 1024))
             .wrap(middleware::Logger::default())
             .service(
                 web::resource("/collections")
@@ -758,8 +1900,42 @@ async fn main() -> std::io::Result<()> {
                 web::resource("/collections/{name}/batch")
                     .route(web::post().to(batch_insert_to_collection))
                     .route(web::get().to(batch_get_from_collection))
+                    .route(web::put().to(batch_update_in_collection))
                     .route(web::delete().to(batch_delete_from_collection)),
             )
+            .service(
+                web::resource("/collections/{name}/indexes")
+                    .route(web::post().to(create_index))
+                    .route(web::get().to(list_indexes)),
+            )
+            .service(
+                web::resource("/collections/{name}/indexes/{index}")
+                    .route(web::delete().to(drop_index)),
+            )
+            .service(
+                web::resource("/collections/{name}/subcollections")
+                    .route(web::post().to(find_subcollections)),
+            )
+            .service(
+                web::resource("/collections/{name}/constraints")
+                    .route(web::post().to(add_constraint))
+                    .route(web::delete().to(remove_constraint)),
+            )
+            .service(
+                web::resource("/collections/{name}/codec")
+                    .route(web::put().to(reencode_collection)),
+            )
+            .service(
+                web::resource("/collections/{name}/query").route(web::post().to(query_collection)),
+            )
+            .service(
+                web::resource("/collections/{name}/changes").route(web::get().to(watch_collection)),
+            )
+            .service(
+                web::resource("/collections/{name}/trash")
+                    .route(web::get().to(list_deleted_from_collection))
+                    .route(web::delete().to(purge_collection)),
+            )
             .service(
                 web::resource("/collections/{name}")
                     .route(web::get().to(get_collection_info))
@@ -767,33 +1943,108 @@ async fn main() -> std::io::Result<()> {
             )
             .service(
                 web::resource("/collections/{name}/{id}")
+                    .route(web::post().to(insert_to_collection_with_id))
                     .route(web::get().to(get_from_collection))
                     .route(web::put().to(update_in_collection))
                     .route(web::delete().to(delete_from_collection)),
             )
+            .service(
+                web::resource("/collections/{name}/{id}/upsert")
+                    .route(web::put().to(upsert_in_collection)),
+            )
+            .service(
+                web::resource("/collections/{name}/{id}/insert-if-absent")
+                    .route(web::post().to(insert_if_absent_to_collection)),
+            )
+            .service(
+                web::resource("/collections/{name}/{id}/compare-and-swap")
+                    .route(web::post().to(compare_and_swap_in_collection)),
+            )
+            .service(
+                web::resource("/collections/{name}/{id}/history")
+                    .route(web::get().to(get_collection_item_history)),
+            )
+            .service(
+                web::resource("/collections/{name}/{id}/at")
+                    .route(web::get().to(get_from_collection_at)),
+            )
+            .service(
+                web::resource("/collections/{name}/{id}/revert")
+                    .route(web::post().to(revert_in_collection)),
+            )
+            .service(
+                web::resource("/collections/{name}/{id}/restore")
+                    .route(web::post().to(restore_to_collection)),
+            )
             .service(web::resource("/subcollections").route(web::post().to(create_subcollection)))
             .service(
                 web::resource("/subcollections/{name}/keys")
                     .route(web::get().to(get_subcollection_keys)),
             )
+            .service(
+                web::resource("/subcollections/{name}/changes")
+                    .route(web::get().to(watch_subcollection)),
+            )
+            .service(
+                web::resource("/subcollections/{name}/trash")
+                    .route(web::get().to(list_deleted_from_subcollection)),
+            )
             .service(
                 web::resource("/subcollections/{name}/batch")
                     .route(web::post().to(batch_insert_to_subcollection))
                     .route(web::get().to(batch_get_from_subcollection))
+                    .route(web::put().to(batch_update_in_subcollection))
                     .route(web::delete().to(batch_delete_from_subcollection)),
             )
+            .service(
+                web::resource("/subcollections/{name}/query")
+                    .route(web::post().to(query_subcollection)),
+            )
             .service(
                 web::resource("/subcollections/{name}")
                     .route(web::post().to(insert_to_subcollection)),
             )
             .service(
                 web::resource("/subcollections/{name}/{id}")
+                    .route(web::post().to(insert_to_subcollection_with_id))
                     .route(web::get().to(get_from_subcollection))
                     .route(web::put().to(update_in_subcollection))
                     .route(web::delete().to(delete_from_subcollection)),
             )
+            .service(
+                web::resource("/subcollections/{name}/{id}/upsert")
+                    .route(web::put().to(upsert_in_subcollection)),
+            )
+            .service(
+                web::resource("/subcollections/{name}/{id}/insert-if-absent")
+                    .route(web::post().to(insert_if_absent_to_subcollection)),
+            )
+            .service(
+                web::resource("/subcollections/{name}/{id}/compare-and-swap")
+                    .route(web::post().to(compare_and_swap_in_subcollection)),
+            )
+            .service(
+                web::resource("/subcollections/{name}/{id}/history")
+                    .route(web::get().to(get_subcollection_item_history)),
+            )
+            .service(
+                web::resource("/subcollections/{name}/{id}/at")
+                    .route(web::get().to(get_from_subcollection_at)),
+            )
+            .service(
+                web::resource("/subcollections/{name}/{id}/revert")
+                    .route(web::post().to(revert_in_subcollection)),
+            )
+            .service(
+                web::resource("/subcollections/{name}/{id}/restore")
+                    .route(web::post().to(restore_to_subcollection)),
+            )
     })
     .bind(bind_address)?
     .run()
-    .await
+    .await?;
+
+    db.flush_async()
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))
 }
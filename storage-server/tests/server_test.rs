@@ -1,5 +1,4 @@
 // tests/api_tests.rs
-use reqwest;
 use serde_json::{json, Value};
 use std::fs;
 use std::path::Path;
@@ -24,6 +23,14 @@ fn cleanup_test_dir(dir: &str) {
 }
 
 async fn start_test_server(db_path: &str, port: u16) -> std::process::Child {
+    start_test_server_with_args(db_path, port, &[]).await
+}
+
+async fn start_test_server_with_args(
+    db_path: &str,
+    port: u16,
+    args: &[&str],
+) -> std::process::Child {
     let child = Command::new("cargo")
         .arg("run")
         .arg(format!("--manifest-path={}", "../Cargo.toml"))
@@ -32,6 +39,7 @@ async fn start_test_server(db_path: &str, port: u16) -> std::process::Child {
         .arg(db_path)
         .arg("--bind-address")
         .arg(format!("127.0.0.1:{}", port))
+        .args(args)
         .spawn()
         .expect("Failed to start server");
 
@@ -51,7 +59,7 @@ async fn test_collection_crud() {
     let base_url = format!("http://127.0.0.1:{}", port);
 
     let response = client
-        .post(&format!("{}/collections", base_url))
+        .post(format!("{}/collections", base_url))
         .json(&json!({
             "name": "test_collection"
         }))
@@ -64,7 +72,7 @@ async fn test_collection_crud() {
     assert_eq!(json["success"], true);
 
     let response = client
-        .get(&format!("{}/collections", base_url))
+        .get(format!("{}/collections", base_url))
         .send()
         .await
         .unwrap();
@@ -79,7 +87,7 @@ async fn test_collection_crud() {
         .any(|x| x == "test_collection"));
 
     let response = client
-        .get(&format!("{}/collections/test_collection/exists", base_url))
+        .get(format!("{}/collections/test_collection/exists", base_url))
         .send()
         .await
         .unwrap();
@@ -90,7 +98,7 @@ async fn test_collection_crud() {
     assert_eq!(json["data"], true);
 
     let response = client
-        .delete(&format!("{}/collections", base_url))
+        .delete(format!("{}/collections", base_url))
         .json(&json!({
             "name": "test_collection"
         }))
@@ -103,7 +111,7 @@ async fn test_collection_crud() {
     assert_eq!(json["success"], true);
 
     let response = client
-        .get(&format!("{}/collections/test_collection/exists", base_url))
+        .get(format!("{}/collections/test_collection/exists", base_url))
         .send()
         .await
         .unwrap();
@@ -147,7 +155,7 @@ async fn test_collection_with_schema() {
     .to_string();
 
     let response = client
-        .post(&format!("{}/collections/schema", base_url))
+        .post(format!("{}/collections/schema", base_url))
         .json(&json!({
             "name": "users",
             "body_schema": body_schema,
@@ -162,7 +170,7 @@ async fn test_collection_with_schema() {
     assert_eq!(json["success"], true);
 
     let response = client
-        .get(&format!("{}/collections/users", base_url))
+        .get(format!("{}/collections/users", base_url))
         .send()
         .await
         .unwrap();
@@ -173,7 +181,7 @@ async fn test_collection_with_schema() {
     assert_eq!(json["data"]["has_schema"], true);
 
     let response = client
-        .post(&format!("{}/collections/users", base_url))
+        .post(format!("{}/collections/users", base_url))
         .json(&json!({
             "body": {
                 "name": "John Doe",
@@ -193,7 +201,7 @@ async fn test_collection_with_schema() {
     let id = json["data"]["id"].as_str().unwrap().to_string();
 
     let response = client
-        .post(&format!("{}/collections/users", base_url))
+        .post(format!("{}/collections/users", base_url))
         .json(&json!({
             "body": {
                 "age": 30
@@ -209,7 +217,7 @@ async fn test_collection_with_schema() {
     assert_eq!(response.status(), 400);
 
     let response = client
-        .get(&format!("{}/collections/users/{}", base_url, id))
+        .get(format!("{}/collections/users/{}", base_url, id))
         .send()
         .await
         .unwrap();
@@ -222,7 +230,7 @@ async fn test_collection_with_schema() {
     assert_eq!(json["data"]["dependencies"]["user_id"], "123456");
 
     let response = client
-        .put(&format!("{}/collections/users/{}", base_url, id))
+        .put(format!("{}/collections/users/{}", base_url, id))
         .json(&json!({
             "body": {
                 "name": "Jane Doe",
@@ -239,7 +247,7 @@ async fn test_collection_with_schema() {
     assert_eq!(response.status(), 200);
 
     let response = client
-        .get(&format!("{}/collections/users/{}", base_url, id))
+        .get(format!("{}/collections/users/{}", base_url, id))
         .send()
         .await
         .unwrap();
@@ -250,7 +258,7 @@ async fn test_collection_with_schema() {
     assert_eq!(json["data"]["body"]["age"], 29);
 
     let response = client
-        .delete(&format!("{}/collections/users/{}", base_url, id))
+        .delete(format!("{}/collections/users/{}", base_url, id))
         .send()
         .await
         .unwrap();
@@ -258,7 +266,7 @@ async fn test_collection_with_schema() {
     assert_eq!(response.status(), 200);
 
     let response = client
-        .get(&format!("{}/collections/users/{}", base_url, id))
+        .get(format!("{}/collections/users/{}", base_url, id))
         .send()
         .await
         .unwrap();
@@ -280,7 +288,7 @@ async fn test_batch_operations() {
     let base_url = format!("http://127.0.0.1:{}", port);
 
     let response = client
-        .post(&format!("{}/collections", base_url))
+        .post(format!("{}/collections", base_url))
         .json(&json!({
             "name": "batch_test"
         }))
@@ -291,7 +299,7 @@ async fn test_batch_operations() {
     assert_eq!(response.status(), 200);
 
     let response = client
-        .post(&format!("{}/collections/batch_test/batch", base_url))
+        .post(format!("{}/collections/batch_test/batch", base_url))
         .json(&json!([
             {
                 "body": {
@@ -330,7 +338,7 @@ async fn test_batch_operations() {
     assert_eq!(ids.len(), 3);
 
     let response = client
-        .get(&format!("{}/collections/batch_test/batch", base_url))
+        .get(format!("{}/collections/batch_test/batch", base_url))
         .json(&json!(ids
             .iter()
             .map(|v| v.as_str().unwrap())
@@ -345,7 +353,56 @@ async fn test_batch_operations() {
     assert_eq!(json["data"]["found"].as_array().unwrap().len(), 3);
 
     let response = client
-        .delete(&format!("{}/collections/batch_test/batch", base_url))
+        .put(format!("{}/collections/batch_test/batch", base_url))
+        .json(&json!([
+            [
+                ids[0],
+                {
+                    "body": {
+                        "item": "Item 1 updated"
+                    },
+                    "dependencies": {
+                        "category": "test"
+                    }
+                }
+            ],
+            [
+                "missing",
+                {
+                    "body": {
+                        "item": "Missing"
+                    },
+                    "dependencies": {
+                        "category": "test"
+                    }
+                }
+            ]
+        ]))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["success"], true);
+    assert_eq!(json["data"]["updated"], json!([ids[0]]));
+    assert_eq!(json["data"]["failed"].as_array().unwrap().len(), 1);
+
+    let response = client
+        .get(format!(
+            "{}/collections/batch_test/{}",
+            base_url,
+            ids[0].as_str().unwrap()
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["body"]["item"], "Item 1 updated");
+
+    let response = client
+        .delete(format!("{}/collections/batch_test/batch", base_url))
         .json(&json!(ids
             .iter()
             .map(|v| v.as_str().unwrap())
@@ -374,7 +431,7 @@ async fn test_subcollections() {
     let base_url = format!("http://127.0.0.1:{}", port);
 
     let response = client
-        .post(&format!("{}/collections", base_url))
+        .post(format!("{}/collections", base_url))
         .json(&json!({
             "name": "products"
         }))
@@ -385,7 +442,7 @@ async fn test_subcollections() {
     assert_eq!(response.status(), 200);
 
     let response = client
-        .post(&format!("{}/subcollections", base_url))
+        .post(format!("{}/subcollections", base_url))
         .json(&json!({
             "collection": "products",
             "dependencies": {
@@ -399,7 +456,7 @@ async fn test_subcollections() {
     assert_eq!(response.status(), 200);
 
     let response = client
-        .post(&format!(
+        .post(format!(
             "{}/subcollections/products?collection=products&dependencies={}",
             base_url,
             serde_json::to_string(&json!({"category": "electronics"})).unwrap()
@@ -417,7 +474,7 @@ async fn test_subcollections() {
     let id = json["data"]["id"].as_str().unwrap().to_string();
 
     let response = client
-        .get(&format!(
+        .get(format!(
             "{}/subcollections/products/{}?collection=products&dependencies={}",
             base_url,
             id,
@@ -433,7 +490,7 @@ async fn test_subcollections() {
     assert_eq!(json["data"]["price"], 999.99);
 
     let response = client
-        .get(&format!(
+        .get(format!(
             "{}/subcollections/products/keys?collection=products&dependencies={}",
             base_url,
             serde_json::to_string(&json!({"category": "electronics"})).unwrap()
@@ -448,7 +505,7 @@ async fn test_subcollections() {
     assert!(json["data"].as_array().unwrap().contains(&json!(id)));
 
     let response = client
-        .put(&format!(
+        .put(format!(
             "{}/subcollections/products/{}?collection=products&dependencies={}",
             base_url,
             id,
@@ -465,7 +522,7 @@ async fn test_subcollections() {
     assert_eq!(response.status(), 200);
 
     let response = client
-        .get(&format!(
+        .get(format!(
             "{}/subcollections/products/{}?collection=products&dependencies={}",
             base_url,
             id,
@@ -481,7 +538,7 @@ async fn test_subcollections() {
     assert_eq!(json["data"]["price"], 1299.99);
 
     let response = client
-        .delete(&format!(
+        .delete(format!(
             "{}/subcollections/products/{}?collection=products&dependencies={}",
             base_url,
             id,
@@ -494,7 +551,7 @@ async fn test_subcollections() {
     assert_eq!(response.status(), 200);
 
     let response = client
-        .get(&format!(
+        .get(format!(
             "{}/subcollections/products/{}?collection=products&dependencies={}",
             base_url,
             id,
@@ -521,7 +578,7 @@ async fn test_error_handling() {
     let base_url = format!("http://127.0.0.1:{}", port);
 
     let response = client
-        .get(&format!("{}/collections/nonexistent", base_url))
+        .get(format!("{}/collections/nonexistent", base_url))
         .send()
         .await
         .unwrap();
@@ -556,7 +613,7 @@ async fn test_error_handling() {
     .to_string();
 
     let response = client
-        .post(&format!("{}/collections/schema", base_url))
+        .post(format!("{}/collections/schema", base_url))
         .json(&json!({
             "name": "users_with_validation",
             "body_schema": body_schema,
@@ -569,7 +626,7 @@ async fn test_error_handling() {
     assert_eq!(response.status(), 200);
 
     let response = client
-        .post(&format!("{}/collections/users_with_validation", base_url))
+        .post(format!("{}/collections/users_with_validation", base_url))
         .json(&json!({
             "body": {
                 "email": "not-an-email"
@@ -587,7 +644,7 @@ async fn test_error_handling() {
     assert_eq!(json["success"], false);
 
     let response = client
-        .post(&format!("{}/collections/users_with_validation", base_url))
+        .post(format!("{}/collections/users_with_validation", base_url))
         .json(&json!({
             "body": {
                 "email": "test@example.com"
@@ -607,3 +664,1359 @@ async fn test_error_handling() {
     server.kill().unwrap();
     cleanup_test_dir(&test_dir);
 }
+
+#[tokio::test]
+async fn test_query() {
+    let test_dir = setup_test_dir();
+    let port = 8086;
+
+    let mut server = start_test_server(&test_dir, port).await;
+
+    let client = reqwest::Client::new();
+    let base_url = format!("http://127.0.0.1:{}", port);
+    let dependencies = serde_json::to_string(&json!({"category": "electronics"})).unwrap();
+
+    let response = client
+        .post(format!("{}/collections", base_url))
+        .json(&json!({
+            "name": "products"
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    for (name, price) in [("Laptop", 999.99), ("Phone", 599.0), ("Cable", 9.5)] {
+        let response = client
+            .post(format!(
+                "{}/subcollections/products?collection=products&dependencies={}",
+                base_url, dependencies
+            ))
+            .json(&json!({
+                "name": name,
+                "price": price
+            }))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+    }
+
+    let response = client
+        .post(format!("{}/collections/products/indexes", base_url))
+        .json(&json!({
+            "name": "by_price",
+            "path": "body.price"
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let response = client
+        .post(format!("{}/collections/products/indexes", base_url))
+        .json(&json!({
+            "name": "by_price",
+            "path": "body.name"
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 409);
+
+    let response = client
+        .get(format!("{}/collections/products/indexes", base_url))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"][0]["name"], "by_price");
+    assert_eq!(json["data"][0]["ready"], true);
+
+    let response = client
+        .post(format!("{}/collections/products/query", base_url))
+        .json(&json!({
+            "filter": {"gt": ["body.price", 100]},
+            "sort": [{"path": "body.price", "descending": true}],
+            "projection": ["body.name"]
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["count"], 2);
+    assert_eq!(
+        json["data"]["items"][0][1],
+        json!({"body": {"name": "Laptop"}})
+    );
+    assert_eq!(
+        json["data"]["items"][1][1],
+        json!({"body": {"name": "Phone"}})
+    );
+
+    let response = client
+        .post(format!(
+            "{}/subcollections/products/query?collection=products&dependencies={}",
+            base_url, dependencies
+        ))
+        .json(&json!({
+            "filter": {"eq": ["body.name", "Cable"]}
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["count"], 1);
+    assert_eq!(json["data"]["items"][0][1]["body"]["price"], 9.5);
+
+    let response = client
+        .post(format!("{}/collections/products/query", base_url))
+        .json(&json!({
+            "filter": {"unknown": ["body.name", "Cable"]}
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 400);
+
+    let response = client
+        .post(format!("{}/collections/products/subcollections", base_url))
+        .json(&json!({"eq": ["dependencies.category", "electronics"]}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"], json!([{"category": "electronics"}]));
+
+    let response = client
+        .delete(format!(
+            "{}/collections/products/indexes/by_price",
+            base_url
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    server.kill().unwrap();
+    cleanup_test_dir(&test_dir);
+}
+
+#[tokio::test]
+async fn test_read_only() {
+    let test_dir = setup_test_dir();
+    let port = 8087;
+
+    let missing_dir = format!("{}/missing", test_dir);
+    let mut server = start_test_server_with_args(&missing_dir, port, &["--must-exist"]).await;
+    assert!(!server.wait().unwrap().success());
+
+    let id = {
+        let db = dbuf_storage::Database::new(Some(&test_dir)).unwrap();
+        let collection = db.create_collection("read_only_test").unwrap();
+        collection
+            .insert_json(json!({"body": {"x": 1}, "dependencies": {"y": 2}}).to_string())
+            .unwrap()
+    };
+
+    let mut server =
+        start_test_server_with_args(&test_dir, port, &["--read-only", "--must-exist"]).await;
+
+    let client = reqwest::Client::new();
+    let base_url = format!("http://127.0.0.1:{}", port);
+
+    let response = client
+        .get(format!("{}/collections/read_only_test/{}", base_url, id))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["body"]["x"], 1);
+
+    let response = client
+        .post(format!("{}/collections/read_only_test", base_url))
+        .json(&json!({"body": {"x": 2}, "dependencies": {"y": 2}}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 403);
+
+    let response = client
+        .post(format!("{}/collections", base_url))
+        .json(&json!({"name": "another"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 403);
+
+    server.kill().unwrap();
+    cleanup_test_dir(&test_dir);
+}
+
+#[tokio::test]
+async fn test_storage_codec() {
+    let test_dir = setup_test_dir();
+    let port = 8088;
+
+    let mut server = start_test_server(&test_dir, port).await;
+
+    let client = reqwest::Client::new();
+    let base_url = format!("http://127.0.0.1:{}", port);
+
+    let response = client
+        .post(format!("{}/collections", base_url))
+        .json(&json!({
+            "name": "codec_test",
+            "codec": "cbor"
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let response = client
+        .post(format!("{}/collections/codec_test", base_url))
+        .json(&json!({
+            "body": {"name": "Item", "tags": ["a", "b"], "price": 1.5},
+            "dependencies": {"category": "test"}
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    let id = json["data"]["id"].as_str().unwrap().to_string();
+
+    let response = client
+        .get(format!("{}/collections/codec_test", base_url))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["codec"], "cbor");
+
+    let response = client
+        .put(format!("{}/collections/codec_test/codec", base_url))
+        .json(&json!({"codec": "messagepack"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["reencoded"], 1);
+
+    let response = client
+        .get(format!("{}/collections/codec_test/{}", base_url, id))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(
+        json["data"],
+        json!({
+            "body": {"name": "Item", "tags": ["a", "b"], "price": 1.5},
+            "dependencies": {"category": "test"}
+        })
+    );
+
+    let response = client
+        .put(format!("{}/collections/codec_test/codec", base_url))
+        .json(&json!({"codec": "yaml"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 400);
+
+    server.kill().unwrap();
+    cleanup_test_dir(&test_dir);
+}
+
+#[tokio::test]
+async fn test_constraints() {
+    let test_dir = setup_test_dir();
+    let port = 8090;
+
+    let mut server = start_test_server(&test_dir, port).await;
+
+    let client = reqwest::Client::new();
+    let base_url = format!("http://127.0.0.1:{}", port);
+
+    let response = client
+        .post(format!("{}/collections", base_url))
+        .json(&json!({
+            "name": "constrained",
+            "constraints": ["body.total == body.price * dependencies.quantity"]
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let response = client
+        .post(format!("{}/collections/constrained", base_url))
+        .json(&json!({
+            "body": {"price": 5, "total": 15},
+            "dependencies": {"quantity": 3}
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let response = client
+        .post(format!("{}/collections/constrained", base_url))
+        .json(&json!({
+            "body": {"price": 5, "total": 10},
+            "dependencies": {"quantity": 3}
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 400);
+
+    let response = client
+        .post(format!("{}/collections/constrained/constraints", base_url))
+        .json(&json!({"constraint": "dependencies.quantity > 5"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 400);
+
+    let response = client
+        .post(format!("{}/collections/constrained/constraints", base_url))
+        .json(&json!({"constraint": "body.price > 0"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let response = client
+        .get(format!("{}/collections/constrained", base_url))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(
+        json["data"]["constraints"],
+        json!([
+            "body.total == body.price * dependencies.quantity",
+            "body.price > 0"
+        ])
+    );
+
+    let response = client
+        .delete(format!("{}/collections/constrained/constraints", base_url))
+        .json(&json!({"constraint": "body.price > 0"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let response = client
+        .delete(format!("{}/collections/constrained/constraints", base_url))
+        .json(&json!({"constraint": "body.price > 0"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 404);
+
+    let response = client
+        .post(format!("{}/collections", base_url))
+        .json(&json!({"name": "broken", "constraints": ["body.a =="]}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 400);
+
+    server.kill().unwrap();
+    cleanup_test_dir(&test_dir);
+}
+
+#[tokio::test]
+async fn test_id_strategy_and_custom_ids() {
+    let test_dir = setup_test_dir();
+    let port = 8091;
+
+    let mut server = start_test_server(&test_dir, port).await;
+
+    let client = reqwest::Client::new();
+    let base_url = format!("http://127.0.0.1:{}", port);
+
+    let response = client
+        .post(format!("{}/collections", base_url))
+        .json(&json!({"name": "numbered", "id_strategy": "counter"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let item = json!({
+        "body": {"name": "Item"},
+        "dependencies": {"category": "test"}
+    });
+
+    let response = client
+        .post(format!("{}/collections/numbered", base_url))
+        .json(&item)
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["id"], "00000000000000000001");
+
+    let response = client
+        .post(format!("{}/collections/numbered/chosen", base_url))
+        .json(&item)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["id"], "chosen");
+
+    let response = client
+        .post(format!("{}/collections/numbered/chosen", base_url))
+        .json(&item)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 409);
+
+    // Items named like the routes next to them could never be read back.
+    let response = client
+        .put(format!("{}/collections/numbered/trash/upsert", base_url))
+        .json(&item)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 400);
+
+    let response = client
+        .post(format!("{}/collections/numbered/keys", base_url))
+        .json(&item)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 400);
+
+    let response = client
+        .post(format!(
+            "{}/subcollections/numbered/sub?collection=numbered&dependencies={}",
+            base_url,
+            serde_json::to_string(&json!({"category": "test"})).unwrap()
+        ))
+        .json(&json!({"name": "Body"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let response = client
+        .get(format!("{}/collections/numbered/sub", base_url))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["body"], json!({"name": "Body"}));
+
+    let response = client
+        .get(format!("{}/collections/numbered", base_url))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["id_strategy"], "counter");
+
+    let response = client
+        .post(format!("{}/collections", base_url))
+        .json(&json!({"name": "bad", "id_strategy": "sequential"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 400);
+
+    server.kill().unwrap();
+    cleanup_test_dir(&test_dir);
+}
+
+#[tokio::test]
+async fn test_conditional_writes() {
+    let test_dir = setup_test_dir();
+    let port = 8092;
+
+    let mut server = start_test_server(&test_dir, port).await;
+
+    let client = reqwest::Client::new();
+    let base_url = format!("http://127.0.0.1:{}", port);
+
+    let response = client
+        .post(format!("{}/collections", base_url))
+        .json(&json!({"name": "locks"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let message = |owner: &str| {
+        json!({
+            "body": {"owner": owner},
+            "dependencies": {"resource": "printer"}
+        })
+    };
+
+    let response = client
+        .put(format!("{}/collections/locks/printer/upsert", base_url))
+        .json(&message("alice"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["created"], true);
+
+    let response = client
+        .put(format!("{}/collections/locks/printer/upsert", base_url))
+        .json(&message("bob"))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["created"], false);
+
+    let response = client
+        .post(format!(
+            "{}/collections/locks/printer/insert-if-absent",
+            base_url
+        ))
+        .json(&message("carol"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["inserted"], false);
+
+    // A stale expectation is a conflict reporting the current item.
+    let response = client
+        .post(format!(
+            "{}/collections/locks/printer/compare-and-swap",
+            base_url
+        ))
+        .json(&json!({"expected": message("alice"), "new": message("carol")}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 409);
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["success"], false);
+    assert_eq!(json["data"]["current"], message("bob"));
+
+    let response = client
+        .post(format!(
+            "{}/collections/locks/printer/compare-and-swap",
+            base_url
+        ))
+        .json(&json!({"expected": message("bob"), "new": null}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let response = client
+        .get(format!("{}/collections/locks/printer", base_url))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 404);
+
+    let dependencies = serde_json::to_string(&json!({"resource": "scanner"})).unwrap();
+
+    let response = client
+        .post(format!(
+            "{}/subcollections/locks/scanner/insert-if-absent?collection=locks&dependencies={}",
+            base_url, dependencies
+        ))
+        .json(&json!({"owner": "alice"}))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["inserted"], true);
+
+    let response = client
+        .post(format!(
+            "{}/subcollections/locks/scanner/compare-and-swap?collection=locks&dependencies={}",
+            base_url, dependencies
+        ))
+        .json(&json!({"expected": null, "new": {"owner": "bob"}}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 409);
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["current"], json!({"owner": "alice"}));
+
+    let response = client
+        .put(format!(
+            "{}/subcollections/locks/scanner/upsert?collection=locks&dependencies={}",
+            base_url, dependencies
+        ))
+        .json(&json!({"owner": "bob"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let response = client
+        .get(format!("{}/collections/locks/scanner", base_url))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["body"], json!({"owner": "bob"}));
+
+    server.kill().unwrap();
+    cleanup_test_dir(&test_dir);
+}
+
+#[tokio::test]
+async fn test_etags() {
+    let test_dir = setup_test_dir();
+    let port = 8093;
+
+    let mut server = start_test_server(&test_dir, port).await;
+
+    let client = reqwest::Client::new();
+    let base_url = format!("http://127.0.0.1:{}", port);
+
+    let response = client
+        .post(format!("{}/collections", base_url))
+        .json(&json!({"name": "documents"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let message = |text: &str| {
+        json!({
+            "body": {"text": text},
+            "dependencies": {"author": "alice"}
+        })
+    };
+
+    let response = client
+        .post(format!("{}/collections/documents", base_url))
+        .json(&message("draft"))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    let id = json["data"]["id"].as_str().unwrap().to_string();
+    let item_url = format!("{}/collections/documents/{}", base_url, id);
+
+    let response = client.get(&item_url).send().await.unwrap();
+
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["etag"], "\"1\"");
+
+    let response = client
+        .put(&item_url)
+        .header("If-Match", "\"1\"")
+        .json(&message("final"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["etag"], "\"2\"");
+
+    // The second writer read version 1 too and must not overwrite.
+    let response = client
+        .put(&item_url)
+        .header("If-Match", "\"1\"")
+        .json(&message("other"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 412);
+
+    let response = client
+        .delete(&item_url)
+        .header("If-Match", "\"1\"")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 412);
+
+    let response = client
+        .put(&item_url)
+        .header("If-Match", "*")
+        .json(&message("again"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let response = client.get(&item_url).send().await.unwrap();
+
+    assert_eq!(response.headers()["etag"], "\"3\"");
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"], message("again"));
+
+    let dependencies = serde_json::to_string(&json!({"author": "alice"})).unwrap();
+    let subcollection_url = format!(
+        "{}/subcollections/documents/{}?collection=documents&dependencies={}",
+        base_url, id, dependencies
+    );
+
+    let response = client.get(&subcollection_url).send().await.unwrap();
+
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["etag"], "\"3\"");
+
+    let response = client
+        .delete(&subcollection_url)
+        .header("If-Match", "\"3\"")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let response = client.get(&item_url).send().await.unwrap();
+
+    assert_eq!(response.status(), 404);
+
+    // Inserted again, the item does not match ETags from before the delete.
+    let response = client
+        .post(&item_url)
+        .json(&message("reborn"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let response = client
+        .put(&item_url)
+        .header("If-Match", "\"1\"")
+        .json(&message("stale"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 412);
+
+    let response = client.get(&item_url).send().await.unwrap();
+
+    assert_eq!(response.headers()["etag"], "\"5\"");
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"], message("reborn"));
+
+    server.kill().unwrap();
+    cleanup_test_dir(&test_dir);
+}
+
+// Reads the next Server-Sent Event of a change stream, skipping comments.
+async fn next_change(
+    response: &mut reqwest::Response,
+    buffer: &mut String,
+) -> (u64, String, Value) {
+    loop {
+        if let Some(end) = buffer.find("\n\n") {
+            let block: String = buffer.drain(..end + 2).collect();
+            if block.starts_with(':') {
+                continue;
+            }
+
+            let field = |name: &str| {
+                block
+                    .lines()
+                    .find_map(|line| line.strip_prefix(name))
+                    .unwrap()
+                    .to_string()
+            };
+
+            return (
+                field("id: ").parse().unwrap(),
+                field("event: "),
+                serde_json::from_str(&field("data: ")).unwrap(),
+            );
+        }
+
+        let chunk = time::timeout(Duration::from_secs(5), response.chunk())
+            .await
+            .expect("No change within 5 seconds")
+            .unwrap()
+            .expect("Change stream ended");
+        buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+}
+
+#[tokio::test]
+async fn test_change_stream() {
+    let test_dir = setup_test_dir();
+    let port = 8094;
+
+    let mut server = start_test_server(&test_dir, port).await;
+
+    let client = reqwest::Client::new();
+    let base_url = format!("http://127.0.0.1:{}", port);
+
+    let response = client
+        .post(format!("{}/collections", base_url))
+        .json(&json!({"name": "feed", "changes_retained": 100}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let message = |n: i64| {
+        json!({
+            "body": {"n": n},
+            "dependencies": {"topic": "news"}
+        })
+    };
+
+    let response = client
+        .post(format!("{}/collections/feed", base_url))
+        .json(&message(1))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    let id = json["data"]["id"].as_str().unwrap().to_string();
+
+    // Changes already made are replayed from `since`.
+    let mut stream = client
+        .get(format!("{}/collections/feed/changes?since=1", base_url))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(stream.status(), 200);
+    assert_eq!(stream.headers()["content-type"], "text/event-stream");
+
+    let mut buffer = String::new();
+    let (seq, kind, data) = next_change(&mut stream, &mut buffer).await;
+    assert_eq!((seq, kind.as_str()), (1, "insert"));
+    assert_eq!(data["id"], id.as_str());
+    assert_eq!(data["new"], message(1));
+
+    let dependencies = serde_json::to_string(&json!({"topic": "news"})).unwrap();
+    let mut sub_stream = client
+        .get(format!(
+            "{}/subcollections/feed/changes?collection=feed&dependencies={}",
+            base_url, dependencies
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(sub_stream.status(), 200);
+
+    client
+        .put(format!("{}/collections/feed/{}", base_url, id))
+        .json(&message(2))
+        .send()
+        .await
+        .unwrap();
+
+    let (seq, kind, data) = next_change(&mut stream, &mut buffer).await;
+    assert_eq!((seq, kind.as_str()), (2, "update"));
+    assert_eq!(data["old"], message(1));
+    assert_eq!(data["new"], message(2));
+
+    let mut sub_buffer = String::new();
+    let (seq, kind, data) = next_change(&mut sub_stream, &mut sub_buffer).await;
+    assert_eq!((seq, kind.as_str()), (2, "update"));
+    assert_eq!(data["new"], json!({"n": 2}));
+
+    client
+        .delete(format!("{}/collections/feed/{}", base_url, id))
+        .send()
+        .await
+        .unwrap();
+
+    // A reconnecting EventSource resumes after the last event it saw.
+    let mut resumed = client
+        .get(format!("{}/collections/feed/changes", base_url))
+        .header("Last-Event-ID", "2")
+        .send()
+        .await
+        .unwrap();
+
+    let (seq, kind, data) = next_change(&mut resumed, &mut String::new()).await;
+    assert_eq!((seq, kind.as_str()), (3, "delete"));
+    assert_eq!(data["old"], message(2));
+
+    // No event comes after the largest sequence number.
+    let response = client
+        .get(format!("{}/collections/feed/changes", base_url))
+        .header("Last-Event-ID", u64::MAX.to_string())
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 400);
+
+    let response = client
+        .get(format!("{}/collections/missing/changes", base_url))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 404);
+
+    let response = client
+        .get(format!("{}/collections/feed", base_url))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["changes_retained"], 100);
+
+    // Collections have no change feed unless created with one.
+    let response = client
+        .post(format!("{}/collections", base_url))
+        .json(&json!({"name": "quiet"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let response = client
+        .get(format!("{}/collections/quiet/changes", base_url))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 404);
+
+    server.kill().unwrap();
+    cleanup_test_dir(&test_dir);
+}
+
+#[tokio::test]
+async fn test_item_history() {
+    let test_dir = setup_test_dir();
+    let port = 8095;
+
+    let mut server = start_test_server(&test_dir, port).await;
+
+    let client = reqwest::Client::new();
+    let base_url = format!("http://127.0.0.1:{}", port);
+
+    let response = client
+        .post(format!("{}/collections", base_url))
+        .json(&json!({"name": "pages", "history": {"versions": 3}}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let message = |text: &str| {
+        json!({
+            "body": {"text": text},
+            "dependencies": {"site": "docs"}
+        })
+    };
+
+    let item_url = format!("{}/collections/pages/home", base_url);
+
+    for text in ["one", "two", "three", "four"] {
+        let response = client
+            .put(format!("{}/upsert", item_url))
+            .json(&message(text))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+    }
+
+    // Only the last three versions are retained.
+    let response = client
+        .get(format!("{}/history", item_url))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    let json: Value = response.json().await.unwrap();
+    let history = json["data"].as_array().unwrap();
+    let versions: Vec<u64> = history
+        .iter()
+        .map(|entry| entry["version"].as_u64().unwrap())
+        .collect();
+    assert_eq!(versions, vec![2, 3, 4]);
+    assert_eq!(history[0]["message"], message("two"));
+    let written_at = history[2]["timestamp"].as_u64().unwrap();
+
+    let response = client
+        .get(format!("{}/at?version=2", item_url))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"], message("two"));
+
+    let response = client
+        .get(format!("{}/at?time={}", item_url, written_at))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"], message("four"));
+
+    let response = client
+        .get(format!("{}/at?time=0", item_url))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 404);
+
+    let response = client
+        .get(format!("{}/at?version=1", item_url))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 404);
+
+    let response = client.get(format!("{}/at", item_url)).send().await.unwrap();
+
+    assert_eq!(response.status(), 400);
+
+    let response = client
+        .post(format!("{}/revert", item_url))
+        .json(&json!({"version": 2}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["etag"], "\"5\"");
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["version"], 5);
+
+    let response = client.get(&item_url).send().await.unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"], message("two"));
+
+    // Subcollections answer with bodies.
+    let dependencies = serde_json::to_string(&json!({"site": "docs"})).unwrap();
+    let response = client
+        .get(format!(
+            "{}/subcollections/pages/home/at?version=4&collection=pages&dependencies={}",
+            base_url, dependencies
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"], json!({"text": "four"}));
+
+    let response = client
+        .get(format!("{}/collections/pages", base_url))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["history"], json!({"versions": 3}));
+
+    server.kill().unwrap();
+    cleanup_test_dir(&test_dir);
+}
+
+#[tokio::test]
+async fn test_soft_delete() {
+    let test_dir = setup_test_dir();
+    let port = 8096;
+
+    let mut server = start_test_server(&test_dir, port).await;
+
+    let client = reqwest::Client::new();
+    let base_url = format!("http://127.0.0.1:{}", port);
+
+    let response = client
+        .post(format!("{}/collections", base_url))
+        .json(&json!({"name": "notes", "soft_delete": true}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let message = json!({
+        "body": {"text": "remember"},
+        "dependencies": {"owner": "bob"}
+    });
+
+    let response = client
+        .post(format!("{}/collections/notes/first", base_url))
+        .json(&message)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let item_url = format!("{}/collections/notes/first", base_url);
+    let trash_url = format!("{}/collections/notes/trash", base_url);
+
+    let response = client.delete(&item_url).send().await.unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let response = client.get(&item_url).send().await.unwrap();
+
+    assert_eq!(response.status(), 404);
+
+    let response = client.get(&trash_url).send().await.unwrap();
+
+    assert_eq!(response.status(), 200);
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"][0]["id"], "first");
+    assert_eq!(json["data"][0]["message"], message);
+
+    let dependencies = serde_json::to_string(&json!({"owner": "bob"})).unwrap();
+    let response = client
+        .get(format!(
+            "{}/subcollections/notes/trash?collection=notes&dependencies={}",
+            base_url, dependencies
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"][0]["message"], json!({"text": "remember"}));
+
+    let response = client
+        .post(format!("{}/restore", item_url))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let response = client.get(&item_url).send().await.unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"], message);
+
+    let response = client
+        .post(format!("{}/restore", item_url))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 404);
+
+    // Purging spares items deleted more recently than asked.
+    client.delete(&item_url).send().await.unwrap();
+
+    let response = client
+        .delete(format!("{}?older_than=3600", trash_url))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["purged"], 0);
+
+    let response = client.delete(&trash_url).send().await.unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["purged"], 1);
+
+    let response = client.get(&trash_url).send().await.unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"], json!([]));
+
+    let response = client
+        .get(format!("{}/collections/notes", base_url))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["soft_delete"], true);
+
+    server.kill().unwrap();
+    cleanup_test_dir(&test_dir);
+}
+
+#[tokio::test]
+async fn test_item_ttl() {
+    let test_dir = setup_test_dir();
+    let port = 8097;
+
+    let mut server = start_test_server_with_args(&test_dir, port, &["--sweep-interval", "1"]).await;
+
+    let client = reqwest::Client::new();
+    let base_url = format!("http://127.0.0.1:{}", port);
+
+    let response = client
+        .post(format!("{}/collections", base_url))
+        .json(&json!({"name": "sessions", "default_ttl": 3600}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let response = client
+        .get(format!("{}/collections/sessions", base_url))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"]["default_ttl"], 3600);
+
+    let message = json!({
+        "body": {"token": "abc"},
+        "dependencies": {"user": "ann"}
+    });
+
+    let response = client
+        .post(format!("{}/collections/sessions?ttl=1", base_url))
+        .json(&message)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    let json: Value = response.json().await.unwrap();
+    let short = json["data"]["id"].as_str().unwrap().to_string();
+
+    let response = client
+        .post(format!("{}/collections/sessions", base_url))
+        .json(&message)
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    let long = json["data"]["id"].as_str().unwrap().to_string();
+
+    let dependencies = serde_json::to_string(&json!({"user": "ann"})).unwrap();
+    let response = client
+        .post(format!(
+            "{}/subcollections/sessions?collection=sessions&dependencies={}&ttl=1",
+            base_url, dependencies
+        ))
+        .json(&json!({"token": "def"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    let response = client
+        .get(format!("{}/collections/sessions/{}", base_url, short))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    time::sleep(Duration::from_millis(2500)).await;
+
+    let response = client
+        .get(format!("{}/collections/sessions/{}", base_url, short))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 404);
+
+    let response = client
+        .get(format!(
+            "{}/subcollections/sessions/keys?collection=sessions&dependencies={}",
+            base_url, dependencies
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["data"], json!([long]));
+
+    server.kill().unwrap();
+    cleanup_test_dir(&test_dir);
+}